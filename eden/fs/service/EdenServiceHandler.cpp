@@ -60,6 +60,10 @@
 #include "eden/fs/utils/ProcUtil.h"
 #include "eden/fs/utils/StatTimes.h"
 
+#ifdef EDEN_HAVE_RUST_DATAPACK
+#include "eden/scm/lib/backingstore/c_api/HgNativeBackingStore.h" // @manual
+#endif
+
 using folly::Future;
 using folly::makeFuture;
 using folly::StringPiece;
@@ -1254,6 +1258,9 @@ void EdenServiceHandler::debugSetLogLevel(
   result.categoryCreated = !db.getCategoryOrNull(*category);
   folly::Logger(*category).getCategory()->setLevel(
       folly::stringToLogLevel(*level), inherit);
+#ifdef EDEN_HAVE_RUST_DATAPACK
+  HgNativeBackingStore::updateLogLevel();
+#endif
 }
 
 void EdenServiceHandler::getAccessCounts(
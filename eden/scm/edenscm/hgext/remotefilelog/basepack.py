@@ -253,6 +253,7 @@ class basepackstore(object):
                             self.ui.warn(_("deleting corrupt pack '%s'\n") % filepath)
                             util.tryunlink(filepath + self.PACKSUFFIX)
                             util.tryunlink(filepath + self.INDEXSUFFIX)
+                            util.tryunlink(filepath + self.BLOOMSUFFIX)
                         else:
                             self.ui.warn(
                                 _("detected corrupt pack '%s' - ignoring it\n")
@@ -304,6 +305,7 @@ class basepackstore(object):
                         self.ui.warn(_("deleting corrupt pack '%s'\n") % pack.path())
                         util.tryunlink(pack.packpath())
                         util.tryunlink(pack.indexpath())
+                        util.tryunlink(pack.path() + self.BLOOMSUFFIX)
             else:
                 for pack, err in badpacks:
                     if err != errno.ENOENT:
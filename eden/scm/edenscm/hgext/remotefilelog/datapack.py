@@ -25,11 +25,13 @@ NOBASEINDEXMARK = -2
 
 INDEXSUFFIX = ".dataidx"
 PACKSUFFIX = ".datapack"
+BLOOMSUFFIX = ".databloom"
 
 
 class datapackstore(basepack.basepackstore):
     INDEXSUFFIX = INDEXSUFFIX
     PACKSUFFIX = PACKSUFFIX
+    BLOOMSUFFIX = BLOOMSUFFIX
 
     def __init__(self, ui, path, deletecorruptpacks=False):
         super(datapackstore, self).__init__(
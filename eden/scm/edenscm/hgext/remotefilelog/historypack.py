@@ -33,6 +33,7 @@ ENTRYCOUNTSIZE = 4
 
 INDEXSUFFIX = ".histidx"
 PACKSUFFIX = ".histpack"
+BLOOMSUFFIX = ".histbloom"
 
 ANC_NODE = 0
 ANC_P1NODE = 1
@@ -44,6 +45,7 @@ ANC_COPYFROM = 4
 class historypackstore(basepack.basepackstore):
     INDEXSUFFIX = INDEXSUFFIX
     PACKSUFFIX = PACKSUFFIX
+    BLOOMSUFFIX = BLOOMSUFFIX
 
     def __init__(self, ui, path, deletecorruptpacks=False):
         super(historypackstore, self).__init__(
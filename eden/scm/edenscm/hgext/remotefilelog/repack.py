@@ -168,8 +168,10 @@ def _cleanuptemppacks(ui, packpath):
     extensions = [
         datapack.PACKSUFFIX,
         datapack.INDEXSUFFIX,
+        datapack.BLOOMSUFFIX,
         historypack.PACKSUFFIX,
         historypack.INDEXSUFFIX,
+        historypack.BLOOMSUFFIX,
     ]
 
     def _shouldhold(f):
@@ -245,14 +247,16 @@ def _cleanupoldpacks(ui, packpath, limit):
                 return
 
             root, ext = os.path.splitext(f)
-            try:
-                if ext == datapack.PACKSUFFIX:
-                    util.unlink(root + datapack.INDEXSUFFIX)
-                else:
-                    util.unlink(root + historypack.INDEXSUFFIX)
-            except OSError as ex:
-                if ex.errno != errno.ENOENT:
-                    raise
+            if ext == datapack.PACKSUFFIX:
+                suffixes = [datapack.INDEXSUFFIX, datapack.BLOOMSUFFIX]
+            else:
+                suffixes = [historypack.INDEXSUFFIX, historypack.BLOOMSUFFIX]
+            for suffix in suffixes:
+                try:
+                    util.unlink(root + suffix)
+                except OSError as ex:
+                    if ex.errno != errno.ENOENT:
+                        raise
 
             try:
                 util.unlink(f)
@@ -30,11 +30,67 @@ std::unique_ptr<folly::IOBuf> bytesToIOBuf(RustCBytes* bytes) {
       },
       reinterpret_cast<void*>(bytes));
 }
+
+/**
+ * Writes a message logged by the Rust code to the EdenFS log.
+ */
+void logFromRust(
+    RustLogLevel level,
+    const uint8_t* target,
+    uintptr_t targetLen,
+    const uint8_t* message,
+    uintptr_t messageLen) {
+  folly::StringPiece targetPiece{reinterpret_cast<const char*>(target),
+                                 targetLen};
+  folly::StringPiece messagePiece{reinterpret_cast<const char*>(message),
+                                  messageLen};
+  switch (level) {
+    case RustLogLevel::Off:
+      break;
+    case RustLogLevel::Error:
+      XLOG(ERR) << targetPiece << ": " << messagePiece;
+      break;
+    case RustLogLevel::Warn:
+      XLOG(WARN) << targetPiece << ": " << messagePiece;
+      break;
+    case RustLogLevel::Info:
+      XLOG(INFO) << targetPiece << ": " << messagePiece;
+      break;
+    case RustLogLevel::Debug:
+      XLOG(DBG4) << targetPiece << ": " << messagePiece;
+      break;
+    case RustLogLevel::Trace:
+      XLOG(DBG7) << targetPiece << ": " << messagePiece;
+      break;
+  }
+}
+
+/**
+ * Returns the most verbose Rust level that `logFromRust` writes when the
+ * messages of this file are logged at `level` or above.
+ */
+RustLogLevel toRustLogLevel(folly::LogLevel level) {
+  if (level <= folly::LogLevel::DBG7) {
+    return RustLogLevel::Trace;
+  } else if (level <= folly::LogLevel::DBG4) {
+    return RustLogLevel::Debug;
+  } else if (level <= folly::LogLevel::INFO) {
+    return RustLogLevel::Info;
+  } else if (level <= folly::LogLevel::WARN) {
+    return RustLogLevel::Warn;
+  } else if (level <= folly::LogLevel::ERR) {
+    return RustLogLevel::Error;
+  }
+  return RustLogLevel::Off;
+}
 } // namespace
 
 HgNativeBackingStore::HgNativeBackingStore(
     folly::StringPiece repository,
     bool useEdenApi) {
+  rust_backingstore_set_log_callback(logFromRust);
+  updateLogLevel();
+
   RustCFallible<RustBackingStore> store(
       rust_backingstore_new(repository.data(), repository.size(), useEdenApi),
       rust_backingstore_free);
@@ -46,6 +102,14 @@ HgNativeBackingStore::HgNativeBackingStore(
   store_ = store.unwrap();
 }
 
+void HgNativeBackingStore::setLogLevel(RustLogLevel level) {
+  rust_backingstore_set_log_level(static_cast<uint8_t>(level));
+}
+
+void HgNativeBackingStore::updateLogLevel() {
+  setLogLevel(toRustLogLevel(XLOG_GET_CATEGORY()->getEffectiveLevel()));
+}
+
 std::unique_ptr<folly::IOBuf> HgNativeBackingStore::getBlob(
     folly::ByteRange name,
     folly::ByteRange node) {
@@ -82,5 +146,33 @@ std::shared_ptr<RustTree> HgNativeBackingStore::getTree(folly::ByteRange node) {
 
   return manifest.unwrap();
 }
+
+std::shared_ptr<RustPathChanges> HgNativeBackingStore::getChangesSince(
+    folly::ByteRange from,
+    folly::ByteRange to,
+    folly::StringPiece prefix) {
+  XLOG(DBG7) << "Diffing tree from=" << folly::hexlify(from)
+             << " to=" << folly::hexlify(to) << " prefix=" << prefix;
+
+  RustCFallible<RustPathChanges> changes(
+      rust_backingstore_get_changes_since(
+          store_.get(),
+          from.data(),
+          from.size(),
+          to.data(),
+          to.size(),
+          reinterpret_cast<const uint8_t*>(prefix.data()),
+          prefix.size()),
+      rust_path_changes_free);
+
+  if (changes.isError()) {
+    XLOG(DBG5) << "Error while diffing tree from=" << folly::hexlify(from)
+               << " to=" << folly::hexlify(to) << " prefix=" << prefix
+               << " in backingstore: " << changes.getError();
+    return nullptr;
+  }
+
+  return changes.unwrap();
+}
 } // namespace eden
 } // namespace facebook
@@ -28,6 +28,26 @@ class HgNativeBackingStore {
 
   std::shared_ptr<RustTree> getTree(folly::ByteRange node);
 
+  /**
+   * Returns the files that differ between the trees `from` and `to` under the
+   * directory `prefix`. An empty `prefix` covers the whole repository.
+   */
+  std::shared_ptr<RustPathChanges> getChangesSince(
+      folly::ByteRange from,
+      folly::ByteRange to,
+      folly::StringPiece prefix);
+
+  /**
+   * Sets the most verbose level of the Rust messages written to the EdenFS log.
+   */
+  static void setLogLevel(RustLogLevel level);
+
+  /**
+   * Sets the level of the Rust messages from the effective level of the XLOG
+   * category of this class. Call it again after changing the EdenFS log levels.
+   */
+  static void updateLogLevel();
+
  private:
   std::unique_ptr<RustBackingStore, std::function<void(RustBackingStore*)>>
       store_;
@@ -7,7 +7,7 @@
  * This file is generated with cbindgen. Please run `./tools/cbindgen.sh` to
  * update this file.
  *
- * @generated SignedSource<<a2648dfe5f7e8cfabc80a9e2da5bf690>>
+ * @generated SignedSource<<2354ca9a9a42705d0c2d5dbd6d367251>>
  *
  */
 
@@ -76,6 +76,21 @@ public:
 #include <cstdlib>
 #include <new>
 
+enum class RustChangeType : uint8_t {
+  Added,
+  Removed,
+  Modified,
+};
+
+enum class RustLogLevel : uint8_t {
+  Off,
+  Error,
+  Warn,
+  Info,
+  Debug,
+  Trace,
+};
+
 enum class RustTreeEntryType : uint8_t {
   Tree,
   RegularFile,
@@ -101,6 +116,26 @@ operator folly::ByteRange() const {
 }
 };
 
+/// Receives a message with its level and target, which is usually the module path of the code
+/// logging it. Both strings are UTF-8, and are only valid for the duration of the call.
+using RustLogCallback = void(*)(RustLogLevel level,
+                                const uint8_t *target,
+                                uintptr_t target_len,
+                                const uint8_t *message,
+                                uintptr_t message_len);
+
+struct RustPathChange {
+  RustCBytes path;
+  RustChangeType change_type;
+};
+
+struct RustPathChanges {
+  const RustPathChange *entries;
+  /// This makes sure `entries` above is pointing to a valid memory.
+  RustVec<RustPathChange> *entries_ptr;
+  uintptr_t length;
+};
+
 struct RustTreeEntry {
   RustCBytes hash;
   RustCBytes name;
@@ -127,6 +162,14 @@ RustCFallibleBase rust_backingstore_get_blob(RustBackingStore *store,
                                                          const uint8_t *node,
                                                          uintptr_t node_len);
 
+RustCFallibleBase rust_backingstore_get_changes_since(RustBackingStore *store,
+                                                                       const uint8_t *from,
+                                                                       uintptr_t from_len,
+                                                                       const uint8_t *to,
+                                                                       uintptr_t to_len,
+                                                                       const uint8_t *prefix,
+                                                                       uintptr_t prefix_len);
+
 RustCFallibleBase rust_backingstore_get_tree(RustBackingStore *store,
                                                        const uint8_t *node,
                                                        uintptr_t node_len);
@@ -135,10 +178,19 @@ RustCFallibleBase rust_backingstore_new(const char *repository,
                                                           size_t repository_len,
                                                           bool use_edenapi);
 
+/// Sends the messages to `callback`. A null `callback` discards the messages.
+void rust_backingstore_set_log_callback(RustLogCallback callback);
+
+/// Logs the messages at `level` and the levels above it, and discards the others. `level` is a
+/// `LogLevel`. Other values are ignored.
+void rust_backingstore_set_log_level(uint8_t level);
+
 void rust_cbytes_free(RustCBytes *vec);
 
 void rust_cfallible_free_error(char *ptr);
 
+void rust_path_changes_free(RustPathChanges *changes);
+
 RustCBytes rust_test_cbytes();
 
 /// Returns a `CFallible` with error message "failure!". This function is intended to be called
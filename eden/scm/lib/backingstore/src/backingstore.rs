@@ -10,8 +10,9 @@ use anyhow::Result;
 use configparser::config::ConfigSet;
 use configparser::hg::ConfigSetHgExt;
 use edenapi::{EdenApi, EdenApiCurlClient};
-use manifest::{List, Manifest};
+use manifest::{DiffEntry, List, Manifest};
 use manifest_tree::TreeManifest;
+use pathmatcher::{plain_to_glob, AlwaysMatcher, Matcher, TreeMatcher};
 use revisionstore::{ContentStore, ContentStoreBuilder, DataStore, EdenApiRemoteStore};
 use std::path::Path;
 use std::sync::Arc;
@@ -28,7 +29,14 @@ impl BackingStore {
         let mut config = ConfigSet::new();
         config.load_system();
         config.load_user();
-        config.load_hgrc(hg.join("hgrc"), "repository");
+        let (_, ignored) = config.load_repo_hgrc(repository.as_ref());
+        for item in ignored {
+            log::warn!(
+                "ignoring untrusted config {} in {}",
+                item,
+                hg.join("hgrc").display()
+            );
+        }
 
         let store_path = hg.join("store");
         let blobstore = ContentStoreBuilder::new(&store_path, &config);
@@ -82,6 +90,33 @@ impl BackingStore {
 
         manifest.list(RepoPath::empty())
     }
+
+    /// Returns the files that differ between the trees `from` and `to`, limited to the
+    /// directory `prefix`. An empty `prefix` covers the whole repository.
+    ///
+    /// Unchanged subtrees are skipped by comparing their hashes, so this only reads the trees
+    /// along the changed paths.
+    pub fn get_changes_since(
+        &self,
+        from: &[u8],
+        to: &[u8],
+        prefix: &[u8],
+    ) -> Result<Vec<DiffEntry>> {
+        let from = TreeManifest::durable(self.treestore.clone(), Node::from_slice(from)?);
+        let to = TreeManifest::durable(self.treestore.clone(), Node::from_slice(to)?);
+        let matcher = prefix_matcher(RepoPath::from_utf8(prefix)?)?;
+
+        from.diff(&to, &matcher).collect()
+    }
+}
+
+/// Returns a matcher for the files under the directory `prefix`.
+fn prefix_matcher(prefix: &RepoPath) -> Result<Box<dyn Matcher>> {
+    if prefix.is_empty() {
+        return Ok(Box::new(AlwaysMatcher::new()));
+    }
+    let rule = format!("{}/**", plain_to_glob(prefix.as_str()));
+    Ok(Box::new(TreeMatcher::from_rules(std::iter::once(rule))?))
 }
 
 /// Removes the possible metadata header at the beginning of a blob.
@@ -114,6 +149,18 @@ fn discard_metadata_header(data: Vec<u8>) -> Vec<u8> {
     }
 }
 
+#[test]
+fn test_prefix_matcher() {
+    let matcher = prefix_matcher(RepoPath::from_str("a/b*").unwrap()).unwrap();
+    assert!(matcher.matches_file(RepoPath::from_str("a/b*/c").unwrap()));
+    assert!(matcher.matches_file(RepoPath::from_str("a/b*/c/d").unwrap()));
+    assert!(!matcher.matches_file(RepoPath::from_str("a/bc/d").unwrap()));
+    assert!(!matcher.matches_file(RepoPath::from_str("a/c").unwrap()));
+
+    let matcher = prefix_matcher(RepoPath::empty()).unwrap();
+    assert!(matcher.matches_file(RepoPath::from_str("a/c").unwrap()));
+}
+
 #[test]
 fn test_discard_metadata_header() {
     assert_eq!(discard_metadata_header(vec![]), Vec::<u8>::new());
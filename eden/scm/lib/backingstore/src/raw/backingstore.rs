@@ -13,7 +13,7 @@ use std::convert::TryInto;
 use std::{slice, str};
 
 use crate::backingstore::BackingStore;
-use crate::raw::{CBytes, CFallible, Tree};
+use crate::raw::{CBytes, CFallible, PathChanges, Tree};
 
 fn stringpiece_to_slice<'a, T, U>(ptr: *const T, length: size_t) -> Result<&'a [U]> {
     ensure!(!ptr.is_null(), "string ptr is null");
@@ -104,6 +104,52 @@ pub extern "C" fn rust_backingstore_get_tree(
     backingstore_get_tree(store, node, node_len).into()
 }
 
+fn backingstore_get_changes_since(
+    store: *mut BackingStore,
+    from: *const u8,
+    from_len: usize,
+    to: *const u8,
+    to_len: usize,
+    prefix: *const u8,
+    prefix_len: usize,
+) -> Result<*mut PathChanges> {
+    assert!(!store.is_null());
+    let store = unsafe { &*store };
+    let from = stringpiece_to_slice(from, from_len)?;
+    let to = stringpiece_to_slice(to, to_len)?;
+    // A default `folly::StringPiece` has a null pointer. It is an empty prefix.
+    let prefix: &[u8] = if prefix_len == 0 {
+        &[]
+    } else {
+        stringpiece_to_slice(prefix, prefix_len)?
+    };
+
+    store
+        .get_changes_since(from, to, prefix)
+        .map(PathChanges::from)
+        .map(|result| Box::into_raw(Box::new(result)))
+}
+
+#[no_mangle]
+pub extern "C" fn rust_backingstore_get_changes_since(
+    store: *mut BackingStore,
+    from: *const u8,
+    from_len: usize,
+    to: *const u8,
+    to_len: usize,
+    prefix: *const u8,
+    prefix_len: usize,
+) -> CFallible<PathChanges> {
+    backingstore_get_changes_since(store, from, from_len, to, to_len, prefix, prefix_len).into()
+}
+
+#[no_mangle]
+pub extern "C" fn rust_path_changes_free(changes: *mut PathChanges) {
+    assert!(!changes.is_null());
+    let changes = unsafe { Box::from_raw(changes) };
+    drop(changes);
+}
+
 #[no_mangle]
 pub extern "C" fn rust_tree_free(tree: *mut Tree) {
     assert!(!tree.is_null());
@@ -0,0 +1,72 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Representation of the files changed between two trees, as returned to EdenFS.
+
+use crate::raw::CBytes;
+use manifest::{DiffEntry, DiffType};
+
+#[repr(u8)]
+pub enum ChangeType {
+    Added,
+    Removed,
+    Modified,
+}
+
+impl From<&DiffType> for ChangeType {
+    fn from(diff_type: &DiffType) -> Self {
+        match diff_type {
+            DiffType::LeftOnly(_) => ChangeType::Removed,
+            DiffType::RightOnly(_) => ChangeType::Added,
+            DiffType::Changed(_, _) => ChangeType::Modified,
+        }
+    }
+}
+
+#[repr(C)]
+pub struct PathChange {
+    path: CBytes,
+    change_type: ChangeType,
+}
+
+impl From<DiffEntry> for PathChange {
+    fn from(entry: DiffEntry) -> Self {
+        PathChange {
+            change_type: (&entry.diff_type).into(),
+            path: entry.path.into_string().into_bytes().into(),
+        }
+    }
+}
+
+#[repr(C)]
+pub struct PathChanges {
+    entries: *const PathChange,
+    /// This makes sure `entries` above is pointing to a valid memory.
+    entries_ptr: *mut Vec<PathChange>,
+    length: usize,
+}
+
+impl From<Vec<DiffEntry>> for PathChanges {
+    fn from(entries: Vec<DiffEntry>) -> Self {
+        let entries: Box<Vec<PathChange>> =
+            Box::new(entries.into_iter().map(PathChange::from).collect());
+        let length = entries.len();
+
+        PathChanges {
+            entries: entries.as_ptr(),
+            entries_ptr: Box::into_raw(entries),
+            length,
+        }
+    }
+}
+
+impl Drop for PathChanges {
+    fn drop(&mut self) {
+        let entries = unsafe { Box::from_raw(self.entries_ptr) };
+        drop(entries);
+    }
+}
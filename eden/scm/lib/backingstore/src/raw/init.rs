@@ -10,10 +10,10 @@ use std::sync::Once;
 static RUST_INIT: Once = Once::new();
 
 /// We use this function to ensure everything we need to initialized as the Rust code may not be
-/// called when EdenFS starts. Right now it only calls `env_logger::init` so we can see logs from
-/// `edenapi` and other crates. In longer term we should bridge the logs to folly logging.
+/// called when EdenFS starts. Right now it only installs the logger so we can see logs from
+/// `edenapi` and other crates. See `crate::raw::logging` to bridge the logs to folly logging.
 pub(crate) fn backingstore_global_init() {
     RUST_INIT.call_once(|| {
-        env_logger::init();
+        super::logging::init();
     });
 }
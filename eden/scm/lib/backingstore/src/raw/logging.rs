@@ -0,0 +1,282 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Routes the `log` records and `tracing` events emitted by Rust code to EdenFS.
+//!
+//! EdenFS registers a callback with `rust_backingstore_set_log_callback` to write the messages to
+//! its own log, and changes the verbosity at runtime with `rust_backingstore_set_log_level`.
+//! Messages are written to stderr while no callback is registered.
+//!
+//! `RUST_LOG` takes `env_logger` filters, like `edenapi=debug,revisionstore=trace`. Messages
+//! must pass both these filters and the runtime level.
+
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::RwLock;
+
+use env_logger::filter::{self, Filter};
+use once_cell::sync::{Lazy, OnceCell};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Record},
+    subscriber::Interest,
+    Event, Id, Metadata, Subscriber,
+};
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn from_u8(level: u8) -> Option<Self> {
+        match level {
+            0 => Some(LogLevel::Off),
+            1 => Some(LogLevel::Error),
+            2 => Some(LogLevel::Warn),
+            3 => Some(LogLevel::Info),
+            4 => Some(LogLevel::Debug),
+            5 => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+
+    fn to_level_filter(self) -> log::LevelFilter {
+        match self {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+fn to_log_level(level: &tracing::Level) -> log::Level {
+    match *level {
+        tracing::Level::ERROR => log::Level::Error,
+        tracing::Level::WARN => log::Level::Warn,
+        tracing::Level::INFO => log::Level::Info,
+        tracing::Level::DEBUG => log::Level::Debug,
+        tracing::Level::TRACE => log::Level::Trace,
+    }
+}
+
+/// Receives a message with its level and target, which is usually the module path of the code
+/// logging it. Both strings are UTF-8, and are only valid for the duration of the call.
+pub type LogCallback = extern "C" fn(
+    level: LogLevel,
+    target: *const u8,
+    target_len: usize,
+    message: *const u8,
+    message_len: usize,
+);
+
+/// The most verbose level that is logged, as a `LogLevel`.
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Error as u8);
+/// The filters from `RUST_LOG`, if it is set.
+static FILTER: OnceCell<Filter> = OnceCell::new();
+static CALLBACK: Lazy<RwLock<Option<LogCallback>>> = Lazy::new(|| RwLock::new(None));
+static NEXT_SPAN_ID: AtomicU64 = AtomicU64::new(1);
+
+fn enabled(metadata: &log::Metadata) -> bool {
+    if LogLevel::from(metadata.level()) as u8 > LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+    match FILTER.get() {
+        Some(filter) => filter.enabled(metadata),
+        None => true,
+    }
+}
+
+fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    log::set_max_level(level.to_level_filter());
+}
+
+fn emit(level: LogLevel, target: &str, message: &str) {
+    // Do not hold the lock while calling the callback, which might log or change the callback.
+    let callback = *CALLBACK.read().unwrap();
+    match callback {
+        Some(callback) => callback(
+            level,
+            target.as_ptr(),
+            target.len(),
+            message.as_ptr(),
+            message.len(),
+        ),
+        None => eprintln!("[{:?} {}] {}", level, target, message),
+    }
+}
+
+/// Forwards the `log` records and `tracing` events to `emit`. Spans are ignored.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if enabled(record.metadata()) {
+            let level = record.level().into();
+            emit(level, record.target(), &record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl Subscriber for Logger {
+    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        // The level can change at any time, so `enabled` is checked for every event.
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        enabled(
+            &log::Metadata::builder()
+                .level(to_log_level(metadata.level()))
+                .target(metadata.target())
+                .build(),
+        )
+    }
+
+    fn new_span(&self, _span: &Attributes) -> Id {
+        Id::from_u64(NEXT_SPAN_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn record(&self, _span: &Id, _values: &Record) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event) {
+        let metadata = event.metadata();
+        let mut message = MessageVisitor(String::new());
+        event.record(&mut message);
+        let level = to_log_level(metadata.level()).into();
+        emit(level, metadata.target(), &message.0);
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+/// Formats the fields of an event as its message followed by ` name=value` pairs.
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let fields = std::mem::replace(&mut self.0, format!("{:?}", value));
+            self.0.push_str(&fields);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Installs the logger. The filters and the initial level are read from `RUST_LOG`, like
+/// `env_logger` does.
+pub(crate) fn init() {
+    let level = match std::env::var("RUST_LOG") {
+        Ok(filters) => {
+            let filter = filter::Builder::new().parse(&filters).build();
+            let level = filter.filter();
+            let _ = FILTER.set(filter);
+            LogLevel::from_u8(level as u8).unwrap_or(LogLevel::Error)
+        }
+        Err(_) => LogLevel::Error,
+    };
+    set_level(level);
+    static LOGGER: Logger = Logger;
+    let _ = log::set_logger(&LOGGER);
+    let _ = tracing::subscriber::set_global_default(Logger);
+}
+
+/// Logs the messages at `level` and the levels above it, and discards the others. `level` is a
+/// `LogLevel`. Other values are ignored.
+#[no_mangle]
+pub extern "C" fn rust_backingstore_set_log_level(level: u8) {
+    super::init::backingstore_global_init();
+    match LogLevel::from_u8(level) {
+        Some(level) => set_level(level),
+        None => log::warn!("ignored invalid log level {}", level),
+    }
+}
+
+/// Sends the messages to `callback` instead of stderr. A null `callback` restores stderr.
+#[no_mangle]
+pub extern "C" fn rust_backingstore_set_log_callback(callback: Option<LogCallback>) {
+    super::init::backingstore_global_init();
+    *CALLBACK.write().unwrap() = callback;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{slice, str, sync::Mutex};
+
+    static MESSAGES: Lazy<Mutex<Vec<(LogLevel, String)>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+    extern "C" fn callback(
+        level: LogLevel,
+        _target: *const u8,
+        _target_len: usize,
+        message: *const u8,
+        message_len: usize,
+    ) {
+        let message = unsafe { slice::from_raw_parts(message, message_len) };
+        let message = str::from_utf8(message).unwrap().to_string();
+        MESSAGES.lock().unwrap().push((level, message));
+    }
+
+    #[test]
+    fn test_log_callback() {
+        rust_backingstore_set_log_callback(Some(callback));
+        rust_backingstore_set_log_level(LogLevel::Info as u8);
+        tracing::info!(path = "a/b", "fetching tree");
+        tracing::debug!("not logged");
+        log::warn!("fetching {}", "blob");
+        log::debug!("not logged");
+        rust_backingstore_set_log_level(LogLevel::Debug as u8);
+        rust_backingstore_set_log_level(42);
+        tracing::debug!("logged");
+        rust_backingstore_set_log_callback(None);
+
+        assert_eq!(
+            *MESSAGES.lock().unwrap(),
+            vec![
+                (LogLevel::Info, "fetching tree path=\"a/b\"".to_string()),
+                (LogLevel::Warn, "fetching blob".to_string()),
+                (LogLevel::Warn, "ignored invalid log level 42".to_string()),
+                (LogLevel::Debug, "logged".to_string()),
+            ]
+        );
+    }
+}
@@ -13,10 +13,14 @@
 mod backingstore;
 mod cbytes;
 mod cfallible;
+mod changes;
 mod init;
+mod logging;
 mod tests;
 mod tree;
 
 pub use cbytes::CBytes;
 pub use cfallible::CFallible;
+pub use changes::PathChanges;
+pub use logging::{LogCallback, LogLevel};
 pub use tree::Tree;
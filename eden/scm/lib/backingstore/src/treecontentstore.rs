@@ -8,7 +8,7 @@
 use anyhow::{format_err, Result};
 use bytes::Bytes;
 use manifest_tree::TreeStore;
-use revisionstore::{ContentStore, DataStore};
+use revisionstore::{ContentStore, DataStore, Delta, Metadata, MutableDeltaStore};
 use types::{HgId, Key, RepoPath};
 
 pub(crate) struct TreeContentStore {
@@ -31,7 +31,17 @@ impl TreeStore for TreeContentStore {
         })
     }
 
-    fn insert(&self, _path: &RepoPath, _hgid: HgId, _data: Bytes) -> Result<()> {
-        Err(format_err!("insert is not implemented."))
+    fn insert(&self, path: &RepoPath, hgid: HgId, data: Bytes) -> Result<()> {
+        let delta = Delta {
+            data,
+            base: None,
+            key: Key::new(path.to_owned(), hgid),
+        };
+        self.inner.add(&delta, &Metadata::default())
+    }
+
+    fn flush(&self) -> Result<()> {
+        MutableDeltaStore::flush(&self.inner)?;
+        Ok(())
     }
 }
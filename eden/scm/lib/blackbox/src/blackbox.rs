@@ -7,6 +7,7 @@
 
 use super::{capture_pattern, json, match_pattern};
 use crate::event::Event;
+use crate::summary::DurationSummary;
 use anyhow::Result;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use indexedlog::log::IndexOutput;
@@ -14,10 +15,10 @@ use indexedlog::rotate::{OpenOptions, RotateLog, RotateLowLevelExt};
 use lazy_static::lazy_static;
 use serde_json::Value;
 use std::cell::Cell;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, VecDeque};
 use std::fs;
 use std::io::{Cursor, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 /// Local, rotated log consists of events tagged with "Invocation ID" and
@@ -30,15 +31,30 @@ pub struct Blackbox {
     // An ID that can be "grouped by" to figure everything about a session.
     pub(crate) session_id: u64,
 
+    // The directory of the log. `None` if the log is in memory.
+    pub(crate) path: Option<PathBuf>,
+
+    // The duration summaries of an in-memory log.
+    pub(crate) summaries: Vec<DurationSummary>,
+
     // The on-disk files are considered bad (ex. no permissions, or no disk space)
     // and further write attempts will be ignored.
     is_broken: Cell<bool>,
+
+    // Short of disk space. Only high severity entries are kept, in `pending`,
+    // until there is enough space to write them.
+    is_degraded: bool,
+    pending: VecDeque<Vec<u8>>,
+
+    // Timestamp of the last free space check.
+    space_checked_ms: u64,
 }
 
 #[derive(Copy, Clone)]
 pub struct BlackboxOptions {
     max_bytes_per_log: u64,
     max_log_count: u8,
+    min_free_space: u64,
 }
 
 /// A wrapper for some serializable data.
@@ -68,6 +84,13 @@ pub trait ToValue {
 // In case the format changes in the future, a simple strategy will be just
 // renaming the directory used for logging.
 
+/// Number of high severity entries kept in memory while short of disk space.
+/// Older entries are dropped first.
+const PENDING_ENTRY_COUNT: usize = 64;
+
+/// Minimal interval between free space checks.
+const SPACE_CHECK_INTERVAL_MS: u64 = 1000;
+
 const TIMESTAMP_BYTES: usize = 8;
 const SESSION_ID_BYTES: usize = 8;
 const HEADER_BYTES: usize = TIMESTAMP_BYTES + SESSION_ID_BYTES;
@@ -91,7 +114,12 @@ impl BlackboxOptions {
             opts: self,
             // pid is used as an initial guess of "unique" session id
             session_id: new_session_id(),
+            path: Some(path.to_path_buf()),
+            summaries: Vec::new(),
             is_broken: Cell::new(false),
+            is_degraded: false,
+            pending: VecDeque::new(),
+            space_checked_ms: 0,
         };
         Ok(blackbox)
     }
@@ -104,7 +132,12 @@ impl BlackboxOptions {
             opts: self,
             // pid is used as an initial guess of "unique" session id
             session_id: new_session_id(),
+            path: None,
+            summaries: Vec::new(),
             is_broken: Cell::new(false),
+            is_degraded: false,
+            pending: VecDeque::new(),
+            space_checked_ms: 0,
         })
     }
 
@@ -112,6 +145,7 @@ impl BlackboxOptions {
         Self {
             max_bytes_per_log: 100_000_000,
             max_log_count: 3,
+            min_free_space: 100_000_000,
         }
     }
 
@@ -125,6 +159,18 @@ impl BlackboxOptions {
         self
     }
 
+    /// Stop writing to disk if the free space of the log directory drops below
+    /// `bytes`. Only high severity events are kept in memory until there is
+    /// enough space again.
+    ///
+    /// Defaults to 100 MB. This applies to every user of the blackbox: on a
+    /// disk with less free space than that, low severity events are dropped.
+    /// Set it to 0 to always write to disk.
+    pub fn min_free_space(mut self, bytes: u64) -> Self {
+        self.min_free_space = bytes;
+        self
+    }
+
     fn rotate_log_open_options(&self) -> OpenOptions {
         OpenOptions::new()
             .max_bytes_per_log(self.max_bytes_per_log)
@@ -250,25 +296,77 @@ impl Blackbox {
     ///
     /// If an error happens, `log` will try to rotate the bad logs and retry.
     /// If it still fails, `log` will simply give up.
+    ///
+    /// If the disk is short of space, only high severity events are kept, in
+    /// memory. They are written by a later `sync` once there is enough space.
     pub fn log(&mut self, data: &Event) {
         if self.is_broken.get() {
             return;
         }
 
         let now = time_to_u64(&SystemTime::now());
+        self.check_free_space(now);
+        if self.is_degraded && !data.is_high_severity() {
+            return;
+        }
         if let Some(buf) = Entry::to_vec(data, now, self.session_id) {
-            let _ = self.log.append(&buf);
+            if self.is_degraded {
+                self.push_pending(buf);
+            } else if self.log.append(&buf).is_err() {
+                // Appending can write to disk. Check whether the disk is full,
+                // and keep the event for later if it is.
+                self.space_checked_ms = 0;
+                self.check_free_space(now);
+                if self.is_degraded && data.is_high_severity() {
+                    self.push_pending(buf);
+                }
+            }
         }
     }
 
+    /// Keep an entry in memory until there is enough space to write it.
+    /// The oldest entries are dropped if there are too many.
+    fn push_pending(&mut self, buf: Vec<u8>) {
+        if self.pending.len() >= PENDING_ENTRY_COUNT {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(buf);
+    }
+
     /// Write buffered data to disk.
+    ///
+    /// If the disk is short of space, nothing is written.
     pub fn sync(&mut self) {
-        if !self.is_broken.get() {
+        if self.is_broken.get() {
+            return;
+        }
+        let now = time_to_u64(&SystemTime::now());
+        self.check_free_space(now);
+        if !self.is_degraded {
             // Ignore failures.
             let _ = self.log.sync();
         }
     }
 
+    /// Update `is_degraded` from the free space of the log directory.
+    /// Leaving the degraded mode appends the pending entries to the log.
+    fn check_free_space(&mut self, now: u64) {
+        if now.saturating_sub(self.space_checked_ms) < SPACE_CHECK_INTERVAL_MS {
+            return;
+        }
+        self.space_checked_ms = now;
+        let is_degraded = match &self.path {
+            Some(path) => free_space(path).map_or(false, |free| free < self.opts.min_free_space),
+            None => false,
+        };
+        if self.is_degraded && !is_degraded {
+            for buf in self.pending.drain(..) {
+                let _ = self.log.append(&buf);
+            }
+        }
+        self.is_degraded = is_degraded;
+    }
+
     /// Filter blackbox by patterns.
     /// See `match_pattern.rs` for how to specify patterns.
     ///
@@ -463,7 +561,7 @@ impl Entry {
         }
     }
 
-    fn from_slice(bytes: &[u8]) -> Option<Self> {
+    pub(crate) fn from_slice(bytes: &[u8]) -> Option<Self> {
         if bytes.len() >= HEADER_BYTES {
             let mut cur = Cursor::new(bytes);
             let timestamp = cur.read_u64::<BigEndian>().unwrap();
@@ -508,6 +606,27 @@ fn u64_to_boxed_slice(value: u64) -> Box<[u8]> {
     (&u64_to_slice(value)[..]).to_vec().into_boxed_slice()
 }
 
+/// Free space, in bytes, of the file system containing `path`.
+/// `None` if it cannot be determined.
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } == 0 {
+        Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
 fn time_to_u64(time: &SystemTime) -> u64 {
     time.duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
@@ -616,6 +735,41 @@ pub(crate) mod tests {
         assert_eq!(query(2), &events[4..5]);
     }
 
+    #[test]
+    fn test_short_of_disk_space() {
+        let dir = tempdir().unwrap();
+        let mut blackbox = BlackboxOptions::new()
+            .min_free_space(u64::max_value())
+            .open(&dir.path())
+            .unwrap();
+
+        let exception = Event::Exception {
+            msg: "x".to_string(),
+        };
+        blackbox.log(&Event::Alias {
+            from: "a".to_string(),
+            to: "b".to_string(),
+        });
+        for _ in 0..PENDING_ENTRY_COUNT + 1 {
+            blackbox.log(&exception);
+        }
+        blackbox.sync();
+        assert_eq!(blackbox.pending.len(), PENDING_ENTRY_COUNT);
+        assert!(all_entries(&blackbox).is_empty());
+
+        // With enough space, pending entries are written.
+        blackbox.opts.min_free_space = 0;
+        blackbox.space_checked_ms = 0;
+        blackbox.sync();
+        assert!(blackbox.pending.is_empty());
+        let entries = all_entries(&blackbox);
+        assert_eq!(entries.len(), PENDING_ENTRY_COUNT);
+        assert!(entries.iter().all(|e| e.data == exception));
+
+        let blackbox = BlackboxOptions::new().open(&dir.path()).unwrap();
+        assert_eq!(all_entries(&blackbox).len(), PENDING_ENTRY_COUNT);
+    }
+
     pub(crate) fn all_entries(blackbox: &Blackbox) -> Vec<Entry> {
         let session_ids = blackbox.session_ids_by_pattern(&json!("_"));
         session_ids
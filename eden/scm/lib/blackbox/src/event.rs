@@ -513,6 +513,16 @@ impl Event {
     pub fn from_json(json: &str) -> Result<Self> {
         Ok(serde_json::from_str(json)?)
     }
+
+    /// Whether the event is still logged when the blackbox is short of disk space.
+    ///
+    /// These are the events that describe how a process started and ended.
+    pub fn is_high_severity(&self) -> bool {
+        match self {
+            Event::Exception { .. } | Event::Finish { .. } | Event::Start { .. } => true,
+            _ => false,
+        }
+    }
 }
 
 impl ToValue for Event {
@@ -18,9 +18,11 @@
 mod blackbox;
 mod match_pattern;
 mod singleton;
+mod summary;
 
 pub use self::blackbox::{Blackbox, BlackboxOptions, Entry, SessionId, ToValue};
 pub use self::singleton::{init, log, sync, SINGLETON};
+pub use self::summary::DurationSummary;
 pub use match_pattern::{capture_pattern, match_pattern};
 pub use serde_json::{self, json, Value};
 
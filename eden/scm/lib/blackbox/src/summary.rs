@@ -0,0 +1,219 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Duration percentiles of commands.
+//!
+//! Computing the percentiles scans the whole log, so it is meant to be done
+//! as a maintenance task. The result is stored next to the log, so reports
+//! like `hg rage` can read it cheaply.
+
+use crate::blackbox::Blackbox;
+use crate::event::Event;
+use crate::Entry;
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+
+/// File name of the stored summaries, in the directory of the log.
+const SUMMARY_FILE_NAME: &str = "duration_summary.json";
+
+/// Duration percentiles of a command, in milliseconds.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DurationSummary {
+    pub command: String,
+    pub count: usize,
+    pub p50_ms: u64,
+    pub p90_ms: u64,
+    pub p99_ms: u64,
+}
+
+impl Blackbox {
+    /// Compute the duration percentiles of the commands in the log, and store
+    /// them so [`Blackbox::duration_summaries`] can read them.
+    ///
+    /// Commands are matched with their durations by session. Sessions without
+    /// both a `Start` and a `Finish` event are ignored. Since old logs are
+    /// rotated away, the summaries cover the recent commands.
+    pub fn update_duration_summaries(&mut self) -> Result<Vec<DurationSummary>> {
+        let summaries = compute_summaries(
+            self.log
+                .iter()
+                .filter_map(|bytes| bytes.ok().and_then(Entry::from_slice)),
+        );
+        match &self.path {
+            Some(path) => {
+                let json = serde_json::to_vec(&summaries)?;
+                indexedlog::utils::atomic_write(path.join(SUMMARY_FILE_NAME), json, false)?;
+            }
+            None => self.summaries = summaries.clone(),
+        }
+        Ok(summaries)
+    }
+
+    /// The duration percentiles stored by the last
+    /// [`Blackbox::update_duration_summaries`], sorted by command name.
+    ///
+    /// Returns an empty list if they were never computed.
+    pub fn duration_summaries(&self) -> Result<Vec<DurationSummary>> {
+        match &self.path {
+            Some(path) => match fs::read(path.join(SUMMARY_FILE_NAME)) {
+                Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+                Err(err) => Err(err.into()),
+            },
+            None => Ok(self.summaries.clone()),
+        }
+    }
+}
+
+fn compute_summaries(entries: impl IntoIterator<Item = Entry>) -> Vec<DurationSummary> {
+    let mut commands = HashMap::new();
+    let mut durations = HashMap::new();
+    for entry in entries {
+        match entry.data {
+            Event::Start { args, .. } => {
+                if let Some(command) = command_name(&args) {
+                    commands.insert(entry.session_id, command.to_string());
+                }
+            }
+            Event::Finish { duration_ms, .. } => {
+                durations.insert(entry.session_id, duration_ms);
+            }
+            _ => (),
+        }
+    }
+
+    let mut by_command: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+    for (session_id, command) in commands {
+        if let Some(&duration_ms) = durations.get(&session_id) {
+            by_command.entry(command).or_default().push(duration_ms);
+        }
+    }
+
+    by_command
+        .into_iter()
+        .map(|(command, mut durations)| {
+            durations.sort_unstable();
+            DurationSummary {
+                command,
+                count: durations.len(),
+                p50_ms: percentile(&durations, 50),
+                p90_ms: percentile(&durations, 90),
+                p99_ms: percentile(&durations, 99),
+            }
+        })
+        .collect()
+}
+
+/// The nearest-rank percentile of non-empty sorted `values`.
+fn percentile(values: &[u64], percent: usize) -> u64 {
+    let rank = (values.len() * percent + 99) / 100;
+    values[rank.max(1) - 1]
+}
+
+/// Find the command name in the command line arguments, skipping the program
+/// name and the global options.
+fn command_name(args: &[String]) -> Option<&str> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Global options taking a value.
+            "-R" | "--repository" | "--repo" | "--cwd" | "--config" | "--configfile" => {
+                args.next();
+            }
+            arg if arg.starts_with('-') => (),
+            arg => return Some(arg),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BlackboxOptions;
+    use tempfile::tempdir;
+
+    fn log_command(blackbox: &mut Blackbox, args: &[&str], duration_ms: u64) {
+        blackbox.refresh_session_id();
+        blackbox.log(&Event::Start {
+            pid: 0,
+            uid: 0,
+            nice: 0,
+            args: args.iter().map(|s| s.to_string()).collect(),
+            timestamp_ms: 0,
+        });
+        blackbox.log(&Event::Finish {
+            exit_code: 0,
+            max_rss: 0,
+            duration_ms,
+            timestamp_ms: 0,
+        });
+    }
+
+    #[test]
+    fn test_duration_summaries() {
+        let dir = tempdir().unwrap();
+        let mut blackbox = BlackboxOptions::new().open(&dir.path()).unwrap();
+        assert!(blackbox.duration_summaries().unwrap().is_empty());
+
+        for duration_ms in 1..=100 {
+            log_command(&mut blackbox, &["hg", "status"], duration_ms);
+        }
+        log_command(&mut blackbox, &["hg", "-R", "repo", "--verbose", "log"], 7);
+        blackbox.refresh_session_id();
+        blackbox.log(&Event::Start {
+            pid: 0,
+            uid: 0,
+            nice: 0,
+            args: vec!["hg".to_string(), "pull".to_string()],
+            timestamp_ms: 0,
+        });
+
+        let summaries = blackbox.update_duration_summaries().unwrap();
+        assert_eq!(
+            summaries,
+            [
+                DurationSummary {
+                    command: "log".to_string(),
+                    count: 1,
+                    p50_ms: 7,
+                    p90_ms: 7,
+                    p99_ms: 7,
+                },
+                DurationSummary {
+                    command: "status".to_string(),
+                    count: 100,
+                    p50_ms: 50,
+                    p90_ms: 90,
+                    p99_ms: 99,
+                },
+            ]
+        );
+
+        // The summaries are read from disk without scanning the log.
+        drop(blackbox);
+        let blackbox = BlackboxOptions::new().open(&dir.path()).unwrap();
+        assert_eq!(blackbox.duration_summaries().unwrap(), summaries);
+    }
+
+    #[test]
+    fn test_command_name() {
+        let name = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+            command_name(&args).map(|s| s.to_string())
+        };
+        assert_eq!(name(&["hg"]), None);
+        assert_eq!(name(&["hg", "log", "-r", "."]), Some("log".to_string()));
+        assert_eq!(
+            name(&["hg", "--config", "a.b=c", "-q", "diff"]),
+            Some("diff".to_string())
+        );
+    }
+}
@@ -170,6 +170,15 @@ pub fn dispatch(command_table: &CommandTable, args: Vec<String>, io: &mut IO) ->
         &env::current_dir()?,
         configparser::hg::load()?,
     )?;
+    if let OptionalRepo::Some(repo) = &optional_repo {
+        for item in repo.ignored_config() {
+            io.write_err(format!(
+                "warning: ignoring untrusted config {} in {}\n",
+                item,
+                repo.path().join(".hg/hgrc").display()
+            ))?;
+        }
+    }
     override_config(
         optional_repo.config_mut(),
         &global_opts.configfile,
@@ -7,7 +7,10 @@
 
 use crate::errors;
 use anyhow::Result;
-use configparser::{config::ConfigSet, hg::ConfigSetHgExt};
+use configparser::{
+    config::ConfigSet,
+    hg::{ConfigSetHgExt, IgnoredConfig},
+};
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
@@ -17,6 +20,7 @@ pub struct Repo {
     config: ConfigSet,
     bundle_path: Option<PathBuf>,
     shared_path: PathBuf,
+    ignored_config: Vec<IgnoredConfig>,
 }
 
 /// Either an optional [`Repo`] which owns a [`ConfigSet`], or a [`ConfigSet`]
@@ -88,14 +92,16 @@ impl OptionalRepo {
 impl Repo {
     /// Load the repo from explicit path.
     ///
-    /// Load repo configurations.
+    /// Load repo configurations. The repo config is not trusted: the items that the policy set
+    /// by the user and system config does not allow are ignored (see
+    /// [`configparser::hg::ConfigPolicy::from_config`]).
     fn from_raw_path<P>(path: P, mut config: ConfigSet) -> Result<Self>
     where
         P: Into<PathBuf>,
     {
         let path = path.into();
         assert!(path.is_absolute());
-        let mut errors = config.load_hgrc(path.join(".hg/hgrc"), "repository");
+        let (mut errors, ignored_config) = config.load_repo_hgrc(&path);
         if let Some(error) = errors.pop() {
             Err(error.into())
         } else {
@@ -105,6 +111,7 @@ impl Repo {
                 config,
                 bundle_path: None,
                 shared_path,
+                ignored_config,
             })
         }
     }
@@ -122,6 +129,11 @@ impl Repo {
     pub fn config(&self) -> &ConfigSet {
         &self.config
     }
+
+    /// The items of the repo config that were ignored because they are not trusted.
+    pub fn ignored_config(&self) -> &[IgnoredConfig] {
+        &self.ignored_config
+    }
 }
 
 fn find_hg_repo_root(current_path: &Path) -> Option<PathBuf> {
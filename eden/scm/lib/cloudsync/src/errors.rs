@@ -0,0 +1,18 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[error("failed to parse {}", .path.display())]
+pub struct InvalidSyncState {
+    pub(crate) path: PathBuf,
+    #[source]
+    pub(crate) source: anyhow::Error,
+}
@@ -0,0 +1,22 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! cloudsync - Local state of commit cloud workspace synchronization.
+//!
+//! A [`SyncState`] records what the cloud workspace contained at the last successful sync. It
+//! is persisted by a [`SyncStateStore`] using the same file format as the Python `syncstate`
+//! module, so that both implementations can share a repository while the sync algorithm is
+//! moved to Rust.
+//!
+//! The [`merge`] module reconciles the heads of the local repository with the heads of the
+//! cloud workspace, using the last sync state to tell additions from removals.
+
+pub mod errors;
+pub mod merge;
+mod state;
+
+pub use crate::state::{SyncState, SyncStateStore};
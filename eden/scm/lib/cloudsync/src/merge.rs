@@ -0,0 +1,182 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Reconciliation of local and cloud heads.
+//!
+//! A sync happens in two directions. Changes made in the cloud since the last sync are applied
+//! to the local repository with [`merge_cloud_heads`], then local changes are sent to the cloud
+//! with [`merge_local_heads`]. In both directions the [`SyncState`] of the last sync is the
+//! common base: a head that was synced and is now missing on one side was removed there, while
+//! a head that was not synced was added.
+
+use std::collections::HashSet;
+
+use types::HgId;
+
+use crate::SyncState;
+
+/// The heads to send to the cloud workspace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloudHeads {
+    /// The new heads of the workspace.
+    pub heads: Vec<HgId>,
+    /// The heads of the workspace that are not present locally, because they were omitted when
+    /// pulling them.
+    pub omitted_heads: Vec<HgId>,
+}
+
+/// Works out the heads to send to the cloud after the local heads changed. Returns `None` if
+/// the local heads are the heads synced last time, and there is nothing to send.
+///
+/// Heads that were omitted from the last sync are kept, since their absence from the local
+/// repository does not mean they were removed. The cloud order of the heads that are kept is
+/// preserved, and new local heads are appended.
+pub fn merge_local_heads(last: &SyncState, local_heads: &[HgId]) -> Option<CloudHeads> {
+    let local: HashSet<&HgId> = local_heads.iter().collect();
+    let synced: HashSet<&HgId> = last.synced_heads().collect();
+    if last.version != 0 && local == synced {
+        return None;
+    }
+
+    let omitted: HashSet<&HgId> = last.omitted_heads.iter().collect();
+    let heads = dedup(
+        last.heads
+            .iter()
+            .filter(|head| local.contains(head) || omitted.contains(head))
+            .chain(local_heads),
+    );
+    let omitted_heads = heads
+        .iter()
+        .filter(|head| !local.contains(head))
+        .cloned()
+        .collect();
+    Some(CloudHeads {
+        heads,
+        omitted_heads,
+    })
+}
+
+/// Works out the new local heads after the cloud workspace changed to `cloud_heads`. Returns
+/// `None` if the local heads already match the cloud.
+///
+/// `cloud_heads` should not include the heads that are omitted from this sync. Heads that were
+/// synced last time and have since been removed on either side are removed, and heads added on
+/// either side are kept.
+pub fn merge_cloud_heads(
+    last: &SyncState,
+    cloud_heads: &[HgId],
+    local_heads: &[HgId],
+) -> Option<Vec<HgId>> {
+    let local: HashSet<&HgId> = local_heads.iter().collect();
+    let cloud: HashSet<&HgId> = cloud_heads.iter().collect();
+    if local == cloud {
+        return None;
+    }
+
+    let synced: Vec<&HgId> = last.synced_heads().collect();
+    let removed: HashSet<&HgId> = synced
+        .iter()
+        .cloned()
+        .filter(|head| !local.contains(head) || !cloud.contains(head))
+        .collect();
+    let heads = dedup(
+        synced
+            .into_iter()
+            .chain(cloud_heads)
+            .chain(local_heads)
+            .filter(|head| !removed.contains(head)),
+    );
+    Some(heads)
+}
+
+/// Removes duplicated heads, keeping the first occurrence of each.
+fn dedup<'a>(heads: impl IntoIterator<Item = &'a HgId>) -> Vec<HgId> {
+    let mut seen = HashSet::new();
+    heads
+        .into_iter()
+        .filter(|head| seen.insert(*head))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use types::testutil::*;
+
+    fn state(version: u64, heads: &[&str], omitted: &[&str]) -> SyncState {
+        SyncState {
+            version,
+            heads: heads.iter().map(|h| hgid(h)).collect(),
+            omitted_heads: omitted.iter().map(|h| hgid(h)).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn hgids(hexes: &[&str]) -> Vec<HgId> {
+        hexes.iter().map(|h| hgid(h)).collect()
+    }
+
+    #[test]
+    fn test_merge_local_heads_unchanged() {
+        let last = state(2, &["1", "2", "3"], &["3"]);
+        assert_eq!(merge_local_heads(&last, &hgids(&["2", "1"])), None);
+
+        // A workspace that was never synced is always sent.
+        let last = state(0, &[], &[]);
+        assert_eq!(
+            merge_local_heads(&last, &[]),
+            Some(CloudHeads {
+                heads: vec![],
+                omitted_heads: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_local_heads() {
+        let last = state(2, &["1", "2", "3"], &["3"]);
+        assert_eq!(
+            merge_local_heads(&last, &hgids(&["4", "1"])),
+            Some(CloudHeads {
+                heads: hgids(&["1", "3", "4"]),
+                omitted_heads: hgids(&["3"]),
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_cloud_heads_unchanged() {
+        let last = state(2, &["1"], &[]);
+        assert_eq!(
+            merge_cloud_heads(&last, &hgids(&["1", "2"]), &hgids(&["2", "1"])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_merge_cloud_heads() {
+        // "1" was removed in the cloud, "2" was removed locally, "3" was added in the cloud and
+        // "4" was added locally. "5" is unchanged.
+        let last = state(2, &["1", "2", "5"], &[]);
+        assert_eq!(
+            merge_cloud_heads(&last, &hgids(&["2", "3", "5"]), &hgids(&["1", "4", "5"])),
+            Some(hgids(&["5", "3", "4"]))
+        );
+    }
+
+    #[test]
+    fn test_merge_cloud_heads_ignores_omitted() {
+        // "2" was omitted from the last sync, so its absence locally is not a removal.
+        let last = state(2, &["1", "2"], &["2"]);
+        assert_eq!(
+            merge_cloud_heads(&last, &hgids(&["1", "2"]), &hgids(&["1"])),
+            Some(hgids(&["1", "2"]))
+        );
+    }
+}
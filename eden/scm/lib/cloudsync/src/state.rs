@@ -0,0 +1,257 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use indexedlog::utils::atomic_write;
+use types::HgId;
+
+use crate::errors::InvalidSyncState;
+
+/// The state of a cloud workspace as of the last sync.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyncState {
+    /// The version of the workspace. 0 if the workspace was never synced.
+    pub version: u64,
+    /// The heads of the workspace, in the order of the cloud.
+    pub heads: Vec<HgId>,
+    pub bookmarks: BTreeMap<String, HgId>,
+    pub remote_bookmarks: BTreeMap<String, HgId>,
+    /// Heads of the workspace that were deliberately not pulled, for example because they are
+    /// older than `max_age`. They are not expected to be present locally.
+    pub omitted_heads: Vec<HgId>,
+    pub omitted_bookmarks: Vec<String>,
+    pub snapshots: Vec<HgId>,
+    /// The maximum age of the heads to pull, in days.
+    pub max_age: Option<u64>,
+    /// When the state was last saved, in seconds since the epoch.
+    pub last_update_time: Option<f64>,
+}
+
+impl SyncState {
+    /// The heads that were synced and are expected to be present locally.
+    pub fn synced_heads(&self) -> impl Iterator<Item = &HgId> {
+        self.heads
+            .iter()
+            .filter(move |head| !self.omitted_heads.contains(head))
+    }
+}
+
+/// The on-disk format, shared with the Python implementation.
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    version: u64,
+    heads: Vec<String>,
+    bookmarks: BTreeMap<String, String>,
+    #[serde(default, rename = "remotebookmarks")]
+    remote_bookmarks: BTreeMap<String, String>,
+    #[serde(default, rename = "omittedheads")]
+    omitted_heads: Vec<String>,
+    #[serde(default, rename = "omittedbookmarks")]
+    omitted_bookmarks: Vec<String>,
+    #[serde(default)]
+    snapshots: Vec<String>,
+    #[serde(default, rename = "maxage")]
+    max_age: Option<u64>,
+    #[serde(default, rename = "lastupdatetime")]
+    last_update_time: Option<f64>,
+}
+
+impl StateFile {
+    fn from_state(state: &SyncState) -> Self {
+        let hexes = |hgids: &[HgId]| hgids.iter().map(|h| h.to_hex()).collect();
+        let hex_map = |map: &BTreeMap<String, HgId>| {
+            map.iter()
+                .map(|(name, hgid)| (name.clone(), hgid.to_hex()))
+                .collect()
+        };
+        StateFile {
+            version: state.version,
+            heads: hexes(&state.heads),
+            bookmarks: hex_map(&state.bookmarks),
+            remote_bookmarks: hex_map(&state.remote_bookmarks),
+            omitted_heads: hexes(&state.omitted_heads),
+            omitted_bookmarks: state.omitted_bookmarks.clone(),
+            snapshots: hexes(&state.snapshots),
+            max_age: state.max_age,
+            last_update_time: state.last_update_time,
+        }
+    }
+
+    fn into_state(self) -> Result<SyncState> {
+        let hgids = |hexes: Vec<String>| -> Result<Vec<HgId>> {
+            hexes.iter().map(|hex| HgId::from_str(hex)).collect()
+        };
+        let hgid_map = |map: BTreeMap<String, String>| -> Result<BTreeMap<String, HgId>> {
+            map.into_iter()
+                .map(|(name, hex)| Ok((name, HgId::from_str(&hex)?)))
+                .collect()
+        };
+        Ok(SyncState {
+            version: self.version,
+            heads: hgids(self.heads)?,
+            bookmarks: hgid_map(self.bookmarks)?,
+            remote_bookmarks: hgid_map(self.remote_bookmarks)?,
+            omitted_heads: hgids(self.omitted_heads)?,
+            omitted_bookmarks: self.omitted_bookmarks,
+            snapshots: hgids(self.snapshots)?,
+            max_age: self.max_age,
+            last_update_time: self.last_update_time,
+        })
+    }
+}
+
+/// Persists the [`SyncState`] of a workspace in the store directory of a repository.
+pub struct SyncStateStore {
+    path: PathBuf,
+}
+
+impl SyncStateStore {
+    pub fn new(store_dir: &Path, workspace: &str) -> Self {
+        SyncStateStore {
+            path: store_dir.join(file_name(workspace)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the state. A workspace that was never synced has the default state.
+    pub fn load(&self) -> Result<SyncState> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(SyncState::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let parsed = serde_json::from_slice::<StateFile>(&data)
+            .map_err(anyhow::Error::from)
+            .and_then(StateFile::into_state);
+        parsed.map_err(|source| {
+            InvalidSyncState {
+                path: self.path.clone(),
+                source,
+            }
+            .into()
+        })
+    }
+
+    /// Replaces the state on disk and records the time of the update in `state`.
+    ///
+    /// The file is replaced atomically and synced to disk, so a crash leaves either the old or
+    /// the new state.
+    pub fn save(&self, state: &mut SyncState) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        state.last_update_time = Some(now.as_secs_f64());
+        let data = serde_json::to_vec(&StateFile::from_state(state))?;
+        atomic_write(&self.path, data, true)?;
+        Ok(())
+    }
+
+    /// Removes the state, for example to recover from a broken workspace.
+    pub fn erase(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The name of the state file of `workspace`. It has to stay in sync with the Python
+/// implementation.
+fn file_name(workspace: &str) -> String {
+    let readable: String = workspace
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect();
+    let digest = Sha256::digest(workspace.as_bytes());
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("commitcloudstate.{}.{}", readable, &hex[..5])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use types::testutil::*;
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(
+            file_name("user/test@example.com/default"),
+            "commitcloudstate.usertestexamplecomdefault.4b2ec"
+        );
+    }
+
+    #[test]
+    fn test_load_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SyncStateStore::new(dir.path(), "workspace");
+        assert_eq!(store.load().unwrap(), SyncState::default());
+        store.erase().unwrap();
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SyncStateStore::new(dir.path(), "workspace");
+        let mut state = SyncState {
+            version: 3,
+            heads: vec![hgid("1"), hgid("2")],
+            omitted_heads: vec![hgid("2")],
+            max_age: Some(14),
+            ..Default::default()
+        };
+        state.bookmarks.insert("feature".to_string(), hgid("1"));
+        store.save(&mut state).unwrap();
+        assert!(state.last_update_time.is_some());
+        assert_eq!(store.load().unwrap(), state);
+        assert_eq!(state.synced_heads().collect::<Vec<_>>(), vec![&hgid("1")]);
+
+        store.erase().unwrap();
+        assert_eq!(store.load().unwrap(), SyncState::default());
+    }
+
+    #[test]
+    fn test_load_python_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SyncStateStore::new(dir.path(), "workspace");
+        let data = format!(
+            r#"{{"version": 2, "heads": ["{0}"], "bookmarks": {{"bm": "{0}"}},
+                "omittedheads": [], "maxage": null, "lastupdatetime": 1577836800.5}}"#,
+            hgid("1")
+        );
+        fs::write(store.path(), data).unwrap();
+        let state = store.load().unwrap();
+        assert_eq!(state.version, 2);
+        assert_eq!(state.heads, vec![hgid("1")]);
+        assert_eq!(state.bookmarks["bm"], hgid("1"));
+        assert_eq!(state.last_update_time, Some(1577836800.5));
+        assert!(state.remote_bookmarks.is_empty());
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SyncStateStore::new(dir.path(), "workspace");
+        fs::write(
+            store.path(),
+            r#"{"version": 1, "heads": ["xyz"], "bookmarks": {}}"#,
+        )
+        .unwrap();
+        let err = store.load().unwrap_err();
+        assert!(err.downcast_ref::<InvalidSyncState>().is_some());
+    }
+}
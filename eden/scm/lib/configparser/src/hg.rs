@@ -7,11 +7,14 @@
 
 //! Mercurial-specific config postprocessing
 
+use std::cell::RefCell;
 use std::cmp::Eq;
 use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fmt;
 use std::hash::Hash;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 use anyhow::Result;
 use bytes::Bytes;
@@ -60,6 +63,21 @@ pub trait ConfigSetHgExt {
     /// Return errors parsing files.
     fn load_hgrc(&mut self, path: impl AsRef<Path>, source: &'static str) -> Vec<Error>;
 
+    /// Load a config file that is not trusted, like the config of a repo cloned from elsewhere.
+    /// Config items not allowed by `policy` are ignored. Respect HGPLAIN environment variables.
+    /// Return errors parsing files, and the ignored config items.
+    fn load_untrusted_hgrc(
+        &mut self,
+        path: impl AsRef<Path>,
+        source: &'static str,
+        policy: &ConfigPolicy,
+    ) -> (Vec<Error>, Vec<IgnoredConfig>);
+
+    /// Load the `.hg/hgrc` config file of the repo at `repo_path`, with the policy that the
+    /// config already loaded sets for the repo (see [`ConfigPolicy::from_config`]).
+    /// Return errors parsing files, and the ignored config items.
+    fn load_repo_hgrc(&mut self, repo_path: impl AsRef<Path>) -> (Vec<Error>, Vec<IgnoredConfig>);
+
     /// Get a config item. Convert to type `T`.
     fn get_opt<T: FromConfigValue>(&self, section: &str, name: &str) -> Result<Option<T>>;
 
@@ -87,6 +105,193 @@ pub trait FromConfigValue: Sized {
     fn try_from_bytes(bytes: &[u8]) -> Result<Self>;
 }
 
+/// Restrictions on the config items set by untrusted config files.
+///
+/// The default policy ignores config that runs commands or changes where shared data is
+/// stored: the `[hooks]`, `[extensions]`, `[merge-tools]`, `[merge-patterns]` and `[extdiff]`
+/// sections, shell aliases (`[alias]` items starting with `!`), `ui.merge`, `ui.editor`,
+/// `ui.ssh`, `ui.remotecmd`, `pager.pager` and `remotefilelog.cachepath`.
+#[derive(Clone, Debug)]
+pub struct ConfigPolicy {
+    allowed_sections: Option<HashSet<Bytes>>,
+    denied_sections: HashSet<Bytes>,
+    denied_items: HashSet<(Bytes, Bytes)>,
+    excepted_sections: HashSet<Bytes>,
+    excepted_items: HashSet<(Bytes, Bytes)>,
+    deny_shell_aliases: bool,
+}
+
+/// A config item ignored by a [`ConfigPolicy`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct IgnoredConfig {
+    pub section: Bytes,
+    pub name: Bytes,
+    pub value: Option<Bytes>,
+}
+
+impl Default for ConfigPolicy {
+    fn default() -> Self {
+        ConfigPolicy::allow_all()
+            .deny_sections(vec![
+                "hooks",
+                "extensions",
+                "merge-tools",
+                "merge-patterns",
+                "extdiff",
+            ])
+            .deny_items(vec![
+                ("remotefilelog", "cachepath"),
+                ("ui", "ssh"),
+                ("ui", "remotecmd"),
+                ("ui", "merge"),
+                ("ui", "editor"),
+                ("pager", "pager"),
+            ])
+            .deny_shell_aliases()
+    }
+}
+
+impl ConfigPolicy {
+    /// A policy that does not ignore any config item.
+    pub fn allow_all() -> Self {
+        ConfigPolicy {
+            allowed_sections: None,
+            denied_sections: HashSet::new(),
+            denied_items: HashSet::new(),
+            excepted_sections: HashSet::new(),
+            excepted_items: HashSet::new(),
+            deny_shell_aliases: false,
+        }
+    }
+
+    /// The policy for the config of the repo at `repo_path`, set by the trusted `config`.
+    ///
+    /// Repos listed in `untrusted.trusted-repos` are trusted, and their config is not
+    /// restricted. Otherwise the default policy is used, adjusted by the `untrusted.allow` and
+    /// `untrusted.deny` lists of `section` or `section.name` entries, and by the
+    /// `untrusted.allow:<repo path>` and `untrusted.deny:<repo path>` lists for this repo only.
+    /// For example:
+    ///
+    /// ```text
+    /// [untrusted]
+    /// allow = merge-tools
+    /// deny:/home/user/repo = ui.username
+    /// ```
+    pub fn from_config(config: &ConfigSet, repo_path: impl AsRef<Path>) -> Result<Self> {
+        let repo_path = repo_path.as_ref();
+        let trusted: Vec<PathBuf> = config.get_or_default("untrusted", "trusted-repos")?;
+        if trusted.iter().any(|path| path == repo_path) {
+            return Ok(ConfigPolicy::allow_all());
+        }
+
+        let repo = repo_path.to_string_lossy();
+        let mut policy = ConfigPolicy::default();
+        for name in &["allow".to_string(), format!("allow:{}", repo)] {
+            let entries: Vec<String> = config.get_or_default("untrusted", name)?;
+            let (sections, items) = split_entries(entries);
+            policy = policy.except_sections(sections).except_items(items);
+        }
+        for name in &["deny".to_string(), format!("deny:{}", repo)] {
+            let entries: Vec<String> = config.get_or_default("untrusted", name)?;
+            let (sections, items) = split_entries(entries);
+            policy = policy.deny_sections(sections).deny_items(items);
+        }
+        Ok(policy)
+    }
+
+    /// Only allow the given sections. Items in other sections are ignored.
+    pub fn allow_sections<B: Into<Bytes>>(mut self, sections: Vec<B>) -> Self {
+        let allowed = self.allowed_sections.get_or_insert_with(HashSet::new);
+        allowed.extend(sections.into_iter().map(Into::into));
+        self
+    }
+
+    /// Ignore items in the given sections.
+    pub fn deny_sections<B: Into<Bytes>>(mut self, sections: Vec<B>) -> Self {
+        self.denied_sections
+            .extend(sections.into_iter().map(Into::into));
+        self
+    }
+
+    /// Ignore the given items. `items` contains a list of tuple `(section, name)`.
+    pub fn deny_items<S: Into<Bytes>, N: Into<Bytes>>(mut self, items: Vec<(S, N)>) -> Self {
+        self.denied_items.extend(
+            items
+                .into_iter()
+                .map(|(section, name)| (section.into(), name.into())),
+        );
+        self
+    }
+
+    /// Ignore `[alias]` items that run shell commands, whose values start with `!`.
+    pub fn deny_shell_aliases(mut self) -> Self {
+        self.deny_shell_aliases = true;
+        self
+    }
+
+    /// Allow items in the given sections, even if the sections or their items are denied.
+    pub fn except_sections<B: Into<Bytes>>(mut self, sections: Vec<B>) -> Self {
+        self.excepted_sections
+            .extend(sections.into_iter().map(Into::into));
+        self
+    }
+
+    /// Allow the given items, even if they are denied. `items` contains a list of tuple
+    /// `(section, name)`.
+    pub fn except_items<S: Into<Bytes>, N: Into<Bytes>>(mut self, items: Vec<(S, N)>) -> Self {
+        self.excepted_items.extend(
+            items
+                .into_iter()
+                .map(|(section, name)| (section.into(), name.into())),
+        );
+        self
+    }
+
+    /// Test if the policy allows setting `section.name` to `value`.
+    pub fn allows(&self, section: &Bytes, name: &Bytes, value: Option<&Bytes>) -> bool {
+        if let Some(allowed) = &self.allowed_sections {
+            if !allowed.contains(section) {
+                return false;
+            }
+        }
+        let item = (section.clone(), name.clone());
+        if self.excepted_sections.contains(section) || self.excepted_items.contains(&item) {
+            return true;
+        }
+        let shell_alias = self.deny_shell_aliases
+            && &section[..] == b"alias"
+            && value.and_then(|value| value.iter().find(|b| !b.is_ascii_whitespace()))
+                == Some(&b'!');
+        !shell_alias
+            && !self.denied_sections.contains(section)
+            && !self.denied_items.contains(&item)
+    }
+}
+
+/// Split `section` and `section.name` entries of a policy list.
+fn split_entries(entries: Vec<String>) -> (Vec<String>, Vec<(String, String)>) {
+    let mut sections = Vec::new();
+    let mut items = Vec::new();
+    for entry in entries {
+        match entry.find('.') {
+            Some(dot) => items.push((entry[..dot].to_string(), entry[dot + 1..].to_string())),
+            None => sections.push(entry),
+        }
+    }
+    (sections, items)
+}
+
+impl fmt::Display for IgnoredConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}",
+            String::from_utf8_lossy(&self.section),
+            String::from_utf8_lossy(&self.name)
+        )
+    }
+}
+
 /// Load system, user config files.
 pub fn load() -> Result<ConfigSet> {
     let mut set = ConfigSet::new();
@@ -322,6 +527,51 @@ impl ConfigSetHgExt for ConfigSet {
         self.load_path(path, &opts)
     }
 
+    fn load_untrusted_hgrc(
+        &mut self,
+        path: impl AsRef<Path>,
+        source: &'static str,
+        policy: &ConfigPolicy,
+    ) -> (Vec<Error>, Vec<IgnoredConfig>) {
+        let ignored = Rc::new(RefCell::new(Vec::new()));
+        let filter = {
+            let policy = policy.clone();
+            let ignored = ignored.clone();
+            move |section: Bytes, name: Bytes, value: Option<Bytes>| {
+                if policy.allows(&section, &name, value.as_ref()) {
+                    Some((section, name, value))
+                } else {
+                    ignored.borrow_mut().push(IgnoredConfig {
+                        section,
+                        name,
+                        value,
+                    });
+                    None
+                }
+            }
+        };
+        // The policy is applied after HGPLAIN so items dropped by HGPLAIN are not reported.
+        let opts = Options::new()
+            .source(source)
+            .process_hgplain()
+            .append_filter(Box::new(filter));
+        let errors = self.load_path(path, &opts);
+        let ignored = ignored.replace(Vec::new());
+        (errors, ignored)
+    }
+
+    fn load_repo_hgrc(&mut self, repo_path: impl AsRef<Path>) -> (Vec<Error>, Vec<IgnoredConfig>) {
+        let repo_path = repo_path.as_ref();
+        let policy = match ConfigPolicy::from_config(self, repo_path) {
+            Ok(policy) => policy,
+            Err(error) => {
+                let error = Error::Convert(format!("invalid untrusted config policy: {}", error));
+                return (vec![error], Vec::new());
+            }
+        };
+        self.load_untrusted_hgrc(repo_path.join(".hg/hgrc"), "repository", &policy)
+    }
+
     fn get_opt<T: FromConfigValue>(&self, section: &str, name: &str) -> Result<Option<T>> {
         ConfigSet::get(self, section, name)
             .map(|bytes| T::try_from_bytes(&bytes))
@@ -899,6 +1149,111 @@ mod tests {
         assert_eq!(cfg.get("z", "c"), Some("3".into()));
     }
 
+    #[test]
+    fn test_load_untrusted_hgrc() {
+        let dir = TempDir::new("test_load_untrusted_hgrc").unwrap();
+        let path = dir.path().join("hgrc");
+        write_file(dir.path().join("included.rc"), "[hooks]\nb=2\n");
+        write_file(
+            path.clone(),
+            "[x]\n\
+             a=1\n\
+             [hooks]\n\
+             pre-commit=false\n\
+             %include included.rc\n\
+             [remotefilelog]\n\
+             cachepath=/tmp/cache\n\
+             reponame=repo\n",
+        );
+
+        let _guard = ENV_LOCK.lock();
+        env::remove_var(HGPLAIN);
+        env::remove_var(HGPLAINEXCEPT);
+
+        let mut cfg = ConfigSet::new();
+        let (errors, ignored) = cfg.load_untrusted_hgrc(&path, "repo", &ConfigPolicy::default());
+        assert!(errors.is_empty());
+        assert_eq!(
+            ignored
+                .iter()
+                .map(|item| item.to_string())
+                .collect::<Vec<_>>(),
+            vec!["hooks.pre-commit", "hooks.b", "remotefilelog.cachepath"]
+        );
+        assert_eq!(ignored[2].value, Some("/tmp/cache".into()));
+        assert!(cfg.keys("hooks").is_empty());
+        assert_eq!(cfg.get("remotefilelog", "cachepath"), None);
+        assert_eq!(cfg.get("remotefilelog", "reponame"), Some("repo".into()));
+        assert_eq!(cfg.get("x", "a"), Some("1".into()));
+
+        let policy = ConfigPolicy::allow_all().allow_sections(vec!["x"]);
+        let mut cfg = ConfigSet::new();
+        let (_, ignored) = cfg.load_untrusted_hgrc(&path, "repo", &policy);
+        assert_eq!(ignored.len(), 4);
+        assert_eq!(cfg.sections(), vec![Bytes::from("x")]);
+    }
+
+    #[test]
+    fn test_load_repo_hgrc() {
+        let dir = TempDir::new("test_load_repo_hgrc").unwrap();
+        let repo = dir.path().join("repo");
+        write_file(
+            repo.join(".hg/hgrc"),
+            "[alias]\n\
+             l=log -l 10\n\
+             sh= !rm -rf /\n\
+             [merge-tools]\n\
+             evil.executable=/bin/evil\n\
+             [ui]\n\
+             editor=evil\n\
+             username=someone\n\
+             [pager]\n\
+             pager=evil\n",
+        );
+
+        let _guard = ENV_LOCK.lock();
+        env::remove_var(HGPLAIN);
+        env::remove_var(HGPLAINEXCEPT);
+
+        let ignored_items = |user_config: &str| {
+            let mut cfg = ConfigSet::new();
+            cfg.parse(
+                user_config.replace("REPO", &repo.to_string_lossy()),
+                &Options::new(),
+            );
+            let (errors, ignored) = cfg.load_repo_hgrc(&repo);
+            assert!(errors.is_empty());
+            ignored
+                .iter()
+                .map(|item| item.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ignored_items(""),
+            vec![
+                "alias.sh",
+                "merge-tools.evil.executable",
+                "ui.editor",
+                "pager.pager"
+            ]
+        );
+        assert_eq!(
+            ignored_items("[untrusted]\nallow = merge-tools ui.editor\ndeny = ui.username\n"),
+            vec!["alias.sh", "ui.username", "pager.pager"]
+        );
+        assert_eq!(
+            ignored_items("[untrusted]\ndeny:REPO = alias\ndeny:/elsewhere = pager\n"),
+            vec![
+                "alias.l",
+                "alias.sh",
+                "merge-tools.evil.executable",
+                "ui.editor",
+                "pager.pager"
+            ]
+        );
+        assert!(ignored_items("[untrusted]\ntrusted-repos = REPO\n").is_empty());
+    }
+
     #[test]
     fn test_parse_list() {
         fn b<B: AsRef<[u8]>>(bytes: B) -> Bytes {
@@ -0,0 +1,80 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::Result;
+
+use configparser::{config::ConfigSet, hg::ConfigSetHgExt};
+
+/// Tunables of the rename detection, read from the `[copytrace]` config
+/// section.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CopyTraceConfig {
+    /// `copytrace.maxcandidates`: how many removed files, picked by path
+    /// similarity, have their content compared with each added file.
+    pub max_candidates: usize,
+    /// `copytrace.similarity`: the minimum content similarity, in percent,
+    /// for a removed and an added file to be considered a rename.
+    pub similarity: u32,
+    /// `copytrace.samplesize`: how many bytes at the start of each file
+    /// are compared.
+    pub sample_size: usize,
+}
+
+impl Default for CopyTraceConfig {
+    fn default() -> Self {
+        CopyTraceConfig {
+            max_candidates: 20,
+            similarity: 50,
+            sample_size: 8192,
+        }
+    }
+}
+
+impl CopyTraceConfig {
+    pub fn from_config(config: &ConfigSet) -> Result<Self> {
+        let default = CopyTraceConfig::default();
+        Ok(CopyTraceConfig {
+            max_candidates: config
+                .get_or("copytrace", "maxcandidates", || default.max_candidates)?,
+            similarity: config
+                .get_or("copytrace", "similarity", || default.similarity)?
+                .min(100),
+            sample_size: config.get_or("copytrace", "samplesize", || default.sample_size)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use configparser::config::Options;
+
+    #[test]
+    fn test_from_config() {
+        let mut config = ConfigSet::new();
+        assert_eq!(
+            CopyTraceConfig::from_config(&config).unwrap(),
+            CopyTraceConfig::default()
+        );
+
+        config.set("copytrace", "similarity", Some(b"80"), &Options::default());
+        config.set(
+            "copytrace",
+            "maxcandidates",
+            Some(b"5"),
+            &Options::default(),
+        );
+        let copytrace = CopyTraceConfig::from_config(&config).unwrap();
+        assert_eq!(copytrace.similarity, 80);
+        assert_eq!(copytrace.max_candidates, 5);
+        assert_eq!(copytrace.sample_size, 8192);
+
+        config.set("copytrace", "similarity", Some(b"x"), &Options::default());
+        assert!(CopyTraceConfig::from_config(&config).is_err());
+    }
+}
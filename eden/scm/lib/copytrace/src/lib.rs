@@ -0,0 +1,26 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! copytrace - Find files renamed between two manifests.
+//!
+//! Renames are recorded in the file history when they are made with
+//! `hg mv`, but that information is missing when files were moved by other
+//! tools, or too expensive to fetch when the history is not available
+//! locally. This crate guesses renames heuristically instead: files
+//! removed between two manifests are paired with added files, scored by
+//! the similarity of their paths and of a sample of their content read
+//! from a `DataStore`.
+//!
+//! Merge and rebase use the renames to apply changes made to the old path
+//! of a file to its new path.
+
+mod config;
+mod similarity;
+mod trace;
+
+pub use crate::config::CopyTraceConfig;
+pub use crate::trace::{CopyTrace, Rename};
@@ -0,0 +1,147 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashMap;
+
+use types::RepoPath;
+
+/// How similar two paths are, between 0 and 100.
+///
+/// Half of the score comes from the file names: the same name scores best,
+/// then the same name with another extension or the same extension. The
+/// other half comes from the directory components the paths have in
+/// common, regardless of their order.
+pub(crate) fn path_similarity(a: &RepoPath, b: &RepoPath) -> u32 {
+    let (a_dir, a_name) = split(a.as_str());
+    let (b_dir, b_name) = split(b.as_str());
+
+    let name = if a_name == b_name {
+        50
+    } else {
+        let (a_stem, a_ext) = split_extension(a_name);
+        let (b_stem, b_ext) = split_extension(b_name);
+        if a_stem == b_stem || (!a_ext.is_empty() && a_ext == b_ext) {
+            25
+        } else {
+            0
+        }
+    };
+
+    let a_dir: Vec<&str> = a_dir.split('/').filter(|c| !c.is_empty()).collect();
+    let b_dir: Vec<&str> = b_dir.split('/').filter(|c| !c.is_empty()).collect();
+    let dir = if a_dir.is_empty() && b_dir.is_empty() {
+        50
+    } else {
+        let common = common_count(a_dir.iter(), b_dir.iter());
+        (100 * common / (a_dir.len() + b_dir.len())) as u32
+    };
+
+    name + dir
+}
+
+/// How similar two file contents are, between 0 and 100: the proportion of
+/// lines they have in common.
+pub(crate) fn content_similarity(a: &[u8], b: &[u8]) -> u32 {
+    if a == b {
+        return 100;
+    }
+    let a = lines(a);
+    let b = lines(b);
+    if a.is_empty() && b.is_empty() {
+        return 100;
+    }
+    let common = common_count(a.iter(), b.iter());
+    (200 * common / (a.len() + b.len())) as u32
+}
+
+/// Keep the first `size` bytes of a file content, and cut it after its last
+/// complete line so a partial line doesn't count as different.
+pub(crate) fn sample(content: &[u8], size: usize) -> &[u8] {
+    if content.len() <= size {
+        return content;
+    }
+    let content = &content[..size];
+    match content.iter().rposition(|&c| c == b'\n') {
+        Some(i) => &content[..i + 1],
+        None => content,
+    }
+}
+
+fn split(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    }
+}
+
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    }
+}
+
+fn lines(content: &[u8]) -> Vec<&[u8]> {
+    content
+        .split(|&c| c == b'\n')
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+/// The size of the intersection of two multisets.
+fn common_count<T: Eq + std::hash::Hash>(
+    a: impl Iterator<Item = T>,
+    b: impl Iterator<Item = T>,
+) -> usize {
+    let mut counts: HashMap<T, usize> = HashMap::new();
+    for item in a {
+        *counts.entry(item).or_default() += 1;
+    }
+    let mut common = 0;
+    for item in b {
+        if let Some(count) = counts.get_mut(&item) {
+            if *count > 0 {
+                *count -= 1;
+                common += 1;
+            }
+        }
+    }
+    common
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use types::testutil::*;
+
+    #[test]
+    fn test_path_similarity() {
+        let score = |a, b| path_similarity(repo_path(a), repo_path(b));
+        assert_eq!(score("a/b/c.rs", "a/b/c.rs"), 100);
+        assert_eq!(score("a/b/c.rs", "a/x/c.rs"), 75);
+        assert_eq!(score("c.rs", "d.rs"), 75);
+        assert_eq!(score("a/c.rs", "b/c.py"), 25);
+        assert_eq!(score("a/c.rs", "b/d.py"), 0);
+    }
+
+    #[test]
+    fn test_content_similarity() {
+        assert_eq!(content_similarity(b"", b""), 100);
+        assert_eq!(content_similarity(b"a\nb\n", b"a\nb\n"), 100);
+        assert_eq!(content_similarity(b"a\nb\n", b"b\na\n"), 100);
+        assert_eq!(content_similarity(b"a\nb\nc\nd\n", b"a\nb\nx\ny\n"), 50);
+        assert_eq!(content_similarity(b"a\n", b"b\n"), 0);
+    }
+
+    #[test]
+    fn test_sample() {
+        assert_eq!(sample(b"abc\ndef\n", 100), b"abc\ndef\n");
+        assert_eq!(sample(b"abc\ndef\n", 6), b"abc\n");
+        assert_eq!(sample(b"abcdef", 3), b"abc");
+    }
+}
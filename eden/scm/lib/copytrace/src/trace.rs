@@ -0,0 +1,307 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{format_err, Result};
+
+use manifest::{DiffType, File, FileType, Manifest};
+use pathmatcher::Matcher;
+use revisionstore::DataStore;
+use types::{Key, RepoPathBuf};
+
+use crate::config::CopyTraceConfig;
+use crate::similarity::{content_similarity, path_similarity, sample};
+
+/// A file that was renamed from `from` to `to`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rename {
+    pub from: RepoPathBuf,
+    pub to: RepoPathBuf,
+    /// The similarity of the sampled contents, in percent.
+    pub similarity: u32,
+}
+
+/// Guesses renames from file contents in a `DataStore`.
+pub struct CopyTrace<'a> {
+    store: &'a dyn DataStore,
+    config: CopyTraceConfig,
+    /// Content samples, by file.
+    samples: RefCell<HashMap<Key, Vec<u8>>>,
+}
+
+impl<'a> CopyTrace<'a> {
+    pub fn new(store: &'a dyn DataStore, config: CopyTraceConfig) -> Self {
+        CopyTrace {
+            store,
+            config,
+            samples: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Finds the files of `old` that were renamed in `new`, among the paths
+    /// that match `matcher`.
+    pub fn trace<M: Manifest>(
+        &self,
+        old: &M,
+        new: &M,
+        matcher: &impl Matcher,
+    ) -> Result<Vec<Rename>> {
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        for entry in old.diff(new, matcher) {
+            let entry = entry?;
+            match entry.diff_type {
+                DiffType::LeftOnly(meta) => removed.push(File::new(entry.path, meta)),
+                DiffType::RightOnly(meta) => added.push(File::new(entry.path, meta)),
+                DiffType::Changed(..) => {}
+            }
+        }
+        self.find_renames(&removed, &added)
+    }
+
+    /// Pairs `removed` files with the `added` files they were renamed to.
+    ///
+    /// Each added file is compared with the removed files whose paths are
+    /// the most similar, up to `copytrace.maxcandidates` of them. The pairs
+    /// whose contents are similar enough are then picked greedily, the most
+    /// similar first, so each file is part of at most one rename. The
+    /// renames are sorted by their source path.
+    pub fn find_renames(&self, removed: &[File], added: &[File]) -> Result<Vec<Rename>> {
+        let mut matches = Vec::new();
+        for new in added {
+            let mut candidates: Vec<(u32, &File)> = removed
+                .iter()
+                .filter(|old| is_link(old.meta.file_type) == is_link(new.meta.file_type))
+                .map(|old| (path_similarity(&old.path, &new.path), old))
+                .collect();
+            candidates.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.path.cmp(&b.1.path)));
+            candidates.truncate(self.config.max_candidates);
+
+            for (path_score, old) in candidates {
+                let content_score = self.content_similarity(old, new)?;
+                if content_score >= self.config.similarity {
+                    matches.push((content_score, path_score, old, new));
+                }
+            }
+        }
+        matches.sort_by(|a, b| {
+            (b.0, b.1)
+                .cmp(&(a.0, a.1))
+                .then_with(|| (&a.2.path, &a.3.path).cmp(&(&b.2.path, &b.3.path)))
+        });
+
+        let mut used_old = HashSet::new();
+        let mut used_new = HashSet::new();
+        let mut renames = Vec::new();
+        for (similarity, _, old, new) in matches {
+            if used_old.contains(&old.path) || used_new.contains(&new.path) {
+                continue;
+            }
+            used_old.insert(&old.path);
+            used_new.insert(&new.path);
+            renames.push(Rename {
+                from: old.path.clone(),
+                to: new.path.clone(),
+                similarity,
+            });
+        }
+        renames.sort_by(|a, b| a.from.cmp(&b.from));
+        Ok(renames)
+    }
+
+    fn content_similarity(&self, old: &File, new: &File) -> Result<u32> {
+        if old.meta.hgid == new.meta.hgid {
+            return Ok(100);
+        }
+        let old = self.sample(old)?;
+        let new = self.sample(new)?;
+        Ok(content_similarity(&old, &new))
+    }
+
+    fn sample(&self, file: &File) -> Result<Vec<u8>> {
+        let key = Key::new(file.path.clone(), file.meta.hgid);
+        if let Some(sample) = self.samples.borrow().get(&key) {
+            return Ok(sample.clone());
+        }
+        let content = self
+            .store
+            .get(&key)?
+            .ok_or_else(|| format_err!("no content for {} in the store", key))?;
+        let content = strip_metadata(&content);
+        let sample = sample(content, self.config.sample_size).to_vec();
+        self.samples.borrow_mut().insert(key, sample.clone());
+        Ok(sample)
+    }
+}
+
+fn is_link(file_type: FileType) -> bool {
+    file_type == FileType::Symlink
+}
+
+/// Remove the copy metadata header (`\1\n...\1\n`) of a file revision.
+fn strip_metadata(content: &[u8]) -> &[u8] {
+    if !content.starts_with(b"\x01\n") {
+        return content;
+    }
+    match content[2..].windows(2).position(|w| w == b"\x01\n") {
+        Some(i) => &content[i + 4..],
+        None => content,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use manifest::FileMetadata;
+    use manifest_tree::{testutil::TestStore, TreeManifest};
+    use pathmatcher::AlwaysMatcher;
+    use revisionstore::{Delta, LocalStore, Metadata};
+    use types::{testutil::*, HgId};
+
+    #[derive(Default)]
+    struct MemoryDataStore(HashMap<Key, Vec<u8>>);
+
+    impl DataStore for MemoryDataStore {
+        fn get(&self, key: &Key) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.get(key).cloned())
+        }
+
+        fn get_delta(&self, _key: &Key) -> Result<Option<Delta>> {
+            Ok(None)
+        }
+
+        fn get_delta_chain(&self, _key: &Key) -> Result<Option<Vec<Delta>>> {
+            Ok(None)
+        }
+
+        fn get_meta(&self, _key: &Key) -> Result<Option<Metadata>> {
+            Ok(None)
+        }
+    }
+
+    impl LocalStore for MemoryDataStore {
+        fn get_missing(&self, keys: &[Key]) -> Result<Vec<Key>> {
+            Ok(keys
+                .iter()
+                .filter(|k| !self.0.contains_key(k))
+                .cloned()
+                .collect())
+        }
+    }
+
+    struct Repo {
+        data: MemoryDataStore,
+        trees: Arc<TestStore>,
+    }
+
+    impl Repo {
+        fn new() -> Self {
+            Repo {
+                data: MemoryDataStore::default(),
+                trees: Arc::new(TestStore::new()),
+            }
+        }
+
+        fn tree(&mut self, files: &[(&str, &str)]) -> TreeManifest {
+            let mut tree = TreeManifest::ephemeral(self.trees.clone());
+            for (path, content) in files {
+                let mut id = [0; HgId::len()];
+                id[..8].copy_from_slice(&(self.data.0.len() as u64 + 1).to_be_bytes());
+                let hgid = HgId::from_byte_array(id);
+                let key = Key::new(repo_path_buf(path), hgid);
+                self.data.0.insert(key, content.as_bytes().to_vec());
+                tree.insert(repo_path_buf(path), FileMetadata::regular(hgid))
+                    .unwrap();
+            }
+            tree
+        }
+    }
+
+    fn renames(renames: Vec<Rename>) -> Vec<(String, String)> {
+        renames
+            .into_iter()
+            .map(|r| (r.from.to_string(), r.to.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut repo = Repo::new();
+        let old = repo.tree(&[
+            ("src/a.rs", "fn a() {}\nfn b() {}\nfn c() {}\n"),
+            ("src/b.rs", "fn x() {}\nfn y() {}\n"),
+            ("src/c.rs", "unchanged\n"),
+            ("src/d.rs", "removed\n"),
+        ]);
+        let new = repo.tree(&[
+            ("lib/a.rs", "fn a() {}\nfn b() {}\nfn c2() {}\n"),
+            (
+                "src/bb.rs",
+                "\x01\ncopy: src/b.rs\n\x01\nfn x() {}\nfn y() {}\n",
+            ),
+            ("src/c.rs", "unchanged\n"),
+            ("src/e.rs", "added\n"),
+        ]);
+        let copytrace = CopyTrace::new(&repo.data, CopyTraceConfig::default());
+        let found = copytrace.trace(&old, &new, &AlwaysMatcher::new()).unwrap();
+        assert_eq!(
+            renames(found),
+            vec![
+                ("src/a.rs".to_string(), "lib/a.rs".to_string()),
+                ("src/b.rs".to_string(), "src/bb.rs".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_renames_prefers_most_similar() {
+        let mut repo = Repo::new();
+        let old = repo.tree(&[("a", "1\n2\n3\n4\n"), ("b", "1\n2\n3\n5\n")]);
+        let new = repo.tree(&[("c", "1\n2\n3\n4\n")]);
+        let files = |tree: &TreeManifest| {
+            tree.files(&AlwaysMatcher::new())
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        };
+        let (removed, added) = (files(&old), files(&new));
+
+        let copytrace = CopyTrace::new(&repo.data, CopyTraceConfig::default());
+        let found = copytrace.find_renames(&removed, &added).unwrap();
+        assert_eq!(
+            found,
+            vec![Rename {
+                from: repo_path_buf("a"),
+                to: repo_path_buf("c"),
+                similarity: 100,
+            }]
+        );
+
+        // With a single candidate, only the path similarity picks between
+        // `a` and `b`, and both paths are equally different from `c`.
+        let config = CopyTraceConfig {
+            max_candidates: 1,
+            similarity: 80,
+            ..Default::default()
+        };
+        let copytrace = CopyTrace::new(&repo.data, config);
+        let found = copytrace.find_renames(&removed, &added).unwrap();
+        assert_eq!(renames(found), vec![("a".to_string(), "c".to_string())]);
+
+        let config = CopyTraceConfig {
+            similarity: 100,
+            ..Default::default()
+        };
+        let copytrace = CopyTrace::new(&repo.data, config);
+        let found = copytrace.find_renames(&removed[1..], &added).unwrap();
+        assert!(found.is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! # filtereddag
+//!
+//! A view of a [`Dag`] with some ids hidden.
+
+use crate::id::Id;
+use crate::segment::Dag;
+use crate::spanset::SpanSet;
+use anyhow::Result;
+
+/// A [`Dag`] with a hidden set removed from the inputs and the results of
+/// queries.
+///
+/// The hidden set is expected to include its descendants, like obsolete
+/// commits and everything based on them. Then paths between visible ids
+/// never go through hidden ids, and queries behave as if hidden ids were
+/// not in the [`Dag`]. Use [`Visibility::filtered`] to hide ids that are
+/// not reachable from visible heads.
+///
+/// [`Visibility::filtered`]: crate::visibility::Visibility::filtered
+pub struct FilteredDag<'a> {
+    dag: &'a Dag,
+    hidden: SpanSet,
+}
+
+impl<'a> FilteredDag<'a> {
+    pub fn new(dag: &'a Dag, hidden: SpanSet) -> Self {
+        Self { dag, hidden }
+    }
+
+    /// The underlying [`Dag`], without filtering.
+    pub fn dag(&self) -> &'a Dag {
+        self.dag
+    }
+
+    /// The hidden ids.
+    pub fn hidden(&self) -> &SpanSet {
+        &self.hidden
+    }
+
+    /// Remove hidden ids from `set`.
+    pub fn filter(&self, set: impl Into<SpanSet>) -> SpanSet {
+        set.into().difference(&self.hidden)
+    }
+
+    /// Test if `id` is hidden.
+    pub fn is_hidden(&self, id: Id) -> bool {
+        self.hidden.contains(id)
+    }
+}
+
+// Filtered version of algorithms on Dag.
+impl<'a> FilteredDag<'a> {
+    /// See [`Dag::all`].
+    pub fn all(&self) -> Result<SpanSet> {
+        Ok(self.filter(self.dag.all()?))
+    }
+
+    /// See [`Dag::ancestors`].
+    pub fn ancestors(&self, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        Ok(self.filter(self.dag.ancestors(self.filter(set))?))
+    }
+
+    /// See [`Dag::descendants`].
+    pub fn descendants(&self, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        Ok(self.filter(self.dag.descendants(self.filter(set))?))
+    }
+
+    /// See [`Dag::parents`].
+    pub fn parents(&self, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        Ok(self.filter(self.dag.parents(self.filter(set))?))
+    }
+
+    /// See [`Dag::children`].
+    pub fn children(&self, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        Ok(self.filter(self.dag.children(self.filter(set))?))
+    }
+
+    /// See [`Dag::heads`].
+    pub fn heads(&self, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        self.dag.heads(self.filter(set))
+    }
+
+    /// See [`Dag::roots`].
+    pub fn roots(&self, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        self.dag.roots(self.filter(set))
+    }
+
+    /// See [`Dag::heads_ancestors`].
+    pub fn heads_ancestors(&self, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        self.dag.heads_ancestors(self.filter(set))
+    }
+
+    /// See [`Dag::range`].
+    pub fn range(&self, roots: impl Into<SpanSet>, heads: impl Into<SpanSet>) -> Result<SpanSet> {
+        let range = self.dag.range(self.filter(roots), self.filter(heads))?;
+        Ok(self.filter(range))
+    }
+
+    /// See [`Dag::only`].
+    pub fn only(&self, set: impl Into<SpanSet>, excluded: impl Into<SpanSet>) -> Result<SpanSet> {
+        let only = self.dag.only(self.filter(set), self.filter(excluded))?;
+        Ok(self.filter(only))
+    }
+
+    /// See [`Dag::common_ancestors`].
+    pub fn common_ancestors(&self, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        Ok(self.filter(self.dag.common_ancestors(self.filter(set))?))
+    }
+
+    /// See [`Dag::gca_all`].
+    pub fn gca_all(&self, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        Ok(self.filter(self.dag.gca_all(self.filter(set))?))
+    }
+
+    /// See [`Dag::gca_one`].
+    pub fn gca_one(&self, set: impl Into<SpanSet>) -> Result<Option<Id>> {
+        Ok(self.gca_all(set)?.max())
+    }
+
+    /// See [`Dag::is_ancestor`]. Hidden ids are not ancestors of anything.
+    pub fn is_ancestor(&self, ancestor_id: Id, descendant_id: Id) -> Result<bool> {
+        if self.is_hidden(ancestor_id) || self.is_hidden(descendant_id) {
+            return Ok(false);
+        }
+        self.dag.is_ancestor(ancestor_id, descendant_id)
+    }
+}
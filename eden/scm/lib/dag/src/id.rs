@@ -110,6 +110,12 @@ impl Group {
     pub const fn max_id(self) -> Id {
         Id(self.min_id().0 + ((1u64 << (64 - Self::BITS)) - 1))
     }
+
+    /// The [`Id`] at `offset` in this group. See [`Id::offset`].
+    pub fn id(self, offset: u64) -> Id {
+        debug_assert!(offset <= self.max_id().0 - self.min_id().0);
+        Id(self.min_id().0 + offset)
+    }
 }
 
 impl Id {
@@ -120,6 +126,12 @@ impl Id {
         Group(group)
     }
 
+    /// The position of an Id in its [`Group`]. The first [`Id`] of a group
+    /// has offset 0.
+    pub fn offset(self) -> u64 {
+        self.0 - self.group().min_id().0
+    }
+
     /// Similar to `self..=other`.
     pub fn to(self, other: Id) -> IdIter {
         IdIter {
@@ -147,11 +159,10 @@ impl Id {
 
 impl fmt::Display for Id {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let group = self.group();
-        if group == Group::NON_MASTER {
+        if self.group() == Group::NON_MASTER {
             write!(f, "N")?;
         }
-        write!(f, "{}", self.0 - group.min_id().0)
+        write!(f, "{}", self.offset())
     }
 }
 
@@ -10,6 +10,7 @@
 //! See [`IdMap`] for the main structure.
 
 use crate::id::{Group, Id, VertexName};
+use crate::spanset::SpanSet;
 use anyhow::{bail, ensure, format_err, Result};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use fs2::FileExt;
@@ -20,6 +21,7 @@ use std::io::{Cursor, Read, Write};
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{self, AtomicU64};
+use vlqencoding::{VLQDecode, VLQEncode};
 
 /// Bi-directional mapping between an integer id and a name (`[u8]`).
 pub struct IdMap {
@@ -27,6 +29,7 @@ pub struct IdMap {
     path: PathBuf,
     cached_next_free_ids: [AtomicU64; Group::COUNT],
     pub(crate) need_rebuild_non_master: bool,
+    read_only: bool,
 }
 
 /// Guard to make sure [`IdMap`] on-disk writes are race-free.
@@ -48,18 +51,43 @@ impl IdMap {
     /// with this.
     const MAGIC_CLEAR_NON_MASTER: &'static [u8] = b"CLRNM";
 
+    /// Magic bytes in `Log` that indicates "remove the id->name mappings of
+    /// the ids that follow". A valid entry starts with its group, which is
+    /// smaller than `b'R'`, so it does not conflict with this.
+    const MAGIC_REMOVE_IDS: &'static [u8] = b"RMIDS";
+
     /// Create an [`IdMap`] backed by the given directory.
     ///
     /// By default, only read-only operations are allowed. For writing
     /// access, call [`IdMap::make_writable`] to get a writable instance.
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let path = path.as_ref();
+        Self::open_internal(path.as_ref(), false)
+    }
+
+    /// Open an existing [`IdMap`] at the given directory for reading.
+    ///
+    /// Like [`Dag::open_read_only`], reading does not take locks,
+    /// [`IdMap::reload`] picks up data written by other processes, and
+    /// writes to disk are errors.
+    ///
+    /// [`Dag::open_read_only`]: crate::segment::Dag::open_read_only
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_internal(path.as_ref(), true)
+    }
+
+    fn open_internal(path: &Path, read_only: bool) -> Result<Self> {
         let log = log::OpenOptions::new()
-            .create(true)
+            .create(!read_only)
             .index("id", |data| {
                 assert!(Self::MAGIC_CLEAR_NON_MASTER.len() < 8);
                 assert!(Group::BITS == 8);
-                if data.len() < 8 {
+                assert!(Group::COUNT < Self::MAGIC_REMOVE_IDS[0] as usize);
+                if data.starts_with(Self::MAGIC_REMOVE_IDS) {
+                    data[Self::MAGIC_REMOVE_IDS.len()..]
+                        .chunks(8)
+                        .map(|key| log::IndexOutput::Remove(key.to_vec().into_boxed_slice()))
+                        .collect()
+                } else if data.len() < 8 {
                     if data == Self::MAGIC_CLEAR_NON_MASTER {
                         vec![log::IndexOutput::RemovePrefix(Box::new([
                             Group::NON_MASTER.0 as u8,
@@ -72,7 +100,7 @@ impl IdMap {
                 }
             })
             .index("name", |data| {
-                if data.len() >= 8 {
+                if data.len() >= 8 && !data.starts_with(Self::MAGIC_REMOVE_IDS) {
                     vec![log::IndexOutput::Reference(8..data.len() as u64)]
                 } else {
                     Vec::new()
@@ -88,6 +116,7 @@ impl IdMap {
             path,
             cached_next_free_ids: Default::default(),
             need_rebuild_non_master: false,
+            read_only,
         })
     }
 
@@ -99,6 +128,7 @@ impl IdMap {
     ///
     /// Block if another instance is taking the lock.
     pub fn prepare_filesystem_sync(&mut self) -> Result<SyncableIdMap> {
+        ensure!(!self.read_only, "{:?} is opened read-only", &self.path);
         ensure!(
             self.log.iter_dirty().next().is_none(),
             "programming error: prepare_filesystem_sync must be called without dirty in-memory entries",
@@ -136,6 +166,39 @@ impl IdMap {
         Ok(())
     }
 
+    /// Write id assignments that only exist in memory to disk.
+    ///
+    /// This takes the filesystem lock and reloads, so assignments written by
+    /// other processes meanwhile are kept. Pending assignments that conflict
+    /// with them are an error. In that case nothing is written and the
+    /// pending assignments are dropped. The underlying log replaces its
+    /// metadata atomically, so readers never see a partial write.
+    ///
+    /// Pending removals are not supported. Use [`SyncableIdMap`] for them.
+    pub fn flush(&mut self) -> Result<()> {
+        ensure!(!self.read_only, "{:?} is opened read-only", &self.path);
+        let mut entries = Vec::new();
+        for data in self.log.iter_dirty() {
+            let mut data = data?;
+            ensure!(
+                data.len() >= 8 && !data.starts_with(Self::MAGIC_REMOVE_IDS),
+                "flush does not support pending removals"
+            );
+            let id = Id(data.read_u64::<BigEndian>().unwrap());
+            entries.push((id, data.to_vec()));
+        }
+
+        self.reload()?;
+        let mut map = self.prepare_filesystem_sync()?;
+        for (id, name) in entries {
+            if let Err(err) = map.insert(id, &name) {
+                map.reload()?;
+                return Err(err);
+            }
+        }
+        map.sync()
+    }
+
     /// Find name by a specified integer id.
     pub fn find_name_by_id(&self, id: Id) -> Result<Option<&[u8]>> {
         let mut key = Vec::with_capacity(8);
@@ -158,15 +221,15 @@ impl IdMap {
             Some(Ok(mut entry)) => {
                 ensure!(entry.len() >= 8, "index key should have 8 bytes at least");
                 let id = Id(entry.read_u64::<BigEndian>().unwrap());
-                // Double check. Id should <= next_free_id. This is useful for 'remove_non_master'
-                // and re-insert ids.
-                // This is because 'remove_non_master' only removes the id->name index, not
-                // the name->id index.
-                let group = id.group();
-                if group != Group::MASTER && self.next_free_id(group)? <= id {
-                    Ok(None)
-                } else {
+                // Double check. The id should map back to the name. This is useful for
+                // 'remove_non_master' and 'remove_ids', and re-insert ids.
+                // This is because 'remove_non_master' and 'remove_ids' only remove the
+                // id->name index, not the name->id index. A removed id might also be
+                // re-assigned to another name.
+                if self.find_name_by_id(id)? == Some(name) {
                     Ok(Some(id))
+                } else {
+                    Ok(None)
                 }
             }
             None => Ok(None),
@@ -189,6 +252,85 @@ impl IdMap {
         }))
     }
 
+    /// Find names starting with the given hex prefix. Return at most `limit`
+    /// names.
+    ///
+    /// This is useful to resolve abbreviated commit hashes.
+    pub fn find_names_by_hex_prefix(
+        &self,
+        hex_prefix: &[u8],
+        limit: usize,
+    ) -> Result<Vec<VertexName>> {
+        let mut names = Vec::with_capacity(limit.min(16));
+        for entry in self
+            .log
+            .lookup_prefix_hex(Self::INDEX_NAME_TO_ID, hex_prefix)?
+        {
+            if names.len() >= limit {
+                break;
+            }
+            let (name, _) = entry?;
+            // Skip names removed by 'remove_non_master' or 'remove_ids'.
+            if self.find_id_by_name(&name)?.is_some() {
+                names.push(VertexName::copy_from(&name));
+            }
+        }
+        Ok(names)
+    }
+
+    /// Find names of all ids in `set`, in the same (descending) order as
+    /// `set.iter()`.
+    ///
+    /// This does one range lookup per span, instead of one lookup per id.
+    /// Errors if an id does not have a name.
+    pub fn find_names(&self, set: &SpanSet) -> Result<Vec<VertexName>> {
+        let mut names = Vec::with_capacity(set.count().min(1 << 20) as usize);
+        for span in set.as_spans() {
+            let low = span.low.0.to_be_bytes();
+            let high = span.high.0.to_be_bytes();
+            let mut count = 0;
+            for entry in self
+                .log
+                .lookup_range(Self::INDEX_ID_TO_NAME, &low[..]..=&high[..])?
+                .rev()
+            {
+                let (key, mut values) = entry?;
+                let expected = span.high - count;
+                let id = Id((&key[..]).read_u64::<BigEndian>()?);
+                ensure!(id == expected, "{} not found", expected);
+                let entry = match values.next() {
+                    Some(entry) => entry?,
+                    None => bail!("key {:?} should have some values", key),
+                };
+                ensure!(entry.len() >= 8, "index key should have 8 bytes at least");
+                names.push(VertexName::copy_from(&entry[8..]));
+                count += 1;
+            }
+            if count < span.count() {
+                bail!("{} not found", span.high - count);
+            }
+        }
+        Ok(names)
+    }
+
+    /// Find ids of all `names`.
+    ///
+    /// Names are looked up in sorted order, so lookups of similar names share
+    /// index pages. Errors if a name is not found.
+    pub fn find_ids(&self, names: &[VertexName]) -> Result<SpanSet> {
+        let mut sorted: Vec<&VertexName> = names.iter().collect();
+        sorted.sort_unstable_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        sorted.dedup();
+        let mut ids = Vec::with_capacity(sorted.len());
+        for name in sorted {
+            match self.find_id_by_name(name.as_ref())? {
+                Some(id) => ids.push(id),
+                None => bail!("{:?} not found", name),
+            }
+        }
+        Ok(SpanSet::from_spans(ids))
+    }
+
     /// Insert a new entry mapping from a name to an id.
     ///
     /// Errors if the new entry conflicts with existing entries.
@@ -279,6 +421,98 @@ impl IdMap {
     }
 }
 
+// Portable snapshots.
+impl IdMap {
+    const EXPORT_VERSION: u8 = 1;
+
+    /// Write the names of ids in `ids` to a file at `path`, which can be
+    /// read by [`IdMap::import`]. Return the number of names written.
+    ///
+    /// The file is independent from the on-disk format of [`IdMap`]. It
+    /// lists `(id, name)` pairs sorted by id, with ids delta-encoded:
+    ///
+    /// ```plain,ignore
+    /// EXPORT_VERSION (u8) COUNT (VLQ) (ID_DELTA (VLQ) NAME_LEN (VLQ) NAME)*
+    /// ```
+    ///
+    /// Ids without names are skipped.
+    pub fn export(&self, ids: impl Into<SpanSet>, path: impl AsRef<Path>) -> Result<usize> {
+        let ids: SpanSet = ids.into();
+        let mut entries = Vec::new();
+        for span in ids.as_spans().iter().rev() {
+            let low = span.low.to_bytearray();
+            let high = span.high.to_bytearray();
+            let range = &low[..]..=&high[..];
+            for item in self.log.lookup_range(Self::INDEX_ID_TO_NAME, range)? {
+                let (_, mut values) = item?;
+                if let Some(entry) = values.nth(0) {
+                    let mut entry = entry?;
+                    ensure!(entry.len() >= 8, "index key should have 8 bytes at least");
+                    let id = entry.read_u64::<BigEndian>()?;
+                    entries.push((id, entry));
+                }
+            }
+        }
+
+        let mut buf = Vec::with_capacity(entries.len() * 24 + 8);
+        buf.push(Self::EXPORT_VERSION);
+        buf.write_vlq(entries.len())?;
+        let mut previous_id = 0;
+        for (id, name) in entries.iter() {
+            buf.write_vlq(id - previous_id)?;
+            buf.write_vlq(name.len())?;
+            buf.write_all(name)?;
+            previous_id = *id;
+        }
+        indexedlog::utils::atomic_write(path, &buf, true)?;
+        Ok(entries.len())
+    }
+
+    /// Insert the `(id, name)` pairs from a file written by
+    /// [`IdMap::export`]. Return the number of pairs read.
+    ///
+    /// Like [`IdMap::insert`], errors if a pair conflicts with existing
+    /// entries. Pairs already in the map are ignored. Changes are in memory
+    /// until [`SyncableIdMap::sync`] is called.
+    pub fn import(&mut self, path: impl AsRef<Path>) -> Result<usize> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        let mut cur = Cursor::new(&data[..]);
+        match data.first() {
+            Some(&Self::EXPORT_VERSION) => cur.set_position(1),
+            Some(version) => bail!("unsupported IdMap export version {} in {:?}", version, path),
+            None => bail!("cannot read IdMap export version in {:?}", path),
+        }
+        let count: usize = cur.read_vlq()?;
+        let mut id = 0u64;
+        for i in 0..count {
+            let delta: u64 = cur.read_vlq()?;
+            ensure!(
+                i == 0 || delta > 0,
+                "invalid IdMap export: ids are not sorted"
+            );
+            id = id
+                .checked_add(delta)
+                .filter(|&id| id <= Id::MAX.0)
+                .ok_or_else(|| format_err!("invalid IdMap export: id out of range"))?;
+            let len: usize = cur.read_vlq()?;
+            let start = cur.position() as usize;
+            ensure!(
+                data.len() - start >= len,
+                "invalid IdMap export: name is truncated"
+            );
+            self.insert(Id(id), &data[start..start + len])?;
+            cur.set_position((start + len) as u64);
+        }
+        ensure!(
+            cur.position() as usize == data.len(),
+            "invalid IdMap export: unexpected data after {} names",
+            count
+        );
+        Ok(count)
+    }
+}
+
 // Interaction with a DAG.
 impl IdMap {
     /// Assign an id for a head in a DAG. This implies ancestors of the
@@ -439,6 +673,41 @@ impl IdMap {
         );
         Ok(())
     }
+
+    /// Remove the mappings of the given ids, for example the ids removed by
+    /// [`crate::segment::Dag::strip`].
+    ///
+    /// In each group, the removed ids must be the largest ids of the group,
+    /// so the remaining ids stay contiguous. Otherwise, this is an error and
+    /// nothing is removed.
+    pub fn remove_ids(&mut self, set: &SpanSet) -> Result<()> {
+        let mut data = Self::MAGIC_REMOVE_IDS.to_vec();
+        for &group in Group::ALL.iter() {
+            let group_span: SpanSet = (group.min_id()..=group.max_id()).into();
+            let group_removed = set.intersection(&group_span);
+            let low = match group_removed.min() {
+                Some(low) => low,
+                None => continue,
+            };
+            let next = self.next_free_id(group)?;
+            ensure!(
+                low < next
+                    && group_removed.max() == Some(next - 1)
+                    && group_removed.count() == next.0 - low.0,
+                "cannot remove {:?}: ids are not reassigned so only the largest ids of a group can be removed",
+                &group_removed
+            );
+            for id in low.to(next - 1) {
+                data.write_u64::<BigEndian>(id.0).unwrap();
+            }
+        }
+        if data.len() > Self::MAGIC_REMOVE_IDS.len() {
+            self.log.append(data)?;
+            // Invalidate the next free id cache.
+            self.cached_next_free_ids = Default::default();
+        }
+        Ok(())
+    }
 }
 
 impl<'a> SyncableIdMap<'a> {
@@ -458,6 +727,10 @@ impl fmt::Debug for IdMap {
         write!(f, "IdMap {{\n")?;
         for data in self.log.iter() {
             if let Ok(mut data) = data {
+                // Skip "remove" markers.
+                if data.len() < 8 || data.starts_with(Self::MAGIC_REMOVE_IDS) {
+                    continue;
+                }
                 let id = data.read_u64::<BigEndian>().unwrap();
                 let mut name = Vec::with_capacity(20);
                 data.read_to_end(&mut name).unwrap();
@@ -512,6 +785,7 @@ impl IdMapLike for IdMap {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::segment::Dag;
     use tempfile::tempdir;
 
     #[test]
@@ -571,4 +845,155 @@ mod tests {
 "#
         );
     }
+
+    #[test]
+    fn test_flush() {
+        let dir = tempdir().unwrap();
+        let mut map1 = IdMap::open(dir.path()).unwrap();
+        let mut map2 = IdMap::open(dir.path()).unwrap();
+        map1.insert(Id(0), b"abc").unwrap();
+        map1.insert(Id(1), b"def").unwrap();
+        map2.insert(Id(0), b"abc").unwrap();
+        map2.insert(Id(2), b"ghi").unwrap();
+
+        // Both processes' assignments are kept.
+        map1.flush().unwrap();
+        map2.flush().unwrap();
+        let map = IdMap::open(dir.path()).unwrap();
+        assert_eq!(map.find_id_by_name(b"def").unwrap(), Some(Id(1)));
+        assert_eq!(map.find_id_by_name(b"ghi").unwrap(), Some(Id(2)));
+
+        // Conflicting assignments are not written.
+        map1.insert(Id(3), b"jkl").unwrap();
+        map2.insert(Id(3), b"mno").unwrap();
+        map1.flush().unwrap();
+        map2.flush().unwrap_err();
+        let map = IdMap::open(dir.path()).unwrap();
+        assert_eq!(map.find_name_by_id(Id(3)).unwrap().unwrap(), b"jkl");
+        assert_eq!(map.find_id_by_name(b"mno").unwrap(), None);
+        assert!(map2.prepare_filesystem_sync().is_ok());
+    }
+
+    #[test]
+    fn test_assign_head_deep() {
+        // A long linear history does not overflow the stack.
+        let dir = tempdir().unwrap();
+        let mut map = IdMap::open(dir.path().join("idmap")).unwrap();
+        let mut map = map.prepare_filesystem_sync().unwrap();
+        let n: u64 = 100000;
+        let name = |i: u64| VertexName::copy_from(&i.to_be_bytes());
+        let parents_by_name = |name: VertexName| -> Result<Vec<VertexName>> {
+            let i = (&name.as_ref()[..]).read_u64::<BigEndian>()?;
+            Ok(if i == 0 {
+                Vec::new()
+            } else {
+                vec![VertexName::copy_from(&(i - 1).to_be_bytes())]
+            })
+        };
+        let id = map
+            .assign_head(name(n - 1), parents_by_name, Group::MASTER)
+            .unwrap();
+        assert_eq!(id, Id(n - 1));
+        assert_eq!(map.find_id_by_name(name(0).as_ref()).unwrap(), Some(Id(0)));
+
+        let mut dag = Dag::open(dir.path().join("segments")).unwrap();
+        let get_parents = map.build_get_parents_by_id(&parents_by_name);
+        dag.build_segments_volatile(id, &get_parents).unwrap();
+        assert_eq!(format!("{:?}", dag), "Lv0: RH0-99999[]");
+    }
+
+    #[test]
+    fn test_export_import() {
+        let dir = tempdir().unwrap();
+        let mut map = IdMap::open(dir.path().join("a")).unwrap();
+        map.insert(Id(0), b"abc").unwrap();
+        map.insert(Id(1), b"abd").unwrap();
+        map.insert(Id(5), b"def").unwrap();
+        map.insert(Id(300), b"ghi").unwrap();
+        let id = map.next_free_id(Group::NON_MASTER).unwrap();
+        map.insert(id, b"jkl").unwrap();
+
+        let path = dir.path().join("export");
+        let count = map
+            .export(SpanSet::from_spans(vec![1..=5, 200..=400]), &path)
+            .unwrap();
+        assert_eq!(count, 3);
+
+        let mut imported = IdMap::open(dir.path().join("b")).unwrap();
+        assert_eq!(imported.import(&path).unwrap(), 3);
+        assert_eq!(
+            format!("{:?}", imported),
+            "IdMap {\n  abd: 1,\n  def: 5,\n  ghi: 300,\n}\n"
+        );
+        // Importing again is a no-op.
+        assert_eq!(imported.import(&path).unwrap(), 3);
+
+        // Conflicting names are rejected.
+        let mut conflict = IdMap::open(dir.path().join("c")).unwrap();
+        conflict.insert(Id(5), b"xyz").unwrap();
+        assert!(conflict.import(&path).is_err());
+
+        // Malformed files are rejected.
+        let data = fs::read(&path).unwrap();
+        for bad in vec![&[][..], &[2][..], &data[..data.len() - 1]] {
+            fs::write(&path, bad).unwrap();
+            assert!(IdMap::open(dir.path().join("d"))
+                .unwrap()
+                .import(&path)
+                .is_err());
+        }
+
+        let all = map.export(SpanSet::full(), &path).unwrap();
+        assert_eq!(all, 5);
+    }
+
+    #[test]
+    fn test_find_names_by_hex_prefix() {
+        let dir = tempdir().unwrap();
+        let mut map = IdMap::open(dir.path()).unwrap();
+        map.insert(Id(0), b"abc").unwrap();
+        map.insert(Id(1), b"abd").unwrap();
+        map.insert(Id(2), b"def").unwrap();
+
+        let find = |prefix: &str, limit| -> Vec<Vec<u8>> {
+            map.find_names_by_hex_prefix(prefix.as_bytes(), limit)
+                .unwrap()
+                .into_iter()
+                .map(|name| name.as_ref().to_vec())
+                .collect()
+        };
+        assert_eq!(find("6162", 10), vec![b"abc".to_vec(), b"abd".to_vec()]);
+        assert_eq!(find("6162", 1), vec![b"abc".to_vec()]);
+        assert_eq!(find("616264", 10), vec![b"abd".to_vec()]);
+        assert_eq!(find("646566", 10), vec![b"def".to_vec()]);
+        assert!(find("7", 10).is_empty());
+    }
+
+    #[test]
+    fn test_find_names() {
+        let dir = tempdir().unwrap();
+        let mut map = IdMap::open(dir.path()).unwrap();
+        let id = Group::NON_MASTER.min_id();
+        map.insert(Id(0), b"abc").unwrap();
+        map.insert(Id(1), b"abd").unwrap();
+        map.insert(Id(2), b"def").unwrap();
+        map.insert(id, b"ghi").unwrap();
+
+        let set = SpanSet::from_spans(vec![Id(0)..=Id(2), id..=id]);
+        let names = map.find_names(&set).unwrap();
+        let name = |s: &[u8]| VertexName::copy_from(s);
+        assert_eq!(
+            names,
+            vec![name(b"ghi"), name(b"def"), name(b"abd"), name(b"abc")]
+        );
+        assert!(map.find_names(&SpanSet::empty()).unwrap().is_empty());
+        assert!(map.find_names(&SpanSet::from(Id(0)..=Id(3))).is_err());
+        assert!(map.find_names(&SpanSet::from(id..=(id + 1))).is_err());
+
+        let ids = map
+            .find_ids(&[name(b"def"), name(b"abc"), name(b"ghi")])
+            .unwrap();
+        assert_eq!(ids.iter().collect::<Vec<Id>>(), vec![id, Id(2), Id(0)]);
+        assert!(map.find_ids(&[name(b"abc"), name(b"xyz")]).is_err());
+    }
 }
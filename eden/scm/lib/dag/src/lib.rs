@@ -11,17 +11,29 @@
 //!
 //! Building blocks for the commit graph used by source control.
 
+pub mod filtereddag;
 pub mod id;
 pub mod idmap;
 pub mod nameddag;
+pub mod phases;
 pub mod protocol;
+pub mod revlogindex;
+pub mod revset;
 pub mod segment;
+pub mod setcache;
 pub mod spanset;
+#[cfg(any(test, feature = "for-tests"))]
+pub mod testutil;
+pub mod visibility;
 
+pub use filtereddag::FilteredDag;
 pub use id::{Group, Id, VertexName};
 pub use idmap::IdMap;
 pub use nameddag::NamedDag;
+pub use phases::Phases;
 pub use segment::Dag;
+pub use setcache::SetCache;
+pub use visibility::Visibility;
 
 #[cfg(test)]
 mod tests;
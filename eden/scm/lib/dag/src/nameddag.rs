@@ -14,10 +14,17 @@ use crate::id::VertexName;
 use crate::idmap::IdMap;
 use crate::idmap::IdMapLike;
 use crate::idmap::SyncableIdMap;
+use crate::phases::Phases;
+use crate::protocol::{
+    CloneData, Process, RemoteProtocol, RequestLocationToName, RequestNameToLocation,
+};
+use crate::revlogindex::{self, ImportPhase, RevlogIndex};
 use crate::segment::Dag;
 use crate::segment::SyncableDag;
-use anyhow::{bail, Result};
+use crate::spanset::SpanSet;
+use anyhow::{bail, ensure, Result};
 use std::collections::{HashMap, HashSet};
+use std::ops::DerefMut;
 use std::path::Path;
 
 /// A DAG that uses VertexName instead of ids as vertexes.
@@ -42,6 +49,23 @@ impl NamedDag {
         Ok(Self { dag, map })
     }
 
+    /// Open an existing [`NamedDag`] for reading, without taking locks.
+    ///
+    /// Writers sync the map before the dag, and the dag is loaded before the
+    /// map here, so every id in the dag has a name in the map. This only
+    /// holds if writers append. A reader racing with
+    /// [`NamedDag::remove_non_master`] or [`NamedDag::strip`] can see
+    /// non-master ids whose names were removed or reassigned. Use
+    /// [`NamedDag::reload`] to get a newer view.
+    ///
+    /// See [`Dag::open_read_only`] for details.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let dag = Dag::open_read_only(path.join("segments"))?;
+        let map = IdMap::open_read_only(path.join("idmap"))?;
+        Ok(Self { dag, map })
+    }
+
     /// Build segments. Write to disk.
     pub fn build<F>(
         &mut self,
@@ -84,19 +108,308 @@ impl NamedDag {
         Ok(())
     }
 
+    /// Mark `heads` and their ancestors as public in `phases`, and move them
+    /// to the master group. Write the dag to disk.
+    ///
+    /// Public commits are not expected to be stripped or hidden, so they
+    /// belong to the master group. Remaining non-master ids are reassigned.
+    /// Draft roots are tracked by names, so `phases` stays valid after ids
+    /// change. Use [`Phases::flush`] to write `phases` to disk.
+    pub fn make_public<F>(
+        &mut self,
+        parent_names_func: F,
+        phases: &mut Phases,
+        heads: &[VertexName],
+    ) -> Result<()>
+    where
+        F: Fn(VertexName) -> Result<Vec<VertexName>>,
+    {
+        self.build(parent_names_func, heads, &[])?;
+        phases.make_public(self, heads.iter().cloned())
+    }
+
+    /// Import all revisions from a revlog index into an empty dag. Write to
+    /// disk.
+    ///
+    /// See [`revlogindex::import`] for details.
+    pub fn import_revlog(
+        &mut self,
+        index: &RevlogIndex,
+        master_heads: &[VertexName],
+        progress: &dyn Fn(ImportPhase, usize, usize),
+    ) -> Result<()> {
+        // Take lock.
+        let mut map = self.map.prepare_filesystem_sync()?;
+        let mut dag = self.dag.prepare_filesystem_sync()?;
+
+        revlogindex::import(&mut map, &mut dag, index, master_heads, progress)?;
+
+        // Write to disk.
+        map.sync()?;
+        dag.sync(std::iter::once(&mut self.dag))?;
+        Ok(())
+    }
+
+    /// Remove all vertexes in the non-master group from both the map and
+    /// the dag. Write to disk.
+    ///
+    /// This drops local commits that were never added to the master group,
+    /// for example abandoned drafts. The master group is not renumbered, and
+    /// the non-master group is reused from its first id by later builds.
+    pub fn remove_non_master(&mut self) -> Result<()> {
+        // Take lock.
+        let mut map = self.map.prepare_filesystem_sync()?;
+        let mut dag = self.dag.prepare_filesystem_sync()?;
+
+        dag.remove_non_master()?;
+        map.remove_non_master()?;
+
+        // Write to disk.
+        map.sync()?;
+        dag.sync(std::iter::once(&mut self.dag))?;
+        Ok(())
+    }
+
+    /// Remove the given vertexes and their descendants from both the map
+    /// and the dag. Write to disk.
+    ///
+    /// Remaining non-master vertexes get new ids. Master ids are not
+    /// reassigned, so stripping a master vertex requires all master ids
+    /// after it to be its descendants.
+    pub fn strip(&mut self, names: &[VertexName]) -> Result<()> {
+        // Take lock.
+        let mut map = self.map.prepare_filesystem_sync()?;
+        let mut dag = self.dag.prepare_filesystem_sync()?;
+
+        let ids = names
+            .iter()
+            .map(|name| map.vertex_id(name.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        strip(&mut map, &mut dag, SpanSet::from_spans(ids))?;
+
+        // Write to disk.
+        map.sync()?;
+        dag.sync(std::iter::once(&mut self.dag))?;
+        Ok(())
+    }
+
+    /// Check invariants of the dag and the map. See [`Dag::verify`].
+    ///
+    /// In addition, heads of the dag must have names in the map, and the
+    /// names must resolve back to the same ids.
+    pub fn verify(&self) -> Result<()> {
+        self.dag.verify()?;
+        for head in self.dag.heads(self.dag.all()?)? {
+            let name = match self.map.find_name_by_id(head)? {
+                Some(name) => name,
+                None => bail!("head {} does not have a name", head),
+            };
+            ensure!(
+                self.map.find_id_by_name(name)? == Some(head),
+                "name {:?} of head {} does not resolve to it",
+                VertexName::copy_from(name),
+                head
+            );
+        }
+        Ok(())
+    }
+
     /// Reload segments from disk.
+    ///
+    /// The dag is reloaded before the map, for the same reason as
+    /// [`NamedDag::open_read_only`].
     pub fn reload(&mut self) -> Result<()> {
-        self.map.reload()?;
         self.dag.reload()?;
+        self.map.reload()?;
         Ok(())
     }
+}
+
+// Conversion between names and ids.
+impl NamedDag {
+    /// Convert names to a [`SpanSet`] of ids.
+    ///
+    /// Errors if a name is not in the dag.
+    pub fn to_id_set(&self, names: &[VertexName]) -> Result<SpanSet> {
+        self.map.find_ids(names)
+    }
+
+    /// Convert a [`SpanSet`] of ids to names, in descending id order.
+    /// Descendants come before their ancestors in that order.
+    pub fn to_names(&self, set: &SpanSet) -> Result<Vec<VertexName>> {
+        self.map.find_names(set)
+    }
 
-    // TODO: Consider implementing these:
-    // - NamedSpanSet - SpanSet wrapper that only exposes "names".
-    //   - Potentially, it has to implement smartset-like interfaces.
-    // - On NamedDag, methods wrapping dag algorithms that uses NamedSpanSet
-    //   as input and output.
-    // Before those APIs, LowLevelAccess might have to be used by callsites.
+    /// Run an algorithm on [`Dag`], with names as input and output.
+    fn map_names(
+        &self,
+        names: &[VertexName],
+        func: impl Fn(&Dag, SpanSet) -> Result<SpanSet>,
+    ) -> Result<Vec<VertexName>> {
+        let set = self.to_id_set(names)?;
+        self.to_names(&func(&self.dag, set)?)
+    }
+}
+
+// User-facing DAG-related algorithms, using names.
+// See the [`Dag`] methods with the same names for details.
+impl NamedDag {
+    /// All vertexes in the dag.
+    pub fn all(&self) -> Result<Vec<VertexName>> {
+        self.to_names(&self.dag.all()?)
+    }
+
+    pub fn ancestors(&self, names: &[VertexName]) -> Result<Vec<VertexName>> {
+        self.map_names(names, |dag, set| dag.ancestors(set))
+    }
+
+    pub fn descendants(&self, names: &[VertexName]) -> Result<Vec<VertexName>> {
+        self.map_names(names, |dag, set| dag.descendants(set))
+    }
+
+    pub fn parents(&self, names: &[VertexName]) -> Result<Vec<VertexName>> {
+        self.map_names(names, |dag, set| dag.parents(set))
+    }
+
+    pub fn children(&self, names: &[VertexName]) -> Result<Vec<VertexName>> {
+        self.map_names(names, |dag, set| dag.children(set))
+    }
+
+    pub fn heads(&self, names: &[VertexName]) -> Result<Vec<VertexName>> {
+        self.map_names(names, |dag, set| dag.heads(set))
+    }
+
+    pub fn roots(&self, names: &[VertexName]) -> Result<Vec<VertexName>> {
+        self.map_names(names, |dag, set| dag.roots(set))
+    }
+
+    pub fn heads_ancestors(&self, names: &[VertexName]) -> Result<Vec<VertexName>> {
+        self.map_names(names, |dag, set| dag.heads_ancestors(set))
+    }
+
+    pub fn gca_all(&self, names: &[VertexName]) -> Result<Vec<VertexName>> {
+        self.map_names(names, |dag, set| dag.gca_all(set))
+    }
+
+    pub fn gca_one(&self, names: &[VertexName]) -> Result<Option<VertexName>> {
+        let set = self.to_id_set(names)?;
+        match self.dag.gca_one(set)? {
+            Some(id) => Ok(Some(self.map.vertex_name(id)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn common_ancestors(&self, names: &[VertexName]) -> Result<Vec<VertexName>> {
+        self.map_names(names, |dag, set| dag.common_ancestors(set))
+    }
+
+    pub fn is_ancestor(&self, ancestor: &VertexName, descendant: &VertexName) -> Result<bool> {
+        let ancestor_id = self.map.vertex_id(ancestor.clone())?;
+        let descendant_id = self.map.vertex_id(descendant.clone())?;
+        self.dag.is_ancestor(ancestor_id, descendant_id)
+    }
+
+    pub fn only(&self, names: &[VertexName], excluded: &[VertexName]) -> Result<Vec<VertexName>> {
+        let excluded = self.to_id_set(excluded)?;
+        self.map_names(names, |dag, set| dag.only(set, excluded.clone()))
+    }
+
+    pub fn range(&self, roots: &[VertexName], heads: &[VertexName]) -> Result<Vec<VertexName>> {
+        let roots = self.to_id_set(roots)?;
+        self.map_names(heads, |dag, heads| dag.range(roots.clone(), heads))
+    }
+
+    /// Parents of a single vertex, in order. Unlike [`NamedDag::parents`],
+    /// the first parent comes first.
+    pub fn parent_names(&self, name: &VertexName) -> Result<Vec<VertexName>> {
+        let id = self.map.vertex_id(name.clone())?;
+        self.dag
+            .parent_ids(id)?
+            .into_iter()
+            .map(|id| self.map.vertex_name(id))
+            .collect()
+    }
+
+    pub fn first_ancestor_nth(&self, name: &VertexName, n: u64) -> Result<VertexName> {
+        let id = self.map.vertex_id(name.clone())?;
+        self.map.vertex_name(self.dag.first_ancestor_nth(id, n)?)
+    }
+}
+
+// Clone and lazy IdMap.
+impl NamedDag {
+    /// Segments and universally known names of the master group, for
+    /// [`NamedDag::import_clone_data`] on a client.
+    pub fn export_clone_data(&self) -> Result<CloneData> {
+        (&self.map, &self.dag).process(())
+    }
+
+    /// Initialize an empty dag with segments and a sparse IdMap from a
+    /// server. Write to disk.
+    ///
+    /// Ancestry queries work on the complete segments. Names that are not
+    /// in the sparse IdMap can be fetched by [`NamedDag::resolve_ids_remotely`]
+    /// and [`NamedDag::resolve_names_remotely`].
+    pub fn import_clone_data(&mut self, data: CloneData) -> Result<()> {
+        // Take lock.
+        let mut map = self.map.prepare_filesystem_sync()?;
+        let mut dag = self.dag.prepare_filesystem_sync()?;
+
+        (map.deref_mut(), &mut dag).process(data)?;
+
+        // Write to disk.
+        map.sync()?;
+        dag.sync(std::iter::once(&mut self.dag))?;
+        Ok(())
+    }
+
+    /// Fetch the names of ids in `set` that are missing from the local IdMap.
+    /// Write to disk.
+    pub fn resolve_ids_remotely(
+        &mut self,
+        set: &SpanSet,
+        remote: &dyn RemoteProtocol,
+    ) -> Result<()> {
+        let mut ids = Vec::new();
+        for id in set.iter() {
+            if self.map.find_name_by_id(id)?.is_none() {
+                ids.push(id);
+            }
+        }
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let request: RequestLocationToName = (&self.map, &self.dag).process(ids)?;
+        let response = remote.resolve_locations_to_names(request)?;
+
+        let mut map = self.map.prepare_filesystem_sync()?;
+        (map.deref_mut(), &self.dag).process(&response)?;
+        map.sync()
+    }
+
+    /// Fetch the ids of `names` that are missing from the local IdMap.
+    /// Write to disk.
+    pub fn resolve_names_remotely(
+        &mut self,
+        names: &[VertexName],
+        remote: &dyn RemoteProtocol,
+    ) -> Result<()> {
+        let mut missing = Vec::new();
+        for name in names {
+            if self.map.find_id_by_name(name.as_ref())?.is_none() {
+                missing.push(name.clone());
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let request: RequestNameToLocation = (&self.map, &self.dag).process(missing)?;
+        let response = remote.resolve_names_to_locations(request)?;
+
+        let mut map = self.map.prepare_filesystem_sync()?;
+        (map.deref_mut(), &self.dag).process(&response)?;
+        map.sync()
+    }
 }
 
 /// Export non-master DAG as parent_names_func on HashMap.
@@ -128,6 +441,62 @@ fn non_master_parent_names(
 pub fn rebuild_non_master(map: &mut SyncableIdMap, dag: &mut SyncableDag) -> Result<()> {
     // backup part of the named graph in memory.
     let parents = non_master_parent_names(map, dag)?;
+
+    // Remove existing non-master data.
+    dag.remove_non_master()?;
+    map.remove_non_master()?;
+
+    // Rebuild them.
+    build_non_master(map, dag, parents)
+}
+
+/// Remove the given ids and their descendants from IdMap and Segments.
+///
+/// Non-master ids are re-assigned so they stay contiguous.
+pub fn strip(map: &mut SyncableIdMap, dag: &mut SyncableDag, set: SpanSet) -> Result<()> {
+    let removed = dag.descendants(set)?;
+    let master = removed.intersection(&dag.master_group()?);
+    let non_master = removed.difference(&master);
+    if let Some(low) = master.min() {
+        // Check before changing anything.
+        let remaining = dag.master_group()?.difference(&master);
+        ensure!(
+            remaining.max() < Some(low),
+            "cannot strip {:?}: master ids are not reassigned and {:?} would be left behind",
+            &master,
+            remaining.intersection(&(low..=Group::MASTER.max_id()).into())
+        );
+    }
+
+    // backup the remaining part of the non-master graph in memory.
+    let parents = if non_master.is_empty() {
+        None
+    } else {
+        let mut parents = non_master_parent_names(map, dag)?;
+        for id in non_master.iter() {
+            parents.remove(&map.vertex_name(id)?);
+        }
+        dag.remove_non_master()?;
+        map.remove_non_master()?;
+        Some(parents)
+    };
+
+    dag.strip(master.clone())?;
+    map.remove_ids(&master)?;
+
+    match parents {
+        Some(parents) => build_non_master(map, dag, parents),
+        None => Ok(()),
+    }
+}
+
+/// Build the non-master group from a parent map exported by
+/// `non_master_parent_names`.
+fn build_non_master(
+    map: &mut SyncableIdMap,
+    dag: &mut SyncableDag,
+    parents: HashMap<VertexName, Vec<VertexName>>,
+) -> Result<()> {
     let mut heads = parents
         .keys()
         .collect::<HashSet<_>>()
@@ -141,11 +510,6 @@ pub fn rebuild_non_master(map: &mut SyncableIdMap, dag: &mut SyncableDag) -> Res
         .collect::<Vec<_>>();
     heads.sort_unstable();
 
-    // Remove existing non-master data.
-    dag.remove_non_master()?;
-    map.remove_non_master()?;
-
-    // Rebuild them.
     let parent_func = |name: VertexName| match parents.get(&name) {
         Some(names) => Ok(names.iter().cloned().collect()),
         None => bail!(
@@ -0,0 +1,177 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! # phases
+//!
+//! Track the boundary between public and draft commits.
+
+use crate::id::{Id, VertexName};
+use crate::idmap::IdMapLike;
+use crate::nameddag::NamedDag;
+use crate::spanset::SpanSet;
+use anyhow::{format_err, Result};
+use indexedlog::utils::atomic_write;
+use std::fs;
+use std::io::{Cursor, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use vlqencoding::{VLQDecode, VLQEncode};
+
+/// Phases of commits, stored as the roots of the draft commits.
+///
+/// A commit is draft if it is a descendant of a draft root, and public
+/// otherwise. Storing roots instead of per-commit phases keeps the boundary
+/// small, and lets [`Dag`](crate::Dag) algorithms answer phase queries for
+/// whole sets at once. Commits added to the graph later get the phase implied
+/// by their ancestors: descendants of draft commits are draft.
+///
+/// The draft set is cached. The cache is invalidated when the roots change,
+/// or when the content of the [`NamedDag`] changes.
+pub struct Phases {
+    path: PathBuf,
+    draft_roots: Vec<VertexName>,
+    // (Dag::invalidation_key, draft set)
+    cache: Mutex<Option<(u64, SpanSet)>>,
+}
+
+impl Phases {
+    /// Load phases from the given file. A missing file means all commits are
+    /// public.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let draft_roots = match fs::read(&path) {
+            Ok(data) => Self::parse_roots(&data)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path,
+            draft_roots,
+            cache: Mutex::new(None),
+        })
+    }
+
+    /// Write the draft roots to disk.
+    pub fn flush(&self) -> Result<()> {
+        let mut data = Vec::new();
+        for root in &self.draft_roots {
+            data.write_vlq(root.as_ref().len())?;
+            data.extend_from_slice(root.as_ref());
+        }
+        atomic_write(&self.path, data, true)?;
+        Ok(())
+    }
+
+    fn parse_roots(data: &[u8]) -> Result<Vec<VertexName>> {
+        let mut cur = Cursor::new(data);
+        let mut roots = Vec::new();
+        while (cur.position() as usize) < data.len() {
+            let len: usize = cur.read_vlq()?;
+            let start = cur.position() as usize;
+            let name = data
+                .get(start..start + len)
+                .ok_or_else(|| format_err!("truncated phase roots"))?;
+            roots.push(VertexName::copy_from(name));
+            cur.set_position((start + len) as u64);
+        }
+        Ok(roots)
+    }
+
+    /// The roots of the draft commits.
+    pub fn draft_roots(&self) -> &[VertexName] {
+        &self.draft_roots
+    }
+
+    /// Calculate the draft ids in `dag`. This is `draft()` in revsets.
+    ///
+    /// Draft roots that are not in `dag` are ignored.
+    pub fn draft(&self, dag: &NamedDag) -> Result<SpanSet> {
+        let key = dag.dag.invalidation_key()?;
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((cached_key, draft)) = cache.as_ref() {
+            if *cached_key == key {
+                return Ok(draft.clone());
+            }
+        }
+
+        let draft = dag.dag.descendants(self.root_ids(dag)?)?;
+        *cache = Some((key, draft.clone()));
+        Ok(draft)
+    }
+
+    /// Calculate the public ids in `dag`. This is `public()` in revsets.
+    pub fn public(&self, dag: &NamedDag) -> Result<SpanSet> {
+        Ok(dag.dag.all()?.difference(&self.draft(dag)?))
+    }
+
+    /// Test if `id` is draft.
+    pub fn is_draft(&self, dag: &NamedDag, id: Id) -> Result<bool> {
+        Ok(self.draft(dag)?.contains(id))
+    }
+
+    /// Mark `names` and their descendants as draft. This is used for commits
+    /// created locally.
+    pub fn add_draft_roots(
+        &mut self,
+        dag: &NamedDag,
+        names: impl IntoIterator<Item = VertexName>,
+    ) -> Result<()> {
+        let mut roots = self.root_ids(dag)?;
+        for name in names {
+            match dag.map.find_id_by_name(name.as_ref())? {
+                Some(id) => roots.push(id),
+                None => self.draft_roots.push(name),
+            }
+        }
+        self.set_draft(dag, dag.dag.descendants(roots)?)
+    }
+
+    /// Mark `heads` and their ancestors as public. This is used when commits
+    /// are pushed, or pulled from a public repository.
+    pub fn make_public(
+        &mut self,
+        dag: &NamedDag,
+        heads: impl IntoIterator<Item = VertexName>,
+    ) -> Result<()> {
+        let mut head_ids = SpanSet::empty();
+        for name in heads {
+            if let Some(id) = dag.map.find_id_by_name(name.as_ref())? {
+                head_ids.push(id);
+            }
+        }
+        let draft = self.draft(dag)?;
+        let public = dag.dag.ancestors(head_ids)?;
+        self.set_draft(dag, draft.difference(&public))
+    }
+
+    /// Replace the draft roots with the roots of `draft`, keeping the roots
+    /// that are not in `dag`.
+    fn set_draft(&mut self, dag: &NamedDag, draft: SpanSet) -> Result<()> {
+        let mut roots = Vec::new();
+        for name in &self.draft_roots {
+            if dag.map.find_id_by_name(name.as_ref())?.is_none() {
+                roots.push(name.clone());
+            }
+        }
+        for id in dag.dag.roots(draft)?.iter().rev() {
+            roots.push(dag.map.vertex_name(id)?);
+        }
+        self.draft_roots = roots;
+        *self.cache.get_mut().unwrap() = None;
+        Ok(())
+    }
+
+    fn root_ids(&self, dag: &NamedDag) -> Result<SpanSet> {
+        let mut ids = SpanSet::empty();
+        for name in &self.draft_roots {
+            if let Some(id) = dag.map.find_id_by_name(name.as_ref())? {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+}
@@ -15,13 +15,18 @@
 //!
 //! - Id -> Name: Id -> RequestLocationToName -> ResponseIdNamePair -> Name
 //! - Name -> Id: Name -> RequestNameToLocation -> ResponseIdNamePair -> Id
+//!
+//! At clone time, the client gets all segments of the master group, which
+//! are compact, but only a sparse idmap, as [`CloneData`]. Ancestry queries
+//! work locally on ids. Names are fetched on demand using [`RemoteProtocol`].
 
 use crate::id::VertexName;
 use crate::idmap::IdMapLike;
 use crate::segment::FirstAncestorConstraint;
+use crate::segment::SyncableDag;
 use crate::spanset::SpanSet;
-use crate::{segment::Dag, Id, IdMap};
-use anyhow::{format_err, Result};
+use crate::{segment::Dag, Id, IdMap, NamedDag};
+use anyhow::{ensure, format_err, Result};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
@@ -58,6 +63,21 @@ pub struct ResponseIdNamePair {
     pub path_names: Vec<(AncestorPath, Vec<VertexName>)>,
 }
 
+/// Segments and a sparse IdMap of the master group, sent to a client at
+/// clone time.
+///
+/// The IdMap only has the names that are known universally. See
+/// [`FirstAncestorConstraint::KnownUniversally`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloneData {
+    /// Flat segments as `(low, high, parents)`, sorted by `low`.
+    #[serde(rename = "s")]
+    pub flat_segments: Vec<(u64, u64, Vec<u64>)>,
+
+    #[serde(rename = "m")]
+    pub idmap: Vec<(u64, VertexName)>,
+}
+
 /// The `n`-th first ancestor of `x`. `x~n` in hg revset syntax.
 /// Usually, `x` is commonly known by the client and the server.
 ///
@@ -99,6 +119,25 @@ pub(crate) trait Process<I, O> {
     fn process(self, input: I) -> Result<O>;
 }
 
+/// A server with the complete IdMap and Dag.
+///
+/// A client with a sparse IdMap uses it to fetch segments at clone time,
+/// and to convert ids and names that are not known locally on demand.
+pub trait RemoteProtocol {
+    /// Segments and universally known names of the master group.
+    fn clone_data(&self) -> Result<CloneData>;
+
+    fn resolve_names_to_locations(
+        &self,
+        request: RequestNameToLocation,
+    ) -> Result<ResponseIdNamePair>;
+
+    fn resolve_locations_to_names(
+        &self,
+        request: RequestLocationToName,
+    ) -> Result<ResponseIdNamePair>;
+}
+
 // Basic implementation ------------------------------------------------------
 
 // Name -> Id, step 1: Name -> RequestNameToLocation
@@ -232,7 +271,7 @@ impl<'a> Process<&ResponseIdNamePair, ()> for (&'a mut IdMap, &'a Dag) {
             let mut id = dag.first_ancestor_nth(x, path.n)?;
             for (i, name) in names.iter().enumerate() {
                 if i > 0 {
-                    id = dag.first_ancestor_nth(x, 1)?;
+                    id = dag.first_ancestor_nth(id, 1)?;
                 }
                 map.insert(id, name.as_ref())?;
             }
@@ -240,3 +279,87 @@ impl<'a> Process<&ResponseIdNamePair, ()> for (&'a mut IdMap, &'a Dag) {
         Ok(())
     }
 }
+
+// Clone, step 1: () -> CloneData
+// Works on a complete IdMap, server-side.
+impl<M: IdMapLike> Process<(), CloneData> for (&M, &Dag) {
+    fn process(self, _input: ()) -> Result<CloneData> {
+        let map = &self.0;
+        let dag = &self.1;
+        let flat_segments = dag
+            .master_flat_segments()?
+            .into_iter()
+            .map(|(low, high, parents)| (low.0, high.0, parents.into_iter().map(|p| p.0).collect()))
+            .collect();
+        let idmap = dag
+            .universal()?
+            .into_iter()
+            .map(|id| Ok((id.0, map.vertex_name(id)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(CloneData {
+            flat_segments,
+            idmap,
+        })
+    }
+}
+
+// Clone, step 2: Apply CloneData to an empty IdMap and Dag.
+// Client-side.
+impl<'a> Process<CloneData, ()> for (&'a mut IdMap, &'a mut SyncableDag) {
+    fn process(self, data: CloneData) -> Result<()> {
+        let map = self.0;
+        let dag = self.1;
+        ensure!(
+            dag.all()?.is_empty(),
+            "clone data can only be applied to an empty dag"
+        );
+        let segments = &data.flat_segments;
+        let get_parents = |id: Id| -> Result<Vec<Id>> {
+            let index = segments
+                .binary_search_by(|&(low, high, _)| {
+                    if high < id.0 {
+                        std::cmp::Ordering::Less
+                    } else if low > id.0 {
+                        std::cmp::Ordering::Greater
+                    } else {
+                        std::cmp::Ordering::Equal
+                    }
+                })
+                .map_err(|_| format_err!("clone data does not cover {}", id))?;
+            let (low, _, parents) = &segments[index];
+            if *low == id.0 {
+                Ok(parents.iter().cloned().map(Id).collect())
+            } else {
+                Ok(vec![id - 1])
+            }
+        };
+        if let Some(&(_, high, _)) = segments.last() {
+            dag.build_segments_persistent(Id(high), &get_parents)?;
+        }
+        for (id, name) in data.idmap.iter() {
+            map.insert(Id(*id), name.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+// A local complete NamedDag can act as the server.
+impl RemoteProtocol for NamedDag {
+    fn clone_data(&self) -> Result<CloneData> {
+        (&self.map, &self.dag).process(())
+    }
+
+    fn resolve_names_to_locations(
+        &self,
+        request: RequestNameToLocation,
+    ) -> Result<ResponseIdNamePair> {
+        (&self.map, &self.dag).process(request)
+    }
+
+    fn resolve_locations_to_names(
+        &self,
+        request: RequestLocationToName,
+    ) -> Result<ResponseIdNamePair> {
+        (&self.map, &self.dag).process(request)
+    }
+}
@@ -0,0 +1,309 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! # revlogindex
+//!
+//! Read the commit graph from a Mercurial revlog index (ex.
+//! `00changelog.i`), and import it into [`IdMap`] and [`Dag`] in bulk.
+//!
+//! [`IdMap`]: crate::idmap::IdMap
+//! [`Dag`]: crate::segment::Dag
+
+use crate::id::{Group, Id, VertexName};
+use crate::idmap::SyncableIdMap;
+use crate::segment::SyncableDag;
+use anyhow::{bail, ensure, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::cell::Cell;
+use std::fs;
+use std::path::Path;
+
+/// Parents and nodes of revisions in a revlog index.
+pub struct RevlogIndex {
+    // (p1, p2, node) by revision number. Parents are -1 if missing.
+    entries: Vec<(i32, i32, [u8; 20])>,
+}
+
+/// Phases of [`import`], reported with progress.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ImportPhase {
+    /// Assigning ids to revisions.
+    AssignIds,
+    /// Building flat segments.
+    BuildSegments,
+}
+
+impl RevlogIndex {
+    const ENTRY_SIZE: usize = 64;
+    const FLAG_INLINE_DATA: u32 = 1 << 16;
+    const VERSION_NG: u32 = 1;
+
+    /// Read a revlog index file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Parse the content of a revlog index file. Both inline and separated
+    /// revision data are supported. Revision data is skipped.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut entries = Vec::with_capacity(data.len() / Self::ENTRY_SIZE);
+        if data.is_empty() {
+            return Ok(Self { entries });
+        }
+        ensure!(data.len() >= 4, "revlog index is too short");
+        let header = BigEndian::read_u32(&data[0..4]);
+        let version = header & 0xffff;
+        ensure!(
+            version == Self::VERSION_NG,
+            "unsupported revlog version {}",
+            version
+        );
+        let inline = header & Self::FLAG_INLINE_DATA != 0;
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let entry = match data.get(pos..pos + Self::ENTRY_SIZE) {
+                Some(entry) => entry,
+                None => bail!("revlog index is truncated at offset {}", pos),
+            };
+            let rev = entries.len() as i32;
+            let p1 = BigEndian::read_i32(&entry[24..28]);
+            let p2 = BigEndian::read_i32(&entry[28..32]);
+            for &p in [p1, p2].iter() {
+                ensure!(
+                    p < rev && p >= -1,
+                    "revision {} has invalid parent {}",
+                    rev,
+                    p
+                );
+            }
+            let mut node = [0u8; 20];
+            node.copy_from_slice(&entry[32..52]);
+            entries.push((p1, p2, node));
+            pos += Self::ENTRY_SIZE;
+            if inline {
+                pos += BigEndian::read_u32(&entry[8..12]) as usize;
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    /// Number of revisions.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Node (commit hash) of a revision.
+    pub fn node(&self, rev: u32) -> VertexName {
+        VertexName::copy_from(&self.entries[rev as usize].2)
+    }
+
+    /// Parent revisions of a revision. The first parent comes first.
+    pub fn parent_revs(&self, rev: u32) -> Vec<u32> {
+        let (p1, p2, _) = self.entries[rev as usize];
+        match (p1, p2) {
+            (-1, -1) => Vec::new(),
+            (p, -1) | (-1, p) => vec![p as u32],
+            (p1, p2) if p1 == p2 => vec![p1 as u32],
+            (p1, p2) => vec![p1 as u32, p2 as u32],
+        }
+    }
+}
+
+/// Import all revisions in `index` into an empty IdMap and Dag.
+///
+/// Ancestors of `master_heads` go to the master group. Other revisions go
+/// to the non-master group. Ids are assigned in revision order, which is
+/// already topologically sorted.
+///
+/// `progress` is called for every revision with the current phase, the
+/// number of revisions processed in that phase, and the total.
+pub fn import(
+    map: &mut SyncableIdMap,
+    dag: &mut SyncableDag,
+    index: &RevlogIndex,
+    master_heads: &[VertexName],
+    progress: &dyn Fn(ImportPhase, usize, usize),
+) -> Result<()> {
+    ensure!(
+        dag.all()?.is_empty()
+            && Group::ALL
+                .iter()
+                .all(|&g| map.next_free_id(g).ok() == Some(g.min_id())),
+        "revlog can only be imported into an empty dag"
+    );
+    let total = index.len();
+
+    // Find ancestors of master heads.
+    let mut is_master = vec![false; total];
+    for name in master_heads {
+        match index.entries.iter().position(|e| e.2 == name.as_ref()) {
+            Some(rev) => is_master[rev] = true,
+            None => bail!("master head {:?} is not in the revlog", name),
+        }
+    }
+    for rev in (0..total).rev() {
+        if is_master[rev] {
+            for p in index.parent_revs(rev as u32) {
+                is_master[p as usize] = true;
+            }
+        }
+    }
+
+    // Assign ids.
+    let mut next_ids = [Group::MASTER.min_id(), Group::NON_MASTER.min_id()];
+    let mut rev_to_id = Vec::with_capacity(total);
+    for (rev, &is_master) in is_master.iter().enumerate() {
+        let group = if is_master {
+            Group::MASTER
+        } else {
+            Group::NON_MASTER
+        };
+        let id = next_ids[group.0];
+        next_ids[group.0] = id + 1;
+        map.insert(id, index.entries[rev].2.as_ref())?;
+        rev_to_id.push(id);
+        progress(ImportPhase::AssignIds, rev + 1, total);
+    }
+
+    // Build segments.
+    let mut id_to_rev = [Vec::new(), Vec::new()];
+    for (rev, id) in rev_to_id.iter().enumerate() {
+        id_to_rev[id.group().0].push(rev as u32);
+    }
+    let done = Cell::new(0);
+    let get_parents = |id: Id| -> Result<Vec<Id>> {
+        let rev = id_to_rev[id.group().0][id.offset() as usize];
+        done.set(done.get() + 1);
+        progress(ImportPhase::BuildSegments, done.get(), total);
+        Ok(index
+            .parent_revs(rev)
+            .into_iter()
+            .map(|p| rev_to_id[p as usize])
+            .collect())
+    };
+    for &group in Group::ALL.iter() {
+        let id = next_ids[group.0];
+        if id > group.min_id() {
+            dag.build_segments_persistent(id - 1, &get_parents)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NamedDag;
+    use byteorder::WriteBytesExt;
+    use tempfile::tempdir;
+
+    /// Serialize a revlog index. Inline revisions have `data_len` bytes of
+    /// data after each entry.
+    fn revlog_index(parents: &[(i32, i32)], inline: bool, data_len: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (rev, &(p1, p2)) in parents.iter().enumerate() {
+            if rev == 0 {
+                let flags = if inline {
+                    RevlogIndex::FLAG_INLINE_DATA
+                } else {
+                    0
+                };
+                buf.write_u32::<BigEndian>(flags | RevlogIndex::VERSION_NG)
+                    .unwrap();
+            } else {
+                buf.write_u32::<BigEndian>(0).unwrap();
+            }
+            buf.write_u32::<BigEndian>(0).unwrap(); // offset and flags
+            buf.write_u32::<BigEndian>(data_len).unwrap(); // compressed length
+            buf.write_u32::<BigEndian>(data_len).unwrap(); // uncompressed length
+            buf.write_i32::<BigEndian>(rev as i32).unwrap(); // base revision
+            buf.write_i32::<BigEndian>(rev as i32).unwrap(); // link revision
+            buf.write_i32::<BigEndian>(p1).unwrap();
+            buf.write_i32::<BigEndian>(p2).unwrap();
+            buf.extend_from_slice(&node(rev));
+            buf.extend_from_slice(&[0u8; 12]);
+            if inline {
+                buf.extend_from_slice(&vec![b'x'; data_len as usize]);
+            }
+        }
+        buf
+    }
+
+    fn node(rev: usize) -> [u8; 20] {
+        let mut node = [0u8; 20];
+        node[0] = b'A' + rev as u8;
+        node
+    }
+
+    #[test]
+    fn test_parse() {
+        // 0-1-2-4
+        //    \ /
+        //     3
+        let parents = [(-1, -1), (0, -1), (1, -1), (1, -1), (2, 3)];
+        for &inline in [false, true].iter() {
+            let index = RevlogIndex::from_bytes(&revlog_index(&parents, inline, 5)).unwrap();
+            assert_eq!(index.len(), 5);
+            assert_eq!(index.parent_revs(0), Vec::<u32>::new());
+            assert_eq!(index.parent_revs(4), vec![2, 3]);
+            assert_eq!(index.node(3), VertexName::copy_from(&node(3)));
+        }
+
+        // Truncated or invalid data.
+        let data = revlog_index(&parents, false, 5);
+        assert!(RevlogIndex::from_bytes(&data[..data.len() - 1]).is_err());
+        assert!(RevlogIndex::from_bytes(&revlog_index(&[(-1, -1), (1, -1)], false, 0)).is_err());
+    }
+
+    #[test]
+    fn test_import() {
+        // 0-1-2-4-6
+        //    \ /
+        //     3-5
+        let parents = [
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (1, -1),
+            (2, 3),
+            (3, -1),
+            (4, -1),
+        ];
+        let index = RevlogIndex::from_bytes(&revlog_index(&parents, true, 3)).unwrap();
+        let dir = tempdir().unwrap();
+        let mut named_dag = NamedDag::open(dir.path()).unwrap();
+        let updates = std::cell::RefCell::new(Vec::new());
+        named_dag
+            .import_revlog(
+                &index,
+                &[VertexName::copy_from(&node(6))],
+                &|phase, done, total| updates.borrow_mut().push((phase, done, total)),
+            )
+            .unwrap();
+
+        assert_eq!(
+            format!("{:?}", named_dag.dag),
+            "Lv0: RH0-2[] 3-3[1] H4-5[2, 3] N0-N0[3]\nLv1: R0-5[] N0-N0[3]"
+        );
+        let id = |rev: usize| named_dag.map.find_id_by_name(&node(rev)).unwrap();
+        assert_eq!(id(6), Some(Id(5)));
+        assert_eq!(id(5), Some(Group::NON_MASTER.min_id()));
+        let updates = updates.into_inner();
+        assert_eq!(updates.len(), 14);
+        assert_eq!(updates[6], (ImportPhase::AssignIds, 7, 7));
+        assert_eq!(updates[13], (ImportPhase::BuildSegments, 7, 7));
+
+        // Importing twice is an error.
+        assert!(named_dag.import_revlog(&index, &[], &|_, _, _| {}).is_err());
+    }
+}
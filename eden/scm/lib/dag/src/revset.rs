@@ -0,0 +1,332 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! # revset
+//!
+//! Parse and evaluate a small subset of Mercurial revset expressions on a
+//! [`NamedDag`].
+//!
+//! Supported syntax, from the lowest precedence to the highest:
+//!
+//! - `x + y`, `x | y`: union.
+//! - `x & y`: intersection. `x - y`: difference.
+//! - `x::y`: descendants of `x` that are ancestors of `y`. `::y` are
+//!   ancestors of `y`. `x::` are descendants of `x`. `::` is everything.
+//!   `..` is the same as `::`.
+//! - `name`, `(x)`, and functions: `all()`, `ancestors(x)`,
+//!   `descendants(x)`, `parents(x)`, `children(x)`, `heads(x)`, `roots(x)`,
+//!   `only(x, y)`.
+//!
+//! A name is resolved to the vertex with that name. If there is no such
+//! vertex, a hex name is resolved as an unambiguous hex prefix.
+
+use crate::idmap::IdMapLike;
+use crate::nameddag::NamedDag;
+use crate::spanset::SpanSet;
+use anyhow::{bail, Result};
+
+/// Parsed revset expression.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Name(String),
+    /// `roots::heads`. Missing sides are not bounded.
+    Range(Option<Box<Expr>>, Option<Box<Expr>>),
+    Union(Box<Expr>, Box<Expr>),
+    Intersection(Box<Expr>, Box<Expr>),
+    Difference(Box<Expr>, Box<Expr>),
+    Func(String, Vec<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Name(String),
+    Op(&'static str),
+}
+
+const OPERATORS: &[&str] = &["::", "..", "(", ")", ",", "+", "|", "&", "-"];
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < text.len() {
+        let rest = &text[pos..];
+        let ch = rest.chars().next().unwrap();
+        if ch.is_whitespace() {
+            pos += ch.len_utf8();
+        } else if let Some(&op) = OPERATORS.iter().find(|&&op| rest.starts_with(op)) {
+            tokens.push((pos, Token::Op(op)));
+            pos += op.len();
+        } else if ch.is_alphanumeric() || ch == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push((pos, Token::Name(rest[..len].to_string())));
+            pos += len;
+        } else {
+            bail!("unexpected {:?} at {}", ch, pos);
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    index: usize,
+    len: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|t| &t.1)
+    }
+
+    fn peek_op(&self, ops: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) if ops.contains(op) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.index).map_or(self.len, |t| t.0)
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<()> {
+        if self.peek_op(&[op]).is_none() {
+            bail!("expected {:?} at {}", op, self.position());
+        }
+        self.index += 1;
+        Ok(())
+    }
+
+    // or := and (("+" | "|") and)*
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.peek_op(&["+", "|"]).is_some() {
+            self.index += 1;
+            expr = Expr::Union(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    // and := range (("&" | "-") range)*
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_range()?;
+        while let Some(op) = self.peek_op(&["&", "-"]) {
+            self.index += 1;
+            let rhs = Box::new(self.parse_range()?);
+            expr = match op {
+                "&" => Expr::Intersection(Box::new(expr), rhs),
+                _ => Expr::Difference(Box::new(expr), rhs),
+            };
+        }
+        Ok(expr)
+    }
+
+    // range := primary | primary? ("::" | "..") primary?
+    fn parse_range(&mut self) -> Result<Expr> {
+        let range_ops = ["::", ".."];
+        let roots = match self.peek_op(&range_ops) {
+            Some(_) => None,
+            None => Some(Box::new(self.parse_primary()?)),
+        };
+        if self.peek_op(&range_ops).is_none() {
+            return Ok(*roots.unwrap());
+        }
+        self.index += 1;
+        let heads = match self.peek() {
+            Some(Token::Name(_)) | Some(Token::Op("(")) => Some(Box::new(self.parse_primary()?)),
+            _ => None,
+        };
+        Ok(Expr::Range(roots, heads))
+    }
+
+    // primary := name | name "(" (or ("," or)*)? ")" | "(" or ")"
+    fn parse_primary(&mut self) -> Result<Expr> {
+        match self.peek().cloned() {
+            Some(Token::Name(name)) => {
+                self.index += 1;
+                if self.peek_op(&["("]).is_none() {
+                    return Ok(Expr::Name(name));
+                }
+                self.index += 1;
+                let mut args = Vec::new();
+                if self.peek_op(&[")"]).is_none() {
+                    args.push(self.parse_or()?);
+                    while self.peek_op(&[","]).is_some() {
+                        self.index += 1;
+                        args.push(self.parse_or()?);
+                    }
+                }
+                self.expect_op(")")?;
+                Ok(Expr::Func(name, args))
+            }
+            Some(Token::Op("(")) => {
+                self.index += 1;
+                let expr = self.parse_or()?;
+                self.expect_op(")")?;
+                Ok(expr)
+            }
+            _ => bail!("expected a name or \"(\" at {}", self.position()),
+        }
+    }
+}
+
+/// Parse a revset expression.
+pub fn parse(text: &str) -> Result<Expr> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        index: 0,
+        len: text.len(),
+    };
+    let expr = parser.parse_or()?;
+    if parser.peek().is_some() {
+        bail!("unexpected token at {}", parser.position());
+    }
+    Ok(expr)
+}
+
+/// Evaluate a parsed revset expression on `dag`.
+pub fn eval(dag: &NamedDag, expr: &Expr) -> Result<SpanSet> {
+    let segments = &dag.dag;
+    let result = match expr {
+        Expr::Name(name) => resolve(dag, name)?,
+        Expr::Range(roots, heads) => match (roots, heads) {
+            (None, None) => segments.all()?,
+            (Some(roots), None) => segments.descendants(eval(dag, roots)?)?,
+            (None, Some(heads)) => segments.ancestors(eval(dag, heads)?)?,
+            (Some(roots), Some(heads)) => segments.range(eval(dag, roots)?, eval(dag, heads)?)?,
+        },
+        Expr::Union(lhs, rhs) => eval(dag, lhs)?.union(&eval(dag, rhs)?),
+        Expr::Intersection(lhs, rhs) => eval(dag, lhs)?.intersection(&eval(dag, rhs)?),
+        Expr::Difference(lhs, rhs) => eval(dag, lhs)?.difference(&eval(dag, rhs)?),
+        Expr::Func(name, args) => {
+            let args = args
+                .iter()
+                .map(|arg| eval(dag, arg))
+                .collect::<Result<Vec<_>>>()?;
+            match (name.as_str(), &args[..]) {
+                ("all", []) => segments.all()?,
+                ("ancestors", [set]) => segments.ancestors(set.clone())?,
+                ("descendants", [set]) => segments.descendants(set.clone())?,
+                ("parents", [set]) => segments.parents(set.clone())?,
+                ("children", [set]) => segments.children(set.clone())?,
+                ("heads", [set]) => segments.heads(set.clone())?,
+                ("roots", [set]) => segments.roots(set.clone())?,
+                ("only", [set, excluded]) => segments.only(set.clone(), excluded.clone())?,
+                _ => bail!("unknown function {}() with {} arguments", name, args.len()),
+            }
+        }
+    };
+    Ok(result)
+}
+
+/// Parse and evaluate a revset expression on `dag`.
+pub fn query(dag: &NamedDag, text: &str) -> Result<SpanSet> {
+    eval(dag, &parse(text)?)
+}
+
+fn resolve(dag: &NamedDag, name: &str) -> Result<SpanSet> {
+    if let Some(id) = dag.map.find_id_by_name(name.as_bytes())? {
+        return Ok(id.into());
+    }
+    if name.chars().all(|c| c.is_ascii_hexdigit()) {
+        let names = dag.map.find_names_by_hex_prefix(name.as_bytes(), 2)?;
+        match names.len() {
+            1 => return Ok(dag.map.vertex_id(names[0].clone())?.into()),
+            2 => bail!("ambiguous prefix {:?}", name),
+            _ => {}
+        }
+    }
+    bail!("unknown name {:?}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::build_segments;
+
+    #[test]
+    fn test_parse() {
+        let name = |s: &str| Box::new(Expr::Name(s.to_string()));
+        assert_eq!(
+            parse("a + b & c").unwrap(),
+            Expr::Union(
+                name("a"),
+                Box::new(Expr::Intersection(name("b"), name("c")))
+            )
+        );
+        assert_eq!(
+            parse("(a | b) - c::").unwrap(),
+            Expr::Difference(
+                Box::new(Expr::Union(name("a"), name("b"))),
+                Box::new(Expr::Range(Some(name("c")), None))
+            )
+        );
+        assert_eq!(parse("::").unwrap(), Expr::Range(None, None));
+        assert_eq!(
+            parse("only(a, ..b)").unwrap(),
+            Expr::Func(
+                "only".to_string(),
+                vec![
+                    Expr::Name("a".to_string()),
+                    Expr::Range(None, Some(name("b")))
+                ]
+            )
+        );
+
+        assert_eq!(
+            parse("a +").unwrap_err().to_string(),
+            "expected a name or \"(\" at 3"
+        );
+        assert_eq!(
+            parse("heads(a").unwrap_err().to_string(),
+            "expected \")\" at 7"
+        );
+        assert_eq!(
+            parse("a b").unwrap_err().to_string(),
+            "unexpected token at 2"
+        );
+        assert_eq!(parse("a~1").unwrap_err().to_string(), "unexpected '~' at 1");
+    }
+
+    #[test]
+    fn test_eval() {
+        let ascii = r#"
+                C-D-\     /--I--J--\
+            A-B------E-F-G-H--------K--L"#;
+        let result = build_segments(ascii, "L", 3);
+        let dag = NamedDag {
+            dag: result.dag,
+            map: result.id_map,
+        };
+        let query = |text: &str| -> String {
+            match query(&dag, text) {
+                Ok(set) => dag
+                    .to_names(&set)
+                    .unwrap()
+                    .iter()
+                    .map(|name| String::from_utf8(name.as_ref().to_vec()).unwrap())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                Err(err) => err.to_string(),
+            }
+        };
+
+        assert_eq!(query("::"), "L K J I H G F E D C B A");
+        assert_eq!(query("::E"), "E D C B A");
+        assert_eq!(query("I::"), "L K J I");
+        assert_eq!(query("C..F"), "F E D C");
+        assert_eq!(query("B::K - H"), "K J I G F E B");
+        assert_eq!(query("heads(::E - E) + roots(all())"), "D C B A");
+        assert_eq!(query("parents(E) & children(A + C)"), "D B");
+        assert_eq!(query("only(K, H)"), "K J I");
+        assert_eq!(query("ancestors(C) | descendants(J)"), "L K J C");
+        assert_eq!(query("X"), "unknown name \"X\"");
+        assert_eq!(query("only(K)"), "unknown function only() with 1 arguments");
+    }
+}
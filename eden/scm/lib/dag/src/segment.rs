@@ -12,23 +12,27 @@
 //! There are 2 flavors of DAG: [`Dag`] and [`SyncableDag`]. [`Dag`] loads
 //! from the filesystem, is responsible for all kinds of queires, and can
 //! have in-memory-only changes. [`SyncableDag`] is the only way to update
-//! the filesystem state, and does not support queires.
+//! the filesystem state. Queries on [`SyncableDag`] see the locked state
+//! plus its pending changes.
 
 use crate::id::{Group, Id};
 use crate::spanset::Span;
 use crate::spanset::SpanSet;
+use crate::spanset::SpanSetIter;
 use anyhow::{bail, ensure, format_err, Result};
 use bitflags::bitflags;
-use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use fs2::FileExt;
 use indexedlog::log;
 use indexmap::set::IndexSet;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::collections::{BTreeSet, BinaryHeap};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque};
 use std::fmt::{self, Debug, Formatter};
 use std::fs::{self, File};
 use std::io::Cursor;
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use vlqencoding::{VLQDecode, VLQDecodeAt, VLQEncode};
 
@@ -51,6 +55,8 @@ pub struct Dag {
     path: PathBuf,
     max_level: Level,
     new_seg_size: usize,
+    new_max_level: Level,
+    read_only: bool,
 }
 
 /// Guard to make sure [`Dag`] on-disk writes are race-free.
@@ -86,16 +92,41 @@ impl Dag {
     /// not conflict with this.
     const MAGIC_CLEAR_NON_MASTER: &'static [u8] = b"CLRNM";
 
+    /// Magic bytes in `Log` that indicates "remove the segments whose
+    /// level-head keys follow". The first byte of a Segment entry is its
+    /// flags, which are smaller than `b'R'`, so it does not conflict with this.
+    const MAGIC_REMOVE_SEGMENTS: &'static [u8] = b"RMSEG";
+
     /// Open [`Dag`] at the given directory. Create it on demand.
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let path = path.as_ref();
+        Self::open_internal(path.as_ref(), false)
+    }
+
+    /// Open an existing [`Dag`] at the given directory for reading.
+    ///
+    /// Many processes can read a [`Dag`] while another process writes to it.
+    /// Reading does not take locks. A [`Dag`] only sees the data on disk at
+    /// open time, and [`Dag::reload`] picks up data written later. Writes
+    /// to disk, like [`Dag::flush`] and [`Dag::prepare_filesystem_sync`],
+    /// are errors. Segments can still be built in memory.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_internal(path.as_ref(), true)
+    }
+
+    fn open_internal(path: &Path, read_only: bool) -> Result<Self> {
         let log = log::OpenOptions::new()
-            .create(true)
+            .create(!read_only)
             .index("level-head", |data| {
                 // (level, high)
                 assert!(Self::MAGIC_CLEAR_NON_MASTER.len() < Segment::OFFSET_DELTA);
                 assert!(Group::BITS == 8);
-                if data.len() < Segment::OFFSET_DELTA {
+                assert!(SegmentFlags::all().bits() < Self::MAGIC_REMOVE_SEGMENTS[0]);
+                if data.starts_with(Self::MAGIC_REMOVE_SEGMENTS) {
+                    data[Self::MAGIC_REMOVE_SEGMENTS.len()..]
+                        .chunks(Self::KEY_LEVEL_HEAD_LEN)
+                        .map(|key| log::IndexOutput::Remove(key.to_vec().into_boxed_slice()))
+                        .collect()
+                } else if data.len() < Segment::OFFSET_DELTA {
                     if data == Self::MAGIC_CLEAR_NON_MASTER {
                         let max_level = 255;
                         (0..=max_level)
@@ -119,7 +150,7 @@ impl Dag {
                 // parent -> child for flat segments
                 let seg = Segment(data);
                 let mut result = Vec::new();
-                if seg.level().ok() == Some(0) {
+                if seg.level().ok() == Some(0) && !data.starts_with(Self::MAGIC_REMOVE_SEGMENTS) {
                     // This should never pass since MAGIC_CLEAR_NON_MASTER[0] != 0.
                     assert_ne!(
                         data,
@@ -150,6 +181,8 @@ impl Dag {
             path: path.to_path_buf(),
             max_level,
             new_seg_size: 16, // see D16660078 for this default setting
+            new_max_level: Level::MAX,
+            read_only,
         };
         dag.build_all_high_level_segments(false)?;
         Ok(dag)
@@ -254,6 +287,8 @@ impl Dag {
     ///
     /// Block if another instance is taking the lock.
     pub fn prepare_filesystem_sync(&self) -> Result<SyncableDag> {
+        ensure!(!self.read_only, "{:?} is opened read-only", &self.path);
+
         // Take a filesystem lock. The file name 'lock' is taken by indexedlog
         // running on Windows, so we choose another file name here.
         let lock_file = {
@@ -281,6 +316,8 @@ impl Dag {
                 path: self.path.clone(),
                 max_level,
                 new_seg_size: self.new_seg_size,
+                new_max_level: self.new_max_level,
+                read_only: false,
             },
             lock_file,
         })
@@ -296,6 +333,39 @@ impl Dag {
         self.new_seg_size = size.max(2);
     }
 
+    /// Set the maximum level of new high-level segments. `0` means only flat
+    /// segments are built.
+    ///
+    /// This does not affect existing segments. Use
+    /// [`Dag::rebuild_high_levels`] to apply it to them.
+    ///
+    /// By default, levels are built until a new level does not reduce the
+    /// number of segments.
+    pub fn set_new_max_level(&mut self, level: Level) {
+        self.new_max_level = level;
+    }
+
+    /// Set the maximum level of new high-level segments from the number of
+    /// flat segments and the segment size. Return the maximum level.
+    ///
+    /// The level is chosen so the highest level has at most "segment size"
+    /// segments, assuming each level is "segment size" times smaller than
+    /// the level below. Levels above it would not speed up queries much, so
+    /// small graphs do not get them.
+    pub fn auto_tune_max_level(&mut self) -> Result<Level> {
+        let mut count = 0;
+        for &group in Group::ALL.iter() {
+            count += self.next_segments(group.min_id(), 0)?.len();
+        }
+        let mut level = 0;
+        while count > self.new_seg_size && level < Level::MAX {
+            count = count.div_ceil(self.new_seg_size);
+            level += 1;
+        }
+        self.new_max_level = level;
+        Ok(level)
+    }
+
     // Used internally to generate the index key for lookup
     fn serialize_head_level_lookup_key(value: Id, level: u8) -> [u8; Self::KEY_LEVEL_HEAD_LEN] {
         let mut buf = [0u8; Self::KEY_LEVEL_HEAD_LEN];
@@ -575,7 +645,7 @@ impl Dag {
     /// Return number of segments inserted.
     fn build_all_high_level_segments(&mut self, drop_last: bool) -> Result<usize> {
         let mut total = 0;
-        for level in 1.. {
+        for level in 1..=self.new_max_level {
             let count = self.build_high_level_segments(level, drop_last)?;
             if count == 0 {
                 break;
@@ -584,11 +654,47 @@ impl Dag {
         }
         Ok(total)
     }
+
+    /// Rebuild all high-level segments from flat segments.
+    ///
+    /// High-level segments built incrementally, for example after many
+    /// small pulls, can be much smaller than the segment size allows, which
+    /// makes queries slower. This removes them and builds them again using
+    /// the current segment size. Flat segments are not changed.
+    ///
+    /// Return number of segments inserted.
+    pub fn rebuild_high_levels(&mut self) -> Result<usize> {
+        self.remove_high_level_segments()?;
+        self.build_all_high_level_segments(false)
+    }
+
+    /// Remove all segments with `level > 0`.
+    fn remove_high_level_segments(&mut self) -> Result<()> {
+        let mut data = Self::MAGIC_REMOVE_SEGMENTS.to_vec();
+        for level in 1..=self.max_level {
+            for &group in Group::ALL.iter() {
+                for seg in self.next_segments(group.min_id(), level)? {
+                    data.extend_from_slice(&Self::serialize_head_level_lookup_key(
+                        seg.high()?,
+                        level,
+                    ));
+                }
+            }
+        }
+        if data.len() > Self::MAGIC_REMOVE_SEGMENTS.len() {
+            self.log.append(data)?;
+            self.max_level = Self::max_level_from_log(&self.log)?;
+        }
+        Ok(())
+    }
 }
 
 // Reload.
 impl Dag {
     /// Reload from the filesystem. Discard pending changes.
+    ///
+    /// This does not take locks. Segments written by one
+    /// [`SyncableDag::sync`] become visible together.
     pub fn reload(&mut self) -> Result<()> {
         self.log.clear_dirty()?;
         self.log.sync()?;
@@ -598,6 +704,145 @@ impl Dag {
     }
 }
 
+// Flush.
+impl Dag {
+    /// Ids covered by flat segments that only exist in memory, for example,
+    /// built by [`Dag::build_segments_volatile`]. They are lost unless
+    /// [`Dag::flush`] writes them to disk.
+    pub fn volatile_ids(&self) -> Result<SpanSet> {
+        let mut spans = Vec::new();
+        for data in self.log.iter_dirty() {
+            let data = data?;
+            if data.len() >= Segment::OFFSET_DELTA && !data.starts_with(Self::MAGIC_REMOVE_SEGMENTS)
+            {
+                let seg = Segment(data);
+                if seg.level()? == 0 {
+                    spans.push(seg.span()?);
+                }
+            }
+        }
+        Ok(SpanSet::from_spans(spans).intersection(&self.all()?))
+    }
+
+    /// Ids covered by flat segments on disk. Other processes opening the
+    /// [`Dag`] can see them.
+    pub fn persisted_ids(&self) -> Result<SpanSet> {
+        Ok(self.all()?.difference(&self.volatile_ids()?))
+    }
+
+    /// Test if there are changes that only exist in memory, and other
+    /// processes cannot see.
+    ///
+    /// High-level segments are not considered, since they are rebuilt from
+    /// flat segments on open. Pending removals, for example, by
+    /// [`Dag::strip`], are considered. They cannot be written by
+    /// [`Dag::flush`]. Use [`SyncableDag`] for them.
+    pub fn is_dirty(&self) -> Result<bool> {
+        for data in self.log.iter_dirty() {
+            let data = data?;
+            let dirty = if data.starts_with(Self::MAGIC_REMOVE_SEGMENTS) {
+                // The first byte of a key is its level.
+                data[Self::MAGIC_REMOVE_SEGMENTS.len()..]
+                    .chunks(Self::KEY_LEVEL_HEAD_LEN)
+                    .any(|key| key[0] == 0)
+            } else if data.len() < Segment::OFFSET_DELTA {
+                // MAGIC_CLEAR_NON_MASTER
+                true
+            } else {
+                Segment(data).level()? == 0
+            };
+            if dirty {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Write flat segments that only exist in memory, for example, built by
+    /// [`Dag::build_segments_volatile`], to disk.
+    ///
+    /// This takes the filesystem lock and reloads, so segments written by
+    /// other processes meanwhile are kept. Pending ids that other processes
+    /// have also written must have the same parents on disk, otherwise this
+    /// is an error and nothing is written. The underlying log replaces its
+    /// metadata atomically, so readers never see a partial write.
+    ///
+    /// High-level segments are rebuilt like
+    /// [`SyncableDag::build_segments_persistent`]. Pending removals are not
+    /// supported. Use [`SyncableDag`] for them.
+    pub fn flush(&mut self) -> Result<()> {
+        let mut spans = Vec::new();
+        for data in self.log.iter_dirty() {
+            let data = data?;
+            ensure!(
+                data.len() >= Segment::OFFSET_DELTA
+                    && !data.starts_with(Self::MAGIC_REMOVE_SEGMENTS),
+                "flush does not support pending removals"
+            );
+            let seg = Segment(data);
+            if seg.level()? == 0 {
+                spans.push(seg.span()?);
+            }
+        }
+        spans.sort_unstable_by_key(|span| span.low);
+
+        let mut syncable = self.prepare_filesystem_sync()?;
+        {
+            let get_parents = |id: Id| self.parent_ids(id);
+            for span in spans {
+                let next = syncable.next_free_id(0, span.low.group())?;
+                // Ids written by other processes must match.
+                for id in span.low.to(span.high) {
+                    if id >= next {
+                        break;
+                    }
+                    let parents = syncable.parent_ids(id)?;
+                    if parents != get_parents(id)? {
+                        bail!(
+                            "cannot flush: {} has parents {:?} on disk, but {:?} in memory",
+                            id,
+                            parents,
+                            get_parents(id)?
+                        );
+                    }
+                }
+                if span.high >= next {
+                    syncable.build_segments_persistent(span.high, &get_parents)?;
+                }
+            }
+        }
+        syncable.sync(std::iter::once(self))
+    }
+}
+
+// Cache.
+impl Dag {
+    /// Return a key that changes whenever the content of this [`Dag`] changes.
+    ///
+    /// Expensive query results (ex. a [`SpanSet`] serialized by
+    /// [`SpanSet::to_bytes`]) can be cached together with the key, and be
+    /// reused by other processes as long as the key stays the same.
+    pub fn invalidation_key(&self) -> Result<u64> {
+        let mut buf = Vec::new();
+        // The master group is append-only, except for 'strip' truncating it.
+        // Its last flat segment changes when ids are added or stripped.
+        let next_master_id = self.next_free_id(0, Group::MASTER)?;
+        buf.write_vlq(next_master_id.0)?;
+        if next_master_id > Group::MASTER.min_id() {
+            if let Some(seg) = self.find_flat_segment_including_id(next_master_id - 1)? {
+                buf.extend_from_slice(seg.0);
+            }
+        }
+        // Non-master ids can be removed and then reassigned to different
+        // vertexes. Include all of them. The non-master group is expected to
+        // be small.
+        for seg in self.next_segments(Group::NON_MASTER.min_id(), 0)? {
+            buf.extend_from_slice(seg.0);
+        }
+        Ok(indexedlog::utils::xxhash(&buf))
+    }
+}
+
 // Remove data.
 impl Dag {
     /// Mark non-master ids as "removed".
@@ -611,6 +856,67 @@ impl Dag {
         }
         Ok(())
     }
+
+    /// Remove `set` and its descendants. Return the removed ids.
+    ///
+    /// Ids are not reassigned, so in each group the removed ids must be the
+    /// largest ids of the group. Otherwise, this is an error and nothing is
+    /// removed. Flat segments covering both removed and remaining ids are
+    /// truncated. High-level segments covering removed ids are rebuilt.
+    ///
+    /// This only changes the segments. Use [`crate::idmap::IdMap::remove_ids`]
+    /// to remove the names of the returned ids.
+    pub fn strip(&mut self, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        let removed = self.remove_descendants(set.into())?;
+        self.build_all_high_level_segments(false)?;
+        Ok(removed)
+    }
+
+    /// Remove `set` and its descendants, without rebuilding high-level
+    /// segments. See [`Dag::strip`].
+    fn remove_descendants(&mut self, set: SpanSet) -> Result<SpanSet> {
+        let removed = self.descendants(set.clone())?;
+        let mut data = Self::MAGIC_REMOVE_SEGMENTS.to_vec();
+        let mut truncated = Vec::new();
+        for &group in Group::ALL.iter() {
+            let group_span: SpanSet = (group.min_id()..=group.max_id()).into();
+            let group_removed = removed.intersection(&group_span);
+            let low = match group_removed.min() {
+                Some(low) => low,
+                None => continue,
+            };
+            let next = self.next_free_id(0, group)?;
+            let remaining = SpanSet::from(low..=(next - 1)).difference(&group_removed);
+            if !remaining.is_empty() {
+                bail!(
+                    "cannot strip {:?}: ids are not reassigned and {:?} would be left behind",
+                    &set,
+                    &remaining
+                );
+            }
+            for level in 0..=self.max_level {
+                for seg in self.next_segments(low, level)? {
+                    let span = seg.span()?;
+                    data.extend_from_slice(&Self::serialize_head_level_lookup_key(
+                        span.high, level,
+                    ));
+                    if level == 0 && span.low < low {
+                        truncated.push((seg.flags()?, span.low, low - 1, seg.parents()?));
+                    }
+                }
+            }
+        }
+        if data.len() > Self::MAGIC_REMOVE_SEGMENTS.len() {
+            self.log.append(data)?;
+            // The truncated segments have the same roots. They are also the
+            // only heads if the original segments are.
+            for (flags, low, high, parents) in truncated {
+                self.insert(flags, 0, low, high, &parents)?;
+            }
+            self.max_level = Self::max_level_from_log(&self.log)?;
+        }
+        Ok(removed)
+    }
 }
 
 // User-facing DAG-related algorithms.
@@ -627,6 +933,35 @@ impl Dag {
         Ok(result)
     }
 
+    /// Iterate through ids in `set` in topological order (parents first).
+    ///
+    /// Ids are assigned in topological order, so this is the ascending order
+    /// of ids and does not need to look at segments.
+    pub fn iter_topo(&self, set: impl Into<SpanSet>) -> std::iter::Rev<SpanSetIter<SpanSet>> {
+        set.into().into_iter().rev()
+    }
+
+    /// Iterate through ids in `set` in reverse topological order (children
+    /// first). This is the descending order of ids.
+    pub fn iter_topo_rev(&self, set: impl Into<SpanSet>) -> SpanSetIter<SpanSet> {
+        set.into().into_iter()
+    }
+
+    /// Iterate through `ancestors(heads)` in reverse topological order
+    /// (children first).
+    ///
+    /// Unlike `iter_topo_rev(ancestors(heads))`, ancestors are not calculated
+    /// upfront. They are discovered while iterating, so taking the first few
+    /// items is cheap even if there are many ancestors.
+    pub fn iter_ancestors_rev(&self, heads: impl Into<SpanSet>) -> AncestorsRevIter<'_> {
+        AncestorsRevIter {
+            dag: self,
+            to_visit: heads.into().iter().collect(),
+            last: None,
+            segment: None,
+        }
+    }
+
     /// Return a [`SpanSet`] that covers all ids stored in the master group.
     pub(crate) fn master_group(&self) -> Result<SpanSet> {
         let group = Group::MASTER;
@@ -649,9 +984,20 @@ impl Dag {
             // Try to (greatly) reduce the size of the `set` to make calculation cheaper.
             set = self.heads_ancestors(set)?;
         }
+        self.ancestors_above(set, Id::MIN)
+    }
+
+    /// Calculate ancestors of the given set, but stop following parents
+    /// below `min`. The result contains all ancestors that are `>= min`, and
+    /// maybe some that are smaller.
+    fn ancestors_above(&self, set: SpanSet, min: Id) -> Result<SpanSet> {
         let mut result = SpanSet::empty();
         let mut to_visit: BinaryHeap<_> = set.iter().collect();
         'outer: while let Some(id) = to_visit.pop() {
+            if id < min {
+                // `to_visit` pops the largest id first. The rest is also below `min`.
+                break;
+            }
             if result.contains(id) {
                 // If `id` is in `result`, then `ancestors(id)` are all in `result`.
                 continue;
@@ -691,6 +1037,60 @@ impl Dag {
         Ok(result)
     }
 
+    /// Calculate ancestors of the given set that are at most `depth`
+    /// generations away. `depth` 0 returns the set itself, 1 adds the
+    /// parents, and so on.
+    ///
+    /// ```plain,ignore
+    /// union(set, parents(set), parents(parents(set)), ...)
+    /// ```
+    ///
+    /// Each generation is calculated on segments, so this is cheaper than
+    /// filtering `ancestors(set)` when `depth` is small.
+    pub fn ancestors_within(&self, set: impl Into<SpanSet>, depth: u64) -> Result<SpanSet> {
+        let set = set.into();
+        if depth >= self.all()?.count() {
+            return self.ancestors(set);
+        }
+        self.expand_within(set, depth, |set| self.parents(set))
+    }
+
+    /// Calculate descendants of the given set that are at most `depth`
+    /// generations away. `depth` 0 returns the set itself, 1 adds the
+    /// children, and so on.
+    ///
+    /// ```plain,ignore
+    /// union(set, children(set), children(children(set)), ...)
+    /// ```
+    pub fn descendants_within(&self, set: impl Into<SpanSet>, depth: u64) -> Result<SpanSet> {
+        let set = set.into();
+        if depth >= self.all()?.count() {
+            return self.descendants(set);
+        }
+        self.expand_within(set, depth, |set| self.children(set))
+    }
+
+    /// Add `step(set)` to `set`, `depth` times. Stop early if a step adds
+    /// nothing new.
+    fn expand_within(
+        &self,
+        set: SpanSet,
+        depth: u64,
+        step: impl Fn(SpanSet) -> Result<SpanSet>,
+    ) -> Result<SpanSet> {
+        let mut result = set.clone();
+        let mut frontier = set;
+        for _ in 0..depth {
+            // Ids already in `result` were reached in fewer generations.
+            frontier = step(frontier)?.difference(&result);
+            if frontier.is_empty() {
+                break;
+            }
+            result = result.union(&frontier);
+        }
+        Ok(result)
+    }
+
     /// Calculate parents of the given set.
     ///
     /// Note: [`SpanSet`] does not preserve order. Use [`Dag::parent_ids`] if
@@ -760,6 +1160,21 @@ impl Dag {
         }
     }
 
+    /// Get the first parent of a single `id`. Return `None` for roots.
+    ///
+    /// This is cheaper than `parent_ids(id)` since parents are not decoded
+    /// unless `id` is the low of a flat segment.
+    pub fn first_parent(&self, id: Id) -> Result<Option<Id>> {
+        let seg = self
+            .find_flat_segment_including_id(id)?
+            .ok_or_else(|| format_err!("id {} is not covered by dag", id))?;
+        if id == seg.span()?.low {
+            Ok(seg.parents()?.first().cloned())
+        } else {
+            Ok(Some(id - 1))
+        }
+    }
+
     /// Calculate the n-th first ancestor. If `n` is 0, return `id` unchanged.
     /// If `n` is 1, return the first parent of `id`.
     pub fn first_ancestor_nth(&self, mut id: Id, mut n: u64) -> Result<Id> {
@@ -789,6 +1204,31 @@ impl Dag {
         Ok(id)
     }
 
+    /// Calculate the ids reachable from the given set by following only first
+    /// parents. That is, the union of `x`, `x~1`, `x~2`, ... for `x` in `set`.
+    ///
+    /// In a flat segment, the first parent of every id but `low` is the
+    /// previous id. So each flat segment is taken at once, and only the first
+    /// parent of its `low` needs to be followed.
+    pub fn first_ancestors(&self, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        let mut remaining = set.into();
+        let mut result = SpanSet::empty();
+        while let Some(id) = remaining.max() {
+            let seg = self
+                .find_flat_segment_including_id(id)?
+                .ok_or_else(|| format_err!("id {} is not covered by dag", id))?;
+            let chain = SpanSet::from(seg.span()?.low..=id);
+            result = result.union(&chain);
+            remaining = remaining.difference(&chain);
+            if let Some(&p1) = seg.parents()?.first() {
+                if !result.contains(p1) {
+                    remaining = remaining.union(&p1.into());
+                }
+            }
+        }
+        Ok(result)
+    }
+
     /// Convert an `id` to `x~n` form with the given constraint.
     ///
     /// Return `None` if the conversion can not be done with the constraints.
@@ -837,6 +1277,12 @@ impl Dag {
             key.write_vlq(head.0).expect("write to Vec should not fail");
             for seg_bytes in self.log.lookup(Self::INDEX_PARENT, &key)? {
                 let child_seg = Segment(seg_bytes?);
+                // Skip segments removed by 'remove_non_master' or 'strip'.
+                // The "parent" index still refers to them.
+                match self.find_segment_by_head_and_level(child_seg.high()?, 0)? {
+                    Some(seg) if seg.0 == child_seg.0 => {}
+                    _ => continue,
+                }
                 if child_seg.parents()?.len() > 1 {
                     // `child_seg.span().low` is a merge, so `head` is a parent of a merge.
                     // Therefore `head` can be used as `x`.
@@ -971,6 +1417,9 @@ impl Dag {
 
     /// Calculate one "greatest common ancestor" of the given set.
     ///
+    /// The set can have any number of ids, for example the parents of an
+    /// octopus merge. The result is an ancestor of all of them.
+    ///
     /// If there are no common ancestors, return None.
     /// If there are multiple greatest common ancestors, pick one arbitrarily.
     /// Use `gca_all` to get all of them.
@@ -980,7 +1429,9 @@ impl Dag {
         Ok(self.common_ancestors(set)?.max())
     }
 
-    /// Calculate all "greatest common ancestor"s of the given set.
+    /// Calculate all "greatest common ancestor"s of the given set, which can
+    /// have any number of ids. That is, the heads of the ids that are
+    /// ancestors of every id in the set.
     /// `gca_one` is faster if an arbitrary answer is ok.
     pub fn gca_all(&self, set: impl Into<SpanSet>) -> Result<SpanSet> {
         let set = set.into();
@@ -1008,10 +1459,15 @@ impl Dag {
                 // Try to reduce the size of `set`.
                 // `common_ancestors(X)` = `common_ancestors(roots(X))`.
                 let set = self.roots(set)?;
-                set.iter()
-                    .fold(Ok(SpanSet::full()), |set: Result<SpanSet>, id| {
-                        Ok(set?.intersection(&self.ancestors(id)?))
-                    })?
+                let mut result = SpanSet::full();
+                for id in set.iter() {
+                    result = result.intersection(&self.ancestors(id)?);
+                    if result.is_empty() {
+                        // No need to calculate ancestors of the remaining ids.
+                        break;
+                    }
+                }
+                result
             }
         };
         Ok(result)
@@ -1023,11 +1479,131 @@ impl Dag {
         Ok(set.contains(ancestor_id))
     }
 
+    /// Test if `ancestor_id` is an ancestor of `descendant_id` for each
+    /// `(ancestor_id, descendant_id)` pair.
+    ///
+    /// This is faster than calling [`Dag::is_ancestor`] for each pair:
+    /// ancestors are calculated once per distinct descendant, and pairs
+    /// where the ancestor has a larger id are answered without looking at
+    /// segments.
+    pub fn is_ancestor_batch(&self, pairs: &[(Id, Id)]) -> Result<Vec<bool>> {
+        let mut ancestors_cache: HashMap<Id, SpanSet> = HashMap::new();
+        let mut result = Vec::with_capacity(pairs.len());
+        for &(ancestor_id, descendant_id) in pairs {
+            let is_ancestor = if ancestor_id > descendant_id {
+                false
+            } else if ancestor_id == descendant_id {
+                true
+            } else {
+                let ancestors = match ancestors_cache.entry(descendant_id) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(self.ancestors(descendant_id)?),
+                };
+                ancestors.contains(ancestor_id)
+            };
+            result.push(is_ancestor);
+        }
+        Ok(result)
+    }
+
+    /// Find a shortest path from `ancestor_id` to `descendant_id`, following
+    /// parent edges backwards. Return ids on the path, starting with
+    /// `ancestor_id` and ending with `descendant_id`, or `None` if
+    /// `ancestor_id` is not an ancestor of `descendant_id`.
+    ///
+    /// Only ids in `range(ancestor_id, descendant_id)` are visited.
+    pub fn find_path(&self, ancestor_id: Id, descendant_id: Id) -> Result<Option<Vec<Id>>> {
+        let range = self.range(ancestor_id, descendant_id)?;
+        if range.is_empty() {
+            return Ok(None);
+        }
+
+        // Breadth-first search from `descendant_id`. `child` points towards
+        // `descendant_id`.
+        let mut child: HashMap<Id, Id> = HashMap::new();
+        let mut to_visit = VecDeque::new();
+        to_visit.push_back(descendant_id);
+        while let Some(id) = to_visit.pop_front() {
+            if id == ancestor_id {
+                break;
+            }
+            for parent in self.parent_ids(id)? {
+                if !range.contains(parent) {
+                    continue;
+                }
+                if let Entry::Vacant(entry) = child.entry(parent) {
+                    entry.insert(id);
+                    to_visit.push_back(parent);
+                }
+            }
+        }
+
+        let mut path = vec![ancestor_id];
+        let mut id = ancestor_id;
+        while id != descendant_id {
+            id = child[&id];
+            path.push(id);
+        }
+        Ok(Some(path))
+    }
+
+    /// Calculate the generation number of `id`: the length of the longest
+    /// path from `id` to a root. Roots have generation 0.
+    ///
+    /// If `x` is an ancestor of `y`, then `generation(x) < generation(y)`.
+    /// So ancestry checks can be skipped if generation numbers do not match.
+    pub fn generation(&self, id: Id) -> Result<u64> {
+        Ok(self.generations(id)?[0].1)
+    }
+
+    /// Calculate generation numbers of all ids in `set`. See
+    /// [`Dag::generation`]. Return `(id, generation)` in descending id order.
+    ///
+    /// Generation numbers are calculated per flat segment, since ids in a
+    /// flat segment are a chain. Each flat segment covering `ancestors(set)`
+    /// is visited once.
+    pub fn generations(&self, set: impl Into<SpanSet>) -> Result<Vec<(Id, u64)>> {
+        let set = set.into();
+        let ancestors = self.ancestors(set.clone())?;
+
+        // Generation number of flat segment lows: low -> (high, generation).
+        let mut low_generations: BTreeMap<Id, (Id, u64)> = BTreeMap::new();
+        let lookup = |low_generations: &BTreeMap<Id, (Id, u64)>, id: Id| -> Result<u64> {
+            match low_generations.range(..=id).next_back() {
+                Some((&low, &(high, generation))) if id <= high => Ok(generation + (id.0 - low.0)),
+                _ => bail!("bug: generation of {} is not calculated", id),
+            }
+        };
+
+        for span in ancestors.as_spans().iter().rev() {
+            let mut id = span.low;
+            while id <= span.high {
+                let seg = self.find_flat_segment_including_id(id)?.expect(
+                    "logic error: flat segments are expected to cover everything but they do not",
+                );
+                let seg_span = seg.span()?;
+                let mut generation = 0;
+                for parent in seg.parents()? {
+                    generation = generation.max(lookup(&low_generations, parent)? + 1);
+                }
+                low_generations.insert(seg_span.low, (seg_span.high, generation));
+                id = seg_span.high + 1;
+            }
+        }
+
+        set.iter()
+            .map(|id| Ok((id, lookup(&low_generations, id)?)))
+            .collect()
+    }
+
     /// Calculate "heads" of the ancestors of the given [`SpanSet`]. That is,
     /// Find Y, which is the smallest subset of set X, where `ancestors(Y)` is
     /// `ancestors(X)`.
     ///
-    /// This is faster than calculating `heads(ancestors(set))`.
+    /// This is faster than calculating `heads(ancestors(set))`: the ancestors
+    /// of each head are only followed down to the smallest id of `set` that
+    /// is not known to be an ancestor yet, so the full ancestors are never
+    /// calculated.
     ///
     /// This is different from `heads`. In case set contains X and Y, and Y is
     /// an ancestor of X, but not the immediate ancestor, `heads` will include
@@ -1036,14 +1612,154 @@ impl Dag {
         let set = set.into();
         let mut remaining = set;
         let mut result = SpanSet::empty();
-        while let Some(id) = remaining.max() {
+        while let (Some(id), Some(min)) = (remaining.max(), remaining.min()) {
             result.push_span((id..=id).into());
             // Remove ancestors reachable from that head.
-            remaining = remaining.difference(&self.ancestors(id)?);
+            remaining = remaining.difference(&self.ancestors_above(id.into(), min)?);
         }
         Ok(result)
     }
 
+    /// Calculate ids reachable from `heads` but not from `common`, in topological
+    /// order (parents first).
+    ///
+    /// ```plain,ignore
+    /// ancestors(heads) - ancestors(common)
+    /// ```
+    ///
+    /// This is what a pull needs to send once discovery found the `common`
+    /// ids. Both sides are calculated on segments, so the cost depends on the
+    /// number of segments, not the number of ids. The ids are produced lazily,
+    /// so they can be sent in batches.
+    pub fn missing_ancestors(
+        &self,
+        heads: impl Into<SpanSet>,
+        common: impl Into<SpanSet>,
+    ) -> Result<std::iter::Rev<SpanSetIter<SpanSet>>> {
+        let missing = self.only(heads, common)?;
+        Ok(self.iter_topo(missing))
+    }
+
+    /// Calculate ids reachable from `set` but not from `excluded`.
+    ///
+    /// ```plain,ignore
+    /// ancestors(set) - ancestors(excluded)
+    /// ```
+    ///
+    /// The ancestors of `set` are not calculated in full: following parents
+    /// stops at the ancestors of `excluded`.
+    pub fn only(&self, set: impl Into<SpanSet>, excluded: impl Into<SpanSet>) -> Result<SpanSet> {
+        let (only, _boundary) = self.only_with_boundary(set.into(), excluded.into())?;
+        Ok(only)
+    }
+
+    /// Calculate `only(set, excluded)`, and the common ancestors of `set`
+    /// and `excluded`.
+    ///
+    /// ```plain,ignore
+    /// (ancestors(set) - ancestors(excluded), ancestors(set) & ancestors(excluded))
+    /// ```
+    pub fn only_both(
+        &self,
+        set: impl Into<SpanSet>,
+        excluded: impl Into<SpanSet>,
+    ) -> Result<(SpanSet, SpanSet)> {
+        let (only, boundary) = self.only_with_boundary(set.into(), excluded.into())?;
+        // The ancestors of `set` that are ancestors of `excluded` are the
+        // ancestors of the ids where following parents stopped.
+        let common = self.ancestors(boundary)?;
+        Ok((only, common))
+    }
+
+    /// Count `ancestors(set)`.
+    ///
+    /// Counting is done on spans, which are derived from segments. Ids are
+    /// not iterated. If the only head of a flat segment is in `set`, its
+    /// ancestors are known without following parents.
+    pub fn count_ancestors(&self, set: impl Into<SpanSet>) -> Result<u64> {
+        Ok(self.ancestors(set)?.count())
+    }
+
+    /// Count `only(set, excluded)`. See [`Dag::count_ancestors`].
+    pub fn count_only(&self, set: impl Into<SpanSet>, excluded: impl Into<SpanSet>) -> Result<u64> {
+        Ok(self.only(set, excluded)?.count())
+    }
+
+    /// Count `range(roots, heads)`. See [`Dag::count_ancestors`].
+    pub fn count_range(&self, roots: impl Into<SpanSet>, heads: impl Into<SpanSet>) -> Result<u64> {
+        let roots = roots.into();
+        let heads = heads.into();
+        match (roots.min(), heads.max()) {
+            (Some(min), Some(max)) if min <= max => Ok(self.range(roots, heads)?.count()),
+            _ => Ok(0),
+        }
+    }
+
+    /// Count ids that are only reachable from `id`, and ids that are only
+    /// reachable from `other`. That is, `(count_only(id, other),
+    /// count_only(other, id))`. Useful for "N ahead, M behind".
+    ///
+    /// This is faster than calling `count_only` twice. Ancestors of `other`
+    /// that are also ancestors of `id` are found while calculating the first
+    /// count, and are subtracted from the count of ancestors of `other`.
+    pub fn count_ahead_behind(&self, id: Id, other: Id) -> Result<(u64, u64)> {
+        let (only, common) = self.only_both(id, other)?;
+        let behind = self.count_ancestors(other)? - common.count();
+        Ok((only.count(), behind))
+    }
+
+    /// Calculate `only(set, excluded)`. Also return the ids in
+    /// `ancestors(excluded)` where following parents from `set` stopped.
+    fn only_with_boundary(&self, set: SpanSet, excluded: SpanSet) -> Result<(SpanSet, SpanSet)> {
+        let mut set = set;
+        if set.count() > 2 {
+            // Try to (greatly) reduce the size of the `set` to make calculation cheaper.
+            set = self.heads_ancestors(set)?;
+        }
+        let excluded = self.ancestors(excluded)?;
+        let mut result = SpanSet::empty();
+        let mut boundary = SpanSet::empty();
+        let mut to_visit: BinaryHeap<_> = set.iter().collect();
+        'outer: while let Some(id) = to_visit.pop() {
+            if result.contains(id) {
+                continue;
+            }
+            if excluded.contains(id) {
+                boundary = boundary.union(&id.into());
+                continue;
+            }
+            // Take an entire high-level segment if none of it is excluded.
+            for level in (1..=self.max_level).rev() {
+                let seg = self.find_segment_by_head_and_level(id, level)?;
+                if let Some(seg) = seg {
+                    let span = seg.span()?;
+                    if excluded.intersection(&span.into()).is_empty() {
+                        result.push_span(span);
+                        to_visit.extend(seg.parents()?);
+                        continue 'outer;
+                    }
+                }
+            }
+            let seg = self
+                .find_flat_segment_including_id(id)?
+                .ok_or_else(|| format_err!("id {} is not covered by dag", id))?;
+            let low = seg.span()?.low;
+            // `excluded` is closed under ancestors, and a flat segment is
+            // linear. So the excluded part of `low..=id` is `low..=max`.
+            match excluded.intersection(&(low..=id).into()).max() {
+                Some(max) => {
+                    result.push_span((max + 1..=id).into());
+                    boundary = boundary.union(&max.into());
+                }
+                None => {
+                    result.push_span((low..=id).into());
+                    to_visit.extend(seg.parents()?);
+                }
+            }
+        }
+        Ok((result, boundary))
+    }
+
     /// Calculate the "dag range" - ids reachable from both sides.
     ///
     /// ```plain,ignore
@@ -1164,6 +1880,56 @@ impl Dag {
         Ok(ctx.result)
     }
 
+    /// Calculate `range(roots, heads)`, together with its roots and heads.
+    ///
+    /// This is faster than calling `range`, `roots` and `heads` separately:
+    /// roots and heads are figured out from flat segments covering the
+    /// range, without calculating `parents` and `children` of the range.
+    pub fn range_meta(
+        &self,
+        roots: impl Into<SpanSet>,
+        heads: impl Into<SpanSet>,
+    ) -> Result<RangeMeta> {
+        let range = self.range(roots, heads)?;
+        let mut range_roots = Vec::new();
+        let mut parent_spans = Vec::new();
+        for span in range.as_spans() {
+            let mut high = span.high;
+            loop {
+                let seg = self.find_flat_segment_including_id(high)?.expect(
+                    "logic error: flat segments are expected to cover everything but they do not",
+                );
+                let seg_low = seg.span()?.low;
+                // `low..=high` is a chain. Only `low` can be a root, and
+                // `low..high` are parents of ids in the range.
+                let low = seg_low.max(span.low);
+                if low < high {
+                    parent_spans.push(Span::from(low..=(high - 1)));
+                }
+                if low > seg_low {
+                    // The parent `low - 1` is not in the range.
+                    range_roots.push(low);
+                } else {
+                    let parents = seg.parents()?;
+                    if !parents.iter().any(|&p| range.contains(p)) {
+                        range_roots.push(low);
+                    }
+                    parent_spans.extend(parents.into_iter().map(Span::from));
+                }
+                if low == span.low {
+                    break;
+                }
+                high = low - 1;
+            }
+        }
+        let range_heads = range.difference(&SpanSet::from_spans(parent_spans));
+        Ok(RangeMeta {
+            roots: SpanSet::from_spans(range_roots),
+            heads: range_heads,
+            range,
+        })
+    }
+
     /// Calculate the descendants of the given set.
     ///
     /// Logically equivalent to `range(set, all())`.
@@ -1242,8 +2008,184 @@ impl Dag {
     }
 }
 
+// Debugging.
+impl Dag {
+    /// Check invariants of segments. Useful after crashes or disk corruption.
+    ///
+    /// - In each group, segments of a level are sorted and connected, and
+    ///   start from the first id of the group.
+    /// - Parents of a segment are smaller than its low id.
+    /// - Flat segments have the "root" flag if and only if they have no
+    ///   parents.
+    /// - A high-level segment starts and ends at boundaries of lower level
+    ///   segments. Its parents are the parents of the lower level segments
+    ///   it covers, excluding ids covered by itself.
+    /// - High-level segments cover no more ids than lower level segments.
+    ///
+    /// Return an error describing the first violation.
+    pub fn verify(&self) -> Result<()> {
+        for &group in Group::ALL.iter() {
+            // low -> (high, parents) of segments in the previous level.
+            let mut lower: BTreeMap<Id, (Id, Vec<Id>)> = BTreeMap::new();
+            let mut lower_next = group.min_id();
+            for level in 0..=self.max_level {
+                let mut current = BTreeMap::new();
+                let mut next = group.min_id();
+                for seg in self.next_segments(group.min_id(), level)? {
+                    let span = seg.span()?;
+                    let parents = seg.parents()?;
+                    ensure!(
+                        span.low == next,
+                        "level {} segment {:?} does not start at {}",
+                        level,
+                        &seg,
+                        next
+                    );
+                    if let Some(&p) = parents.iter().find(|&&p| p >= span.low) {
+                        bail!("level {} segment {:?} has parent {}", level, &seg, p);
+                    }
+                    if level == 0 {
+                        ensure!(
+                            seg.has_root()? == parents.is_empty(),
+                            "flat segment {:?} has a wrong root flag",
+                            &seg
+                        );
+                    } else {
+                        let covered: Vec<_> = lower.range(span.low..=span.high).collect();
+                        ensure!(
+                            covered.first().map(|c| *c.0) == Some(span.low)
+                                && covered.last().map(|c| (c.1).0) == Some(span.high),
+                            "level {} segment {:?} does not match level {} segments",
+                            level,
+                            &seg,
+                            level - 1
+                        );
+                        let expected: BTreeSet<Id> = covered
+                            .iter()
+                            .flat_map(|c| (c.1).1.iter().cloned())
+                            .filter(|&p| p < span.low)
+                            .collect();
+                        ensure!(
+                            parents.iter().cloned().collect::<BTreeSet<Id>>() == expected,
+                            "level {} segment {:?} should have parents {:?}",
+                            level,
+                            &seg,
+                            &expected
+                        );
+                    }
+                    current.insert(span.low, (span.high, parents));
+                    next = span.high + 1;
+                }
+                ensure!(
+                    level == 0 || next <= lower_next,
+                    "level {} segments cover more ids than level {} segments",
+                    level,
+                    level - 1
+                );
+                lower = current;
+                lower_next = next;
+            }
+        }
+        Ok(())
+    }
+
+    /// Render ids in `set`, their parent edges, and the segments covering
+    /// them in the Graphviz DOT format.
+    ///
+    /// Segments are nested clusters labeled with their level and span.
+    /// Edges point from children to parents. Parents outside `set` are not
+    /// rendered.
+    pub fn to_dot(&self, set: impl Into<SpanSet>) -> Result<String> {
+        use std::fmt::Write;
+
+        let set = set.into();
+        let mut out = String::new();
+        writeln!(out, "digraph dag {{")?;
+        writeln!(out, "  rankdir=BT;")?;
+        for &group in Group::ALL.iter() {
+            let (low, high) = (group.min_id(), group.max_id());
+            self.write_dot_segments(&mut out, &set, low, high, self.max_level, 1)?;
+        }
+        for id in set.iter() {
+            for parent in self.parent_ids(id)? {
+                if set.contains(parent) {
+                    writeln!(out, "  \"{}\" -> \"{}\";", id, parent)?;
+                }
+            }
+        }
+        writeln!(out, "}}")?;
+        Ok(out)
+    }
+
+    /// Render segments at `level` within `low..=high` as clusters. Ids not
+    /// covered by segments at `level` are rendered using lower levels.
+    fn write_dot_segments(
+        &self,
+        out: &mut String,
+        set: &SpanSet,
+        low: Id,
+        high: Id,
+        level: Level,
+        depth: usize,
+    ) -> Result<()> {
+        use std::fmt::Write;
+
+        let indent = "  ".repeat(depth);
+        let mut next = low;
+        for seg in self.next_segments(low, level)? {
+            let span = seg.span()?;
+            if span.low > high {
+                break;
+            }
+            if span.low > next && level > 0 {
+                self.write_dot_segments(out, set, next, span.low - 1, level - 1, depth)?;
+            }
+            next = span.high + 1;
+            if set.intersection(&span.into()).is_empty() {
+                continue;
+            }
+            writeln!(
+                out,
+                "{}subgraph \"cluster_{}_{}\" {{",
+                indent, level, span.low
+            )?;
+            writeln!(
+                out,
+                "{}  label=\"Lv{} {}-{}\";",
+                indent, level, span.low, span.high
+            )?;
+            if level == 0 {
+                for id in span.low.to(span.high) {
+                    if set.contains(id) {
+                        writeln!(out, "{}  \"{}\";", indent, id)?;
+                    }
+                }
+            } else {
+                self.write_dot_segments(out, set, span.low, span.high, level - 1, depth + 1)?;
+            }
+            writeln!(out, "{}}}", indent)?;
+        }
+        if next <= high && level > 0 {
+            self.write_dot_segments(out, set, next, high, level - 1, depth)?;
+        }
+        Ok(())
+    }
+}
+
 // Full IdMap -> Sparse IdMap
 impl Dag {
+    /// Flat segments in the master group as `(low, high, parents)`, sorted
+    /// by `low`. Used to send segments to clients at clone time.
+    pub(crate) fn master_flat_segments(&self) -> Result<Vec<(Id, Id, Vec<Id>)>> {
+        self.next_segments(Group::MASTER.min_id(), 0)?
+            .into_iter()
+            .map(|seg| {
+                let span = seg.span()?;
+                Ok((span.low, span.high, seg.parents()?))
+            })
+            .collect()
+    }
+
     /// Copy a subset of "Universal" mapping from `full_idmap` to
     /// `sparse_idmap`. See [`Dag::universal`].
     pub fn write_sparse_idmap(
@@ -1267,7 +2209,7 @@ impl Dag {
     /// See also [`FirstAncestorConstraint::KnownUniversally`].
     ///
     /// Complexity: `O(flat segments)` for both time and space.
-    fn universal(&self) -> Result<BTreeSet<Id>> {
+    pub(crate) fn universal(&self) -> Result<BTreeSet<Id>> {
         let mut result = BTreeSet::new();
         for seg in self.next_segments(Id::MIN, 0)? {
             let parents = seg.parents()?;
@@ -1287,6 +2229,168 @@ impl Dag {
     }
 }
 
+// Exchange segments.
+impl Dag {
+    /// Version of the data written by [`Dag::export_range`].
+    const EXPORT_VERSION: u8 = 1;
+
+    /// Serialize flat segments covering `span`, so they can be sent to
+    /// another [`Dag`] with the same history, for example over EdenAPI.
+    ///
+    /// Flat segments crossing the boundaries of `span` are cut. High-level
+    /// segments are not included. The receiver builds them using its own
+    /// segment size. `span` must be within a group and covered by flat
+    /// segments.
+    pub fn export_range(&self, span: impl Into<Span>) -> Result<Vec<u8>> {
+        // Format:
+        //
+        // ```plain,ignore
+        // EXPORT := VERSION (1B) + vlq(LOW) + vlq(HIGH) + vlq(COUNT) + SEGMENT * COUNT
+        // SEGMENT := FLAGS (1B) + vlq(HIGH-LOW) + vlq(PARENT_COUNT) + vlq(PARENT) * PARENT_COUNT
+        // ```
+        //
+        // The LOW of the first segment is the LOW of the range. The LOW of
+        // other segments is the HIGH of the previous segment plus one.
+        let span = span.into();
+        let (low, high) = (span.low, span.high);
+        ensure!(
+            low.group() == high.group(),
+            "{:?}..={:?} is not within a group",
+            low,
+            high
+        );
+        let next_free_id = self.next_free_id(0, high.group())?;
+        ensure!(high < next_free_id, "{:?} is not covered by segments", high);
+
+        let mut count: usize = 0;
+        let mut segments = Vec::new();
+        for seg in self.next_segments(low, 0)? {
+            let seg_span = seg.span()?;
+            if seg_span.low > high {
+                break;
+            }
+            let mut flags = seg.flags()?;
+            let mut parents = seg.parents()?;
+            let seg_low = if seg_span.low < low {
+                // The root of the segment is cut. The rest is a linear chain.
+                flags.remove(SegmentFlags::HAS_ROOT);
+                parents = vec![low - 1];
+                low
+            } else {
+                seg_span.low
+            };
+            let seg_high = seg_span.high.min(high);
+            segments.write_u8(flags.bits())?;
+            segments.write_vlq(seg_high.0 - seg_low.0)?;
+            segments.write_vlq(parents.len())?;
+            for parent in parents {
+                segments.write_vlq(parent.0)?;
+            }
+            count += 1;
+        }
+
+        let mut buf = Vec::with_capacity(segments.len() + 20);
+        buf.write_u8(Self::EXPORT_VERSION)?;
+        buf.write_vlq(low.0)?;
+        buf.write_vlq(high.0)?;
+        buf.write_vlq(count)?;
+        buf.extend_from_slice(&segments);
+        Ok(buf)
+    }
+
+    /// Insert flat segments serialized by [`Dag::export_range`] and build
+    /// high-level segments on top of them.
+    ///
+    /// The exported range must start at the next free id of its group.
+    /// Nothing is inserted if `data` is invalid.
+    ///
+    /// Return number of segments inserted.
+    pub fn import_segments(&mut self, data: &[u8]) -> Result<usize> {
+        let mut count = self.import_flat_segments(data)?;
+        count += self.build_all_high_level_segments(false)?;
+        Ok(count)
+    }
+
+    /// Insert flat segments serialized by [`Dag::export_range`].
+    fn import_flat_segments(&mut self, data: &[u8]) -> Result<usize> {
+        let mut cur = Cursor::new(data);
+        let version = cur.read_u8()?;
+        ensure!(
+            version == Self::EXPORT_VERSION,
+            "unsupported segments version {}",
+            version
+        );
+        let low = Id(cur.read_vlq()?);
+        let high = Id(cur.read_vlq()?);
+        let count: usize = cur.read_vlq()?;
+        ensure!(
+            low <= high && low.group() == high.group(),
+            "invalid range {:?}..={:?}",
+            low,
+            high
+        );
+        let next_free_id = self.next_free_id(0, low.group())?;
+        ensure!(
+            low == next_free_id,
+            "segments start at {:?}, expected {:?}",
+            low,
+            next_free_id
+        );
+
+        // Check everything before inserting anything.
+        let known = self.all()?;
+        let mut segments = Vec::with_capacity(count);
+        let mut seg_low = low;
+        for _ in 0..count {
+            let flags = SegmentFlags::from_bits_truncate(cur.read_u8()?);
+            let delta: u64 = cur.read_vlq()?;
+            let seg_high = seg_low + delta;
+            let parent_count: usize = cur.read_vlq()?;
+            let mut parents = Vec::with_capacity(parent_count);
+            for _ in 0..parent_count {
+                let parent = Id(cur.read_vlq()?);
+                ensure!(
+                    parent < seg_low && (parent >= low || known.contains(parent)),
+                    "invalid parent {:?} of {:?}",
+                    parent,
+                    seg_low
+                );
+                parents.push(parent);
+            }
+            ensure!(seg_high <= high, "segments exceed {:?}", high);
+            segments.push((flags, seg_low, seg_high, parents));
+            seg_low = seg_high + 1;
+        }
+        ensure!(
+            seg_low == high + 1,
+            "segments do not cover {:?}..={:?}",
+            low,
+            high
+        );
+        ensure!(
+            cur.position() == data.len() as u64,
+            "unexpected data after segments"
+        );
+
+        for (flags, low, high, parents) in &segments {
+            self.insert(*flags, 0, *low, *high, parents)?;
+        }
+        Ok(segments.len())
+    }
+}
+
+/// Result of [`Dag::range_meta`].
+pub struct RangeMeta {
+    /// `range(roots, heads)`.
+    pub range: SpanSet,
+
+    /// `roots(range)`.
+    pub roots: SpanSet,
+
+    /// `heads(range)`.
+    pub heads: SpanSet,
+}
+
 /// There are many `x~n`s that all resolves to a single commit.
 /// Constraint about `x~n`.
 pub enum FirstAncestorConstraint {
@@ -1358,6 +2462,47 @@ impl SyncableDag {
     pub fn remove_non_master(&mut self) -> Result<()> {
         self.dag.remove_non_master()
     }
+
+    /// Remove `set` and its descendants. Return the removed ids.
+    ///
+    /// This is similar to [`Dag::strip`]. However, like
+    /// [`SyncableDag::build_segments_persistent`], the rebuilt high-level
+    /// segments are intentionally made lagging to reduce fragmentation.
+    pub fn strip(&mut self, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        let removed = self.dag.remove_descendants(set.into())?;
+        self.dag.build_all_high_level_segments(true)?;
+        Ok(removed)
+    }
+
+    /// Rebuild all high-level segments from flat segments.
+    ///
+    /// This is similar to [`Dag::rebuild_high_levels`]. However, like
+    /// [`SyncableDag::build_segments_persistent`], the last high-level
+    /// segments are intentionally made lagging to reduce fragmentation.
+    pub fn rebuild_high_levels(&mut self) -> Result<usize> {
+        self.dag.remove_high_level_segments()?;
+        self.dag.build_all_high_level_segments(true)
+    }
+
+    /// Insert flat segments serialized by [`Dag::export_range`] and build
+    /// high-level segments on top of them.
+    ///
+    /// This is similar to [`Dag::import_segments`]. However, like
+    /// [`SyncableDag::build_segments_persistent`], the last high-level
+    /// segments are intentionally made lagging to reduce fragmentation.
+    pub fn import_segments(&mut self, data: &[u8]) -> Result<usize> {
+        let mut count = self.dag.import_flat_segments(data)?;
+        count += self.dag.build_all_high_level_segments(true)?;
+        Ok(count)
+    }
+}
+
+impl Deref for SyncableDag {
+    type Target = Dag;
+
+    fn deref(&self) -> &Self::Target {
+        &self.dag
+    }
 }
 
 bitflags! {
@@ -1498,6 +2643,55 @@ impl<'a> Debug for Segment<'a> {
     }
 }
 
+/// Iterator of `ancestors(heads)` in descending order. Returned by
+/// [`Dag::iter_ancestors_rev`].
+pub struct AncestorsRevIter<'a> {
+    dag: &'a Dag,
+    to_visit: BinaryHeap<Id>,
+    last: Option<Id>,
+    // The flat segment (span and parents) that includes `last`.
+    segment: Option<(Span, Vec<Id>)>,
+}
+
+impl<'a> AncestorsRevIter<'a> {
+    fn visit(&mut self, id: Id) -> Result<()> {
+        let cached = match &self.segment {
+            Some((span, _)) => span.low <= id && id <= span.high,
+            None => false,
+        };
+        if !cached {
+            let seg = self.dag.find_flat_segment_including_id(id)?.expect(
+                "logic error: flat segments are expected to cover everything but they do not",
+            );
+            self.segment = Some((seg.span()?, seg.parents()?));
+        }
+        let (span, parents) = self.segment.as_ref().unwrap();
+        if id == span.low {
+            self.to_visit.extend(parents.iter().cloned());
+        } else {
+            self.to_visit.push(id - 1);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for AncestorsRevIter<'a> {
+    type Item = Result<Id>;
+
+    fn next(&mut self) -> Option<Result<Id>> {
+        while let Some(id) = self.to_visit.pop() {
+            // Ids are visited in descending order. Duplicated ids are popped
+            // right after each other.
+            if self.last == Some(id) {
+                continue;
+            }
+            self.last = Some(id);
+            return Some(self.visit(id).map(|_| id));
+        }
+        None
+    }
+}
+
 /// Lazily answer `any(...)`, `all(...)`.
 struct LazyPredicate<P> {
     ids: Vec<Id>,
@@ -1665,6 +2859,222 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_flush() {
+        let dir = tempdir().unwrap();
+        let mut dag1 = Dag::open(dir.path()).unwrap();
+        let mut dag2 = Dag::open(dir.path()).unwrap();
+        dag1.build_segments_volatile(Id(100), &get_parents).unwrap();
+        dag2.build_segments_volatile(Id(200), &get_parents).unwrap();
+
+        // Ids written by both processes are kept.
+        dag1.flush().unwrap();
+        dag2.flush().unwrap();
+        let dag = Dag::open(dir.path()).unwrap();
+        assert_eq!(dag.all().unwrap().count(), 201);
+        assert_eq!(
+            dag.children(Id(100)).unwrap().iter().collect::<Vec<Id>>(),
+            vec![Id(200), Id(101)]
+        );
+
+        // Ids written with different parents are an error.
+        let id = Group::NON_MASTER.min_id();
+        let parents_a = |_: Id| -> Result<Vec<Id>> { Ok(vec![Id(50)]) };
+        let parents_b = |_: Id| -> Result<Vec<Id>> { Ok(vec![Id(60)]) };
+        dag1.build_segments_volatile(id, &parents_a).unwrap();
+        dag2.build_segments_volatile(id, &parents_b).unwrap();
+        dag1.flush().unwrap();
+        dag2.flush().unwrap_err();
+        let dag = Dag::open(dir.path()).unwrap();
+        assert_eq!(dag.parent_ids(id).unwrap(), vec![Id(50)]);
+    }
+
+    #[test]
+    fn test_volatile_ids() {
+        let dir = tempdir().unwrap();
+        let mut dag = Dag::open(dir.path()).unwrap();
+        let mut syncable = dag.prepare_filesystem_sync().unwrap();
+        syncable
+            .build_segments_persistent(Id(100), &get_parents)
+            .unwrap();
+        syncable.sync(std::iter::once(&mut dag)).unwrap();
+        assert!(!dag.is_dirty().unwrap());
+        assert!(dag.volatile_ids().unwrap().is_empty());
+
+        dag.build_segments_volatile(Id(150), &get_parents).unwrap();
+        assert!(dag.is_dirty().unwrap());
+        assert_eq!(format!("{:?}", dag.volatile_ids().unwrap()), "101..=150");
+        assert_eq!(format!("{:?}", dag.persisted_ids().unwrap()), "0..=100");
+
+        dag.flush().unwrap();
+        assert!(!dag.is_dirty().unwrap());
+        assert_eq!(format!("{:?}", dag.persisted_ids().unwrap()), "0..=150");
+
+        // Pending removals are changes.
+        dag.strip(Id(150)).unwrap();
+        assert!(dag.is_dirty().unwrap());
+        assert_eq!(format!("{:?}", dag.persisted_ids().unwrap()), "0..=149");
+    }
+
+    #[test]
+    fn test_rebuild_high_levels() {
+        let dir = tempdir().unwrap();
+        let mut dag = Dag::open(dir.path()).unwrap();
+        for i in 0..=100 {
+            dag.build_segments_volatile(Id(i), &get_parents).unwrap();
+        }
+        let fragmented = format!("{:?}", &dag);
+
+        // Same segments as building everything at once.
+        dag.rebuild_high_levels().unwrap();
+        let rebuilt = format!("{:?}", &dag);
+        let dir2 = tempdir().unwrap();
+        let mut dag2 = Dag::open(dir2.path()).unwrap();
+        dag2.build_segments_volatile(Id(100), &get_parents).unwrap();
+        assert_eq!(rebuilt, format!("{:?}", &dag2));
+        assert!(rebuilt.len() < fragmented.len());
+
+        // Persist the rebuilt segments.
+        let mut syncable = dag.prepare_filesystem_sync().unwrap();
+        syncable
+            .build_segments_persistent(Id(100), &get_parents)
+            .unwrap();
+        syncable.rebuild_high_levels().unwrap();
+        syncable.sync(std::iter::once(&mut dag)).unwrap();
+        let dag = Dag::open(dir.path()).unwrap();
+        assert_eq!(format!("{:?}", &dag), rebuilt);
+    }
+
+    #[test]
+    fn test_max_level() {
+        let dir = tempdir().unwrap();
+        let mut dag = Dag::open(dir.path()).unwrap();
+        dag.build_segments_volatile(Id(1001), &get_parents).unwrap();
+        assert_eq!(dag.max_level, 3);
+        let ancestors = dag.ancestors(Id(1001)).unwrap().count();
+
+        // Levels above the cap are removed by rebuilding.
+        dag.set_new_max_level(1);
+        dag.rebuild_high_levels().unwrap();
+        assert_eq!(dag.max_level, 1);
+        assert_eq!(dag.ancestors(Id(1001)).unwrap().count(), ancestors);
+        dag.set_new_max_level(0);
+        dag.rebuild_high_levels().unwrap();
+        assert_eq!(dag.max_level, 0);
+        assert_eq!(dag.ancestors(Id(1001)).unwrap().count(), ancestors);
+
+        // About 1000 flat segments need 2 levels of segment size 16.
+        assert_eq!(dag.auto_tune_max_level().unwrap(), 2);
+        dag.rebuild_high_levels().unwrap();
+        assert_eq!(dag.max_level, 2);
+        dag.set_new_segment_size(1000);
+        assert_eq!(dag.auto_tune_max_level().unwrap(), 1);
+
+        // Small graphs only need flat segments.
+        let dir = tempdir().unwrap();
+        let mut dag = Dag::open(dir.path()).unwrap();
+        dag.build_segments_volatile(Id(10), &get_parents).unwrap();
+        assert_eq!(dag.auto_tune_max_level().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_export_import() {
+        let dir = tempdir().unwrap();
+        let mut dag = Dag::open(dir.path()).unwrap();
+        dag.build_segments_volatile(Id(1001), &get_parents).unwrap();
+        let non_master = Group::NON_MASTER.min_id();
+        let non_master_parents = |id: Id| -> Result<Vec<Id>> {
+            if id == non_master {
+                Ok(vec![Id(1001)])
+            } else {
+                Ok(vec![id - 1])
+            }
+        };
+        dag.build_segments_volatile(non_master + 99, &non_master_parents)
+            .unwrap();
+
+        // Import everything at once.
+        let dir2 = tempdir().unwrap();
+        let mut dag2 = Dag::open(dir2.path()).unwrap();
+        for span in [(Id(0)..=Id(1001)), (non_master..=(non_master + 99))].iter() {
+            let data = dag.export_range(span.clone()).unwrap();
+            dag2.import_segments(&data).unwrap();
+        }
+        assert_eq!(format!("{:?}", &dag2), format!("{:?}", &dag));
+
+        // Import in chunks. Flat segments are cut at chunk boundaries.
+        let dir3 = tempdir().unwrap();
+        let mut dag3 = Dag::open(dir3.path()).unwrap();
+        for &(low, high) in [(0, 499), (500, 1001)].iter() {
+            let data = dag.export_range(Id(low)..=Id(high)).unwrap();
+            dag3.import_segments(&data).unwrap();
+        }
+        for &(low, high) in [(0, 49), (50, 99)].iter() {
+            let data = dag
+                .export_range((non_master + low)..=(non_master + high))
+                .unwrap();
+            dag3.import_segments(&data).unwrap();
+        }
+        dag3.verify().unwrap();
+        assert_eq!(
+            format!("{:?}", dag3.all().unwrap()),
+            format!("{:?}", dag.all().unwrap())
+        );
+        for &id in [Id(500), Id(1001), non_master, non_master + 50].iter() {
+            assert_eq!(dag3.parent_ids(id).unwrap(), dag.parent_ids(id).unwrap());
+            assert_eq!(
+                dag3.ancestors(id).unwrap().count(),
+                dag.ancestors(id).unwrap().count()
+            );
+        }
+
+        // Invalid data is rejected without changing the dag.
+        let dir4 = tempdir().unwrap();
+        let mut dag4 = Dag::open(dir4.path()).unwrap();
+        let data = dag.export_range(Id(500)..=Id(1001)).unwrap();
+        assert!(dag4.import_segments(&data).is_err());
+        let mut data = dag.export_range(Id(0)..=Id(1001)).unwrap();
+        assert!(dag4.import_segments(&data[..data.len() - 1]).is_err());
+        data[0] = 2;
+        assert!(dag4.import_segments(&data).is_err());
+        assert!(dag4.all().unwrap().is_empty());
+        assert!(dag.export_range(Id(0)..=Id(1002)).is_err());
+    }
+
+    #[test]
+    fn test_invalidation_key() {
+        let dir = tempdir().unwrap();
+        let mut dag = Dag::open(dir.path()).unwrap();
+        let empty_key = dag.invalidation_key().unwrap();
+        dag.build_segments_volatile(Id(100), &get_parents).unwrap();
+        let master_key = dag.invalidation_key().unwrap();
+        assert_ne!(master_key, empty_key);
+        dag.build_segments_volatile(Id(101), &get_parents).unwrap();
+        let master_key = dag.invalidation_key().unwrap();
+
+        // Non-master ids that are reassigned to a different graph change the key.
+        let id = Group::NON_MASTER.min_id();
+        let parents_a = |_: Id| -> Result<Vec<Id>> { Ok(vec![Id(50)]) };
+        let parents_b = |_: Id| -> Result<Vec<Id>> { Ok(vec![Id(60)]) };
+        dag.build_segments_volatile(id, &parents_a).unwrap();
+        let key_a = dag.invalidation_key().unwrap();
+        assert_ne!(key_a, master_key);
+        dag.remove_non_master().unwrap();
+        assert_eq!(dag.invalidation_key().unwrap(), master_key);
+        dag.build_segments_volatile(id, &parents_b).unwrap();
+        let key_b = dag.invalidation_key().unwrap();
+        assert_ne!(key_b, key_a);
+
+        // The key is stable across processes.
+        let mut syncable = dag.prepare_filesystem_sync().unwrap();
+        syncable
+            .build_segments_persistent(Id(101), &get_parents)
+            .unwrap();
+        syncable.sync(std::iter::once(&mut dag)).unwrap();
+        let dag2 = Dag::open(dir.path()).unwrap();
+        assert_eq!(dag2.invalidation_key().unwrap(), master_key);
+    }
+
     #[test]
     fn test_all() {
         let dir = tempdir().unwrap();
@@ -0,0 +1,122 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! # setcache
+//!
+//! See [`SetCache`] for the main structure.
+
+use crate::segment::Dag;
+use crate::spanset::SpanSet;
+use anyhow::Result;
+use byteorder::{BigEndian, WriteBytesExt};
+use indexedlog::log::IndexOutput;
+use indexedlog::rotate::{OpenOptions, RotateLog};
+use indexedlog::utils::xxhash;
+use std::io::Cursor;
+use std::path::Path;
+use vlqencoding::{VLQDecode, VLQEncode};
+
+/// Persistent cache of expensive [`SpanSet`] query results.
+///
+/// Results are keyed by the query and the [`Dag::invalidation_key`], so they
+/// are ignored once the segments change. Outdated results are not removed
+/// explicitly, they go away as the underlying logs rotate.
+///
+/// The query is an opaque byte string. It must identify the inputs of the
+/// query (ex. `only(<master ids>, <stable ids>)` instead of
+/// `only(master, stable)`), since they can change without changing the
+/// [`Dag`].
+pub struct SetCache {
+    log: RotateLog,
+}
+
+// Serialization format for cache entries:
+//
+// ```plain,ignore
+// ENTRY := QUERY_HASH (8B) + DAG_KEY (8B) + vlq(QUERY_LEN) + QUERY + SPANSET
+// ```
+//
+// QUERY_HASH is the xxhash of QUERY, DAG_KEY is the Dag::invalidation_key,
+// and SPANSET is serialized by SpanSet::to_bytes. The full QUERY is stored
+// to detect hash collisions.
+
+impl SetCache {
+    const INDEX_KEY: usize = 0;
+    const KEY_LEN: usize = 16;
+
+    /// Create a [`SetCache`] backed by the given directory.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let log = OpenOptions::new()
+            .create(true)
+            .max_bytes_per_log(1 << 20)
+            .max_log_count(3)
+            .index("key", |_| {
+                vec![IndexOutput::Reference(0..SetCache::KEY_LEN as u64)]
+            })
+            .open(path)?;
+        Ok(Self { log })
+    }
+
+    /// Get the cached result of `query` for the current content of `dag`.
+    pub fn get(&self, dag: &Dag, query: &[u8]) -> Result<Option<SpanSet>> {
+        let key = Self::key(dag, query)?;
+        for entry in self.log.lookup(Self::INDEX_KEY, key.to_vec())? {
+            let entry = entry?;
+            let mut cur = Cursor::new(entry);
+            cur.set_position(Self::KEY_LEN as u64);
+            let query_len: usize = cur.read_vlq()?;
+            let start = cur.position() as usize;
+            if entry.get(start..start + query_len) == Some(query) {
+                return Ok(Some(SpanSet::from_bytes(&entry[start + query_len..])?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Cache `set` as the result of `query` for the current content of `dag`.
+    ///
+    /// The result is written to disk by [`SetCache::flush`].
+    pub fn insert(&mut self, dag: &Dag, query: &[u8], set: &SpanSet) -> Result<()> {
+        let mut data = Self::key(dag, query)?.to_vec();
+        data.write_vlq(query.len())?;
+        data.extend_from_slice(query);
+        data.extend_from_slice(&set.to_bytes());
+        self.log.append(data)?;
+        Ok(())
+    }
+
+    /// Get the cached result of `query`, or calculate it using `calculate`
+    /// and cache it.
+    pub fn get_or_insert_with(
+        &mut self,
+        dag: &Dag,
+        query: &[u8],
+        calculate: impl FnOnce() -> Result<SpanSet>,
+    ) -> Result<SpanSet> {
+        if let Some(set) = self.get(dag, query)? {
+            return Ok(set);
+        }
+        let set = calculate()?;
+        self.insert(dag, query, &set)?;
+        Ok(set)
+    }
+
+    /// Write cached results to disk, and load results written by other
+    /// processes.
+    pub fn flush(&mut self) -> Result<()> {
+        self.log.flush()?;
+        Ok(())
+    }
+
+    fn key(dag: &Dag, query: &[u8]) -> Result<[u8; SetCache::KEY_LEN]> {
+        let mut key = [0; Self::KEY_LEN];
+        let mut cur = Cursor::new(&mut key[..]);
+        cur.write_u64::<BigEndian>(xxhash(query))?;
+        cur.write_u64::<BigEndian>(dag.invalidation_key()?)?;
+        Ok(key)
+    }
+}
@@ -10,13 +10,16 @@
 //! See [`SpanSet`] for the main structure.
 
 use crate::id::Id;
+use anyhow::{bail, ensure, Result};
 use std::cmp::{
     Ordering::{self, Equal, Greater, Less},
     PartialOrd,
 };
 use std::collections::BinaryHeap;
 use std::fmt::{self, Debug};
+use std::io::Cursor;
 use std::ops::{Bound, RangeBounds, RangeInclusive};
+use vlqencoding::{VLQDecode, VLQEncode};
 
 /// Range `low..=high`. `low` must be <= `high`.
 #[derive(Copy, Clone, Debug, Eq)]
@@ -434,6 +437,80 @@ impl SpanSet {
     }
 }
 
+// Serialization format for SpanSet:
+//
+// ```plain,ignore
+// SPANSET := VERSION (1B) + vlq(SPAN_COUNT) + SPAN_LIST
+// SPAN_LIST := '' | SPAN_LIST + SPAN
+// SPAN := vlq(GAP) + vlq(HIGH-LOW)
+// ```
+//
+// Spans are written in descending order. GAP is HIGH for the first span, and
+// `PREVIOUS_LOW - HIGH` for the remaining spans. Since spans are not
+// overlapped, GAP is positive except for the first span.
+
+impl SpanSet {
+    const SERIALIZATION_VERSION: u8 = 1;
+
+    /// Serialize the [`SpanSet`] into a compact binary form.
+    ///
+    /// The result can be stored outside the process (ex. cached on disk) and
+    /// be read back using [`SpanSet::from_bytes`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(1 + (self.spans.len() + 1) * 4);
+        buf.push(Self::SERIALIZATION_VERSION);
+        buf.write_vlq(self.spans.len()).unwrap();
+        let mut previous_low = None;
+        for span in self.spans.iter() {
+            let gap = match previous_low {
+                None => span.high.0,
+                Some(low) => low - span.high.0,
+            };
+            buf.write_vlq(gap).unwrap();
+            buf.write_vlq(span.high.0 - span.low.0).unwrap();
+            previous_low = Some(span.low.0);
+        }
+        buf
+    }
+
+    /// Deserialize a [`SpanSet`] written by [`SpanSet::to_bytes`].
+    ///
+    /// Return an error if the data is malformed, or is written by an
+    /// incompatible version.
+    pub fn from_bytes(data: &[u8]) -> Result<SpanSet> {
+        let mut cur = Cursor::new(data);
+        match data.first() {
+            Some(&Self::SERIALIZATION_VERSION) => cur.set_position(1),
+            Some(version) => bail!("unsupported SpanSet serialization version {}", version),
+            None => bail!("cannot read SpanSet serialization version"),
+        }
+        let count: usize = cur.read_vlq()?;
+        let mut spans = Vec::with_capacity(count.min(64));
+        let mut previous_low: Option<u64> = None;
+        for _ in 0..count {
+            let gap: u64 = cur.read_vlq()?;
+            let delta: u64 = cur.read_vlq()?;
+            let high = match previous_low {
+                None => gap,
+                Some(low) => {
+                    ensure!(gap > 0 && gap <= low, "invalid SpanSet gap {}", gap);
+                    low - gap
+                }
+            };
+            ensure!(delta <= high, "invalid SpanSet span length {}", delta);
+            let span = Span::new(Id(high - delta), Id(high));
+            ensure!(span.high <= Id::MAX, "invalid SpanSet id {}", high);
+            spans.push(span);
+            previous_low = Some(span.low.0);
+        }
+        ensure!(
+            cur.position() == data.len() as u64,
+            "unexpected trailing bytes in SpanSet"
+        );
+        Ok(SpanSet::from_spans(spans))
+    }
+}
+
 /// Push a span to `Vec<Span>`. Try to union them in-place.
 fn push_with_union(spans: &mut Vec<Span>, span: Span) {
     match spans.last_mut() {
@@ -768,6 +845,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_serialization() {
+        let sets = vec![
+            SpanSet::empty(),
+            SpanSet::from(0..=0),
+            SpanSet::from_spans(vec![1..=3, 5..=5, 7..=100]),
+            SpanSet::from_spans(vec![Span::new(Id(0), Id::MAX)]),
+            SpanSet::from_spans(vec![Span::from(Id::MAX), Span::from(0..=10)]),
+        ];
+        for set in sets {
+            let bytes = set.to_bytes();
+            let decoded = SpanSet::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded.as_spans(), set.as_spans());
+        }
+
+        let bytes = SpanSet::from_spans(vec![1..=3, 7..=100]).to_bytes();
+        assert_eq!(bytes, [1, 2, 100, 93, 4, 2]);
+
+        // Malformed data.
+        assert!(SpanSet::from_bytes(&[]).is_err());
+        assert!(SpanSet::from_bytes(&[2, 0]).is_err());
+        assert!(SpanSet::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(SpanSet::from_bytes(&[1, 1, 1, 2]).is_err());
+        assert!(SpanSet::from_bytes(&[1, 2, 10, 1, 0, 1]).is_err());
+        assert!(SpanSet::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+    }
+
     #[test]
     fn test_push() {
         let mut set = SpanSet::from(10..=20);
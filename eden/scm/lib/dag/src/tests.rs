@@ -10,8 +10,13 @@ use crate::idmap::IdMap;
 use crate::protocol::{Process, RequestLocationToName, RequestNameToLocation};
 use crate::segment::Dag;
 use crate::segment::FirstAncestorConstraint;
+use crate::segment::{Level, SegmentFlags};
 use crate::spanset::SpanSet;
+use crate::testutil::{build_segments, parents_by_name};
 use crate::NamedDag;
+use crate::Phases;
+use crate::SetCache;
+use crate::Visibility;
 use anyhow::Result;
 use tempfile::tempdir;
 
@@ -148,6 +153,86 @@ Lv3: R0-11[]"#
     );
 }
 
+#[test]
+fn test_lazy_clone() {
+    let built = build_segments(ASCII_DAG1, "L", 3);
+    let server = NamedDag {
+        dag: built.dag,
+        map: built.id_map,
+    };
+    let name = |s: &str| VertexName::copy_from(s.as_bytes());
+
+    // The client gets all segments, but only universally known names.
+    let dir = tempdir().unwrap();
+    let mut client = NamedDag::open(dir.path()).unwrap();
+    let data = server.export_clone_data().unwrap();
+    client.import_clone_data(data).unwrap();
+    let flat_segments = |dag: &Dag| format!("{:?}", dag).lines().next().unwrap().to_string();
+    assert_eq!(flat_segments(&client.dag), flat_segments(&server.dag));
+    assert_eq!(client.map.find_id_by_name(b"L").unwrap(), Some(Id(11)));
+    assert!(client.map.find_id_by_name(b"I").unwrap().is_none());
+    assert!(client.map.find_name_by_id(Id(10)).unwrap().is_none());
+
+    // Missing names and ids are fetched on demand.
+    client
+        .resolve_names_remotely(&[name("I"), name("L")], &server)
+        .unwrap();
+    client
+        .resolve_ids_remotely(&SpanSet::from_spans(vec![Id(10), Id(0)]), &server)
+        .unwrap();
+    client.reload().unwrap();
+    assert_eq!(client.map.find_id_by_name(b"I").unwrap(), Some(Id(8)));
+    assert_eq!(
+        client.parent_names(&name("K")).unwrap(),
+        vec![name("H"), name("J")]
+    );
+    assert!(client.map.find_name_by_id(Id(0)).unwrap().is_some());
+}
+
+#[test]
+fn test_to_dot() {
+    let built = build_segments(ASCII_DAG1, "L", 3);
+    assert_eq!(
+        built.dag.to_dot(SpanSet::from(Id(5)..=Id(11))).unwrap(),
+        r#"digraph dag {
+  rankdir=BT;
+  subgraph "cluster_2_0" {
+    label="Lv2 0-11";
+    subgraph "cluster_1_0" {
+      label="Lv1 0-7";
+      subgraph "cluster_0_4" {
+        label="Lv0 4-7";
+        "5";
+        "6";
+        "7";
+      }
+    }
+    subgraph "cluster_1_8" {
+      label="Lv1 8-11";
+      subgraph "cluster_0_8" {
+        label="Lv0 8-9";
+        "8";
+        "9";
+      }
+      subgraph "cluster_0_10" {
+        label="Lv0 10-11";
+        "10";
+        "11";
+      }
+    }
+  }
+  "11" -> "10";
+  "10" -> "7";
+  "10" -> "9";
+  "9" -> "8";
+  "8" -> "6";
+  "7" -> "6";
+  "6" -> "5";
+}
+"#
+    );
+}
+
 #[test]
 fn test_segment_examples() {
     assert_eq!(
@@ -434,6 +519,63 @@ Lv1: R0-2[] 3-3[0, 1]"#
     );
 }
 
+#[test]
+fn test_segment_multiple_gcas_n_way() {
+    let ascii_dag = r#"
+        B---C
+         \ /
+        A---D
+         \
+          E"#;
+    let result = build_segments(ascii_dag, "C D E", 3);
+    let dag = result.dag;
+    let id_map = result.id_map;
+    let ids = |names: &str| -> SpanSet {
+        SpanSet::from_spans(names.split(' ').map(|name| {
+            let id = id_map.find_id_by_name(name.as_bytes()).unwrap().unwrap();
+            id..=id
+        }))
+    };
+    let gca_all = |names| -> String { format_set(dag.gca_all(ids(names)).unwrap()) };
+
+    assert_eq!(gca_all("C D"), format_set(ids("A B")));
+    assert_eq!(gca_all("C D E"), format_set(ids("A")));
+    assert_eq!(gca_all("B C D"), format_set(ids("B")));
+    assert_eq!(gca_all("B C E"), "");
+    assert_eq!(dag.gca_one(ids("C D E")).unwrap(), ids("A").max());
+    assert_eq!(dag.gca_one(ids("B C E")).unwrap(), None);
+
+    // Compare with common ancestors found by following parent_ids.
+    let result = build_segments(ASCII_DAG2, "W", 3);
+    let dag = result.dag;
+    let ancestors = |id: Id| -> SpanSet {
+        let mut result = SpanSet::empty();
+        let mut to_visit = vec![id];
+        while let Some(id) = to_visit.pop() {
+            if !result.contains(id) {
+                result = result.union(&id.into());
+                to_visit.extend(dag.parent_ids(id).unwrap());
+            }
+        }
+        result
+    };
+    for a in (0..=22).step_by(2) {
+        for b in a..=22 {
+            for c in b..=22 {
+                let common = ancestors(Id(a))
+                    .intersection(&ancestors(Id(b)))
+                    .intersection(&ancestors(Id(c)));
+                let set = SpanSet::from_spans(vec![a..=a, b..=b, c..=c]);
+                assert_eq!(
+                    dag.gca_all(set.clone()).unwrap().as_spans(),
+                    dag.heads(common.clone()).unwrap().as_spans(),
+                );
+                assert_eq!(dag.gca_one(set).unwrap(), common.max());
+            }
+        }
+    }
+}
+
 #[test]
 fn test_parents() {
     let result = build_segments(ASCII_DAG1, "L", 3);
@@ -514,6 +656,526 @@ Lv2: R0-11[]"#
     assert_eq!(to_first_ancestor_nth(11), "Some((11, 0))");
 }
 
+#[test]
+fn test_first_parent() {
+    let result = build_segments(ASCII_DAG2, "W", 3);
+    let dag = result.dag;
+    for id in dag.all().unwrap().iter() {
+        let parents = dag.parent_ids(id).unwrap();
+        assert_eq!(dag.first_parent(id).unwrap(), parents.first().cloned());
+    }
+    assert_eq!(dag.first_parent(Id(0)).unwrap(), None);
+    assert!(dag.first_parent(Id(100)).is_err());
+}
+
+#[test]
+fn test_first_ancestors() {
+    let result = build_segments(ASCII_DAG2, "W", 3);
+    let dag = result.dag;
+    let first_ancestors =
+        |spans| -> String { format_set(dag.first_ancestors(SpanSet::from_spans(spans)).unwrap()) };
+
+    assert_eq!(first_ancestors(vec![]), "");
+    assert_eq!(first_ancestors(vec![0..=0]), "0");
+    assert_eq!(first_ancestors(vec![15..=15]), "0..=3 6 7 11 12 15");
+    assert_eq!(first_ancestors(vec![4..=5, 14..=14]), "0 1 4 5 13 14");
+
+    // Compare with following the first parents one by one.
+    for a in 0..=22 {
+        for b in a..=22 {
+            let mut expected = SpanSet::empty();
+            for &id in &[a, b] {
+                let mut id = Id(id);
+                loop {
+                    expected = expected.union(&id.into());
+                    match dag.parent_ids(id).unwrap().first() {
+                        Some(&p1) => id = p1,
+                        None => break,
+                    }
+                }
+            }
+            let set = SpanSet::from_spans(vec![a..=a, b..=b]);
+            assert_eq!(
+                dag.first_ancestors(set).unwrap().as_spans(),
+                expected.as_spans()
+            );
+        }
+    }
+}
+
+#[test]
+fn test_remove_non_master() {
+    let ascii = r#"
+    C   f
+    |   |
+    B   e
+    |   |
+    A   d"#;
+    let result = build_segments(ascii, "B f", 2);
+    let _dir = result.dir;
+    let mut named_dag = NamedDag {
+        dag: result.dag,
+        map: result.id_map,
+    };
+    let name = |s: &str| VertexName::copy_from(s.as_bytes());
+    let id = |dag: &NamedDag, s: &str| dag.map.find_id_by_name(s.as_bytes()).unwrap();
+    assert_eq!(format_set(named_dag.dag.all().unwrap()), "0 1 N0 N1 N2");
+
+    named_dag.remove_non_master().unwrap();
+    assert_eq!(format_set(named_dag.dag.all().unwrap()), "0 1");
+    assert_eq!(id(&named_dag, "f"), None);
+    assert_eq!(id(&named_dag, "B"), Some(Id(1)));
+
+    // The non-master group is reused. Master ids are not affected.
+    let parents = drawdag::parse(ascii);
+    let parents_by_name = parents_by_name(&parents);
+    named_dag
+        .build(parents_by_name, &[name("C")], &[name("e")])
+        .unwrap();
+    assert_eq!(format_set(named_dag.dag.all().unwrap()), "0 1 2 N0 N1");
+    assert_eq!(id(&named_dag, "e"), Some(Group::NON_MASTER.min_id() + 1));
+
+    // The removal is persisted.
+    named_dag.reload().unwrap();
+    assert_eq!(id(&named_dag, "f"), None);
+}
+
+#[test]
+fn test_strip() {
+    let built = build_segments(ASCII_DAG1, "L", 3);
+    let mut dag = built.dag;
+    assert_eq!(
+        format!("{:?}", &dag),
+        "Lv0: RH0-1[] R2-3[] H4-7[1, 3] 8-9[6] H10-11[7, 9]\nLv1: R0-7[] 8-11[6, 7]\nLv2: R0-11[]"
+    );
+
+    // Ids are not reassigned. 8 and 9 are not descendants of 7.
+    assert!(dag.strip(Id(7)).is_err());
+    assert_eq!(format_set(dag.all().unwrap()), "0..=11");
+
+    // The flat segment 8-9 is truncated.
+    assert_eq!(format_set(dag.strip(Id(9)).unwrap()), "9 10 11");
+    assert_eq!(format_set(dag.all().unwrap()), "0..=8");
+    assert_eq!(
+        format!("{:?}", &dag),
+        "Lv0: RH0-1[] R2-3[] H4-7[1, 3] 8-8[6]\nLv1: R0-7[] 8-8[6]"
+    );
+    assert_eq!(format_set(dag.heads(dag.all().unwrap()).unwrap()), "7 8");
+    assert_eq!(format_set(dag.children(Id(6)).unwrap()), "7 8");
+}
+
+#[test]
+fn test_strip_named() {
+    let ascii = r#"
+    C   f g
+    |   |/
+    B   e
+    |   |
+    A   d"#;
+    let result = build_segments(ascii, "C f g", 2);
+    let _dir = result.dir;
+    let mut named_dag = NamedDag {
+        dag: result.dag,
+        map: result.id_map,
+    };
+    let name = |s: &str| VertexName::copy_from(s.as_bytes());
+    let id = |dag: &NamedDag, s: &str| dag.map.find_id_by_name(s.as_bytes()).unwrap();
+    assert_eq!(format_set(named_dag.dag.all().unwrap()), "0 1 2 N0..=N3");
+    assert_eq!(id(&named_dag, "g"), Some(Group::NON_MASTER.min_id() + 3));
+
+    // Non-master ids are reassigned.
+    named_dag.strip(&[name("f")]).unwrap();
+    assert_eq!(format_set(named_dag.dag.all().unwrap()), "0 1 2 N0 N1 N2");
+    assert_eq!(id(&named_dag, "f"), None);
+    assert_eq!(id(&named_dag, "g"), Some(Group::NON_MASTER.min_id() + 2));
+
+    // Master ids are truncated.
+    named_dag.strip(&[name("B")]).unwrap();
+    assert_eq!(format_set(named_dag.dag.all().unwrap()), "0 N0 N1 N2");
+    assert_eq!(id(&named_dag, "C"), None);
+
+    // The removal is persisted.
+    named_dag.reload().unwrap();
+    assert_eq!(id(&named_dag, "B"), None);
+    assert_eq!(format!("{:?}", named_dag.dag), "Lv0: RH0-0[] RN0-N2[]");
+
+    // Ids are reused by later builds.
+    let parents = drawdag::parse(ascii);
+    let parents_by_name = parents_by_name(&parents);
+    named_dag
+        .build(parents_by_name, &[name("C")], &[name("f")])
+        .unwrap();
+    assert_eq!(id(&named_dag, "C"), Some(Id(2)));
+    assert_eq!(id(&named_dag, "f"), Some(Group::NON_MASTER.min_id() + 3));
+}
+
+#[test]
+fn test_verify() {
+    for &(ascii, heads) in [(ASCII_DAG1, "L"), (ASCII_DAG2, "W"), (ASCII_DAG3, "G")].iter() {
+        let result = build_segments(ascii, heads, 3);
+        let named_dag = NamedDag {
+            dag: result.dag,
+            map: result.id_map,
+        };
+        named_dag.verify().unwrap();
+    }
+
+    let dir = tempdir().unwrap();
+    let verify = |segments: &[(SegmentFlags, Level, u64, u64, &[u64])]| -> String {
+        let mut dag = Dag::open(dir.path().join(segments.len().to_string())).unwrap();
+        for &(flags, level, low, high, parents) in segments {
+            let parents: Vec<Id> = parents.iter().map(|&p| Id(p)).collect();
+            dag.insert(flags, level, Id(low), Id(high), &parents)
+                .unwrap();
+        }
+        match dag.verify() {
+            Ok(()) => "ok".to_string(),
+            Err(err) => err.to_string(),
+        }
+    };
+    let root = SegmentFlags::HAS_ROOT;
+    let none = SegmentFlags::empty();
+    assert_eq!(verify(&[(root, 0, 0, 2, &[]), (none, 0, 3, 4, &[1])]), "ok");
+    assert_eq!(
+        verify(&[(root, 0, 0, 2, &[]), (none, 0, 4, 5, &[1])]),
+        "level 0 segment 4-5[1] does not start at 3"
+    );
+    assert_eq!(
+        verify(&[(root, 0, 0, 2, &[]), (none, 0, 3, 4, &[3])]),
+        "level 0 segment 3-4[3] has parent 3"
+    );
+    assert_eq!(
+        verify(&[(none, 0, 0, 2, &[])]),
+        "flat segment 0-2[] has a wrong root flag"
+    );
+
+    // High-level segments.
+    let mut dag = build_segments(ASCII_DAG1, "L", 3).dag;
+    dag.verify().unwrap();
+    let id = Group::NON_MASTER.min_id();
+    dag.insert(SegmentFlags::empty(), 1, id, id, &[]).unwrap();
+    assert_eq!(
+        dag.verify().unwrap_err().to_string(),
+        "level 1 segment N0-N0[] does not match level 0 segments"
+    );
+
+    // Heads without names.
+    let result = build_segments(ASCII_DAG1, "L", 3);
+    let named_dag = NamedDag {
+        dag: result.dag,
+        map: IdMap::open(dir.path().join("idmap")).unwrap(),
+    };
+    assert_eq!(
+        named_dag.verify().unwrap_err().to_string(),
+        "head 11 does not have a name"
+    );
+}
+
+#[test]
+fn test_named_dag_algorithms() {
+    let result = build_segments(ASCII_DAG1, "L", 3);
+    let _dir = result.dir;
+    let named_dag = NamedDag {
+        dag: result.dag,
+        map: result.id_map,
+    };
+    let names = |s: &str| -> Vec<VertexName> {
+        s.split_whitespace()
+            .map(|s| VertexName::copy_from(s.as_bytes()))
+            .collect()
+    };
+    let format = |names: Vec<VertexName>| -> String {
+        names
+            .into_iter()
+            .map(|name| String::from_utf8(name.as_ref().to_vec()).unwrap())
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    assert_eq!(format(named_dag.all().unwrap()), "L K J I H G F E D C B A");
+    assert_eq!(
+        format(named_dag.ancestors(&names("E")).unwrap()),
+        "E D C B A"
+    );
+    assert_eq!(
+        format(named_dag.descendants(&names("I")).unwrap()),
+        "L K J I"
+    );
+    assert_eq!(format(named_dag.parents(&names("E K")).unwrap()), "J H D B");
+    assert_eq!(format(named_dag.children(&names("G")).unwrap()), "I H");
+    assert_eq!(format(named_dag.heads(&names("A B C")).unwrap()), "C B");
+    assert_eq!(format(named_dag.roots(&names("E F G")).unwrap()), "E");
+    assert_eq!(format(named_dag.gca_all(&names("J H")).unwrap()), "G");
+    assert_eq!(
+        format(named_dag.only(&names("K"), &names("H")).unwrap()),
+        "K J I"
+    );
+    assert_eq!(
+        format(named_dag.range(&names("G"), &names("K")).unwrap()),
+        "K J I H G"
+    );
+    assert_eq!(
+        format(named_dag.parent_names(&names("E")[0]).unwrap()),
+        "B D"
+    );
+    let name = named_dag.first_ancestor_nth(&names("K")[0], 2).unwrap();
+    assert_eq!(format(vec![name]), "G");
+    let (a, l) = (&names("A")[0], &names("L")[0]);
+    assert!(named_dag.is_ancestor(a, l).unwrap());
+    assert!(!named_dag.is_ancestor(l, a).unwrap());
+    assert_eq!(named_dag.gca_one(&names("C B")).unwrap(), None);
+
+    // Unknown names are errors.
+    assert!(named_dag.ancestors(&names("X")).is_err());
+}
+
+#[test]
+fn test_visibility() {
+    let ascii = r#"
+    C   f g
+    |   |/
+    B   e
+    |   |
+    A   d"#;
+    let result = build_segments(ascii, "C f", 2);
+    let _dir = result.dir;
+    let mut named_dag = NamedDag {
+        dag: result.dag,
+        map: result.id_map,
+    };
+    let name = |s: &str| VertexName::copy_from(s.as_bytes());
+    let id = |dag: &NamedDag, s: &str| dag.map.find_id_by_name(s.as_bytes()).unwrap().unwrap();
+
+    // "g" is not in the dag yet.
+    let mut visibility = Visibility::new(vec![name("e"), name("g")]);
+    assert_eq!(
+        format_set(visibility.visible(&named_dag).unwrap()),
+        "0 1 2 N0 N1"
+    );
+    assert_eq!(format_set(visibility.hidden(&named_dag).unwrap()), "N2");
+    let d = id(&named_dag, "d");
+    assert_eq!(
+        format_set(visibility.descendants(&named_dag, d).unwrap()),
+        "N0 N1"
+    );
+    let f = id(&named_dag, "f");
+    assert_eq!(
+        format_set(visibility.ancestors(&named_dag, f).unwrap()),
+        "N0 N1"
+    );
+
+    // Changing the dag invalidates the cache.
+    let parents = drawdag::parse(ascii);
+    let parents_by_name = parents_by_name(&parents);
+    named_dag
+        .build(&parents_by_name, &[], &[name("g")])
+        .unwrap();
+    assert_eq!(format_set(visibility.hidden(&named_dag).unwrap()), "N2");
+    let g = id(&named_dag, "g");
+    assert!(visibility.visible(&named_dag).unwrap().contains(g));
+
+    visibility.set_heads(vec![name("f")]);
+    assert_eq!(
+        format_set(
+            visibility
+                .filter(&named_dag, SpanSet::from_spans(vec![f, g]))
+                .unwrap()
+        ),
+        format_set(SpanSet::from(f))
+    );
+}
+
+#[test]
+fn test_filtered_dag() {
+    let ascii = r#"
+    C   f g
+    |   |/
+    B   e
+    |   |
+    A   d"#;
+    let result = build_segments(ascii, "C f g", 2);
+    let _dir = result.dir;
+    let named_dag = NamedDag {
+        dag: result.dag,
+        map: result.id_map,
+    };
+    let name = |s: &str| VertexName::copy_from(s.as_bytes());
+    let id = |s: &str| {
+        named_dag
+            .map
+            .find_id_by_name(s.as_bytes())
+            .unwrap()
+            .unwrap()
+    };
+    let (d, e, f, g) = (id("d"), id("e"), id("f"), id("g"));
+
+    // "g" is hidden.
+    let visibility = Visibility::new(vec![name("f")]);
+    let dag = visibility.filtered(&named_dag).unwrap();
+    assert_eq!(format_set(dag.hidden().clone()), format_set(g.into()));
+    assert_eq!(format_set(dag.all().unwrap()), "0 1 2 N0 N1 N2");
+    assert_eq!(format_set(dag.descendants(d).unwrap()), "N0 N1 N2");
+    assert_eq!(format_set(dag.children(e).unwrap()), format_set(f.into()));
+    assert_eq!(
+        format_set(dag.heads(dag.all().unwrap()).unwrap()),
+        format_set(SpanSet::from_spans(vec![id("C"), f]))
+    );
+    assert_eq!(format_set(dag.range(d, (f, g)).unwrap()), "N0 N1 N2");
+    assert_eq!(format_set(dag.ancestors(g).unwrap()), "");
+    assert_eq!(dag.gca_one((f, g)).unwrap(), Some(f));
+    assert!(dag.is_ancestor(e, f).unwrap());
+    assert!(!dag.is_ancestor(e, g).unwrap());
+
+    // The unfiltered dag still has "g".
+    assert!(dag.dag().is_ancestor(e, g).unwrap());
+}
+
+#[test]
+fn test_phases() {
+    let ascii = r#"
+    C   f g
+    |   |/
+    B   e
+    |   |
+    A   d"#;
+    let result = build_segments(ascii, "C f g", 2);
+    let dir = result.dir;
+    let named_dag = NamedDag {
+        dag: result.dag,
+        map: result.id_map,
+    };
+    let name = |s: &str| VertexName::copy_from(s.as_bytes());
+    let path = dir.path().join("phaseroots");
+
+    let mut phases = Phases::open(&path).unwrap();
+    assert_eq!(format_set(phases.draft(&named_dag).unwrap()), "");
+
+    phases
+        .add_draft_roots(&named_dag, vec![name("e"), name("x")])
+        .unwrap();
+    assert_eq!(format_set(phases.draft(&named_dag).unwrap()), "N1 N2 N3");
+    assert_eq!(format_set(phases.public(&named_dag).unwrap()), "0 1 2 N0");
+
+    // Pushing "f" makes it and its ancestors public. "g" stays draft.
+    phases.make_public(&named_dag, vec![name("f")]).unwrap();
+    assert_eq!(format_set(phases.draft(&named_dag).unwrap()), "N3");
+    assert_eq!(phases.draft_roots(), &[name("x"), name("g")][..]);
+
+    phases.flush().unwrap();
+    let phases = Phases::open(&path).unwrap();
+    assert_eq!(phases.draft_roots(), &[name("x"), name("g")][..]);
+    let g = named_dag.map.find_id_by_name(b"g").unwrap().unwrap();
+    assert!(phases.is_draft(&named_dag, g).unwrap());
+}
+
+#[test]
+fn test_make_public() {
+    let ascii = r#"
+    C   f g
+    |   |/
+    B   e
+    |   |
+    A   d"#;
+    let result = build_segments(ascii, "C f g", 2);
+    let dir = result.dir;
+    let mut named_dag = NamedDag {
+        dag: result.dag,
+        map: result.id_map,
+    };
+    let name = |s: &str| VertexName::copy_from(s.as_bytes());
+    let parents = drawdag::parse(ascii);
+    let parents_by_name = parents_by_name(&parents);
+
+    let mut phases = Phases::open(dir.path().join("phaseroots")).unwrap();
+    phases.add_draft_roots(&named_dag, vec![name("d")]).unwrap();
+    assert_eq!(format_set(phases.draft(&named_dag).unwrap()), "N0..=N3");
+
+    // "d", "e", "f" become public and move to the master group.
+    named_dag
+        .make_public(parents_by_name, &mut phases, &[name("f")])
+        .unwrap();
+    assert_eq!(format_set(named_dag.dag.all().unwrap()), "0..=5 N0");
+    assert_eq!(format_set(phases.draft(&named_dag).unwrap()), "N0");
+    assert_eq!(phases.draft_roots(), &[name("g")][..]);
+}
+
+#[test]
+fn test_read_only() {
+    let ascii = r#"
+    A-B-C-D"#;
+    let result = build_segments(ascii, "B", 2);
+    let dir = result.dir;
+    let mut writer = NamedDag {
+        dag: result.dag,
+        map: result.id_map,
+    };
+    let name = |s: &str| VertexName::copy_from(s.as_bytes());
+    let parents = drawdag::parse(ascii);
+    let parents_by_name = parents_by_name(&parents);
+
+    let mut reader = NamedDag::open_read_only(dir.path().join("n")).unwrap();
+    assert_eq!(reader.all().unwrap(), vec![name("B"), name("A")]);
+
+    // New data is visible after reload.
+    writer.build(parents_by_name, &[name("D")], &[]).unwrap();
+    assert_eq!(reader.all().unwrap().len(), 2);
+    reader.reload().unwrap();
+    assert_eq!(
+        reader.heads(&reader.all().unwrap()).unwrap(),
+        vec![name("D")]
+    );
+    reader.verify().unwrap();
+
+    // Writes to disk are errors.
+    assert!(reader.remove_non_master().is_err());
+    assert!(reader.dag.flush().is_err());
+    assert!(reader.map.flush().is_err());
+    assert!(NamedDag::open_read_only(dir.path().join("missing")).is_err());
+}
+
+#[test]
+fn test_set_cache() {
+    let ascii = r#"
+    C   f
+    |   |
+    B   e
+    |   |
+    A   d"#;
+    let result = build_segments(ascii, "C f", 2);
+    let mut dag = result.dag;
+    let path = result.dir.path().join("setcache");
+
+    let mut cache = SetCache::open(&path).unwrap();
+    assert!(cache.get(&dag, b"heads").unwrap().is_none());
+    let mut calculated = 0;
+    let mut heads = |cache: &mut SetCache, dag: &Dag| {
+        cache
+            .get_or_insert_with(dag, b"heads", || {
+                calculated += 1;
+                dag.heads(dag.all()?)
+            })
+            .unwrap()
+    };
+    assert_eq!(format_set(heads(&mut cache, &dag)), "2 N2");
+    assert_eq!(format_set(heads(&mut cache, &dag)), "2 N2");
+    cache.flush().unwrap();
+    assert_eq!(
+        format_set(cache.get(&dag, b"heads").unwrap().unwrap()),
+        "2 N2"
+    );
+    assert!(cache.get(&dag, b"roots").unwrap().is_none());
+
+    // Cached results are shared with other processes.
+    let mut cache = SetCache::open(&path).unwrap();
+    assert_eq!(format_set(heads(&mut cache, &dag)), "2 N2");
+
+    // Results are recalculated after the dag changes.
+    dag.remove_non_master().unwrap();
+    assert_eq!(format_set(heads(&mut cache, &dag)), "2");
+    drop(heads);
+    assert_eq!(calculated, 2);
+}
+
 #[test]
 fn test_children() {
     let result = build_segments(ASCII_DAG1, "L", 3);
@@ -554,6 +1216,138 @@ fn test_children() {
     assert_eq!(children(vec![1..=1, 4..=4, 6..=6, 10..=10]), "4 5 7 8 11");
 }
 
+#[test]
+fn test_iter_topo() {
+    let built = build_segments(ASCII_DAG2, "W", 3);
+    let dag = built.dag;
+    let set = SpanSet::from_spans(vec![Id(2)..=Id(6), Id(12)..=Id(16)]);
+    let ids: Vec<Id> = dag.iter_topo(set.clone()).collect();
+    assert_eq!(ids.len() as u64, set.count());
+
+    // Parents come before their children.
+    for (i, &id) in ids.iter().enumerate() {
+        for parent in dag.parent_ids(id).unwrap() {
+            if let Some(j) = ids.iter().position(|&p| p == parent) {
+                assert!(j < i, "{} should be before {}", parent, id);
+            }
+        }
+    }
+    assert_eq!(ids[0], Id(2));
+}
+
+#[test]
+fn test_iter_topo_rev() {
+    let built = build_segments(ASCII_DAG2, "W", 3);
+    let dag = built.dag;
+    let set = SpanSet::from_spans(vec![Id(2)..=Id(6), Id(12)..=Id(16)]);
+    let ids: Vec<Id> = dag.iter_topo_rev(set.clone()).collect();
+    let mut rev_ids: Vec<Id> = dag.iter_topo(set).collect();
+    rev_ids.reverse();
+    assert_eq!(ids, rev_ids);
+
+    // Bounded by heads. Ancestors are the same as ancestors(heads).
+    for &heads in [&[16][..], &[6, 12], &[22], &[0]].iter() {
+        let heads = SpanSet::from_spans(heads.iter().map(|&i| Id(i)));
+        let ids: Vec<Id> = dag
+            .iter_ancestors_rev(heads.clone())
+            .collect::<Result<_>>()
+            .unwrap();
+        let expected: Vec<Id> = dag.iter_topo_rev(dag.ancestors(heads).unwrap()).collect();
+        assert_eq!(ids, expected);
+    }
+
+    // Iteration is lazy.
+    let ids: Vec<Id> = dag
+        .iter_ancestors_rev(Id(22))
+        .take(3)
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(ids, [Id(22), Id(21), Id(20)]);
+}
+
+#[test]
+fn test_is_ancestor_batch() {
+    let built = build_segments(ASCII_DAG2, "W", 3);
+    let dag = built.dag;
+    let all: Vec<Id> = dag.all().unwrap().iter().collect();
+    let pairs: Vec<(Id, Id)> = all
+        .iter()
+        .flat_map(|&a| all.iter().map(move |&d| (a, d)))
+        .collect();
+    let expected: Vec<bool> = pairs
+        .iter()
+        .map(|&(a, d)| dag.is_ancestor(a, d).unwrap())
+        .collect();
+    assert_eq!(dag.is_ancestor_batch(&pairs).unwrap(), expected);
+    assert!(dag.is_ancestor_batch(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn test_find_path() {
+    let built = build_segments(ASCII_DAG1, "L", 3);
+    let dag = built.dag;
+    let find_path = |a, d| -> String { format!("{:?}", dag.find_path(Id(a), Id(d)).unwrap()) };
+    assert_eq!(find_path(0, 0), "Some([0])");
+    assert_eq!(find_path(0, 11), "Some([0, 1, 4, 5, 6, 7, 10, 11])");
+    assert_eq!(find_path(2, 10), "Some([2, 3, 4, 5, 6, 7, 10])");
+    assert_eq!(find_path(6, 10), "Some([6, 7, 10])");
+    assert_eq!(find_path(8, 11), "Some([8, 9, 10, 11])");
+    assert_eq!(find_path(8, 7), "None");
+    assert_eq!(find_path(11, 0), "None");
+}
+
+#[test]
+fn test_counts() {
+    let built = build_segments(ASCII_DAG2, "W", 3);
+    let dag = built.dag;
+    assert_eq!(dag.count_ancestors(Id(22)).unwrap(), 23);
+    assert_eq!(dag.count_range(Id(10), Id(5)).unwrap(), 0);
+    for a in 0..=22 {
+        for b in 0..=22 {
+            let (a, b) = (Id(a), Id(b));
+            let only_a = dag.only(a, b).unwrap().count();
+            let only_b = dag.only(b, a).unwrap().count();
+            assert_eq!(dag.count_only(a, b).unwrap(), only_a);
+            assert_eq!(dag.count_ahead_behind(a, b).unwrap(), (only_a, only_b));
+            assert_eq!(
+                dag.count_range(a, b).unwrap(),
+                dag.range(a, b).unwrap().count()
+            );
+        }
+    }
+}
+
+#[test]
+fn test_generation() {
+    let built = build_segments(ASCII_DAG1, "L", 3);
+    let dag = built.dag;
+    assert_eq!(dag.generation(Id(2)).unwrap(), 0); // C
+    assert_eq!(dag.generation(Id(4)).unwrap(), 2); // E
+    assert_eq!(dag.generation(Id(10)).unwrap(), 7); // K
+    assert_eq!(dag.generation(Id(11)).unwrap(), 8); // L
+
+    // Compare with generation numbers calculated from parents.
+    let built = build_segments(ASCII_DAG2, "W", 3);
+    let dag = built.dag;
+    let mut expected = Vec::new();
+    for id in dag.iter_topo(dag.all().unwrap()) {
+        let generation = dag
+            .parent_ids(id)
+            .unwrap()
+            .into_iter()
+            .map(|p| expected[p.0 as usize] + 1)
+            .max()
+            .unwrap_or(0);
+        expected.push(generation);
+    }
+    let set = SpanSet::from_spans(vec![Id(3)..=Id(5), Id(9)..=Id(9), Id(14)..=Id(22)]);
+    let generations = dag.generations(set.clone()).unwrap();
+    assert_eq!(generations.len() as u64, set.count());
+    for (id, generation) in generations {
+        assert_eq!(generation, expected[id.0 as usize], "generation of {}", id);
+    }
+}
+
 #[test]
 fn test_heads() {
     let ascii = r#"
@@ -587,6 +1381,78 @@ Lv2: R0-2[] R3-6[] R7-9[] 10-10[8] 11-11[7]"#
     assert_eq!(heads(vec![1..=2, 4..=6, 7..=7, 11..=11, 9..=9]), "2 6 9 11");
 }
 
+#[test]
+fn test_missing_ancestors() {
+    let ascii = r#"
+    C G   K L
+    | |\  |/
+    B E F I J
+    | |/  |/
+    A D   H"#;
+
+    let result = build_segments(ascii, "C G K L J", 2);
+    let dag = result.dag;
+    let missing = |heads, common| -> Vec<u64> {
+        dag.missing_ancestors(SpanSet::from_spans(heads), SpanSet::from_spans(common))
+            .unwrap()
+            .map(|id| id.0)
+            .collect()
+    };
+
+    assert_eq!(missing(vec![], vec![]), Vec::<u64>::new());
+    assert_eq!(missing(vec![2..=2], vec![]), vec![0, 1, 2]);
+    assert_eq!(missing(vec![6..=6], vec![3..=3]), vec![4, 5, 6]);
+    assert_eq!(
+        missing(vec![6..=6, 10..=10], vec![4..=4, 11..=11]),
+        vec![5, 6, 8, 10]
+    );
+    assert_eq!(missing(vec![9..=9], vec![9..=10]), Vec::<u64>::new());
+    assert_eq!(missing(vec![2..=2], vec![6..=6]), vec![0, 1, 2]);
+}
+
+#[test]
+fn test_only() {
+    let result = build_segments(ASCII_DAG2, "W", 3);
+    let dag = result.dag;
+    let only = |set, excluded| -> String {
+        format_set(
+            dag.only(SpanSet::from_spans(set), SpanSet::from_spans(excluded))
+                .unwrap(),
+        )
+    };
+
+    assert_eq!(only(vec![], vec![]), "");
+    assert_eq!(only(vec![22..=22], vec![]), "0..=22");
+    assert_eq!(only(vec![22..=22], vec![22..=22]), "");
+    assert_eq!(only(vec![15..=15], vec![10..=10]), "11..=15");
+    assert_eq!(
+        only(vec![10..=10, 20..=20], vec![5..=5]),
+        "2 3 6..=10 18 19 20"
+    );
+
+    // Compare with the difference of ancestors.
+    for a in 0..=22 {
+        for b in 0..=22 {
+            for c in (a..=22).step_by(3) {
+                let set = SpanSet::from_spans(vec![a..=a, c..=c]);
+                let excluded = SpanSet::from(Id(b));
+                let ancestors = dag.ancestors(set.clone()).unwrap();
+                let excluded_ancestors = dag.ancestors(excluded.clone()).unwrap();
+                let (only, common) = dag.only_both(set.clone(), excluded.clone()).unwrap();
+                assert_eq!(
+                    only.as_spans(),
+                    ancestors.difference(&excluded_ancestors).as_spans()
+                );
+                assert_eq!(
+                    common.as_spans(),
+                    ancestors.intersection(&excluded_ancestors).as_spans()
+                );
+                assert_eq!(dag.only(set, excluded).unwrap().as_spans(), only.as_spans());
+            }
+        }
+    }
+}
+
 #[test]
 fn test_roots() {
     let ascii = r#"
@@ -743,86 +1609,111 @@ Lv4: R0-9[]"#
     }
 }
 
-// Test utilities
-
-fn format_set(set: SpanSet) -> String {
-    format!("{:?}", set)
+#[test]
+fn test_range_meta() {
+    let built = build_segments(ASCII_DAG2, "W", 3);
+    let dag = built.dag;
+    let all: Vec<Id> = dag.all().unwrap().iter().collect();
+    let check = |roots: SpanSet, heads: SpanSet| {
+        let meta = dag.range_meta(roots.clone(), heads.clone()).unwrap();
+        let range = dag.range(roots, heads).unwrap();
+        assert_eq!(format_set(meta.range), format_set(range.clone()));
+        assert_eq!(
+            format_set(meta.roots),
+            format_set(dag.roots(range.clone()).unwrap())
+        );
+        assert_eq!(
+            format_set(meta.heads),
+            format_set(dag.heads(range).unwrap())
+        );
+    };
+    for &root in all.iter() {
+        for &head in all.iter() {
+            check(root.into(), head.into());
+        }
+    }
+    check((Id(0), Id(6)).into(), (Id(13), Id(16)).into());
 }
 
-impl IdMap {
-    /// Replace names in an ASCII DAG using the ids assigned.
-    fn replace(&self, text: &str) -> String {
-        let mut result = text.to_string();
-        for &group in Group::ALL.iter() {
-            for id in group.min_id().to(self.next_free_id(group).unwrap()) {
-                if let Ok(Some(name)) = self.find_name_by_id(id) {
-                    let name = String::from_utf8(name.to_vec()).unwrap();
-                    let id_str = format!("{:01$}", id, name.len());
-                    if name.len() + 1 == id_str.len() {
-                        // Try to replace while maintaining width
-                        result = result
-                            .replace(&format!("{}-", name), &id_str)
-                            .replace(&format!("{} ", name), &id_str);
+#[test]
+fn test_ancestors_descendants_within() {
+    let result = build_segments(ASCII_DAG1, "L", 3);
+    let dag = result.dag;
+
+    // Calculate the same thing by following parent_ids one generation at a time.
+    let all: Vec<Id> = dag.all().unwrap().iter().collect();
+    let expand = |set: &SpanSet, depth: u64, forward: bool| -> SpanSet {
+        let mut result = set.clone();
+        for _ in 0..depth {
+            let mut next = result.clone();
+            for &id in &all {
+                for parent in dag.parent_ids(id).unwrap() {
+                    let (from, to) = if forward { (id, parent) } else { (parent, id) };
+                    if result.contains(from) {
+                        next = next.union(&to.into());
                     }
-                    result = result.replace(&format!("{}", name), &id_str);
                 }
             }
+            result = next;
         }
         result
-    }
-}
+    };
 
-impl Dag {
-    /// Dump segments in a compact string form.
-    fn dump(&self) -> String {
-        format!("{:?}", self)
+    for (a, b) in (0..=11).flat_map(|a| (a..=11).map(move |b| (a, b))) {
+        let set = SpanSet::from_spans(vec![a..=a, b..=b]);
+        for depth in 0..=4 {
+            assert_eq!(
+                dag.ancestors_within(set.clone(), depth).unwrap().as_spans(),
+                expand(&set, depth, true).as_spans(),
+            );
+            assert_eq!(
+                dag.descendants_within(set.clone(), depth)
+                    .unwrap()
+                    .as_spans(),
+                expand(&set, depth, false).as_spans(),
+            );
+        }
     }
-}
 
-/// Result of `build_segments`.
-struct BuildSegmentResult {
-    ascii: Vec<String>,
-    id_map: IdMap,
-    dag: Dag,
-    dir: tempfile::TempDir,
+    let ancestors_within = |id, depth| format_set(dag.ancestors_within(Id(id), depth).unwrap());
+    let descendants_within = |id, depth| format_set(dag.descendants_within(Id(id), depth).unwrap());
+    assert_eq!(ancestors_within(10, 0), "10");
+    assert_eq!(ancestors_within(10, 1), "7 9 10");
+    assert_eq!(ancestors_within(10, 3), "5..=10");
+    assert_eq!(ancestors_within(10, 100), "0..=10");
+    assert_eq!(descendants_within(6, 2), "6..=10");
+    assert_eq!(descendants_within(6, 100), "6..=11");
 }
 
-/// Take an ASCII DAG, assign segments from given heads.
-/// Return the ASCII DAG and segments strings, together with the IdMap and Dag.
-fn build_segments(text: &str, heads: &str, segment_size: usize) -> BuildSegmentResult {
-    let dir = tempdir().unwrap();
-    let mut named_dag = NamedDag::open(dir.path().join("n")).unwrap();
-    named_dag.dag.set_new_segment_size(segment_size);
-
-    let parents = drawdag::parse(&text);
-    let parents_by_name = |name: VertexName| -> Result<Vec<VertexName>> {
-        Ok(parents[&String::from_utf8(name.as_ref().to_vec()).unwrap()]
-            .iter()
-            .map(|p| VertexName::copy_from(p.as_bytes()))
-            .collect())
-    };
+#[test]
+fn test_heads_ancestors() {
+    let result = build_segments(ASCII_DAG2, "W", 3);
+    let dag = result.dag;
+    let heads_ancestors =
+        |spans| -> String { format_set(dag.heads_ancestors(SpanSet::from_spans(spans)).unwrap()) };
+
+    assert_eq!(heads_ancestors(vec![]), "");
+    assert_eq!(heads_ancestors(vec![0..=22]), "22");
+    assert_eq!(heads_ancestors(vec![0..=10, 19..=19]), "10 19");
+    assert_eq!(heads_ancestors(vec![4..=4, 8..=9, 13..=15]), "15");
+    assert_eq!(heads_ancestors(vec![4..=4, 10..=10, 19..=19]), "10 19");
+
+    // Compare with heads(ancestors(set)) for sets of up to 3 ids.
+    for a in 0..=22 {
+        for b in a..=22 {
+            for c in b..=22 {
+                let set = SpanSet::from_spans(vec![a..=a, b..=b, c..=c]);
+                assert_eq!(
+                    dag.heads_ancestors(set.clone()).unwrap().as_spans(),
+                    dag.heads(dag.ancestors(set).unwrap()).unwrap().as_spans(),
+                );
+            }
+        }
+    }
+}
 
-    let ascii = heads
-        .split(' ')
-        .map(|head| {
-            let binary_head = VertexName::copy_from(head.as_bytes());
-
-            // Assign to non-master if the name starts with a lowercase character.
-            let (master, other) = if head.chars().nth(0).unwrap().is_lowercase() {
-                (vec![], vec![binary_head])
-            } else {
-                (vec![binary_head], vec![])
-            };
-
-            named_dag.build(&parents_by_name, &master, &other).unwrap();
-            format!("{}\n{}", named_dag.map.replace(text), named_dag.dag.dump())
-        })
-        .collect();
+// Test utilities
 
-    BuildSegmentResult {
-        ascii,
-        id_map: named_dag.map,
-        dag: named_dag.dag,
-        dir,
-    }
+fn format_set(set: SpanSet) -> String {
+    format!("{:?}", set)
 }
@@ -0,0 +1,107 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Utilities to build `Dag`s from ASCII graphs in tests.
+
+use crate::id::{Group, VertexName};
+use crate::idmap::IdMap;
+use crate::segment::Dag;
+use crate::NamedDag;
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use tempfile::tempdir;
+
+impl IdMap {
+    /// Replace names in an ASCII DAG using the ids assigned.
+    pub fn replace(&self, text: &str) -> String {
+        let mut result = text.to_string();
+        for &group in Group::ALL.iter() {
+            for id in group.min_id().to(self.next_free_id(group).unwrap()) {
+                if let Ok(Some(name)) = self.find_name_by_id(id) {
+                    let name = String::from_utf8(name.to_vec()).unwrap();
+                    let id_str = format!("{:01$}", id, name.len());
+                    if name.len() + 1 == id_str.len() {
+                        // Try to replace while maintaining width
+                        result = result
+                            .replace(&format!("{}-", name), &id_str)
+                            .replace(&format!("{} ", name), &id_str);
+                    }
+                    result = result.replace(&name, &id_str);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl Dag {
+    /// Dump segments in a compact string form.
+    pub fn dump(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+/// Turn the parents parsed by `drawdag::parse` into a function that looks up the parent names
+/// of a vertex, as used by `NamedDag::build`.
+pub fn parents_by_name(
+    parents: &BTreeMap<String, BTreeSet<String>>,
+) -> impl Fn(VertexName) -> Result<Vec<VertexName>> + '_ {
+    move |name: VertexName| -> Result<Vec<VertexName>> {
+        Ok(parents[&String::from_utf8(name.as_ref().to_vec()).unwrap()]
+            .iter()
+            .map(|p| VertexName::copy_from(p.as_bytes()))
+            .collect())
+    }
+}
+
+/// Result of `build_segments`.
+pub struct BuildSegmentResult {
+    /// The ASCII DAG with names replaced by ids, and the segments, after each head.
+    pub ascii: Vec<String>,
+    pub id_map: IdMap,
+    pub dag: Dag,
+    /// The directory holding `id_map` and `dag`, removed on drop.
+    pub dir: tempfile::TempDir,
+}
+
+/// Take an ASCII DAG, assign segments from given heads.
+/// Return the ASCII DAG and segments strings, together with the IdMap and Dag.
+///
+/// `heads` are separated by spaces and added one by one. Heads starting with a lowercase
+/// character are assigned to the non-master group.
+pub fn build_segments(text: &str, heads: &str, segment_size: usize) -> BuildSegmentResult {
+    let dir = tempdir().unwrap();
+    let mut named_dag = NamedDag::open(dir.path().join("n")).unwrap();
+    named_dag.dag.set_new_segment_size(segment_size);
+
+    let parents = drawdag::parse(text);
+    let parents_by_name = parents_by_name(&parents);
+
+    let ascii = heads
+        .split(' ')
+        .map(|head| {
+            let binary_head = VertexName::copy_from(head.as_bytes());
+
+            // Assign to non-master if the name starts with a lowercase character.
+            let (master, other) = if head.chars().next().unwrap().is_lowercase() {
+                (vec![], vec![binary_head])
+            } else {
+                (vec![binary_head], vec![])
+            };
+
+            named_dag.build(&parents_by_name, &master, &other).unwrap();
+            format!("{}\n{}", named_dag.map.replace(text), named_dag.dag.dump())
+        })
+        .collect();
+
+    BuildSegmentResult {
+        ascii,
+        id_map: named_dag.map,
+        dag: named_dag.dag,
+        dir,
+    }
+}
@@ -0,0 +1,101 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! # visibility
+//!
+//! Hide commits that are not reachable from the visible heads.
+
+use crate::filtereddag::FilteredDag;
+use crate::id::VertexName;
+use crate::nameddag::NamedDag;
+use crate::spanset::SpanSet;
+use anyhow::Result;
+use std::sync::Mutex;
+
+/// Tracks which commits are visible, based on a list of visible heads.
+///
+/// A commit is visible if it is in the master group, or if it is an ancestor
+/// of a visible head. Obsolete commits stay in the [`NamedDag`] after they
+/// are removed from the visible heads, and queries going through
+/// [`Visibility`] filter them out.
+///
+/// The visible set is cached. The cache is invalidated when the heads change,
+/// or when the content of the [`NamedDag`] changes.
+pub struct Visibility {
+    heads: Vec<VertexName>,
+    // (Dag::invalidation_key, visible set)
+    cache: Mutex<Option<(u64, SpanSet)>>,
+}
+
+impl Visibility {
+    pub fn new(heads: impl IntoIterator<Item = VertexName>) -> Self {
+        Self {
+            heads: heads.into_iter().collect(),
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// The visible heads.
+    pub fn heads(&self) -> &[VertexName] {
+        &self.heads
+    }
+
+    /// Replace the visible heads.
+    pub fn set_heads(&mut self, heads: impl IntoIterator<Item = VertexName>) {
+        self.heads = heads.into_iter().collect();
+        *self.cache.get_mut().unwrap() = None;
+    }
+
+    /// Calculate the visible ids in `dag`.
+    ///
+    /// Visible heads that are not in `dag` are ignored.
+    pub fn visible(&self, dag: &NamedDag) -> Result<SpanSet> {
+        let key = dag.dag.invalidation_key()?;
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((cached_key, visible)) = cache.as_ref() {
+            if *cached_key == key {
+                return Ok(visible.clone());
+            }
+        }
+
+        let mut heads = Vec::with_capacity(self.heads.len());
+        for name in &self.heads {
+            if let Some(id) = dag.map.find_id_by_name(name.as_ref())? {
+                heads.push(id);
+            }
+        }
+        let ancestors = dag.dag.ancestors(SpanSet::from_spans(heads))?;
+        let visible = dag.dag.master_group()?.union(&ancestors);
+        *cache = Some((key, visible.clone()));
+        Ok(visible)
+    }
+
+    /// Calculate the hidden ids in `dag`.
+    pub fn hidden(&self, dag: &NamedDag) -> Result<SpanSet> {
+        Ok(dag.dag.all()?.difference(&self.visible(dag)?))
+    }
+
+    /// A view of `dag` without hidden ids.
+    pub fn filtered<'a>(&self, dag: &'a NamedDag) -> Result<FilteredDag<'a>> {
+        Ok(FilteredDag::new(&dag.dag, self.hidden(dag)?))
+    }
+
+    /// Remove hidden ids from `set`.
+    pub fn filter(&self, dag: &NamedDag, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        Ok(set.into().intersection(&self.visible(dag)?))
+    }
+
+    /// Calculate the visible ancestors of `set`.
+    pub fn ancestors(&self, dag: &NamedDag, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        self.filter(dag, dag.dag.ancestors(set)?)
+    }
+
+    /// Calculate the visible descendants of `set`.
+    pub fn descendants(&self, dag: &NamedDag, set: impl Into<SpanSet>) -> Result<SpanSet> {
+        self.filter(dag, dag.dag.descendants(set)?)
+    }
+}
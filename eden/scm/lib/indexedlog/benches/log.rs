@@ -5,7 +5,7 @@
  * GNU General Public License version 2.
  */
 
-use indexedlog::log::{IndexDef, IndexOutput, Log};
+use indexedlog::log::{IndexDef, IndexOutput, Log, OpenOptions};
 use minibench::{bench, elapsed, measure, Measure};
 use rand_chacha::{
     rand_core::{RngCore, SeedableRng},
@@ -212,6 +212,35 @@ fn main() {
         })
     });
 
+    bench("log open (index lag N)", || {
+        let dir = tempdir().unwrap();
+        let buf = gen_buf(N * 20);
+        let mut log = log_with_index(dir.path(), u64::MAX);
+        for i in 0..N {
+            log.append(&buf[20 * i..20 * (i + 1)]).unwrap();
+        }
+        log.sync().unwrap();
+        elapsed(|| {
+            log_with_index(dir.path(), u64::MAX);
+        })
+    });
+
+    bench("log open (index lag N, lazy index)", || {
+        let dir = tempdir().unwrap();
+        let buf = gen_buf(N * 20);
+        let mut log = log_with_index(dir.path(), u64::MAX);
+        for i in 0..N {
+            log.append(&buf[20 * i..20 * (i + 1)]).unwrap();
+        }
+        log.sync().unwrap();
+        let open_opts = OpenOptions::new()
+            .index("i", |_data| vec![IndexOutput::Reference(0..20)])
+            .lazy_index(true);
+        elapsed(|| {
+            open_opts.open(dir.path()).unwrap();
+        })
+    });
+
     bench("log iteration (memory)", || {
         let dir = tempdir().unwrap();
         let mut log = Log::open(dir.path(), vec![]).unwrap();
@@ -41,6 +41,7 @@ use crate::repair::OpenOptionsRepair;
 use crate::utils::{self, atomic_write, mmap_empty, mmap_len, xxhash, xxhash32};
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use memmap::Mmap;
+use once_cell::sync::OnceCell;
 use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::fmt::{self, Debug, Formatter};
@@ -50,7 +51,8 @@ use std::ops::{Range, RangeBounds};
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
-use tracing::debug_span;
+use std::time::Instant;
+use tracing::{debug_span, field};
 use vlqencoding::{VLQDecode, VLQDecodeAt, VLQEncode};
 
 // Constants about file names
@@ -89,7 +91,9 @@ pub struct Log {
     disk_buf: Arc<Mmap>,
     pub(crate) mem_buf: Pin<Box<Vec<u8>>>,
     meta: LogMetadata,
-    indexes: Vec<Index>,
+    // Indexes are loaded on first use if `OpenOptions::lazy_index` is set.
+    // Otherwise they are loaded by `open`.
+    indexes: OnceCell<Vec<Index>>,
     // Whether the index and the log is out-of-sync. In which case, index-based reads (lookups)
     // should return errors because it can no longer be trusted.
     // This could be improved to be per index. For now, it's a single state for simplicity. It's
@@ -235,6 +239,7 @@ pub struct OpenOptions {
     pub(crate) flush_filter: Option<FlushFilterFunc>,
     fsync: bool,
     auto_sync_threshold: Option<u64>,
+    lazy_index: bool,
 }
 
 pub(crate) type FlushFilterFunc =
@@ -337,6 +342,9 @@ impl Log {
     pub fn append<T: AsRef<[u8]>>(&mut self, data: T) -> crate::Result<()> {
         let result: crate::Result<_> = (|| {
             let data = data.as_ref();
+            // In-memory entries are indexed as they are appended. Make sure
+            // lazy indexes are loaded before there are in-memory entries.
+            self.indexes()?;
 
             let checksum_type = if self.open_options.checksum_type == ChecksumType::Auto {
                 // xxhash64 is slower for smaller data. A quick benchmark on x64 platform shows:
@@ -429,8 +437,10 @@ impl Log {
     pub fn clear_dirty(&mut self) -> crate::Result<()> {
         let result: crate::Result<_> = (|| {
             self.maybe_return_index_error()?;
-            for index in self.indexes.iter_mut() {
-                index.clear_dirty();
+            if let Some(indexes) = self.indexes.get_mut() {
+                for index in indexes.iter_mut() {
+                    index.clear_dirty();
+                }
             }
             self.mem_buf.clear();
             self.update_indexes_for_on_disk_entries()?;
@@ -461,11 +471,15 @@ impl Log {
         self.maybe_return_index_error()?;
 
         // Prepare cloned versions of things.
-        let mut indexes = self
-            .indexes
-            .iter()
-            .map(|i| i.try_clone_internal(copy_dirty))
-            .collect::<Result<Vec<Index>, _>>()?;
+        // Indexes that are not loaded yet will be loaded lazily by the clone.
+        let mut indexes = OnceCell::new();
+        if let Some(loaded) = self.indexes.get() {
+            let cloned = loaded
+                .iter()
+                .map(|i| i.try_clone_internal(copy_dirty))
+                .collect::<Result<Vec<Index>, _>>()?;
+            let _ = indexes.set(cloned);
+        }
         let disk_buf = self.disk_buf.clone();
         let mem_buf = if copy_dirty {
             self.mem_buf.clone()
@@ -480,8 +494,10 @@ impl Log {
                     disk_len: self.meta.primary_len,
                     mem_buf,
                 });
-                for index in indexes.iter_mut() {
-                    index.key_buf = index_key_buf.clone();
+                if let Some(indexes) = indexes.get_mut() {
+                    for index in indexes.iter_mut() {
+                        index.key_buf = index_key_buf.clone();
+                    }
                 }
             }
             mem_buf
@@ -563,7 +579,7 @@ impl Log {
                         // already present in the indexes is valid).
                         *self = self.open_options.clone().open_internal(
                             self.dir.as_ref().unwrap(),
-                            if truncated { None } else { Some(&self.indexes) },
+                            if truncated { None } else { self.indexes.get() },
                             None,
                         )?;
                     }
@@ -712,7 +728,7 @@ impl Log {
                 &meta,
                 &self.open_options.index_defs,
                 &self.mem_buf,
-                if changed {
+                if changed || self.indexes.get().is_none() {
                     // Existing indexes cannot be reused.
                     None
                 } else {
@@ -724,10 +740,13 @@ impl Log {
                     // "always-up-to-date", and the on-disk log does not have anything new.
                     // Update "meta" so "update_indexes_for_on_disk_entries" below won't
                     // re-index entries.
-                    Self::set_index_log_len(self.indexes.iter_mut(), meta.primary_len);
-                    Some(&self.indexes)
+                    let indexes = self.indexes.get_mut().unwrap();
+                    Self::set_index_log_len(indexes.iter_mut(), meta.primary_len);
+                    Some(indexes)
                 },
                 self.open_options.fsync,
+                // The indexes are needed below to flush them.
+                false,
             )?;
 
             self.disk_buf = disk_buf;
@@ -737,7 +756,7 @@ impl Log {
             // Step 4: Update the indexes. Optionally flush them.
             self.update_indexes_for_on_disk_entries()?;
             for i in indexes_to_flush {
-                let new_length = self.indexes[i].flush();
+                let new_length = self.indexes_mut()?[i].flush();
                 let new_length = self.maybe_set_index_error(new_length.map_err(Into::into))?;
                 let name = self.open_options.index_defs[i].name.to_string();
                 self.meta.indexes.insert(name, new_length);
@@ -799,8 +818,8 @@ impl Log {
                 }
 
                 // Flush all indexes.
-                for i in 0..self.indexes.len() {
-                    let new_length = self.indexes[i].flush();
+                for i in 0..self.indexes()?.len() {
+                    let new_length = self.indexes_mut()?[i].flush();
                     let new_length = self.maybe_set_index_error(new_length.map_err(Into::into))?;
                     let name = self.open_options.index_defs[i].name.to_string();
                     self.meta.indexes.insert(name, new_length);
@@ -829,6 +848,9 @@ impl Log {
     ///
     /// Return message useful for human consumption.
     pub fn rebuild_indexes(self, force: bool) -> crate::Result<String> {
+        // Load lazy indexes so the ones passing the integrity check can be
+        // kept. Indexes that fail to load are rebuilt.
+        let _ = self.indexes();
         let dir = self.dir.clone();
         let result: crate::Result<_> = (|this: Log| {
             if let Some(dir) = this.dir.clone() {
@@ -855,7 +877,7 @@ impl Log {
                 for (i, def) in self.open_options.index_defs.iter().enumerate() {
                     let name = def.name;
 
-                    if let Some(index) = &self.indexes.get(i) {
+                    if let Some(index) = self.indexes.get().and_then(|indexes| indexes.get(i)) {
                         let should_skip = if force {
                             false
                         } else {
@@ -890,7 +912,8 @@ impl Log {
                             // why it's hard to recover from an error state.
                             //
                             // This is also why this function consumes the Log object.
-                            self.indexes[i] = index::OpenOptions::new().create_in_memory()?;
+                            self.indexes.get_mut().unwrap()[i] =
+                                index::OpenOptions::new().create_in_memory()?;
                         }
                     }
 
@@ -958,7 +981,8 @@ impl Log {
     pub fn lookup<K: AsRef<[u8]>>(&self, index_id: usize, key: K) -> crate::Result<LogLookupIter> {
         let result: crate::Result<_> = (|| {
             self.maybe_return_index_error()?;
-            if let Some(index) = self.indexes.get(index_id) {
+            let indexes = self.indexes()?;
+            if let Some(index) = indexes.get(index_id) {
                 assert!(!key.as_ref().is_empty());
                 let link_offset = index.get(&key)?;
                 let inner_iter = link_offset.values(index);
@@ -971,7 +995,7 @@ impl Log {
                 let msg = format!(
                     "invalid index_id {} (len={}, path={:?})",
                     index_id,
-                    self.indexes.len(),
+                    indexes.len(),
                     &self.dir
                 );
                 Err(crate::Error::programming(msg))
@@ -995,7 +1019,7 @@ impl Log {
     ) -> crate::Result<LogRangeIter> {
         let prefix = prefix.as_ref();
         let result: crate::Result<_> = (|| {
-            let index = self.indexes.get(index_id).unwrap();
+            let index = self.indexes()?.get(index_id).unwrap();
             let inner_iter = index.scan_prefix(prefix)?;
             Ok(LogRangeIter {
                 inner_iter,
@@ -1025,7 +1049,7 @@ impl Log {
         let start = range.start_bound();
         let end = range.end_bound();
         let result: crate::Result<_> = (|| {
-            let index = self.indexes.get(index_id).unwrap();
+            let index = self.indexes()?.get(index_id).unwrap();
             let inner_iter = index.range((start, end))?;
             Ok(LogRangeIter {
                 inner_iter,
@@ -1057,7 +1081,7 @@ impl Log {
     ) -> crate::Result<LogRangeIter> {
         let prefix = hex_prefix.as_ref();
         let result: crate::Result<_> = (|| {
-            let index = self.indexes.get(index_id).unwrap();
+            let index = self.indexes()?.get(index_id).unwrap();
             let inner_iter = index.scan_prefix_hex(prefix)?;
             Ok(LogRangeIter {
                 inner_iter,
@@ -1131,7 +1155,8 @@ impl Log {
         offset: u64,
         data_offset: u64,
     ) -> crate::Result<()> {
-        for (index, def) in self.indexes.iter_mut().zip(&self.open_options.index_defs) {
+        let indexes = self.indexes.get_mut().unwrap();
+        for (index, def) in indexes.iter_mut().zip(&self.open_options.index_defs) {
             for index_output in (def.func)(data) {
                 match index_output {
                     IndexOutput::Reference(range) => {
@@ -1166,7 +1191,12 @@ impl Log {
     fn update_indexes_for_on_disk_entries_unchecked(&mut self) -> crate::Result<()> {
         // It's a programming error to call this when mem_buf is not empty.
         assert!(self.mem_buf.is_empty());
-        for (index, def) in self.indexes.iter_mut().zip(&self.open_options.index_defs) {
+        // Lazy indexes are brought up-to-date when they get loaded.
+        let indexes = match self.indexes.get_mut() {
+            Some(indexes) => indexes,
+            None => return Ok(()),
+        };
+        for (index, def) in indexes.iter_mut().zip(&self.open_options.index_defs) {
             Self::update_index_for_on_disk_entry_unchecked(
                 &self.dir,
                 index,
@@ -1267,6 +1297,9 @@ impl Log {
     /// If `reuse_indexes` is not None, they are existing indexes that match `index_defs`
     /// order. This should only be used in `sync` code path when the on-disk `meta` matches
     /// the in-memory `meta`. Otherwise it is not a sound use.
+    ///
+    /// If `lazy` is true and there are no indexes to reuse, indexes are not
+    /// loaded. They will be loaded on first use by [`Log::indexes`].
     fn load_log_and_indexes(
         dir: Option<&Path>,
         meta: &LogMetadata,
@@ -1274,7 +1307,8 @@ impl Log {
         mem_buf: &Pin<Box<Vec<u8>>>,
         reuse_indexes: Option<&Vec<Index>>,
         fsync: bool,
-    ) -> crate::Result<(Arc<Mmap>, Vec<Index>)> {
+        lazy: bool,
+    ) -> crate::Result<(Arc<Mmap>, OnceCell<Vec<Index>>)> {
         let primary_buf = match dir {
             Some(dir) => Arc::new(mmap_len(&dir.join(PRIMARY_FILE), meta.primary_len)?),
             None => Arc::new(mmap_empty().infallible()?),
@@ -1289,6 +1323,7 @@ impl Log {
         });
 
         let indexes = match reuse_indexes {
+            None if lazy => return Ok((primary_buf, OnceCell::new())),
             None => {
                 // No indexes are reused, reload them.
                 let mut indexes = Vec::with_capacity(index_defs.len());
@@ -1325,7 +1360,65 @@ impl Log {
                 new_indexes
             }
         };
-        Ok((primary_buf, indexes))
+        Ok((primary_buf, OnceCell::from(indexes)))
+    }
+
+    /// Get the indexes. Load them if they were opened lazily and have not
+    /// been used yet.
+    fn indexes(&self) -> crate::Result<&Vec<Index>> {
+        self.indexes.get_or_try_init(|| {
+            let span = debug_span!(
+                "Log::load_indexes",
+                count = self.open_options.index_defs.len(),
+                dir = field::Empty,
+                duration_us = field::Empty,
+            );
+            if let Some(dir) = &self.dir {
+                span.record("dir", dir.to_string_lossy().as_ref());
+            }
+            let _guard = span.enter();
+            let start = Instant::now();
+
+            // Lazy indexes are loaded before any in-memory entries are added.
+            // See `append`.
+            assert!(self.mem_buf.is_empty());
+            let mem_buf: &Vec<u8> = &self.mem_buf;
+            let mem_buf: *const Vec<u8> = mem_buf as *const Vec<u8>;
+            let key_buf = Arc::new(ExternalKeyBuffer {
+                disk_buf: self.disk_buf.clone(),
+                disk_len: self.meta.primary_len,
+                mem_buf,
+            });
+
+            let mut indexes = Vec::with_capacity(self.open_options.index_defs.len());
+            for def in self.open_options.index_defs.iter() {
+                let index_len = self.meta.indexes.get(def.name).cloned().unwrap_or(0);
+                let mut index = Self::load_index(
+                    self.dir.as_deref(),
+                    def.name,
+                    index_len,
+                    key_buf.clone(),
+                    self.open_options.fsync,
+                )?;
+                // The on-disk index can be lagging. Catch up like `open` does.
+                Self::update_index_for_on_disk_entry_unchecked(
+                    &self.dir,
+                    &mut index,
+                    def,
+                    &self.disk_buf,
+                    self.meta.primary_len,
+                )?;
+                indexes.push(index);
+            }
+            span.record("duration_us", &(start.elapsed().as_micros() as u64));
+            Ok(indexes)
+        })
+    }
+
+    /// Mutable version of [`Log::indexes`].
+    fn indexes_mut(&mut self) -> crate::Result<&mut Vec<Index>> {
+        self.indexes()?;
+        Ok(self.indexes.get_mut().unwrap())
     }
 
     /// Load a single index.
@@ -1528,11 +1621,12 @@ impl Log {
 impl Log {
     /// Get the specified index, with error handling.
     fn get_index(&self, index_id: usize) -> crate::Result<&Index> {
-        self.indexes.get(index_id).ok_or_else(|| {
+        let indexes = self.indexes()?;
+        indexes.get(index_id).ok_or_else(|| {
             let msg = format!(
                 "index_id {} is out of bound (len={}, dir={:?})",
                 index_id,
-                self.indexes.len(),
+                indexes.len(),
                 &self.dir
             );
             crate::Error::programming(msg)
@@ -1545,7 +1639,7 @@ impl Log {
             let msg = format!(
                 "index_id {} is out of bound (len={}, dir={:?})",
                 index_id,
-                self.indexes.len(),
+                self.open_options.index_defs.len(),
                 &self.dir
             );
             crate::Error::programming(msg)
@@ -1637,6 +1731,7 @@ impl OpenOptions {
             flush_filter: None,
             fsync: false,
             auto_sync_threshold: None,
+            lazy_index: false,
         }
     }
 
@@ -1694,6 +1789,23 @@ impl OpenOptions {
         self
     }
 
+    /// Sets whether indexes are loaded lazily.
+    ///
+    /// If set to `true`, [`OpenOptions::open`] only reads the metadata and
+    /// maps the primary log. Indexes are loaded the first time they are
+    /// used, for example, by [`Log::lookup`] or [`Log::append`]. This makes
+    /// `open` cheaper for callers that might not need the indexes.
+    ///
+    /// Index errors (ex. corrupted checksum) are reported when the indexes
+    /// are loaded, instead of by `open`.
+    ///
+    /// The time spent is recorded in the `duration_us` field of the
+    /// `Log::open` and `Log::load_indexes` tracing spans.
+    pub fn lazy_index(mut self, lazy: bool) -> Self {
+        self.lazy_index = lazy;
+        self
+    }
+
     /// Sets the flush filter function.
     ///
     /// The function will be called at [`Log::sync`] time, if there are
@@ -1730,10 +1842,18 @@ impl OpenOptions {
     /// transaction.
     pub fn open(&self, dir: impl AsRef<Path>) -> crate::Result<Log> {
         let dir = dir.as_ref();
-        let span = debug_span!("Log::open", dir = &dir.to_string_lossy().as_ref());
+        let span = debug_span!(
+            "Log::open",
+            dir = &dir.to_string_lossy().as_ref(),
+            duration_us = field::Empty
+        );
         let _guard = span.enter();
-        self.open_internal(dir, None, None)
-            .context(|| format!("in log::OpenOptions::open({:?})", dir))
+        let start = Instant::now();
+        let log = self
+            .open_internal(dir, None, None)
+            .context(|| format!("in log::OpenOptions::open({:?})", dir))?;
+        span.record("duration_us", &(start.elapsed().as_micros() as u64));
+        Ok(log)
     }
 
     /// Construct an empty in-memory [`Log`] without side-effects on the
@@ -1753,6 +1873,7 @@ impl OpenOptions {
                 &mem_buf,
                 None,
                 self.fsync,
+                false,
             )?;
 
             Ok(Log {
@@ -1810,6 +1931,7 @@ impl OpenOptions {
             &mem_buf,
             reuse_indexes,
             self.fsync,
+            self.lazy_index,
         )?;
         let mut log = Log {
             dir: Some(dir.to_path_buf()),
@@ -1934,9 +2056,10 @@ impl OpenOptions {
             //
             // Try to open it with indexes so we might reuse them. If that
             // fails, retry with all indexes disabled.
-            let mut log = self
+            let opts = self.clone().lazy_index(false);
+            let mut log = opts
                 .open_with_lock(dir, &lock)
-                .or_else(|_| self.clone().index_defs(Vec::new()).open(dir))
+                .or_else(|_| opts.clone().index_defs(Vec::new()).open(dir))
                 .context("cannot open log for repair")?;
 
             let mut iter = log.iter();
@@ -2369,6 +2492,7 @@ impl fmt::Debug for OpenOptions {
         write!(f, "create: {}, ", self.create)?;
         write!(f, "checksum_type: {:?}, ", self.checksum_type)?;
         write!(f, "auto_sync_threshold: {:?}, ", self.auto_sync_threshold)?;
+        write!(f, "lazy_index: {}, ", self.lazy_index)?;
         let flush_filter_desc = match self.flush_filter {
             Some(ref _buf) => "Some(_)",
             None => "None",
@@ -2672,6 +2796,77 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_lazy_index() {
+        let dir = tempdir().unwrap();
+        let open_opts = OpenOptions::new()
+            .create(true)
+            .index_defs(get_index_defs(20))
+            .lazy_index(true);
+
+        // Indexes are not loaded by open.
+        let mut log = open_opts.open(dir.path()).unwrap();
+        assert!(log.indexes.get().is_none());
+        let entries: [&[u8]; 4] = [b"1", b"2345", b"78", b"3456"];
+        for bytes in entries.iter() {
+            log.append(bytes).unwrap();
+        }
+        assert!(log.indexes.get().is_some());
+        log.sync().unwrap();
+
+        // A clone made before the indexes are loaded can load them on its own.
+        let log = open_opts.open(dir.path()).unwrap();
+        let cloned = log.try_clone().unwrap();
+        assert!(log.indexes.get().is_none());
+        assert_eq!(
+            log.lookup(0, b"34").unwrap().into_vec().unwrap(),
+            [b"3456", b"2345"]
+        );
+        assert!(log.indexes.get().is_some());
+        assert!(cloned.indexes.get().is_none());
+        assert_eq!(
+            cloned.lookup(1, b"345").unwrap().into_vec().unwrap(),
+            [b"3456", b"2345"]
+        );
+
+        // Reloading keeps the indexes lazy.
+        let mut log = open_opts.open(dir.path()).unwrap();
+        let mut other = open_opts.open(dir.path()).unwrap();
+        other.append(b"5678").unwrap();
+        other.sync().unwrap();
+        log.sync().unwrap();
+        assert!(log.indexes.get().is_none());
+        assert_eq!(
+            log.lookup(0, b"56").unwrap().into_vec().unwrap(),
+            [b"5678", b"3456"]
+        );
+    }
+
+    // This test rewrites mmaped files which is unsupoorted by Windows.
+    #[cfg(not(windows))]
+    #[test]
+    fn test_lazy_index_corrupted() {
+        let dir = tempdir().unwrap();
+        let open_opts = OpenOptions::new()
+            .create(true)
+            .index_defs(get_index_defs(0))
+            .lazy_index(true);
+        let mut log = open_opts.open(dir.path()).unwrap();
+        log.append(b"123").unwrap();
+        log.sync().unwrap();
+
+        let size = dir.path().join("index-x").metadata().unwrap().len();
+        let mut index_file = File::create(dir.path().join("index-x")).unwrap();
+        index_file.write_all(&vec![0; size as usize]).unwrap();
+
+        // Eager open fails. Lazy open fails at the first index use.
+        assert!(Log::open(dir.path(), get_index_defs(0)).is_err());
+        let mut log = open_opts.open(dir.path()).unwrap();
+        assert_eq!(log.iter().count(), 1);
+        assert!(log.lookup(0, b"12").is_err());
+        assert!(log.append(b"234").is_err());
+    }
+
     #[test]
     fn test_index_reorder() {
         let dir = tempdir().unwrap();
@@ -112,6 +112,14 @@ impl OpenOptions {
         self
     }
 
+    /// Sets whether indexes of each [`Log`] are loaded on first use.
+    ///
+    /// See [`log::OpenOptions::lazy_index`] for details.
+    pub fn lazy_index(mut self, lazy: bool) -> Self {
+        self.log_open_options = self.log_open_options.lazy_index(lazy);
+        self
+    }
+
     /// Sets the flush filter function.
     ///
     /// The function will be called at [`RotateLog::sync`] time, if there are
@@ -0,0 +1,181 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::{ensure, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{executor::block_on, future::try_join_all};
+
+use pathmatcher::Matcher;
+use types::{HgId, Key, RepoPath, RepoPathBuf};
+
+use crate::{store::Entry, LevelWalk, TreeManifest, TreeStore};
+
+/// The asynchronous counterpart of `TreeStore`, for stores that fetch tree nodes over the
+/// network. Requesting many nodes concurrently overlaps their latency instead of blocking on
+/// every directory.
+#[async_trait]
+pub trait AsyncTreeStore: Send + Sync {
+    async fn get(&self, path: &RepoPath, hgid: HgId) -> Result<Bytes>;
+
+    async fn insert(&self, path: &RepoPath, hgid: HgId, data: Bytes) -> Result<()>;
+
+    /// Retrieve the data of multiple tree nodes, in the same order as `keys`. The default
+    /// implementation issues all the `get` requests concurrently.
+    async fn get_batch(&self, keys: &[Key]) -> Result<Vec<Bytes>> {
+        try_join_all(keys.iter().map(|key| self.get(&key.path, key.hgid))).await
+    }
+}
+
+/// Adapts an `AsyncTreeStore` to the `TreeStore` interface by blocking on every request, so
+/// that it can back a `TreeManifest`. Loading the tree with `TreeManifest::prefetch_async`
+/// first avoids blocking on each directory separately.
+pub struct BlockingTreeStore<S> {
+    store: S,
+}
+
+impl<S: AsyncTreeStore> BlockingTreeStore<S> {
+    pub fn new(store: S) -> Self {
+        BlockingTreeStore { store }
+    }
+}
+
+impl<S: AsyncTreeStore> TreeStore for BlockingTreeStore<S> {
+    fn get(&self, path: &RepoPath, hgid: HgId) -> Result<Bytes> {
+        block_on(self.store.get(path, hgid))
+    }
+
+    fn insert(&self, path: &RepoPath, hgid: HgId, data: Bytes) -> Result<()> {
+        block_on(self.store.insert(path, hgid, data))
+    }
+
+    fn get_batch(&self, keys: &[Key]) -> Result<Vec<Bytes>> {
+        block_on(self.store.get_batch(keys))
+    }
+}
+
+impl TreeManifest {
+    /// Asynchronous version of `prefetch`. Loads the durable directories of the tree that
+    /// match `matcher`, up to `depth` levels below the root, from `store`. The directories of
+    /// each level are requested together with `AsyncTreeStore::get_batch`.
+    ///
+    /// Once loaded, the directories are available to the synchronous methods of the tree
+    /// without going to its `TreeStore`.
+    pub async fn prefetch_async(
+        &self,
+        store: &dyn AsyncTreeStore,
+        matcher: &(dyn Matcher + Sync),
+        depth: Option<usize>,
+    ) -> Result<()> {
+        let mut walk = LevelWalk::new(RepoPathBuf::new(), &self.root, matcher, depth);
+        while let Some(pending) = walk.pending() {
+            if !pending.is_empty() {
+                let keys: Vec<Key> = pending
+                    .iter()
+                    .map(|(path, entry)| Key::new((*path).to_owned(), entry.hgid))
+                    .collect();
+                let fetched = store.get_batch(&keys).await?;
+                ensure!(
+                    fetched.len() == keys.len(),
+                    "store returned {} tree entries for {} keys",
+                    fetched.len(),
+                    keys.len()
+                );
+                for ((path, entry), data) in pending.into_iter().zip(fetched) {
+                    entry.links.get_or_init(|| {
+                        entry.load_links(&self.store, Entry::from_bytes(data), path)
+                    });
+                    entry.touch(&self.store);
+                }
+            }
+            walk.advance(|_, entry| {
+                Ok(entry
+                    .links
+                    .get()
+                    .expect("durable entries of the level were loaded")
+                    .as_ref()
+                    .map_err(|e| e.clone())?)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use manifest::Manifest;
+    use pathmatcher::{AlwaysMatcher, TreeMatcher};
+    use types::testutil::*;
+
+    use crate::testutil::*;
+
+    struct AsyncTestStore(Arc<TestStore>);
+
+    #[async_trait]
+    impl AsyncTreeStore for AsyncTestStore {
+        async fn get(&self, path: &RepoPath, hgid: HgId) -> Result<Bytes> {
+            self.0.get(path, hgid)
+        }
+
+        async fn insert(&self, path: &RepoPath, hgid: HgId, data: Bytes) -> Result<()> {
+            self.0.insert(path, hgid, data)
+        }
+    }
+
+    fn make_durable_tree(store: &Arc<TestStore>) -> HgId {
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b3"), make_meta("30"))
+            .unwrap();
+        tree.flush().unwrap()
+    }
+
+    #[test]
+    fn test_prefetch_async() {
+        let remote = Arc::new(TestStore::new());
+        let hgid = make_durable_tree(&remote);
+        let async_store = AsyncTestStore(remote);
+
+        // The tree's own store is empty: everything has to come from `prefetch_async`.
+        let tree = TreeManifest::durable(Arc::new(TestStore::new()), hgid);
+        let matcher = TreeMatcher::from_rules(["a1/**"].iter()).unwrap();
+        block_on(tree.prefetch_async(&async_store, &matcher, None)).unwrap();
+        let files = tree
+            .files(&matcher)
+            .map(|file| file.unwrap().path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![repo_path_buf("a1/b1/c1"), repo_path_buf("a1/b2")]
+        );
+        assert!(tree.get_file(repo_path("a2/b3")).is_err());
+
+        let tree = TreeManifest::durable(Arc::new(TestStore::new()), hgid);
+        block_on(tree.prefetch_async(&async_store, &AlwaysMatcher::new(), Some(0))).unwrap();
+        assert!(tree.get(repo_path("a1")).unwrap().is_some());
+        assert!(tree.get_file(repo_path("a1/b2")).is_err());
+    }
+
+    #[test]
+    fn test_blocking_tree_store() {
+        let remote = Arc::new(TestStore::new());
+        let hgid = make_durable_tree(&remote);
+        let store = BlockingTreeStore::new(AsyncTestStore(remote));
+        let tree = TreeManifest::durable(Arc::new(store), hgid);
+        assert_eq!(
+            tree.get_file(repo_path("a2/b3")).unwrap(),
+            Some(make_meta("30"))
+        );
+    }
+}
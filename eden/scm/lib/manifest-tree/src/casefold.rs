@@ -0,0 +1,86 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use unicode_normalization::UnicodeNormalization;
+
+/// Rules deciding which path components name the same file on a case-insensitive filesystem.
+///
+/// Two components are equivalent when they have the same folded form. Filesystems differ in
+/// the rules they apply: APFS and HFS+ normalize names to NFC (or NFD) before comparing, and
+/// NTFS only folds case. `UnicodeCaseFold` covers the former; closures implement this trait
+/// for other rules, for example `|name: &str| SimpleCaseFold.fold(&nfc(name))`.
+pub trait CaseFold: Send + Sync {
+    fn fold(&self, component: &str) -> String;
+}
+
+/// Folds case by lowercasing each character. This matches the simple case folding of most
+/// characters, without normalizing the string.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SimpleCaseFold;
+
+impl CaseFold for SimpleCaseFold {
+    fn fold(&self, component: &str) -> String {
+        component.chars().flat_map(char::to_lowercase).collect()
+    }
+}
+
+/// Folds case with the full case folding of Unicode, which maps `ß` to `ss` and `ﬁ` to `fi`,
+/// and normalizes the name before and after folding so that canonically equivalent names fold
+/// the same.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnicodeCaseFold {
+    /// Composes names to NFC, as APFS and HFS+ do before comparing them.
+    Nfc,
+    /// Composes names to NFKC, which also folds compatibility characters such as the
+    /// fullwidth `Ａ` to `a`.
+    Nfkc,
+}
+
+impl CaseFold for UnicodeCaseFold {
+    fn fold(&self, component: &str) -> String {
+        match self {
+            UnicodeCaseFold::Nfc => {
+                let folded = caseless::default_case_fold_str(&component.nfc().collect::<String>());
+                folded.nfc().collect()
+            }
+            UnicodeCaseFold::Nfkc => {
+                let folded = caseless::default_case_fold_str(&component.nfkc().collect::<String>());
+                folded.nfkc().collect()
+            }
+        }
+    }
+}
+
+impl<F> CaseFold for F
+where
+    F: Fn(&str) -> String + Send + Sync,
+{
+    fn fold(&self, component: &str) -> String {
+        self(component)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unicode_case_fold() {
+        let fold = UnicodeCaseFold::Nfc;
+        assert_eq!(fold.fold("Stra\u{df}e"), "strasse");
+        assert_eq!(fold.fold("STRASSE"), fold.fold("stra\u{df}e"));
+        assert_eq!(fold.fold("CAFE\u{301}"), "caf\u{e9}");
+        assert_eq!(fold.fold("\u{fb01}le"), "file");
+        assert_eq!(fold.fold("FILE"), fold.fold("\u{fb01}le"));
+        assert_eq!(fold.fold("\u{ff21}"), "\u{ff41}");
+
+        let fold = UnicodeCaseFold::Nfkc;
+        assert_eq!(fold.fold("\u{fb01}le"), "file");
+        assert_eq!(fold.fold("Stra\u{df}e"), "strasse");
+        assert_eq!(fold.fold("\u{ff21}"), "a");
+    }
+}
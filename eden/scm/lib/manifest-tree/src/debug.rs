@@ -0,0 +1,126 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::BTreeMap;
+
+use serde_json::{json, Map, Value};
+use types::PathComponentBuf;
+
+use crate::{
+    link::{Durable, Ephemeral, Leaf},
+    Link, TreeManifest,
+};
+
+impl TreeManifest {
+    /// Renders the directories and files held in memory, for debugging. Each node has a
+    /// `type`: `file`, `ephemeral` for directories modified in memory, or `durable` for
+    /// directories from the store. Durable directories only have `children` once they are
+    /// loaded, and an `error` if loading them failed; unloaded subtrees are left out.
+    pub fn to_debug_json(&self) -> Value {
+        json!({
+            "parents": self.parents.iter().map(|hgid| hgid.to_hex()).collect::<Vec<_>>(),
+            "root": link_to_json(&self.root),
+        })
+    }
+}
+
+fn link_to_json(link: &Link) -> Value {
+    match link {
+        Leaf(metadata) => json!({
+            "type": "file",
+            "hgid": metadata.hgid.to_hex(),
+            "file_type": format!("{:?}", metadata.file_type),
+        }),
+        Ephemeral(links) => json!({
+            "type": "ephemeral",
+            "children": children(links),
+        }),
+        Durable(entry) => {
+            let mut node = json!({
+                "type": "durable",
+                "hgid": entry.hgid.to_hex(),
+            });
+            match entry.links.get() {
+                None => {}
+                Some(Ok(links)) => node["children"] = Value::Object(children(links)),
+                Some(Err(error)) => node["error"] = Value::String(error.to_string()),
+            }
+            node
+        }
+    }
+}
+
+fn children(links: &BTreeMap<PathComponentBuf, Link>) -> Map<String, Value> {
+    links
+        .iter()
+        .map(|(component, link)| (component.to_string(), link_to_json(link)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use manifest::Manifest;
+    use types::testutil::*;
+
+    use crate::testutil::{make_meta, TestStore};
+
+    #[test]
+    fn test_to_debug_json() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b2"), make_meta("20"))
+            .unwrap();
+        let root = tree.flush().unwrap();
+        let a1 = tree.get(repo_path("a1")).unwrap().unwrap();
+        let a2 = tree.get(repo_path("a2")).unwrap().unwrap();
+        let hex = |node| match node {
+            manifest::FsNodeMetadata::Directory(Some(hgid)) => hgid.to_hex(),
+            _ => panic!("{:?} is not a durable directory", node),
+        };
+
+        let mut tree = TreeManifest::durable(store, root);
+        tree.insert(repo_path_buf("a3"), make_meta("30")).unwrap();
+        tree.get(repo_path("a1/b1")).unwrap();
+        assert_eq!(
+            tree.to_debug_json(),
+            json!({
+                "parents": [root.to_hex()],
+                "root": {
+                    "type": "ephemeral",
+                    "children": {
+                        "a1": {
+                            "type": "durable",
+                            "hgid": hex(a1),
+                            "children": {
+                                "b1": {
+                                    "type": "file",
+                                    "hgid": hgid("10").to_hex(),
+                                    "file_type": "Regular",
+                                },
+                            },
+                        },
+                        "a2": {
+                            "type": "durable",
+                            "hgid": hex(a2),
+                        },
+                        "a3": {
+                            "type": "file",
+                            "hgid": hgid("30").to_hex(),
+                            "file_type": "Regular",
+                        },
+                    },
+                },
+            })
+        );
+    }
+}
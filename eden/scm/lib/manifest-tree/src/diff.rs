@@ -77,6 +77,12 @@ impl<'a> DiffItem<'a> {
 /// will be prefetched from the store, thereby reducing the total
 /// number of tree fetches required to perform a full-tree diff while
 /// only fetching tree nodes that have actually changed.
+///
+/// Each layer is prefetched as a whole, so the directories of a layer are
+/// fetched before any of their entries is returned. The traversal itself
+/// only advances when more entries are requested, so stopping early, as
+/// `TreeManifest::has_diff` does after the first entry, skips the layers
+/// that have not been reached yet.
 pub struct Diff<'a> {
     output: VecDeque<DiffEntry>,
     current: VecDeque<DiffItem<'a>>,
@@ -386,7 +392,7 @@ mod tests {
 
     use std::sync::Arc;
 
-    use manifest::{DiffType, FileMetadata, FileType, Manifest};
+    use manifest::{DiffType, FileMetadata, Manifest};
     use pathmatcher::{AlwaysMatcher, TreeMatcher};
     use types::testutil::*;
 
@@ -432,17 +438,11 @@ mod tests {
         let expected_entries = vec![
             DiffEntry::new(
                 repo_path_buf("a"),
-                DiffType::LeftOnly(FileMetadata {
-                    hgid: hgid("1"),
-                    file_type: FileType::Regular,
-                }),
+                DiffType::LeftOnly(FileMetadata::regular(hgid("1"))),
             ),
             DiffEntry::new(
                 repo_path_buf("c"),
-                DiffType::LeftOnly(FileMetadata {
-                    hgid: hgid("3"),
-                    file_type: FileType::Regular,
-                }),
+                DiffType::LeftOnly(FileMetadata::regular(hgid("3"))),
             ),
         ];
         assert_eq!(entries, expected_entries);
@@ -482,29 +482,17 @@ mod tests {
         let expected = vec![
             DiffEntry::new(
                 repo_path_buf("b"),
-                DiffType::LeftOnly(FileMetadata {
-                    hgid: hgid("2"),
-                    file_type: FileType::Regular,
-                }),
+                DiffType::LeftOnly(FileMetadata::regular(hgid("2"))),
             ),
             DiffEntry::new(
                 repo_path_buf("d"),
-                DiffType::RightOnly(FileMetadata {
-                    hgid: hgid("5"),
-                    file_type: FileType::Regular,
-                }),
+                DiffType::RightOnly(FileMetadata::regular(hgid("5"))),
             ),
             DiffEntry::new(
                 repo_path_buf("e"),
                 DiffType::Changed(
-                    FileMetadata {
-                        hgid: hgid("4"),
-                        file_type: FileType::Regular,
-                    },
-                    FileMetadata {
-                        hgid: hgid("6"),
-                        file_type: FileType::Regular,
-                    },
+                    FileMetadata::regular(hgid("4")),
+                    FileMetadata::regular(hgid("6")),
                 ),
             ),
         ];
@@ -837,4 +825,87 @@ mod tests {
             ),],
         );
     }
+
+    #[test]
+    fn test_diff_skips_unchanged_durable_subtrees() {
+        let store = Arc::new(TestStore::new());
+        let mut left = TreeManifest::ephemeral(store.clone());
+        for (path, hex) in &[
+            ("a1/b1/c1", "10"),
+            ("a1/b2/c2", "20"),
+            ("a2/b3/c3", "30"),
+            ("a3/b4/c4", "40"),
+        ] {
+            left.insert(repo_path_buf(path), make_meta(hex)).unwrap();
+        }
+        let mut right = left.clone();
+        let left_hgid = left.flush().unwrap();
+        right
+            .insert(repo_path_buf("a1/b2/c2"), make_meta("50"))
+            .unwrap();
+        let right_hgid = right.flush().unwrap();
+
+        let left = TreeManifest::durable(store.clone(), left_hgid);
+        let right = TreeManifest::durable(store.clone(), right_hgid);
+        store.prefetched.lock().clear();
+        assert_eq!(
+            Diff::new(&left, &right, &AlwaysMatcher::new())
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            vec![DiffEntry::new(
+                repo_path_buf("a1/b2/c2"),
+                DiffType::Changed(make_meta("20"), make_meta("50"))
+            )],
+        );
+
+        // Only the directories leading to the modified file were fetched.
+        let fetched = store
+            .fetches()
+            .into_iter()
+            .flatten()
+            .map(|key| key.path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fetched,
+            vec![
+                repo_path_buf("a1"),
+                repo_path_buf("a1"),
+                repo_path_buf("a1/b2"),
+                repo_path_buf("a1/b2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_has_diff() {
+        let store = Arc::new(TestStore::new());
+        let mut left = TreeManifest::ephemeral(store.clone());
+        for (path, hex) in &[("a1/b1/c1", "10"), ("a2/b2/c2", "20"), ("a3", "30")] {
+            left.insert(repo_path_buf(path), make_meta(hex)).unwrap();
+        }
+        let mut right = left.clone();
+        let left_hgid = left.flush().unwrap();
+        right
+            .insert(repo_path_buf("a1/b1/c1"), make_meta("40"))
+            .unwrap();
+        let right_hgid = right.flush().unwrap();
+
+        let left = TreeManifest::durable(store.clone(), left_hgid);
+        let right = TreeManifest::durable(store.clone(), right_hgid);
+        let matcher = TreeMatcher::from_rules(["a2/**"].iter()).unwrap();
+        assert!(!left.has_diff(&right, &matcher).unwrap());
+        let matcher = TreeMatcher::from_rules(["a1/**"].iter()).unwrap();
+        assert!(left.has_diff(&right, &matcher).unwrap());
+        assert_eq!(left.fetch_stats().fetches, 3);
+
+        // The diff stops at the first difference.
+        let mut right = right.clone();
+        right.insert(repo_path_buf("a3"), make_meta("50")).unwrap();
+        let right_hgid = right.flush().unwrap();
+        let left = TreeManifest::durable(store.clone(), left_hgid);
+        let right = TreeManifest::durable(store.clone(), right_hgid);
+        assert!(left.has_diff(&right, &AlwaysMatcher::new()).unwrap());
+        assert_eq!(left.fetch_stats().fetches, 1);
+        assert_eq!(right.fetch_stats().fetches, 1);
+    }
 }
@@ -0,0 +1,142 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Conversion between a tree and the flat manifest format, where the whole manifest is a single
+//! blob with one `<path>\0<hex hgid><flag>\n` line per file, sorted by path.
+
+use std::{str::from_utf8, sync::Arc};
+
+use anyhow::{format_err, Result};
+use bytes::Bytes;
+
+use manifest::{FileMetadata, FileType, Manifest};
+use pathmatcher::AlwaysMatcher;
+use types::{HgId, RepoPath};
+
+use crate::{TreeManifest, TreeStore};
+
+impl TreeManifest {
+    /// Builds an ephemeral tree with the files listed in a flat manifest. Flushing the tree
+    /// writes its directories to `store`.
+    pub fn from_flat(store: Arc<dyn TreeStore + Send + Sync>, flat: &[u8]) -> Result<Self> {
+        let mut tree = TreeManifest::ephemeral(store);
+        for line in flat.split(|&b| b == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            let (path, metadata) = parse_line(line)?;
+            tree.insert(path.to_owned(), metadata)?;
+        }
+        Ok(tree)
+    }
+
+    /// Serializes the files of the tree to the flat manifest format. The result is identical to
+    /// the flat manifest the tree was built from, so both have the same hash.
+    pub fn to_flat(&self) -> Result<Bytes> {
+        let mut lines = Vec::new();
+        for file in self.files(&AlwaysMatcher::new()) {
+            let file = file?;
+            let mut line =
+                Vec::with_capacity(file.path.as_byte_slice().len() + HgId::hex_len() + 3);
+            line.extend_from_slice(file.path.as_byte_slice());
+            line.push(0);
+            line.extend_from_slice(file.meta.hgid.to_hex().as_bytes());
+            match file.meta.file_type {
+                FileType::Regular => (),
+                FileType::Executable => line.push(b'x'),
+                FileType::Symlink => line.push(b'l'),
+            }
+            line.push(b'\n');
+            lines.push(line);
+        }
+        // The tree yields the files of a directory before its siblings, like `a/b` before
+        // `a.txt`, while the flat manifest is sorted by the whole path.
+        lines.sort_unstable();
+        Ok(Bytes::from(lines.concat()))
+    }
+}
+
+fn parse_line(line: &[u8]) -> Result<(&RepoPath, FileMetadata)> {
+    let path_len = line
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| format_err!("did not find path delimiter"))?;
+    let path = RepoPath::from_utf8(&line[..path_len])?;
+    let rest = &line[path_len + 1..];
+    if rest.len() < HgId::hex_len() {
+        return Err(format_err!("hgid of '{}' is shorter than expected", path));
+    }
+    let hgid = HgId::from_str(from_utf8(&rest[..HgId::hex_len()])?)?;
+    let file_type = match &rest[HgId::hex_len()..] {
+        b"" => FileType::Regular,
+        b"x" => FileType::Executable,
+        b"l" => FileType::Symlink,
+        flag => {
+            return Err(format_err!(
+                "invalid flag {:?} for '{}'",
+                String::from_utf8_lossy(flag),
+                path
+            ));
+        }
+    };
+    Ok((path, FileMetadata::new(hgid, file_type)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use types::testutil::*;
+
+    use crate::testutil::TestStore;
+
+    #[test]
+    fn test_flat_roundtrip() {
+        let flat = format!(
+            "a.txt\0{}\na/b\0{}x\na/c/d\0{}l\nz\0{}\n",
+            hgid("1"),
+            hgid("2"),
+            hgid("3"),
+            hgid("4"),
+        );
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::from_flat(store.clone(), flat.as_bytes()).unwrap();
+        assert_eq!(
+            tree.get_file(repo_path("a/b")).unwrap(),
+            Some(FileMetadata::executable(hgid("2")))
+        );
+        assert_eq!(
+            tree.get_file(repo_path("a/c/d")).unwrap(),
+            Some(FileMetadata::symlink(hgid("3")))
+        );
+        assert_eq!(tree.to_flat().unwrap(), flat.as_bytes());
+
+        // The flushed tree serializes to the same flat manifest.
+        let root = tree.flush().unwrap();
+        let tree = TreeManifest::durable(store, root);
+        assert_eq!(tree.to_flat().unwrap(), flat.as_bytes());
+    }
+
+    #[test]
+    fn test_from_flat_errors() {
+        let store = Arc::new(TestStore::new());
+        let parse = |flat: String| {
+            TreeManifest::from_flat(store.clone(), flat.as_bytes())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(parse("a".to_string()), "did not find path delimiter");
+        assert_eq!(
+            parse("a\0abc\n".to_string()),
+            "hgid of 'a' is shorter than expected"
+        );
+        assert_eq!(
+            parse(format!("a\0{}q\n", hgid("1"))),
+            "invalid flag \"q\" for 'a'"
+        );
+    }
+}
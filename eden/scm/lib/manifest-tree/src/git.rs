@@ -0,0 +1,282 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Support for git tree objects.
+//!
+//! A git tree object is a list of `<octal mode> <name>\0<20 bytes sha1>` entries, without
+//! separators. The entries are sorted by name, where the names of subtrees are compared as if
+//! they ended with `/`. Git object ids have the same size as `HgId`, so the tree can use them
+//! as node ids directly.
+
+use std::str::from_utf8;
+
+use anyhow::{bail, format_err, Result};
+use bytes::Bytes;
+
+use manifest::FileType;
+use types::{HgId, Key, PathComponent, RepoPath};
+
+use crate::{
+    hash::{GitTreeHasher, TreeHasher},
+    store::{Element, Entry, Flag},
+    TreeStore,
+};
+
+const MODE_REGULAR: &str = "100644";
+const MODE_EXECUTABLE: &str = "100755";
+const MODE_SYMLINK: &str = "120000";
+const MODE_DIRECTORY: &str = "40000";
+const MODE_GITLINK: &str = "160000";
+
+impl Entry {
+    /// Converts a git tree object to an `Entry`.
+    ///
+    /// Submodules cannot be represented in the tree and are rejected.
+    pub fn from_git_tree(data: &[u8]) -> Result<Entry> {
+        let mut elements = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let space = rest
+                .iter()
+                .position(|&b| b == b' ')
+                .ok_or_else(|| format_err!("did not find git tree mode delimiter"))?;
+            let mode = from_utf8(&rest[..space])?;
+            rest = &rest[space + 1..];
+            let nul = rest
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or_else(|| format_err!("did not find git tree name delimiter"))?;
+            let component = PathComponent::from_utf8(&rest[..nul])?.to_owned();
+            rest = &rest[nul + 1..];
+            if rest.len() < HgId::len() {
+                bail!("git tree id of '{}' is shorter than expected", component);
+            }
+            let hgid = HgId::from_slice(&rest[..HgId::len()])?;
+            rest = &rest[HgId::len()..];
+            let flag = match mode {
+                MODE_REGULAR => Flag::File(FileType::Regular),
+                MODE_EXECUTABLE => Flag::File(FileType::Executable),
+                MODE_SYMLINK => Flag::File(FileType::Symlink),
+                MODE_DIRECTORY => Flag::Directory,
+                MODE_GITLINK => bail!("submodule '{}' is not supported", component),
+                _ => bail!("invalid git tree mode {} for '{}'", mode, component),
+            };
+            elements.push(Element::new(component, hgid, flag));
+        }
+        // Git sorts subtrees as if their names ended with `/`, while `Entry` sorts the elements
+        // by name.
+        elements.sort_unstable_by(|a, b| a.component.cmp(&b.component));
+        Entry::from_elements(elements.into_iter().map(Ok))
+    }
+
+    /// Serializes the `Entry` as a git tree object.
+    pub fn to_git_tree(&self) -> Result<Vec<u8>> {
+        let mut elements = self.elements().collect::<Result<Vec<_>>>()?;
+        elements.sort_unstable_by_key(git_sort_key);
+        let mut buffer = Vec::new();
+        for element in elements {
+            let mode = match element.flag {
+                Flag::File(FileType::Regular) => MODE_REGULAR,
+                Flag::File(FileType::Executable) => MODE_EXECUTABLE,
+                Flag::File(FileType::Symlink) => MODE_SYMLINK,
+                Flag::Directory => MODE_DIRECTORY,
+            };
+            buffer.extend_from_slice(mode.as_bytes());
+            buffer.push(b' ');
+            buffer.extend_from_slice(element.component.as_byte_slice());
+            buffer.push(0);
+            buffer.extend_from_slice(element.hgid.as_ref());
+        }
+        Ok(buffer)
+    }
+}
+
+fn git_sort_key(element: &Element) -> Vec<u8> {
+    let mut key = element.component.as_byte_slice().to_vec();
+    if element.flag == Flag::Directory {
+        key.push(b'/');
+    }
+    key
+}
+
+/// A `TreeStore` over a store of git tree objects. Directories are converted from and to the git
+/// format as they are read and written.
+///
+/// Directories written by `TreeManifest::flush` are identified by their git object ids, see
+/// `GitTreeHasher`.
+pub struct GitTreeStore<S> {
+    store: S,
+}
+
+impl<S: TreeStore> GitTreeStore<S> {
+    pub fn new(store: S) -> Self {
+        GitTreeStore { store }
+    }
+}
+
+impl<S: TreeStore> TreeStore for GitTreeStore<S> {
+    fn get(&self, path: &RepoPath, hgid: HgId) -> Result<Bytes> {
+        let data = self.store.get(path, hgid)?;
+        Ok(Entry::from_git_tree(&data)?.to_bytes())
+    }
+
+    fn insert(&self, path: &RepoPath, hgid: HgId, data: Bytes) -> Result<()> {
+        let data = Entry::from_bytes(data).to_git_tree()?;
+        self.store.insert(path, hgid, Bytes::from(data))
+    }
+
+    fn insert_with_parents(
+        &self,
+        path: &RepoPath,
+        hgid: HgId,
+        data: Bytes,
+        p1: HgId,
+        p2: HgId,
+    ) -> Result<()> {
+        let data = Entry::from_bytes(data).to_git_tree()?;
+        self.store
+            .insert_with_parents(path, hgid, Bytes::from(data), p1, p2)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+
+    fn hasher(&self) -> &dyn TreeHasher {
+        &GitTreeHasher
+    }
+
+    fn prefetch(&self, keys: Vec<Key>) -> Result<()> {
+        self.store.prefetch(keys)
+    }
+
+    fn get_batch(&self, keys: &[Key]) -> Result<Vec<Bytes>> {
+        self.store
+            .get_batch(keys)?
+            .into_iter()
+            .map(|data| Ok(Entry::from_git_tree(&data)?.to_bytes()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use manifest::{FileMetadata, Manifest};
+    use types::testutil::*;
+
+    use crate::{testutil::TestStore, TreeManifest};
+
+    fn git_tree(entries: &[(&str, &str, HgId)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (mode, name, id) in entries {
+            data.extend_from_slice(format!("{} {}\0", mode, name).as_bytes());
+            data.extend_from_slice(id.as_ref());
+        }
+        data
+    }
+
+    #[test]
+    fn test_git_tree_roundtrip() {
+        // `a.txt` sorts before the subtree `a`, which git compares as `a/`.
+        let data = git_tree(&[
+            ("100644", "a.txt", hgid("1")),
+            ("40000", "a", hgid("2")),
+            ("100755", "b", hgid("3")),
+            ("120000", "c", hgid("4")),
+        ]);
+        let entry = Entry::from_git_tree(&data).unwrap();
+        assert_eq!(
+            entry.elements().collect::<Result<Vec<_>>>().unwrap(),
+            vec![
+                Element::new(path_component_buf("a"), hgid("2"), Flag::Directory),
+                Element::new(
+                    path_component_buf("a.txt"),
+                    hgid("1"),
+                    Flag::File(FileType::Regular)
+                ),
+                Element::new(
+                    path_component_buf("b"),
+                    hgid("3"),
+                    Flag::File(FileType::Executable)
+                ),
+                Element::new(
+                    path_component_buf("c"),
+                    hgid("4"),
+                    Flag::File(FileType::Symlink)
+                ),
+            ]
+        );
+        assert_eq!(entry.to_git_tree().unwrap(), data);
+    }
+
+    #[test]
+    fn test_git_tree_errors() {
+        let error = |data: &[u8]| Entry::from_git_tree(data).unwrap_err().to_string();
+        assert_eq!(
+            error(&git_tree(&[("160000", "sub", hgid("1"))])),
+            "submodule 'sub' is not supported"
+        );
+        assert_eq!(
+            error(&git_tree(&[("100600", "a", hgid("1"))])),
+            "invalid git tree mode 100600 for 'a'"
+        );
+        assert_eq!(
+            error(b"100644 a\0short"),
+            "git tree id of 'a' is shorter than expected"
+        );
+        assert_eq!(error(b"100644"), "did not find git tree mode delimiter");
+    }
+
+    #[test]
+    fn test_git_tree_store() {
+        let inner = TestStore::new();
+        let root_id = hgid("10");
+        let dir_id = hgid("20");
+        inner
+            .insert(
+                RepoPath::empty(),
+                root_id,
+                Bytes::from(git_tree(&[
+                    ("100644", "a.txt", hgid("1")),
+                    ("40000", "a", dir_id),
+                ])),
+            )
+            .unwrap();
+        inner
+            .insert(
+                repo_path("a"),
+                dir_id,
+                Bytes::from(git_tree(&[("100755", "b", hgid("2"))])),
+            )
+            .unwrap();
+
+        let store = Arc::new(GitTreeStore::new(inner));
+        let mut tree = TreeManifest::durable(store.clone(), root_id);
+        assert_eq!(
+            tree.get_file(repo_path("a/b")).unwrap(),
+            Some(FileMetadata::executable(hgid("2")))
+        );
+
+        tree.insert(repo_path_buf("a/c"), FileMetadata::regular(hgid("3")))
+            .unwrap();
+        tree.flush().unwrap();
+        let dir_id = match tree.get(repo_path("a")).unwrap() {
+            Some(manifest::FsNodeMetadata::Directory(Some(hgid))) => hgid,
+            other => panic!("unexpected {:?}", other),
+        };
+        // The directory is identified by its git object id, as `git mktree` computes it.
+        assert_eq!(dir_id, hgid("096f67ae04f3ba0d003c8ba59d043d48bd977a30"));
+        assert_eq!(
+            store.store.get(repo_path("a"), dir_id).unwrap(),
+            git_tree(&[("100755", "b", hgid("2")), ("100644", "c", hgid("3"))])
+        );
+    }
+}
@@ -0,0 +1,142 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The schemes computing the ids of the directories written by the tree.
+//!
+//! The same directory has a different id in every backend: Mercurial hashes the serialized
+//! directory with the ids of its parent directories, git hashes its tree object. The store
+//! picks the scheme with `TreeStore::hasher`.
+
+use anyhow::Result;
+use crypto::{digest::Digest, sha1::Sha1};
+
+use types::HgId;
+
+use crate::store::Entry;
+
+/// Computes the id of a directory from its serialized `Entry`.
+///
+/// Schemes that are not built in, like BLAKE3 hashes truncated to the size of an `HgId`, can
+/// be provided by implementing this trait.
+pub trait TreeHasher: Send + Sync {
+    /// Returns the id of the directory serialized as `entry`. `TreeManifest::flush` uses it.
+    fn hash(&self, entry: &Entry) -> Result<HgId>;
+
+    /// Returns the id of the directory serialized as `entry`, whose parent directories are
+    /// `p1` and `p2`, which are null when the directory has fewer parents.
+    /// `TreeManifest::finalize` and `flush_with_parents` use it. The default implementation
+    /// ignores the parents and calls `hash`.
+    fn hash_with_parents(&self, entry: &Entry, _p1: HgId, _p2: HgId) -> Result<HgId> {
+        self.hash(entry)
+    }
+}
+
+/// The Mercurial scheme: the SHA-1 of the sorted parents followed by the serialized directory.
+/// Without parents, the SHA-1 of the serialized directory.
+pub struct HgTreeHasher;
+
+impl TreeHasher for HgTreeHasher {
+    fn hash(&self, entry: &Entry) -> Result<HgId> {
+        Ok(sha1(&[entry.as_ref()]))
+    }
+
+    fn hash_with_parents(&self, entry: &Entry, p1: HgId, p2: HgId) -> Result<HgId> {
+        // Even if parents are sorted two hashes go into hash computation but surprise
+        // the NULL_ID is not a special case in this case and gets sorted.
+        let (p1, p2) = if p1 < p2 { (p1, p2) } else { (p2, p1) };
+        Ok(sha1(&[p1.as_ref(), p2.as_ref(), entry.as_ref()]))
+    }
+}
+
+/// The git scheme: the object id of the directory as a git tree object. Parents are ignored.
+pub struct GitTreeHasher;
+
+impl TreeHasher for GitTreeHasher {
+    fn hash(&self, entry: &Entry) -> Result<HgId> {
+        let tree = entry.to_git_tree()?;
+        let header = format!("tree {}\0", tree.len());
+        Ok(sha1(&[header.as_bytes(), &tree]))
+    }
+}
+
+fn sha1(parts: &[&[u8]]) -> HgId {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.input(part);
+    }
+    let mut buf = [0u8; HgId::len()];
+    hasher.result(&mut buf);
+    (&buf).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use manifest::FileType;
+    use types::testutil::*;
+
+    use crate::store::{Element, Flag};
+
+    #[test]
+    fn test_git_tree_hasher() {
+        let empty = Entry::from_elements(Vec::new()).unwrap();
+        assert_eq!(
+            GitTreeHasher.hash(&empty).unwrap(),
+            hgid("4b825dc642cb6eb9a060e54bf8d69288fbee4904")
+        );
+
+        // As computed by `git mktree`.
+        let entry = Entry::from_elements(vec![
+            Ok(Element::new(
+                path_component_buf("a"),
+                hgid("1"),
+                Flag::Directory,
+            )),
+            Ok(Element::new(
+                path_component_buf("b"),
+                hgid("2"),
+                Flag::File(FileType::Regular),
+            )),
+        ])
+        .unwrap();
+        let id = GitTreeHasher.hash(&entry).unwrap();
+        assert_eq!(
+            GitTreeHasher
+                .hash_with_parents(&entry, hgid("3"), hgid("4"))
+                .unwrap(),
+            id
+        );
+        assert_eq!(id, hgid("639ce67e5de1bafcf6547b23d6698abc09c55c44"));
+    }
+
+    #[test]
+    fn test_hg_tree_hasher() {
+        let entry = Entry::from_elements(vec![Ok(Element::new(
+            path_component_buf("a"),
+            hgid("1"),
+            Flag::File(FileType::Regular),
+        ))])
+        .unwrap();
+        let id = HgTreeHasher.hash(&entry).unwrap();
+        assert_ne!(
+            HgTreeHasher
+                .hash_with_parents(&entry, *HgId::null_id(), *HgId::null_id())
+                .unwrap(),
+            id
+        );
+        // The order of the parents doesn't matter.
+        assert_eq!(
+            HgTreeHasher
+                .hash_with_parents(&entry, hgid("2"), hgid("3"))
+                .unwrap(),
+            HgTreeHasher
+                .hash_with_parents(&entry, hgid("3"), hgid("2"))
+                .unwrap()
+        );
+    }
+}
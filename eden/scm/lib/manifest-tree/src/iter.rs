@@ -5,88 +5,237 @@
  * GNU General Public License version 2.
  */
 
-use std::collections::{btree_map, VecDeque};
+use std::{
+    collections::btree_map,
+    ops::{Bound, RangeBounds},
+};
 
 use anyhow::{Error, Result};
 
 use manifest::FsNodeMetadata;
-use pathmatcher::Matcher;
+use pathmatcher::{DirectoryMatch, Matcher};
 use types::{Key, PathComponentBuf, RepoPath, RepoPathBuf};
 
-use crate::{
-    link::{DurableEntry, Link},
-    store::InnerStore,
-    TreeManifest,
-};
-
-pub struct BfsIter<'a> {
-    queue: VecDeque<(RepoPathBuf, &'a Link)>,
+use crate::{link::Link, store::InnerStore, TreeManifest};
+
+/// A depth-first iterator over the nodes of a tree. Nodes are returned in depth-first order by
+/// path component, a directory always comes before its contents. Durable directories are only
+/// read from the store when the iteration reaches them. When a directory is visited, the durable
+/// subdirectories that it contains are prefetched together.
+///
+/// Directories that the matcher rules out are skipped entirely, they are neither fetched nor
+/// traversed. The matcher is not consulted inside directories that it fully matches.
+///
+/// The iteration can be limited to a range of paths, in which case directories outside of the
+/// range are skipped the same way. The order is the order of `RepoPath`, which compares
+/// paths component by component, so `a/b` comes before `a.txt`.
+pub struct DfsIter<'a> {
+    cursor: DfsCursor<'a>,
     store: &'a InnerStore,
     matcher: &'a dyn Matcher,
+    /// Depth of the directory being traversed that matched `DirectoryMatch::Everything`.
+    everything: Option<usize>,
+    start: Bound<RepoPathBuf>,
+    end: Bound<RepoPathBuf>,
+    max_depth: Option<usize>,
 }
 
-impl<'a> BfsIter<'a> {
+/// Where the node that the cursor is visiting is relative to the range of a `DfsIter`.
+enum InRange {
+    /// The node is in the range.
+    Yes,
+    /// The node is not in the range, but its contents may be.
+    No,
+    /// Neither the node nor its contents are in the range.
+    Skip,
+    /// Neither the node nor the nodes after it are in the range.
+    Stop,
+}
+
+impl<'a> DfsIter<'a> {
     pub fn new(tree: &'a TreeManifest, matcher: &'a dyn Matcher) -> Self {
-        BfsIter {
-            queue: vec![(RepoPathBuf::new(), &tree.root)].into(),
+        DfsIter {
+            cursor: tree.root_cursor(),
             store: &tree.store,
             matcher,
+            everything: None,
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+            max_depth: None,
         }
     }
 
-    fn prefetch(&self, extra: (&RepoPath, &DurableEntry)) -> Result<()> {
-        let mut keys = vec![Key::new(extra.0.to_owned(), extra.1.hgid)];
-        let mut entries = vec![extra];
-        for (path, link) in self.queue.iter() {
-            if let Link::Durable(durable_entry) = link {
-                keys.push(Key::new(path.clone(), durable_entry.hgid));
-                entries.push((path, durable_entry));
+    /// Only returns the nodes up to `max_depth` levels below the root. The directories at
+    /// `max_depth` are returned, but not read.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Only returns the nodes with paths in `range`.
+    pub fn with_range(mut self, range: impl RangeBounds<RepoPathBuf>) -> Self {
+        self.start = clone_bound(range.start_bound());
+        self.end = clone_bound(range.end_bound());
+        self
+    }
+
+    /// Returns the nodes in reverse sorted order, except that a directory still comes before
+    /// its contents.
+    pub fn reverse(mut self) -> Self {
+        self.cursor.reverse = true;
+        self
+    }
+
+    fn in_range(&self) -> InRange {
+        let path = self.cursor.path();
+        let before_start = match &self.start {
+            Bound::Included(start) => path < start.as_repo_path(),
+            Bound::Excluded(start) => path <= start.as_repo_path(),
+            Bound::Unbounded => false,
+        };
+        let after_end = match &self.end {
+            Bound::Included(end) => path > end.as_repo_path(),
+            Bound::Excluded(end) => path >= end.as_repo_path(),
+            Bound::Unbounded => false,
+        };
+        // The contents of a directory come right after it, so they are before the start too,
+        // unless the directory is the start or one of its parents.
+        let contents_before_start = before_start
+            && match &self.start {
+                Bound::Included(start) | Bound::Excluded(start) => {
+                    start.as_repo_path() != path && !start.parents().any(|parent| parent == path)
+                }
+                Bound::Unbounded => false,
+            };
+        // The contents of a directory come after it, so they are after the end too.
+        match (self.cursor.reverse, contents_before_start, after_end) {
+            (false, true, _) => InRange::Skip,
+            (false, _, true) => InRange::Stop,
+            (true, true, _) => InRange::Stop,
+            (true, _, true) => InRange::Skip,
+            _ if before_start => InRange::No,
+            _ => InRange::Yes,
+        }
+    }
+
+    /// Returns whether the link that the cursor is visiting should be returned. Updates the
+    /// state of the `DirectoryMatch::Everything` fast path.
+    fn visit(&mut self) -> bool {
+        let path = self.cursor.path();
+        let depth = self.cursor.stack.len();
+        if let Some(everything) = self.everything {
+            if depth > everything {
+                return true;
             }
+            self.everything = None;
         }
-        self.store.prefetch(keys)?;
-        for (path, entry) in entries {
-            entry.materialize_links(self.store, path)?;
+        if path.is_empty() {
+            return true;
+        }
+        match self.cursor.link() {
+            Link::Leaf(_) => self.matcher.matches_file(path),
+            Link::Durable(_) | Link::Ephemeral(_) => match self.matcher.matches_directory(path) {
+                DirectoryMatch::Nothing => false,
+                DirectoryMatch::Everything => {
+                    self.everything = Some(depth);
+                    true
+                }
+                DirectoryMatch::ShouldTraverse => true,
+            },
+        }
+    }
+
+    fn prefetch_children(&self) -> Result<()> {
+        let children = match self.cursor.link() {
+            Link::Leaf(_) => return Ok(()),
+            Link::Ephemeral(children) => children,
+            // The cursor will return failures on the next step.
+            Link::Durable(entry) => match entry.materialize_links(self.store, self.cursor.path()) {
+                Ok(children) => children,
+                Err(_) => return Ok(()),
+            },
+        };
+        let mut keys = vec![];
+        for (component, link) in children.iter() {
+            if let Link::Durable(entry) = link {
+                if entry.links.get().is_some() {
+                    continue;
+                }
+                let mut path = self.cursor.path().to_owned();
+                path.push(component.as_path_component());
+                if self.everything.is_some() || link.matches(&self.matcher, &path) {
+                    keys.push(Key::new(path, entry.hgid));
+                }
+            }
+        }
+        if !keys.is_empty() {
+            self.store.prefetch(keys)?;
         }
         Ok(())
     }
 }
 
-impl<'a> Iterator for BfsIter<'a> {
+impl<'a> Iterator for DfsIter<'a> {
     type Item = Result<(RepoPathBuf, FsNodeMetadata)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let (path, children, hgid) = match self.queue.pop_front() {
-            None => return None,
-            Some((path, link)) => match link {
-                Link::Leaf(file_metadata) => {
-                    return Some(Ok((path, FsNodeMetadata::File(*file_metadata))))
-                }
-                Link::Ephemeral(children) => (path, children, None),
-                Link::Durable(entry) => loop {
-                    match entry.get_links() {
-                        None => match self.prefetch((&path, &entry)) {
-                            Ok(_) => (),
-                            Err(e) => return Some(Err(e)),
-                        },
-                        Some(children_result) => match children_result {
-                            Ok(children) => break (path, children, Some(entry.hgid)),
-                            Err(e) => return Some(Err(e)),
-                        },
+        loop {
+            match self.cursor.step() {
+                Step::Success => {
+                    let in_range = match self.in_range() {
+                        InRange::Yes => true,
+                        InRange::No => false,
+                        InRange::Skip => {
+                            self.cursor.skip_subtree();
+                            continue;
+                        }
+                        InRange::Stop => {
+                            self.cursor.state = State::Done;
+                            return None;
+                        }
                     };
-                },
-            },
-        };
-        for (component, link) in children.iter() {
-            let mut child_path = path.clone();
-            child_path.push(component.as_ref());
-            if link.matches(&self.matcher, &child_path) {
-                self.queue.push_back((child_path, &link));
+                    if !self.visit() {
+                        self.cursor.skip_subtree();
+                        continue;
+                    }
+                    if self.max_depth == Some(self.cursor.stack.len()) {
+                        self.cursor.skip_subtree();
+                    } else if let Err(e) = self.prefetch_children() {
+                        return Some(Err(e));
+                    }
+                    if !in_range {
+                        continue;
+                    }
+                    let path = self.cursor.path().to_owned();
+                    return Some(Ok((path, self.cursor.link().to_fs_node())));
+                }
+                Step::End => return None,
+                Step::Err(e) => return Some(Err(e)),
             }
         }
-        Some(Ok((path, FsNodeMetadata::Directory(hgid))))
     }
 }
 
+fn clone_bound(bound: Bound<&RepoPathBuf>) -> Bound<RepoPathBuf> {
+    match bound {
+        Bound::Included(path) => Bound::Included(path.clone()),
+        Bound::Excluded(path) => Bound::Excluded(path.clone()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// What `TreeManifest::walk` does after visiting a node.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WalkControl {
+    /// Visit the contents of the node, when it is a directory, then the nodes after it.
+    Continue,
+    /// Skip the contents of the node and continue with the nodes after it. Skipped durable
+    /// directories are not read from the store.
+    Skip,
+    /// End the walk.
+    Stop,
+}
+
 /// The cursor is a utility for iterating over [`Link`]s. This structure is inteded to be an
 /// implementation detail of other iterating structures. That is why it has some rought edges
 /// and a particular use pattern.
@@ -99,6 +248,8 @@ pub struct DfsCursor<'a> {
     path: RepoPathBuf,
     link: &'a Link,
     stack: Vec<btree_map::Iter<'a, PathComponentBuf, Link>>,
+    /// Visits the contents of directories in reverse order.
+    reverse: bool,
 }
 
 /// The return type of the [`Cursor::step()`] function.
@@ -130,6 +281,7 @@ impl<'a> DfsCursor<'a> {
             path,
             link,
             stack: Vec::new(),
+            reverse: false,
         }
     }
 
@@ -233,7 +385,12 @@ impl<'a> DfsCursor<'a> {
                         }
                         Some(last) => {
                             // Take an iterator from the stack and see if they have elements.
-                            match last.next() {
+                            let next = if self.reverse {
+                                last.next_back()
+                            } else {
+                                last.next()
+                            };
+                            match next {
                                 None => {
                                     // No more elements in this iterator. Remove it from the stack.
                                     self.stack.pop();
@@ -260,7 +417,7 @@ impl<'a> DfsCursor<'a> {
 mod tests {
     use super::*;
 
-    use std::sync::Arc;
+    use std::{cell::RefCell, sync::Arc};
 
     use manifest::Manifest;
     use pathmatcher::{AlwaysMatcher, TreeMatcher};
@@ -290,9 +447,9 @@ mod tests {
                 .collect::<Result<Vec<_>>>()
                 .unwrap(),
             vec!(
+                make_file("a1/b1/c1/d1", "10"),
                 make_file("a1/b2", "20"),
                 make_file("a2/b2/c2", "30"),
-                make_file("a1/b1/c1/d1", "10"),
             )
         );
 
@@ -301,10 +458,10 @@ mod tests {
             [
                 "Ephemeral ''",
                 "Ephemeral 'a1'",
-                "Ephemeral 'a2'",
                 "Ephemeral 'a1/b1'",
-                "Ephemeral 'a2/b2'",
-                "Ephemeral 'a1/b1/c1'"
+                "Ephemeral 'a1/b1/c1'",
+                "Ephemeral 'a2'",
+                "Ephemeral 'a2/b2'"
             ]
         );
     }
@@ -327,9 +484,9 @@ mod tests {
                 .collect::<Result<Vec<_>>>()
                 .unwrap(),
             vec!(
+                make_file("a1/b1/c1/d1", "10"),
                 make_file("a1/b2", "20"),
                 make_file("a2/b2/c2", "30"),
-                make_file("a1/b1/c1/d1", "10"),
             )
         );
 
@@ -338,10 +495,206 @@ mod tests {
             [
                 "Durable   ''",
                 "Durable   'a1'",
-                "Durable   'a2'",
                 "Durable   'a1/b1'",
-                "Durable   'a2/b2'",
-                "Durable   'a1/b1/c1'"
+                "Durable   'a1/b1/c1'",
+                "Durable   'a2'",
+                "Durable   'a2/b2'"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dirs_mixed() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1/d1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b2/c2"), make_meta("20"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+        let mut tree = TreeManifest::durable(store.clone(), hgid);
+        tree.insert(repo_path_buf("a1/b1/c3"), make_meta("30"))
+            .unwrap();
+
+        assert_eq!(
+            dirs(&tree, &AlwaysMatcher::new()),
+            [
+                "Ephemeral ''",
+                "Ephemeral 'a1'",
+                "Ephemeral 'a1/b1'",
+                "Durable   'a1/b1/c1'",
+                "Durable   'a2'",
+                "Durable   'a2/b2'"
+            ]
+        );
+        let a2 = tree
+            .dirs(&AlwaysMatcher::new())
+            .map(|dir| dir.unwrap())
+            .find(|dir| dir.path == repo_path_buf("a2"))
+            .unwrap();
+        match tree.get(repo_path("a2")).unwrap() {
+            Some(FsNodeMetadata::Directory(hgid)) => assert_eq!(a2.hgid, hgid),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_files_lazy_durable() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1/d1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b2/c2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2-b3"), make_meta("30"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+        let tree = TreeManifest::durable(store.clone(), hgid);
+
+        let matcher = AlwaysMatcher::new();
+        let mut files = tree.files(&matcher);
+        assert_eq!(
+            files.next().unwrap().unwrap(),
+            make_file("a1/b1/c1/d1", "10")
+        );
+        let fetched = store
+            .fetches()
+            .into_iter()
+            .flatten()
+            .map(|key| key.path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fetched,
+            vec![
+                repo_path_buf("a1"),
+                repo_path_buf("a2"),
+                repo_path_buf("a1/b1"),
+                repo_path_buf("a1/b1/c1")
+            ]
+        );
+
+        assert_eq!(
+            files.collect::<Result<Vec<_>>>().unwrap(),
+            vec!(make_file("a2/b2/c2", "20"), make_file("a2-b3", "30"))
+        );
+    }
+
+    #[test]
+    fn test_files_range() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1-b3"), make_meta("30"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b4"), make_meta("40"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+        let tree = TreeManifest::durable(store, hgid);
+
+        let matcher = AlwaysMatcher::new();
+        let range = |range: (Bound<&str>, Bound<&str>)| {
+            let to_path = |bound| match bound {
+                Bound::Included("") => Bound::Included(RepoPathBuf::new()),
+                Bound::Excluded("") => Bound::Excluded(RepoPathBuf::new()),
+                Bound::Included(path) => Bound::Included(repo_path_buf(path)),
+                Bound::Excluded(path) => Bound::Excluded(repo_path_buf(path)),
+                Bound::Unbounded => Bound::Unbounded,
+            };
+            let (start, end) = (to_path(range.0), to_path(range.1));
+            let files = tree
+                .range((start.clone(), end.clone()), &matcher)
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            let mut reversed = tree
+                .range_rev((start, end), &matcher)
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            reversed.reverse();
+            assert_eq!(files, reversed);
+            files
+                .into_iter()
+                .map(|file| file.path.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            range((Bound::Unbounded, Bound::Unbounded)),
+            ["a1/b1/c1", "a1/b2", "a1-b3", "a2/b4"]
+        );
+        assert_eq!(
+            range((Bound::Included("a1/b2"), Bound::Unbounded)),
+            ["a1/b2", "a1-b3", "a2/b4"]
+        );
+        assert_eq!(
+            range((Bound::Excluded("a1/b2"), Bound::Unbounded)),
+            ["a1-b3", "a2/b4"]
+        );
+        assert_eq!(
+            range((Bound::Excluded("a1"), Bound::Excluded("a2/b4"))),
+            ["a1/b1/c1", "a1/b2", "a1-b3"]
+        );
+        assert_eq!(
+            range((Bound::Included("a1/b1/c0"), Bound::Included("a1/b3"))),
+            ["a1/b1/c1", "a1/b2"]
+        );
+        assert_eq!(
+            range((Bound::Included(""), Bound::Excluded("a1/b1/c1"))),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            range((Bound::Excluded(""), Bound::Included("a1/b1/c1"))),
+            ["a1/b1/c1"]
+        );
+
+        assert_eq!(
+            tree.range_rev(.., &matcher)
+                .map(|file| file.unwrap().path.to_string())
+                .collect::<Vec<_>>(),
+            ["a2/b4", "a1-b3", "a1/b2", "a1/b1/c1"]
+        );
+    }
+
+    #[test]
+    fn test_items_max_depth() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1/d1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b3/c2"), make_meta("30"))
+            .unwrap();
+        tree.insert(repo_path_buf("a3"), make_meta("40")).unwrap();
+        let hgid = tree.flush().unwrap();
+        let tree = TreeManifest::durable(store, hgid);
+
+        let matcher = AlwaysMatcher::new();
+        let dirs = |max_depth| {
+            tree.dirs_max_depth(&matcher, max_depth)
+                .map(|dir| dir.unwrap().path.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(dirs(0), [""]);
+        assert_eq!(dirs(1), ["", "a1", "a2"]);
+        assert_eq!(tree.fetch_stats().fetches, 1);
+        assert_eq!(dirs(2), ["", "a1", "a1/b1", "a2", "a2/b3"]);
+        assert_eq!(tree.fetch_stats().fetches, 3);
+
+        let files = |max_depth| {
+            tree.files_max_depth(&matcher, max_depth)
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        };
+        assert_eq!(files(1), vec![make_file("a3", "40")]);
+        assert_eq!(
+            files(3),
+            vec![
+                make_file("a1/b2", "20"),
+                make_file("a2/b3/c2", "30"),
+                make_file("a3", "40")
             ]
         );
     }
@@ -396,14 +749,74 @@ mod tests {
             [
                 "Ephemeral ''",
                 "Ephemeral 'a1'",
-                "Ephemeral 'a2'",
-                "Ephemeral 'a3'",
                 "Ephemeral 'a1/b1'",
+                "Ephemeral 'a1/b1/c1'",
+                "Ephemeral 'a2'",
                 "Ephemeral 'a2/b2'",
-                "Ephemeral 'a3/b2'",
-                "Ephemeral 'a1/b1/c1'"
+                "Ephemeral 'a3'",
+                "Ephemeral 'a3/b2'"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_files_matcher_prunes_directories() {
+        struct RecordingMatcher {
+            inner: TreeMatcher,
+            queried: RefCell<Vec<RepoPathBuf>>,
+        }
+        impl Matcher for RecordingMatcher {
+            fn matches_directory(&self, path: &RepoPath) -> DirectoryMatch {
+                self.queried.borrow_mut().push(path.to_owned());
+                self.inner.matches_directory(path)
+            }
+            fn matches_file(&self, path: &RepoPath) -> bool {
+                self.queried.borrow_mut().push(path.to_owned());
+                self.inner.matches_file(path)
+            }
+        }
+
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b3/c3"), make_meta("30"))
+            .unwrap();
+        tree.insert(repo_path_buf("a3"), make_meta("40")).unwrap();
+        let hgid = tree.flush().unwrap();
+        let tree = TreeManifest::durable(store.clone(), hgid);
+
+        let matcher = RecordingMatcher {
+            inner: TreeMatcher::from_rules(["a1/**"].iter()).unwrap(),
+            queried: RefCell::new(vec![]),
+        };
+        assert_eq!(
+            tree.files(&matcher).collect::<Result<Vec<_>>>().unwrap(),
+            vec!(make_file("a1/b1/c1", "10"), make_file("a1/b2", "20"))
+        );
+
+        // "a2" is ruled out so it is not fetched. "a1" is fully matched so the matcher is not
+        // asked about its contents.
+        let mut queried = matcher.queried.into_inner();
+        queried.sort();
+        queried.dedup();
+        assert_eq!(
+            queried,
+            vec![
+                repo_path_buf("a1"),
+                repo_path_buf("a2"),
+                repo_path_buf("a3")
             ]
         );
+        let fetched = store
+            .fetches()
+            .into_iter()
+            .flatten()
+            .map(|key| key.path)
+            .collect::<Vec<_>>();
+        assert_eq!(fetched, vec![repo_path_buf("a1"), repo_path_buf("a1/b1")]);
     }
 
     #[test]
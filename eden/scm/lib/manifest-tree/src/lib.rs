@@ -5,9 +5,21 @@
  * GNU General Public License version 2.
  */
 
+mod async_store;
+mod casefold;
+mod debug;
 mod diff;
+mod flat;
+mod git;
+mod hash;
 mod iter;
 mod link;
+mod memory;
+#[cfg(any(test, feature = "for-tests"))]
+pub mod model;
+mod normalization;
+mod rename;
+mod sparse;
 mod store;
 #[cfg(any(test, feature = "for-tests"))]
 pub mod testutil;
@@ -15,34 +27,67 @@ pub mod testutil;
 use std::{
     collections::{btree_map::Entry, BTreeMap},
     fmt,
+    ops::RangeBounds,
     sync::Arc,
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use bytes::Bytes;
-use crypto::{digest::Digest, sha1::Sha1};
-use once_cell::sync::OnceCell;
 use thiserror::Error;
 
 use manifest::{DiffEntry, Directory, File, FileMetadata, FsNodeMetadata, List, Manifest};
-use pathmatcher::Matcher;
+use pathmatcher::{AlwaysMatcher, Matcher};
 use types::{HgId, Key, PathComponent, PathComponentBuf, RepoPath, RepoPathBuf};
+use workerpool::{Priority, WorkerPool};
 
 pub(crate) use self::link::Link;
-pub use self::{diff::Diff, store::TreeStore};
+pub use self::{
+    async_store::{AsyncTreeStore, BlockingTreeStore},
+    casefold::{CaseFold, SimpleCaseFold, UnicodeCaseFold},
+    diff::Diff,
+    git::GitTreeStore,
+    hash::{GitTreeHasher, HgTreeHasher, TreeHasher},
+    iter::WalkControl,
+    link::{DurableEntryError, DurableErrorKind},
+    normalization::{NormalizationForm, NormalizationPolicy, Normalize, UnicodeNormalizer},
+    rename::{RenameDetector, RenameDiffEntry, Similarity},
+    sparse::SparseProfile,
+    store::{
+        is_transient, Element, Entry as TreeEntry, FetchObserver, FetchStats, Flag, RetryPolicy,
+        TreeStore,
+    },
+};
 use crate::{
-    iter::{BfsIter, DfsCursor, Step},
+    iter::{DfsCursor, DfsIter, Step},
     link::{DirLink, Durable, DurableEntry, Ephemeral, Leaf},
     store::InnerStore,
 };
 
 /// The Tree implementation of a Manifest dedicates an inner node for each directory in the
 /// repository and a leaf for each file.
+///
+/// A tree can be read from many threads at once, for example to check the directories of a
+/// status in a thread pool. The methods taking `&self` load durable directories on demand,
+/// and each directory is read from the store once: threads that need a directory while it is
+/// being read wait for that read to finish. This is why stores must be `Send + Sync`.
 #[derive(Clone)]
 pub struct TreeManifest {
     store: InnerStore,
     // TODO: root can't be a Leaf
     root: Link,
+    case_fold: Option<Arc<dyn CaseFold>>,
+    normalization: Option<NormalizationPolicy>,
+    memory_limit: Option<usize>,
+    // The roots of the stored trees this tree was derived from.
+    parents: Vec<HgId>,
+}
+
+/// The state of a tree saved by `TreeManifest::checkpoint`, to undo the changes made after it
+/// with `TreeManifest::rollback`.
+#[derive(Clone)]
+pub struct Checkpoint {
+    root: Link,
+    parents: Vec<HgId>,
 }
 
 #[derive(Error, Debug)]
@@ -69,6 +114,10 @@ pub enum InsertErrorCause {
     ParentFileExists(RepoPathBuf),
     #[error("file path is already a directory")]
     DirectoryExistsForPath,
+    #[error("'{0}' differs only in case")]
+    CaseCollision(RepoPathBuf),
+    #[error("'{0}' differs only in Unicode normalization")]
+    NormalizationCollision(RepoPathBuf),
 }
 
 impl TreeManifest {
@@ -77,6 +126,10 @@ impl TreeManifest {
         TreeManifest {
             store: InnerStore::new(store),
             root: Link::durable(hgid),
+            case_fold: None,
+            normalization: None,
+            memory_limit: None,
+            parents: vec![hgid],
         }
     }
 
@@ -84,8 +137,488 @@ impl TreeManifest {
     pub fn ephemeral(store: Arc<dyn TreeStore + Send + Sync>) -> Self {
         TreeManifest {
             store: InnerStore::new(store),
-            root: Link::Ephemeral(BTreeMap::new()),
+            root: Link::ephemeral(),
+            case_fold: None,
+            normalization: None,
+            memory_limit: None,
+            parents: Vec::new(),
+        }
+    }
+
+    /// Sets the roots of the stored trees this tree is derived from, which are used as the
+    /// parents of the directories written by `flush_with_parents`. A tree has at most two
+    /// parents, and a durable tree starts with its own root as its only parent.
+    pub fn with_parents(mut self, parents: Vec<HgId>) -> Self {
+        debug_assert!(parents.len() <= 2);
+        self.parents = parents;
+        self
+    }
+
+    /// Sets how reading directories from the store is retried when it fails with a transient
+    /// error. Defaults to `RetryPolicy::default()`.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.store.set_retry_policy(retry_policy);
+        self
+    }
+
+    /// Calls `fetch_observer` for every tree read from the store.
+    pub fn with_fetch_observer(mut self, fetch_observer: Arc<dyn FetchObserver>) -> Self {
+        self.store.set_fetch_observer(fetch_observer);
+        self
+    }
+
+    /// Returns the number of trees read from the store by this tree, and by the trees it was
+    /// cloned from or cloned into, with their size and the time spent reading them.
+    ///
+    /// Compare the values before and after an operation to find out how many trees it read.
+    pub fn fetch_stats(&self) -> FetchStats {
+        self.store.fetch_stats()
+    }
+
+    /// Makes lookups case-insensitive, with `case_fold` deciding which names are equivalent.
+    ///
+    /// `get` and `list` fall back to an equivalent name when a path component is not found,
+    /// as a case-insensitive filesystem would. `insert` fails with
+    /// `InsertErrorCause::CaseCollision` when a component is equivalent to a different name
+    /// that is already in the tree, since both could not be checked out.
+    pub fn with_case_folding(mut self, case_fold: Arc<dyn CaseFold>) -> Self {
+        self.case_fold = Some(case_fold);
+        self
+    }
+
+    /// Normalizes the path components given to `insert`, `get` and `list` with `policy`.
+    ///
+    /// Trees written on other systems may have names in another form. Lookups fall back to a
+    /// name with the same normalized form, and `insert` fails with
+    /// `InsertErrorCause::NormalizationCollision` when a component only differs from a name
+    /// in the tree by its normalization. `find_normalization_collisions` lists such names.
+    pub fn with_normalization(mut self, policy: NormalizationPolicy) -> Self {
+        self.normalization = Some(policy);
+        self
+    }
+
+    /// Returns the groups of paths in the tree that have the same normalized form under the
+    /// policy set by `with_normalization`. They would be the same file on a filesystem that
+    /// normalizes names. Reads the whole tree.
+    pub fn find_normalization_collisions(&self) -> Result<Vec<Vec<RepoPathBuf>>> {
+        let policy = match self.normalizing_policy() {
+            Some(policy) => policy,
+            None => return Ok(Vec::new()),
+        };
+        let mut groups: BTreeMap<(RepoPathBuf, String), Vec<RepoPathBuf>> = BTreeMap::new();
+        self.walk(|path, _| {
+            if let Some((parent, name)) = path.split_last_component() {
+                groups
+                    .entry((parent.to_owned(), policy.normalize(name.as_str())))
+                    .or_default()
+                    .push(path.to_owned());
+            }
+            Ok(WalkControl::Continue)
+        })?;
+        Ok(groups
+            .into_iter()
+            .filter(|(_, paths)| paths.len() > 1)
+            .map(|(_, paths)| paths)
+            .collect())
+    }
+
+    /// Caps the estimated memory used by the directories loaded from the store to `limit`
+    /// bytes. The tree evicts directories with `evict` after `insert` and `remove` once the
+    /// directories it read from the store take it over the limit, and after every flush. Trees
+    /// that are only read should call `evict` themselves.
+    ///
+    /// Directories loaded from the store stay in memory once they are read, which adds up for
+    /// long-running processes reading large trees. Evicted directories are read from the store
+    /// again when they are accessed.
+    pub fn with_memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Returns an estimate of the memory used by the directories loaded in the tree, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        memory::memory_usage(&self.root)
+    }
+
+    /// Unloads the least recently used directories that were read from the store, until
+    /// `memory_usage` is under the limit set by `with_memory_limit`. Returns the estimated
+    /// number of bytes freed.
+    ///
+    /// Directories modified in memory, and directories shared with a snapshot of the tree,
+    /// are kept. Does nothing when there is no limit.
+    pub fn evict(&mut self) -> usize {
+        match self.memory_limit {
+            Some(limit) => memory::evict(&mut self.root, limit, self.store.memory()),
+            None => 0,
+        }
+    }
+
+    /// Calls `evict` if the directories read from the store since the last eviction may have
+    /// taken the tree over its memory limit.
+    fn evict_over_limit(&mut self) {
+        if let Some(limit) = self.memory_limit {
+            if self.store.memory().over_limit(limit) {
+                self.evict();
+            }
+        }
+    }
+
+    /// Loads the durable directories of the tree that match `matcher`, up to `depth` levels
+    /// below the root, or the whole tree if `depth` is `None`.
+    ///
+    /// The tree is walked breadth-first and the directories of each level are requested from the
+    /// store with a single `prefetch` call, so that stores backed by the network can fetch them
+    /// concurrently instead of one `get` at a time. Directories that are already loaded are not
+    /// requested again.
+    pub fn prefetch(&self, matcher: &dyn Matcher, depth: Option<usize>) -> Result<()> {
+        prefetch_levels(&self.store, RepoPathBuf::new(), &self.root, matcher, depth)
+    }
+
+    /// Runs `prefetch` on `pool`, in the [`Priority::Low`] lane, so that the directories are
+    /// loaded before they are needed without blocking the caller. The loaded directories are
+    /// shared with this tree and its clones. Failures are logged, and the directories that were
+    /// not loaded are read when accessed.
+    pub fn prefetch_in_background(
+        &self,
+        pool: &WorkerPool,
+        matcher: Arc<dyn Matcher + Send + Sync>,
+        depth: Option<usize>,
+    ) {
+        let tree = self.clone();
+        pool.spawn(Priority::Low, move || {
+            if let Err(e) = tree.prefetch(&*matcher, depth) {
+                tracing::warn!("background tree prefetch failed: {:?}", e);
+            }
+        });
+    }
+
+    /// Checks that the durable directories of the tree, up to `depth` levels below the root, or
+    /// the whole tree if `depth` is `None`, can be read from the store and parsed.
+    ///
+    /// Returns the directories that are missing from the store or corrupt, with their paths.
+    /// The contents of a broken directory can't be checked. Transient store failures are
+    /// returned as errors, since they don't tell whether the directory is in the store.
+    pub fn verify(&self, mut depth: Option<usize>) -> Result<Vec<DurableEntryError>> {
+        let mut broken = Vec::new();
+        let mut level = vec![(RepoPathBuf::new(), &self.root)];
+        loop {
+            let mut next = Vec::new();
+            for (path, link) in level {
+                let children = match link {
+                    Leaf(_) => continue,
+                    Ephemeral(children) => children,
+                    Durable(entry) => match entry.materialize_links(&self.store, &path) {
+                        Ok(children) => children,
+                        Err(e) => match entry.get_links() {
+                            Some(Err(error)) => {
+                                broken.push(error);
+                                continue;
+                            }
+                            _ => return Err(e),
+                        },
+                    },
+                };
+                if depth != Some(0) {
+                    for (component, child) in children.iter() {
+                        let mut child_path = path.clone();
+                        child_path.push(component.as_path_component());
+                        next.push((child_path, child));
+                    }
+                }
+            }
+            if next.is_empty() {
+                return Ok(broken);
+            }
+            depth = depth.map(|d| d - 1);
+            level = next;
+        }
+    }
+
+    /// Attaches the stored directory `hgid` at `path`, replacing the directory that was there,
+    /// if any. The directory is not loaded: its contents are read from the store when accessed.
+    ///
+    /// This makes it cheap to take a directory from another commit, or to build a manifest
+    /// from known subtrees. Since the store looks up directories by path and hgid, `hgid` has
+    /// to be a directory that was stored at `path`. The parents of `path` are created as
+    /// needed, and grafting at the empty path replaces the whole tree.
+    pub fn graft(&mut self, path: &RepoPath, hgid: HgId) -> Result<()> {
+        let (parent, last_component) = match path.split_last_component() {
+            Some(split) => split,
+            None => {
+                self.root = Link::durable(hgid);
+                return Ok(());
+            }
+        };
+        let mut cursor = &mut self.root;
+        for (parent, component) in parent.parents().zip(parent.components()) {
+            cursor = cursor
+                .mut_ephemeral_links(&self.store, parent)?
+                .entry(component.to_owned())
+                .or_insert_with(Link::ephemeral);
+        }
+        let links = cursor.mut_ephemeral_links(&self.store, parent)?;
+        if let Some(Leaf(_)) = links.get(last_component) {
+            bail!("Path {} is a file but a directory was expected.", path);
+        }
+        links.insert(last_component.to_owned(), Link::durable(hgid));
+        Ok(())
+    }
+
+    /// Returns a copy of the tree to read while this tree keeps changing, for example to
+    /// compute a status or a diff on another thread.
+    ///
+    /// Taking a snapshot is cheap. The snapshot shares all directories with this tree, and a
+    /// directory modified in memory is only copied when one of the trees changes it. A change
+    /// copies the modified directories along its path, and nothing else.
+    pub fn snapshot(&self) -> TreeManifest {
+        self.clone()
+    }
+
+    /// Saves the contents of the tree, so that the changes made after this call can be undone
+    /// with `rollback`, for example when a later step of a patch fails to apply.
+    ///
+    /// Like a snapshot, a checkpoint is cheap: it shares the directories with the tree, which
+    /// only copies the directories it changes afterwards.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            root: self.root.clone(),
+            parents: self.parents.clone(),
+        }
+    }
+
+    /// Restores the contents of the tree saved by `checkpoint`, undoing the inserts, removes
+    /// and flushes made since. Trees written to the store by a flush stay in the store.
+    ///
+    /// The checkpoint has to come from this tree: the contents of another tree would be
+    /// restored with the settings and store of this one.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.root = checkpoint.root;
+        self.parents = checkpoint.parents;
+    }
+
+    /// Returns a tree of the directory at `prefix`, in which paths are relative to `prefix`.
+    ///
+    /// The view shares the store and the loaded directories with this tree, so creating it is
+    /// cheap, and working with it only reads the directories below `prefix`. Like a snapshot, the
+    /// view and this tree change independently: to apply the changes made to the view, flush it
+    /// and `graft` its root at `prefix`. A `prefix` that is not in the tree gives an empty view.
+    pub fn subtree_view(&self, prefix: &RepoPath) -> Result<TreeManifest> {
+        let root = match self.get_link(prefix)? {
+            None => Link::ephemeral(),
+            Some(Leaf(_)) => bail!("Path {} is a file but a directory was expected.", prefix),
+            Some(link) => link.clone(),
+        };
+        let parents = match &root {
+            Durable(entry) => vec![entry.hgid],
+            _ => Vec::new(),
+        };
+        Ok(TreeManifest {
+            store: self.store.with_prefix(prefix),
+            root,
+            case_fold: self.case_fold.clone(),
+            normalization: self.normalization.clone(),
+            memory_limit: self.memory_limit,
+            parents,
+        })
+    }
+
+    /// Returns the files that match `matcher` and have paths in `range`, in sorted order, like
+    /// `files`. Directories outside of `range` are not loaded, so listing can resume from any
+    /// path cheaply, for example with `tree.range((Excluded(last_path), Unbounded), matcher)`.
+    pub fn range<'a>(
+        &'a self,
+        range: impl RangeBounds<RepoPathBuf>,
+        matcher: &'a dyn Matcher,
+    ) -> impl Iterator<Item = Result<File>> + 'a {
+        files_only(DfsIter::new(self, matcher).with_range(range))
+    }
+
+    /// Returns the files that match `matcher` and have paths in `range`, in reverse sorted
+    /// order. Use `..` as the range to list all the files in reverse.
+    pub fn range_rev<'a>(
+        &'a self,
+        range: impl RangeBounds<RepoPathBuf>,
+        matcher: &'a dyn Matcher,
+    ) -> impl Iterator<Item = Result<File>> + 'a {
+        files_only(DfsIter::new(self, matcher).with_range(range).reverse())
+    }
+
+    /// Returns the number of files in the directory at `path` and its subdirectories, 1 if
+    /// `path` is a file, or `None` if it is not in the tree.
+    ///
+    /// The counts of directories loaded from the store are cached, including across clones of
+    /// the tree, so asking again does not traverse them. Modified directories are counted each
+    /// time, but only down to their unmodified subdirectories.
+    pub fn count_files(&self, path: &RepoPath) -> Result<Option<usize>> {
+        match self.get_link(path)? {
+            None => Ok(None),
+            Some(link) => Ok(Some(link.count_files(&self.store, path)?)),
+        }
+    }
+
+    /// Returns whether `other` has the same files as this tree, with the same metadata.
+    ///
+    /// Directories that are unmodified on both sides and have the same hgid are equal without
+    /// being read, so comparing two trees only reads the directories that differ.
+    pub fn same_as(&self, other: &TreeManifest) -> Result<bool> {
+        self.root
+            .same_as(&self.store, &other.root, &other.store, RepoPath::empty())
+    }
+
+    /// Returns whether the diff with `other` has any entry matching `matcher`. The diff stops at
+    /// the first entry, so finding out whether a directory changed, with a matcher for the
+    /// directory, only reads the changed directories up to the layer of the first changed file.
+    pub fn has_diff(&self, other: &TreeManifest, matcher: &dyn Matcher) -> Result<bool> {
+        match Diff::new(self, other, matcher).next() {
+            None => Ok(false),
+            Some(entry) => entry.map(|_| true),
+        }
+    }
+
+    /// Like `files`, but only returns the files up to `max_depth` levels below the root: with a
+    /// `max_depth` of 1, the files in the root directory. Deeper directories are not read.
+    pub fn files_max_depth<'a>(
+        &'a self,
+        matcher: &'a dyn Matcher,
+        max_depth: usize,
+    ) -> impl Iterator<Item = Result<File>> + 'a {
+        files_only(DfsIter::new(self, matcher).with_max_depth(max_depth))
+    }
+
+    /// Like `dirs`, but only returns the directories up to `max_depth` levels below the root,
+    /// the root being at level 0. The directories at `max_depth` are returned without being
+    /// read, so listing the top levels of a tree only reads the levels above.
+    pub fn dirs_max_depth<'a>(
+        &'a self,
+        matcher: &'a dyn Matcher,
+        max_depth: usize,
+    ) -> impl Iterator<Item = Result<Directory>> + 'a {
+        dirs_only(DfsIter::new(self, matcher).with_max_depth(max_depth))
+    }
+
+    /// Calls `visitor` on every directory and file of the tree, starting with the root, with
+    /// the metadata that `get` would return. Nodes are visited in sorted order and a directory
+    /// comes before its contents.
+    ///
+    /// Unlike `files` or `dirs`, the visitor decides as it goes which directories to descend
+    /// into, and when to stop, with the returned `WalkControl`. For example, returning `Skip`
+    /// for the directories with `max_depth` components limits the walk to `max_depth` levels.
+    /// Durable directories are read from the store when the walk descends into them. Errors
+    /// returned by the visitor end the walk and are returned.
+    pub fn walk(
+        &self,
+        mut visitor: impl FnMut(&RepoPath, FsNodeMetadata) -> Result<WalkControl>,
+    ) -> Result<()> {
+        let mut cursor = self.root_cursor();
+        loop {
+            match cursor.step() {
+                Step::Success => match visitor(cursor.path(), cursor.link().to_fs_node())? {
+                    WalkControl::Continue => (),
+                    WalkControl::Skip => cursor.skip_subtree(),
+                    WalkControl::Stop => return Ok(()),
+                },
+                Step::End => return Ok(()),
+                Step::Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Returns the paths of the directories that changed since they were loaded from the
+    /// store, or since the last `flush`. The root comes first and parents come before their
+    /// children.
+    ///
+    /// These are the directories a `flush` writes to the store. Directories that were not
+    /// changed are durable, and so are all the directories below them, so they are skipped
+    /// without being loaded.
+    pub fn modified_paths(&self) -> impl Iterator<Item = RepoPathBuf> + '_ {
+        let mut stack = match self.root {
+            Ephemeral(_) => vec![(RepoPathBuf::new(), &self.root)],
+            _ => Vec::new(),
+        };
+        std::iter::from_fn(move || {
+            let (path, link) = stack.pop()?;
+            if let Ephemeral(links) = link {
+                for (component, child) in links.iter().rev() {
+                    if let Ephemeral(_) = child {
+                        let mut child_path = path.clone();
+                        child_path.push(component.as_path_component());
+                        stack.push((child_path, child));
+                    }
+                }
+            }
+            Some(path)
+        })
+    }
+
+    /// Looks up `component` in `links`, falling back to a name that is equivalent under case
+    /// folding or normalization.
+    fn get_child<'a>(
+        &self,
+        links: &'a BTreeMap<PathComponentBuf, Link>,
+        component: &PathComponent,
+    ) -> Option<&'a Link> {
+        match links.get(component) {
+            Some(link) => Some(link),
+            None => self
+                .find_equivalent(links, component)
+                .map(|name| &links[name]),
+        }
+    }
+
+    /// Finds a name in `links` that differs from `component` but is equivalent under case
+    /// folding or normalization.
+    fn find_equivalent<'a>(
+        &self,
+        links: &'a BTreeMap<PathComponentBuf, Link>,
+        component: &PathComponent,
+    ) -> Option<&'a PathComponent> {
+        if self.case_fold.is_none() && self.normalizing_policy().is_none() {
+            return None;
+        }
+        let key = self.equivalence_key(component.as_str());
+        links
+            .keys()
+            .find(|name| {
+                name.as_path_component() != component && self.equivalence_key(name.as_str()) == key
+            })
+            .map(|name| name.as_path_component())
+    }
+
+    fn equivalence_key(&self, component: &str) -> String {
+        let normalized = match self.normalizing_policy() {
+            Some(policy) => policy.normalize(component),
+            None => component.to_string(),
+        };
+        match &self.case_fold {
+            Some(case_fold) => case_fold.fold(&normalized),
+            None => normalized,
+        }
+    }
+
+    fn normalizing_policy(&self) -> Option<&NormalizationPolicy> {
+        self.normalization
+            .as_ref()
+            .filter(|policy| policy.form() != NormalizationForm::None)
+    }
+
+    /// Returns `path` with its components normalized, or `None` if they are already
+    /// normalized.
+    fn normalize_path(&self, path: &RepoPath) -> Result<Option<RepoPathBuf>> {
+        let policy = match self.normalizing_policy() {
+            Some(policy) => policy,
+            None => return Ok(None),
+        };
+        let mut normalized = RepoPathBuf::new();
+        for component in path.components() {
+            normalized.push(PathComponent::from_str(
+                &policy.normalize(component.as_str()),
+            )?);
         }
+        Ok(if normalized.as_repo_path() == path {
+            None
+        } else {
+            Some(normalized)
+        })
     }
 
     fn root_cursor<'a>(&'a self) -> DfsCursor<'a> {
@@ -93,12 +626,145 @@ impl TreeManifest {
     }
 }
 
+/// Returns whether `path` is `prefix` or below it.
+fn is_under(path: &RepoPath, prefix: &RepoPath) -> bool {
+    path == prefix || path.parents().any(|parent| parent == prefix)
+}
+
+fn files_only<'a>(
+    iter: impl Iterator<Item = Result<(RepoPathBuf, FsNodeMetadata)>> + 'a,
+) -> impl Iterator<Item = Result<File>> + 'a {
+    iter.filter_map(|result| match result {
+        Ok((path, FsNodeMetadata::File(metadata))) => Some(Ok(File::new(path, metadata))),
+        Ok(_) => None,
+        Err(e) => Some(Err(e)),
+    })
+}
+
+fn dirs_only<'a>(
+    iter: impl Iterator<Item = Result<(RepoPathBuf, FsNodeMetadata)>> + 'a,
+) -> impl Iterator<Item = Result<Directory>> + 'a {
+    iter.filter_map(|result| match result {
+        Ok((path, FsNodeMetadata::Directory(metadata))) => Some(Ok(Directory::new(path, metadata))),
+        Ok(_) => None,
+        Err(e) => Some(Err(e)),
+    })
+}
+
+fn prefetch_levels(
+    store: &InnerStore,
+    path: RepoPathBuf,
+    root: &Link,
+    matcher: &dyn Matcher,
+    depth: Option<usize>,
+) -> Result<()> {
+    let mut walk = LevelWalk::new(path, root, matcher, depth);
+    while let Some(pending) = walk.pending() {
+        if !pending.is_empty() {
+            let keys = pending
+                .iter()
+                .map(|(path, entry)| Key::new((*path).to_owned(), entry.hgid))
+                .collect::<Vec<_>>();
+            store.prefetch(keys)?;
+            DurableEntry::materialize_links_batch(store, &pending)?;
+        }
+        walk.advance(|path, entry| entry.materialize_links(store, path))?;
+    }
+    Ok(())
+}
+
+/// Walks the directories of a tree that match a matcher breadth-first, one level at a time, so
+/// that the durable directories of each level can be loaded together. Used by `prefetch` and
+/// `prefetch_async`, which only differ in how they load a level.
+pub(crate) struct LevelWalk<'a, M: ?Sized> {
+    level: Vec<(RepoPathBuf, &'a Link)>,
+    matcher: &'a M,
+    depth: Option<usize>,
+}
+
+impl<'a, M: Matcher + ?Sized> LevelWalk<'a, M> {
+    /// Starts a walk at `root`, found at `path`, going `depth` levels below it, or the whole
+    /// tree if `depth` is `None`.
+    pub(crate) fn new(
+        path: RepoPathBuf,
+        root: &'a Link,
+        matcher: &'a M,
+        depth: Option<usize>,
+    ) -> Self {
+        LevelWalk {
+            level: vec![(path, root)],
+            matcher,
+            depth,
+        }
+    }
+
+    /// Returns the durable directories of the current level that are not loaded yet, or `None`
+    /// once the walk is complete.
+    pub(crate) fn pending(&self) -> Option<Vec<(&RepoPath, &'a DurableEntry)>> {
+        if self.level.is_empty() {
+            return None;
+        }
+        let pending = self
+            .level
+            .iter()
+            .filter_map(|(path, link)| match link {
+                Durable(entry) if entry.links.get().is_none() => {
+                    Some((path.as_repo_path(), entry.as_ref()))
+                }
+                _ => None,
+            })
+            .collect();
+        Some(pending)
+    }
+
+    /// Moves to the next level. `links` returns the children of a durable directory of the
+    /// current level, which should have been loaded by then.
+    pub(crate) fn advance(
+        &mut self,
+        links: impl Fn(&RepoPath, &'a DurableEntry) -> Result<&'a BTreeMap<PathComponentBuf, Link>>,
+    ) -> Result<()> {
+        self.depth = match self.depth {
+            Some(0) => {
+                self.level.clear();
+                return Ok(());
+            }
+            Some(d) => Some(d - 1),
+            None => None,
+        };
+
+        let mut next = Vec::new();
+        for (path, link) in self.level.drain(..) {
+            let children = match link {
+                Leaf(_) => continue,
+                Ephemeral(children) => children,
+                Durable(entry) => links(&path, entry)?,
+            };
+            for (component, child) in children {
+                if let Leaf(_) = child {
+                    continue;
+                }
+                let mut child_path = path.clone();
+                child_path.push(component.as_path_component());
+                if child.matches(&self.matcher, &child_path) {
+                    next.push((child_path, child));
+                }
+            }
+        }
+        self.level = next;
+        Ok(())
+    }
+}
+
 impl Manifest for TreeManifest {
     fn get(&self, path: &RepoPath) -> Result<Option<FsNodeMetadata>> {
         let result = self.get_link(path)?.map(|link| link.to_fs_node());
         Ok(result)
     }
 
+    /// Lists the files and directories directly in the directory at `path`, with the metadata of
+    /// the files and the hgids of the unmodified directories. Only the directories along `path`
+    /// are read, and the directory itself is read without its subdirectories: once its parents
+    /// are loaded, listing a directory reads a single entry from the store.
     fn list(&self, path: &RepoPath) -> Result<List> {
         let directory = match self.get_link(path)? {
             None => return Ok(List::NotFound),
@@ -116,21 +782,39 @@ impl Manifest for TreeManifest {
     }
 
     fn insert(&mut self, path: RepoPathBuf, file_metadata: FileMetadata) -> Result<()> {
+        let path = match self.normalize_path(&path)? {
+            Some(normalized) => normalized,
+            None => path,
+        };
         let mut cursor = &self.root;
         let mut must_insert = false;
         for (parent, component) in path.parents().zip(path.components()) {
-            let child = match cursor {
+            let links = match cursor {
                 Leaf(_) => Err(InsertError::new(
                     path.clone(), // TODO: get rid of clone (it is borrowed)
                     file_metadata,
                     InsertErrorCause::ParentFileExists(parent.to_owned()),
                 ))?,
-                Ephemeral(links) => links.get(component),
-                Durable(ref entry) => {
-                    let links = entry.materialize_links(&self.store, parent)?;
-                    links.get(component)
-                }
+                Ephemeral(links) => links,
+                Durable(ref entry) => entry.materialize_links(&self.store, parent)?,
             };
+            let child = links.get(component);
+            if child.is_none() {
+                if let Some(existing) = self.find_equivalent(links, component) {
+                    let mut existing_path = parent.to_owned();
+                    existing_path.push(existing);
+                    let cause = match self.normalizing_policy() {
+                        Some(policy)
+                            if policy.normalize(existing.as_str())
+                                == policy.normalize(component.as_str()) =>
+                        {
+                            InsertErrorCause::NormalizationCollision(existing_path)
+                        }
+                        _ => InsertErrorCause::CaseCollision(existing_path),
+                    };
+                    return Err(InsertError::new(path.clone(), file_metadata, cause).into());
+                }
+            }
             match child {
                 None => {
                     must_insert = true;
@@ -160,7 +844,7 @@ impl Manifest for TreeManifest {
             cursor = cursor
                 .mut_ephemeral_links(&self.store, parent)?
                 .entry(component.to_owned())
-                .or_insert_with(|| Ephemeral(BTreeMap::new()));
+                .or_insert_with(Link::ephemeral);
         }
         match cursor
             .mut_ephemeral_links(&self.store, path_parent)?
@@ -177,6 +861,7 @@ impl Manifest for TreeManifest {
                 }
             }
         }
+        self.evict_over_limit();
         Ok(())
     }
 
@@ -217,6 +902,7 @@ impl Manifest for TreeManifest {
                 &mut self.root,
                 &mut path.parents().zip(path.components()),
             )?;
+            self.evict_over_limit();
             Ok(Some(file_metadata))
         } else {
             Ok(None)
@@ -224,13 +910,6 @@ impl Manifest for TreeManifest {
     }
 
     fn flush(&mut self) -> Result<HgId> {
-        fn compute_hgid<C: AsRef<[u8]>>(content: C) -> HgId {
-            let mut hasher = Sha1::new();
-            hasher.input(content.as_ref());
-            let mut buf = [0u8; HgId::len()];
-            hasher.result(&mut buf);
-            (&buf).into()
-        }
         fn do_flush<'a, 'b, 'c>(
             store: &'a InnerStore,
             pathbuf: &'b mut RepoPathBuf,
@@ -246,6 +925,7 @@ impl Manifest for TreeManifest {
                     }
                     Durable(entry) => return Ok((&entry.hgid, store::Flag::Directory)),
                     Ephemeral(links) => {
+                        let links = Arc::make_mut(links);
                         let iter = links.iter_mut().map(|(component, link)| {
                             pathbuf.push(component.as_path_component());
                             let (hgid, flag) = do_flush(store, pathbuf, link)?;
@@ -257,38 +937,38 @@ impl Manifest for TreeManifest {
                             ))
                         });
                         let entry = store::Entry::from_elements(iter)?;
-                        let hgid = compute_hgid(&entry);
+                        let hgid = store.hasher().hash(&entry)?;
                         store.insert_entry(&pathbuf, hgid, entry)?;
 
-                        let cell = OnceCell::new();
                         // TODO: remove clone
-                        cell.set(Ok(links.clone())).unwrap();
-
-                        let durable_entry = DurableEntry { hgid, links: cell };
+                        let durable_entry = DurableEntry::with_links(hgid, links.clone(), store);
                         *cursor = Durable(Arc::new(durable_entry));
                     }
                 }
             }
         }
         let mut path = RepoPathBuf::new();
-        let (hgid, _) = do_flush(&self.store, &mut path, &mut self.root)?;
-        Ok(hgid.clone())
+        let (&hgid, _) = do_flush(&self.store, &mut path, &mut self.root)?;
+        self.store.flush()?;
+        self.parents = vec![hgid];
+        self.evict();
+        Ok(hgid)
     }
 
+    /// Returns an iterator over all the files that are present in the tree
+    /// and match `matcher`. Files are returned in sorted order, the order of
+    /// `RepoPath`, which compares paths component by component. Durable
+    /// directories are loaded lazily as the iteration reaches them.
     fn files<'a, M: Matcher>(
         &'a self,
         matcher: &'a M,
     ) -> Box<dyn Iterator<Item = Result<File>> + 'a> {
-        let files = BfsIter::new(&self, matcher).filter_map(|result| match result {
-            Ok((path, FsNodeMetadata::File(metadata))) => Some(Ok(File::new(path, metadata))),
-            Ok(_) => None,
-            Err(e) => Some(Err(e)),
-        });
-        Box::new(files)
+        Box::new(files_only(DfsIter::new(self, matcher)))
     }
 
     /// Returns an iterator over all the directories that are present in the
-    /// tree.
+    /// tree. Directories are returned in sorted order, each one with its hgid
+    /// if it is durable or `None` if it has been modified in memory.
     ///
     /// Note: the matcher should be a prefix matcher, other kinds of matchers
     /// could be less effective than expected.
@@ -296,14 +976,7 @@ impl Manifest for TreeManifest {
         &'a self,
         matcher: &'a M,
     ) -> Box<dyn Iterator<Item = Result<Directory>> + 'a> {
-        let dirs = BfsIter::new(&self, matcher).filter_map(|result| match result {
-            Ok((path, FsNodeMetadata::Directory(metadata))) => {
-                Some(Ok(Directory::new(path, metadata)))
-            }
-            Ok(_) => None,
-            Err(e) => Some(Err(e)),
-        });
-        Box::new(dirs)
+        Box::new(dirs_only(DfsIter::new(self, matcher)))
     }
 
     fn diff<'a, M: Matcher>(
@@ -360,30 +1033,44 @@ impl fmt::Debug for TreeManifest {
     }
 }
 
+/// The directories created by `TreeManifest::finalize`, as
+/// `(path, hgid, raw entry, p1 hgid, p2 hgid)`.
+///
+/// Directories come before their parent directory, so the entries can be inserted in a store,
+/// or sent to a server, in the order they are returned. The root directory is last, unless it
+/// is unchanged from a parent tree.
+pub struct FinalizedEntries {
+    root: HgId,
+    entries: std::vec::IntoIter<(RepoPathBuf, HgId, Bytes, HgId, HgId)>,
+}
+
+impl FinalizedEntries {
+    /// The hgid of the root directory of the finalized tree.
+    pub fn root(&self) -> HgId {
+        self.root
+    }
+}
+
+impl Iterator for FinalizedEntries {
+    type Item = (RepoPathBuf, HgId, Bytes, HgId, HgId);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
 impl TreeManifest {
-    pub fn finalize(
-        &mut self,
-        parent_trees: Vec<&TreeManifest>,
-    ) -> Result<impl Iterator<Item = (RepoPathBuf, HgId, Bytes, HgId, HgId)>> {
-        fn compute_hgid<C: AsRef<[u8]>>(parent_tree_nodes: &[HgId], content: C) -> HgId {
-            let mut hasher = Sha1::new();
-            debug_assert!(parent_tree_nodes.len() <= 2);
-            let p1 = parent_tree_nodes.get(0).unwrap_or(HgId::null_id());
-            let p2 = parent_tree_nodes.get(1).unwrap_or(HgId::null_id());
-            // Even if parents are sorted two hashes go into hash computation but surprise
-            // the NULL_ID is not a special case in this case and gets sorted.
-            if p1 < p2 {
-                hasher.input(p1.as_ref());
-                hasher.input(p2.as_ref());
-            } else {
-                hasher.input(p2.as_ref());
-                hasher.input(p1.as_ref());
-            }
-            hasher.input(content.as_ref());
-            let mut buf = [0u8; HgId::len()];
-            hasher.result(&mut buf);
-            (&buf).into()
-        }
+    /// Computes the hgids of the directories modified in memory, using `parent_trees` as the
+    /// history of each directory, and returns the directories that do not exist in the parent
+    /// trees. The hgids are computed with the `TreeHasher` of the store.
+    ///
+    /// Unlike `flush`, this does not write to the store: it is up to the caller to store or
+    /// send the returned entries. The store is only read, to load unmodified directories.
+    pub fn finalize(&mut self, parent_trees: Vec<&TreeManifest>) -> Result<FinalizedEntries> {
         struct Executor<'a> {
             store: &'a InnerStore,
             path: RepoPathBuf,
@@ -491,16 +1178,18 @@ impl TreeManifest {
                     entry.add_element(element);
                 }
                 let entry = entry.freeze();
-                let hgid = compute_hgid(&parent_tree_nodes, &entry);
+                debug_assert!(parent_tree_nodes.len() <= 2);
+                let parent_hgid = |id| *parent_tree_nodes.get(id).unwrap_or(HgId::null_id());
+                let hgid = self.store.hasher().hash_with_parents(
+                    &entry,
+                    parent_hgid(0),
+                    parent_hgid(1),
+                )?;
 
-                let cell = OnceCell::new();
                 // TODO: remove clone
-                cell.set(Ok(links.clone())).unwrap();
-
-                let durable_entry = DurableEntry { hgid, links: cell };
+                let durable_entry = DurableEntry::with_links(hgid, links.clone(), self.store);
                 let inner = Arc::new(durable_entry);
                 *link = Durable(inner);
-                let parent_hgid = |id| *parent_tree_nodes.get(id).unwrap_or(HgId::null_id());
                 self.converted_nodes.push((
                     self.path.clone(),
                     hgid,
@@ -513,19 +1202,57 @@ impl TreeManifest {
         }
 
         let mut executor = Executor::new(&self.store, &parent_trees)?;
-        executor.work(&mut self.root, (0..parent_trees.len()).collect())?;
-        Ok(executor.converted_nodes.into_iter())
+        let (root, _) = executor.work(&mut self.root, (0..parent_trees.len()).collect())?;
+        Ok(FinalizedEntries {
+            root,
+            entries: executor.converted_nodes.into_iter(),
+        })
+    }
+
+    /// Writes the directories modified in memory to the store, like `flush`, but hashes them
+    /// with their parent directories and writes them with `TreeStore::insert_with_parents`.
+    ///
+    /// The parents of a directory are the directories at the same path in the trees this tree
+    /// was derived from: the tree it was loaded from, the tree it was last flushed to, or the
+    /// trees set with `with_parents`. Returns the hgid of the root, which becomes the only
+    /// parent of the tree.
+    pub fn flush_with_parents(&mut self) -> Result<HgId> {
+        let parent_trees: Vec<TreeManifest> = self
+            .parents
+            .iter()
+            .map(|&hgid| TreeManifest {
+                store: self.store.clone(),
+                root: Link::durable(hgid),
+                case_fold: None,
+                normalization: None,
+                memory_limit: None,
+                parents: vec![hgid],
+            })
+            .collect();
+        let entries = self.finalize(parent_trees.iter().collect())?;
+        let root = entries.root();
+        for (path, hgid, data, p1, p2) in entries {
+            let entry = store::Entry::from_bytes(data);
+            self.store
+                .insert_entry_with_parents(&path, hgid, entry, p1, p2)?;
+        }
+        self.store.flush()?;
+        self.parents = vec![root];
+        self.evict();
+        Ok(root)
     }
 
     fn get_link(&self, path: &RepoPath) -> Result<Option<&Link>> {
+        let normalized = self.normalize_path(path)?;
+        let path = normalized.as_ref().map_or(path, |path| path.as_repo_path());
         let mut cursor = &self.root;
         for (parent, component) in path.parents().zip(path.components()) {
             let child = match cursor {
                 Leaf(_) => return Ok(None),
-                Ephemeral(links) => links.get(component),
+                Ephemeral(links) => self.get_child(links, component),
                 Durable(ref entry) => {
                     let links = entry.materialize_links(&self.store, parent)?;
-                    links.get(component)
+                    self.get_child(links, component)
                 }
             };
             match child {
@@ -641,42 +1368,26 @@ pub fn compat_subtree_diff(
 pub fn prefetch(
     store: Arc<dyn TreeStore + Send + Sync>,
     key: Key,
-    mut depth: Option<usize>,
+    depth: Option<usize>,
 ) -> Result<()> {
     let tree = TreeManifest::durable(store, key.hgid);
-    let mut dirs = vec![DirLink::from_link(&tree.root, key.path).unwrap()];
-
-    while !dirs.is_empty() {
-        let keys = dirs.iter().filter_map(|d| d.key()).collect::<Vec<_>>();
-        if !keys.is_empty() {
-            // Note that the prefetch() function is expected to filter out
-            // keys that are already present in the client's cache.
-            tree.store.prefetch(keys)?;
-        }
-
-        dirs = dirs
-            .into_iter()
-            .map(|d| Ok(d.list(&tree.store)?.1))
-            .collect::<Result<Vec<_>>>()?
-            .into_iter()
-            .flatten()
-            .collect();
-
-        depth = match depth {
-            Some(0) => break,
-            Some(d) => Some(d - 1),
-            None => None,
-        };
-    }
-
-    Ok(())
+    prefetch_levels(
+        &tree.store,
+        key.path,
+        &tree.root,
+        &AlwaysMatcher::new(),
+        depth,
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::time::Duration;
+
     use manifest::FileType;
+    use pathmatcher::TreeMatcher;
     use types::{hgid::NULL_ID, testutil::*};
 
     use self::testutil::*;
@@ -835,41 +1546,180 @@ mod tests {
     }
 
     #[test]
-    fn test_remove_from_ephemeral() {
+    fn test_case_folding() {
         let mut tree = TreeManifest::ephemeral(Arc::new(TestStore::new()));
-        tree.insert(repo_path_buf("a1/b1/c1/d1"), make_meta("10"))
-            .unwrap();
-        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+        tree.insert(repo_path_buf("Foo/Bar.txt"), make_meta("10"))
             .unwrap();
-        tree.insert(repo_path_buf("a2/b2/c2"), make_meta("30"))
+        assert_eq!(tree.get(repo_path("foo/bar.txt")).unwrap(), None);
+        tree.insert(repo_path_buf("foo/bar.txt"), make_meta("20"))
             .unwrap();
 
-        assert_eq!(tree.remove(repo_path("a1")).unwrap(), None);
-        assert_eq!(tree.remove(repo_path("a1/b1")).unwrap(), None);
-        assert_eq!(tree.remove(repo_path("a1/b1/c1/d1/e1")).unwrap(), None);
-        assert_eq!(
-            tree.remove(repo_path("a1/b1/c1/d1")).unwrap(),
-            Some(make_meta("10"))
-        );
-        assert_eq!(tree.remove(repo_path("a3")).unwrap(), None);
-        assert_eq!(tree.remove(repo_path("a1/b3")).unwrap(), None);
-        assert_eq!(tree.remove(repo_path("a1/b1/c1/d2")).unwrap(), None);
-        assert_eq!(tree.remove(repo_path("a1/b1/c1/d1/e1")).unwrap(), None);
-        assert_eq!(tree.remove(RepoPath::empty()).unwrap(), None);
-        assert_eq!(tree.get(repo_path("a1/b1/c1/d1")).unwrap(), None);
-        assert_eq!(tree.get(repo_path("a1/b1/c1")).unwrap(), None);
+        let mut tree = TreeManifest::ephemeral(Arc::new(TestStore::new()))
+            .with_case_folding(Arc::new(SimpleCaseFold));
+        tree.insert(repo_path_buf("Foo/Bar.txt"), make_meta("10"))
+            .unwrap();
         assert_eq!(
-            tree.get(repo_path("a1/b2")).unwrap(),
-            Some(FsNodeMetadata::File(make_meta("20")))
+            tree.get(repo_path("foo/bar.TXT")).unwrap(),
+            Some(FsNodeMetadata::File(make_meta("10")))
         );
         assert_eq!(
-            tree.remove(repo_path("a1/b2")).unwrap(),
-            Some(make_meta("20"))
+            tree.list(repo_path("FOO")).unwrap(),
+            List::Directory(vec![(
+                path_component_buf("Bar.txt"),
+                FsNodeMetadata::File(make_meta("10"))
+            )])
         );
-        assert_eq!(tree.get(repo_path("a1")).unwrap(), None);
 
-        assert_eq!(
-            tree.get(repo_path("a2/b2/c2")).unwrap(),
+        let collision = |tree: &mut TreeManifest, path| {
+            let err = tree
+                .insert(repo_path_buf(path), make_meta("20"))
+                .unwrap_err();
+            match err.downcast_ref::<InsertError>().unwrap().source {
+                InsertErrorCause::CaseCollision(ref existing) => existing.to_string(),
+                ref cause => panic!("unexpected error {:?}", cause),
+            }
+        };
+        assert_eq!(collision(&mut tree, "foo/baz"), "Foo");
+        assert_eq!(collision(&mut tree, "Foo/bar.txt"), "Foo/Bar.txt");
+        tree.insert(repo_path_buf("Foo/Bar.txt"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("Foo/baz"), make_meta("30"))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_case_folding_with_normalization() {
+        let mut tree = TreeManifest::ephemeral(Arc::new(TestStore::new()))
+            .with_case_folding(Arc::new(UnicodeCaseFold::Nfc));
+        tree.insert(repo_path_buf("cafe\u{301}"), make_meta("10"))
+            .unwrap();
+        assert_eq!(
+            tree.get(repo_path("CAF\u{c9}")).unwrap(),
+            Some(FsNodeMetadata::File(make_meta("10")))
+        );
+        assert!(tree
+            .insert(repo_path_buf("caf\u{e9}"), make_meta("20"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_dirs_of() {
+        let mut tree = TreeManifest::ephemeral(Arc::new(TestStore::new()));
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b3/c2"), make_meta("30"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b4"), make_meta("40"))
+            .unwrap();
+
+        let changed = vec![repo_path("a1/b1/c1"), repo_path("a1/b1/c3")];
+        let dirs = manifest::dirs_of(changed);
+        assert_eq!(
+            dirs.iter().map(|dir| dir.as_str()).collect::<Vec<_>>(),
+            vec!["", "a1", "a1/b1"]
+        );
+        // Files in the affected directories are visited, other directories are skipped.
+        assert_eq!(
+            tree.files(&dirs).collect::<Result<Vec<_>>>().unwrap(),
+            vec![make_file("a1/b1/c1", "10"), make_file("a1/b2", "20")]
+        );
+    }
+
+    #[test]
+    fn test_normalization() {
+        assert_eq!(
+            NormalizationPolicy::from_form(NormalizationForm::Nfd).normalize("caf\u{e9}"),
+            "cafe\u{301}"
+        );
+        let policy = NormalizationPolicy::from_form(NormalizationForm::Nfc);
+        let store = Arc::new(TestStore::new());
+
+        let mut tree = TreeManifest::ephemeral(store.clone()).with_normalization(policy.clone());
+        tree.insert(repo_path_buf("cafe\u{301}/a"), make_meta("10"))
+            .unwrap();
+        assert_eq!(
+            tree.files(&AlwaysMatcher::new())
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            vec![make_file("caf\u{e9}/a", "10")]
+        );
+        assert_eq!(
+            tree.get_file(repo_path("cafe\u{301}/a")).unwrap(),
+            Some(make_meta("10"))
+        );
+
+        // A tree written without normalization can have both forms.
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("cafe\u{301}/a"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("b/caf\u{e9}"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("b/cafe\u{301}"), make_meta("30"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+        assert!(tree.find_normalization_collisions().unwrap().is_empty());
+
+        let mut tree = TreeManifest::durable(store, hgid).with_normalization(policy);
+        assert_eq!(
+            tree.find_normalization_collisions().unwrap(),
+            vec![vec![
+                repo_path_buf("b/cafe\u{301}"),
+                repo_path_buf("b/caf\u{e9}")
+            ]]
+        );
+        assert_eq!(
+            tree.get_file(repo_path("caf\u{e9}/a")).unwrap(),
+            Some(make_meta("10"))
+        );
+        let err = tree
+            .insert(repo_path_buf("caf\u{e9}/b"), make_meta("40"))
+            .unwrap_err();
+        match err.downcast_ref::<InsertError>().unwrap().source {
+            InsertErrorCause::NormalizationCollision(ref existing) => {
+                assert_eq!(existing, &repo_path_buf("cafe\u{301}"))
+            }
+            ref cause => panic!("unexpected error {:?}", cause),
+        }
+    }
+
+    #[test]
+    fn test_remove_from_ephemeral() {
+        let mut tree = TreeManifest::ephemeral(Arc::new(TestStore::new()));
+        tree.insert(repo_path_buf("a1/b1/c1/d1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b2/c2"), make_meta("30"))
+            .unwrap();
+
+        assert_eq!(tree.remove(repo_path("a1")).unwrap(), None);
+        assert_eq!(tree.remove(repo_path("a1/b1")).unwrap(), None);
+        assert_eq!(tree.remove(repo_path("a1/b1/c1/d1/e1")).unwrap(), None);
+        assert_eq!(
+            tree.remove(repo_path("a1/b1/c1/d1")).unwrap(),
+            Some(make_meta("10"))
+        );
+        assert_eq!(tree.remove(repo_path("a3")).unwrap(), None);
+        assert_eq!(tree.remove(repo_path("a1/b3")).unwrap(), None);
+        assert_eq!(tree.remove(repo_path("a1/b1/c1/d2")).unwrap(), None);
+        assert_eq!(tree.remove(repo_path("a1/b1/c1/d1/e1")).unwrap(), None);
+        assert_eq!(tree.remove(RepoPath::empty()).unwrap(), None);
+        assert_eq!(tree.get(repo_path("a1/b1/c1/d1")).unwrap(), None);
+        assert_eq!(tree.get(repo_path("a1/b1/c1")).unwrap(), None);
+        assert_eq!(
+            tree.get(repo_path("a1/b2")).unwrap(),
+            Some(FsNodeMetadata::File(make_meta("20")))
+        );
+        assert_eq!(
+            tree.remove(repo_path("a1/b2")).unwrap(),
+            Some(make_meta("20"))
+        );
+        assert_eq!(tree.get(repo_path("a1")).unwrap(), None);
+
+        assert_eq!(
+            tree.get(repo_path("a2/b2/c2")).unwrap(),
             Some(FsNodeMetadata::File(make_meta("30")))
         );
         assert_eq!(
@@ -952,6 +1802,8 @@ mod tests {
             .unwrap();
 
         let hgid = tree.flush().unwrap();
+        // The store is flushed once, after all the directories are inserted.
+        assert_eq!(store.flushes(), vec![6]);
 
         let tree = TreeManifest::durable(store.clone(), hgid);
         assert_eq!(
@@ -969,6 +1821,530 @@ mod tests {
         assert_eq!(tree.get(repo_path("a2/b1")).unwrap(), None);
     }
 
+    #[test]
+    fn test_get_dir() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b2"), make_meta("20"))
+            .unwrap();
+        assert_eq!(
+            tree.get_dir(repo_path("a1/b1")).unwrap(),
+            Some(Directory::new(repo_path_buf("a1/b1"), None))
+        );
+        assert_eq!(tree.get_dir(repo_path("a1/b1/c1")).unwrap(), None);
+        assert_eq!(tree.get_dir(repo_path("a3")).unwrap(), None);
+
+        let hgid = tree.flush().unwrap();
+        let mut tree = TreeManifest::durable(store.clone(), hgid);
+        assert_eq!(
+            tree.get_dir(RepoPath::empty()).unwrap(),
+            Some(Directory::new(RepoPathBuf::new(), Some(hgid)))
+        );
+        let a1 = tree.get_dir(repo_path("a1")).unwrap().unwrap();
+        assert!(a1.hgid.is_some());
+
+        // Modified directories no longer have an identifier.
+        tree.insert(repo_path_buf("a1/b2"), make_meta("30"))
+            .unwrap();
+        assert_eq!(
+            tree.get_dir(repo_path("a1")).unwrap(),
+            Some(Directory::new(repo_path_buf("a1"), None))
+        );
+        assert!(tree
+            .get_dir(repo_path("a2"))
+            .unwrap()
+            .unwrap()
+            .hgid
+            .is_some());
+    }
+
+    #[test]
+    fn test_modified_paths() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        let modified = |tree: &TreeManifest| -> Vec<String> {
+            tree.modified_paths().map(|p| p.into_string()).collect()
+        };
+        assert_eq!(modified(&tree), vec![""]);
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b2"), make_meta("20"))
+            .unwrap();
+        assert_eq!(modified(&tree), vec!["", "a1", "a1/b1", "a2"]);
+
+        let hgid = tree.flush().unwrap();
+        assert!(modified(&tree).is_empty());
+
+        let mut tree = TreeManifest::durable(store.clone(), hgid);
+        assert!(modified(&tree).is_empty());
+        tree.insert(repo_path_buf("a1/b2"), make_meta("30"))
+            .unwrap();
+        assert_eq!(modified(&tree), vec!["", "a1"]);
+        tree.remove(repo_path("a1/b1/c1")).unwrap();
+        assert_eq!(modified(&tree), vec!["", "a1"]);
+    }
+
+    #[test]
+    fn test_count_files() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b1/c2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("30"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b3"), make_meta("40"))
+            .unwrap();
+        assert_eq!(tree.count_files(RepoPath::empty()).unwrap(), Some(4));
+        let hgid = tree.flush().unwrap();
+
+        let mut tree = TreeManifest::durable(store.clone(), hgid).with_memory_limit(0);
+        assert_eq!(tree.count_files(repo_path("a1/b1")).unwrap(), Some(2));
+        assert_eq!(tree.count_files(repo_path("a1/b1/c1")).unwrap(), Some(1));
+        assert_eq!(tree.count_files(repo_path("a3")).unwrap(), None);
+        assert_eq!(tree.count_files(RepoPath::empty()).unwrap(), Some(4));
+
+        // The counts are kept when the directories are evicted.
+        tree.evict();
+        let fetches = tree.fetch_stats().fetches;
+        assert_eq!(tree.count_files(RepoPath::empty()).unwrap(), Some(4));
+        assert_eq!(tree.fetch_stats().fetches, fetches);
+
+        tree.insert(repo_path_buf("a1/b4"), make_meta("50"))
+            .unwrap();
+        tree.remove(repo_path("a2/b3")).unwrap();
+        assert_eq!(tree.count_files(repo_path("a1")).unwrap(), Some(4));
+        assert_eq!(tree.count_files(RepoPath::empty()).unwrap(), Some(4));
+    }
+
+    #[test]
+    fn test_same_as() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b3"), make_meta("30"))
+            .unwrap();
+        let mut other = tree.clone();
+        assert!(tree.same_as(&other).unwrap());
+        let hgid = tree.flush().unwrap();
+        assert!(tree.same_as(&other).unwrap());
+
+        other
+            .insert(repo_path_buf("a2/b3"), make_meta("40"))
+            .unwrap();
+        assert!(!tree.same_as(&other).unwrap());
+        other
+            .insert(repo_path_buf("a2/b3"), make_meta("30"))
+            .unwrap();
+        assert!(tree.same_as(&other).unwrap());
+        other.remove(repo_path("a1/b2")).unwrap();
+        assert!(!tree.same_as(&other).unwrap());
+
+        // Directories with the same hgid are not read.
+        let tree = TreeManifest::durable(store.clone(), hgid);
+        let mut other = TreeManifest::durable(store.clone(), hgid);
+        assert!(tree.same_as(&other).unwrap());
+        assert_eq!(tree.fetch_stats().fetches, 0);
+        other
+            .insert(repo_path_buf("a2/b4"), make_meta("40"))
+            .unwrap();
+        assert!(!other.same_as(&tree).unwrap());
+        assert!(tree.same_as(&tree.clone()).unwrap());
+        // Only the root and `a2` are read.
+        assert_eq!(tree.fetch_stats().fetches, 2);
+    }
+
+    #[test]
+    fn test_verify() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        let root = tree.flush().unwrap();
+        let tree = TreeManifest::durable(store.clone(), root);
+        assert!(tree.verify(None).unwrap().is_empty());
+
+        let mut tree = tree.clone();
+        tree.graft(repo_path("a1/b3"), hgid("1")).unwrap();
+        store
+            .insert(repo_path("a2"), hgid("2"), Bytes::from(&b"garbage"[..]))
+            .unwrap();
+        tree.graft(repo_path("a2"), hgid("2")).unwrap();
+        let root = tree.flush().unwrap();
+        let tree = TreeManifest::durable(store.clone(), root);
+
+        let broken = |depth| {
+            tree.verify(depth)
+                .unwrap()
+                .into_iter()
+                .map(|error| (error.path().to_string(), error.kind))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(broken(Some(0)), vec![]);
+        assert_eq!(
+            broken(Some(1)),
+            vec![("a2".to_string(), DurableErrorKind::Deserialize)]
+        );
+        assert_eq!(
+            broken(None),
+            vec![
+                ("a2".to_string(), DurableErrorKind::Deserialize),
+                ("a1/b3".to_string(), DurableErrorKind::Fetch),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_evict() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b3"), make_meta("30"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+
+        let mut tree = TreeManifest::durable(store.clone(), hgid);
+        assert_eq!(tree.memory_usage(), 0);
+        tree.get_file(repo_path("a1/b1/c1")).unwrap();
+        tree.get_file(repo_path("a2/b3")).unwrap();
+        let usage = tree.memory_usage();
+        assert!(usage > 0);
+        // There is no limit by default.
+        assert_eq!(tree.evict(), 0);
+
+        let loaded = |tree: &TreeManifest, path| match tree.get_link(repo_path(path)).unwrap() {
+            Some(Durable(entry)) => entry.links.get().is_some(),
+            _ => panic!("{} is not a durable directory", path),
+        };
+        let mut tree = tree.with_memory_limit(usage - 1);
+        let freed = tree.evict();
+        assert!(freed > 0);
+        assert_eq!(tree.memory_usage(), usage - freed);
+        assert!(!loaded(&tree, "a1"));
+        assert!(loaded(&tree, "a2"));
+
+        // Evicted directories are read again when accessed.
+        assert_eq!(
+            tree.get_file(repo_path("a1/b1/c1")).unwrap(),
+            Some(make_meta("10"))
+        );
+        assert!(loaded(&tree, "a1/b1"));
+
+        let mut tree = tree.with_memory_limit(0);
+        tree.evict();
+        assert_eq!(tree.memory_usage(), 0);
+        assert_eq!(
+            tree.get_file(repo_path("a2/b3")).unwrap(),
+            Some(make_meta("30"))
+        );
+
+        // Modifications evict once the directories read take the tree over the limit.
+        let mut tree = TreeManifest::durable(store.clone(), hgid).with_memory_limit(1);
+        tree.get_file(repo_path("a1/b1/c1")).unwrap();
+        tree.insert(repo_path_buf("a2/b4"), make_meta("40"))
+            .unwrap();
+        assert!(!loaded(&tree, "a1"));
+
+        // Flushing evicts the directories it writes.
+        let mut tree = TreeManifest::durable(store, hgid).with_memory_limit(0);
+        tree.insert(repo_path_buf("a3"), make_meta("50")).unwrap();
+        assert!(tree.memory_usage() > 0);
+        tree.flush().unwrap();
+        assert_eq!(tree.memory_usage(), 0);
+    }
+
+    #[test]
+    fn test_fetch_stats() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b2"), make_meta("20"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+        assert_eq!(tree.fetch_stats(), FetchStats::default());
+
+        let fetched = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let observer = {
+            let fetched = fetched.clone();
+            move |path: &RepoPath, _hgid, size, _latency| {
+                fetched.lock().unwrap().push((path.to_owned(), size));
+            }
+        };
+        let tree =
+            TreeManifest::durable(store.clone(), hgid).with_fetch_observer(Arc::new(observer));
+        tree.get_file(repo_path("a1/b1/c1")).unwrap();
+        let fetched = fetched.lock().unwrap().clone();
+        assert_eq!(
+            fetched
+                .iter()
+                .map(|(path, _)| path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["", "a1", "a1/b1"]
+        );
+        let stats = tree.fetch_stats();
+        assert_eq!(stats.fetches, 3);
+        assert_eq!(stats.requests, 3);
+        assert_eq!(
+            stats.bytes,
+            fetched.iter().map(|(_, size)| *size as u64).sum::<u64>()
+        );
+
+        // Loading the trees of a level with a single batch counts as one request.
+        tree.prefetch(&AlwaysMatcher::new(), None).unwrap();
+        let stats = tree.fetch_stats();
+        assert_eq!(stats.fetches, 4);
+        assert_eq!(stats.requests, 4);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b2"), make_meta("20"))
+            .unwrap();
+
+        let snapshot = tree.snapshot();
+        tree.insert(repo_path_buf("a1/b3"), make_meta("30"))
+            .unwrap();
+        tree.remove(repo_path("a1/b1")).unwrap();
+        assert_eq!(
+            snapshot.get_file(repo_path("a1/b1")).unwrap(),
+            Some(make_meta("10"))
+        );
+        assert_eq!(snapshot.get_file(repo_path("a1/b3")).unwrap(), None);
+        assert_eq!(tree.get_file(repo_path("a1/b1")).unwrap(), None);
+
+        // Directories that were not changed since the snapshot are still shared.
+        let shared = |path| match (
+            tree.get_link(repo_path(path)).unwrap(),
+            snapshot.get_link(repo_path(path)).unwrap(),
+        ) {
+            (Some(Ephemeral(left)), Some(Ephemeral(right))) => Arc::ptr_eq(left, right),
+            _ => false,
+        };
+        assert!(shared("a2"));
+        assert!(!shared("a1"));
+
+        let handle = std::thread::spawn(move || snapshot.get_file(repo_path("a2/b2")).unwrap());
+        assert_eq!(handle.join().unwrap(), Some(make_meta("20")));
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<TreeManifest>();
+
+        /// A store that is slow to return trees, so that threads ask for them concurrently.
+        struct SlowStore(TestStore);
+
+        impl TreeStore for SlowStore {
+            fn get(&self, path: &RepoPath, hgid: HgId) -> Result<Bytes> {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                self.0.get(path, hgid)
+            }
+
+            fn insert(&self, path: &RepoPath, hgid: HgId, data: Bytes) -> Result<()> {
+                self.0.insert(path, hgid, data)
+            }
+        }
+
+        let store = Arc::new(SlowStore(TestStore::new()));
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        let paths = ["a1/b1/c1", "a1/b1/c2", "a1/b2", "a2/b3/c3"];
+        for (index, path) in paths.iter().enumerate() {
+            tree.insert(repo_path_buf(path), make_meta(&(index + 1).to_string()))
+                .unwrap();
+        }
+        let hgid = tree.flush().unwrap();
+        let tree = Arc::new(TreeManifest::durable(store, hgid));
+
+        let handles = (0..8)
+            .map(|_| {
+                let tree = tree.clone();
+                std::thread::spawn(move || {
+                    for (index, path) in paths.iter().enumerate() {
+                        assert_eq!(
+                            tree.get_file(repo_path(path)).unwrap(),
+                            Some(make_meta(&(index + 1).to_string()))
+                        );
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // Every directory is read once.
+        assert_eq!(tree.fetch_stats().fetches, 5);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b2"), make_meta("20"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+        let mut tree = TreeManifest::durable(store.clone(), hgid);
+        tree.insert(repo_path_buf("a1/b3"), make_meta("30"))
+            .unwrap();
+
+        let checkpoint = tree.checkpoint();
+        tree.insert(repo_path_buf("a1/b1"), make_meta("40"))
+            .unwrap();
+        tree.remove(repo_path("a2/b2")).unwrap();
+        tree.insert(repo_path_buf("a3"), make_meta("50")).unwrap();
+        tree.flush().unwrap();
+        tree.rollback(checkpoint.clone());
+
+        assert_eq!(
+            tree.files(&AlwaysMatcher::new())
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            vec![
+                make_file("a1/b1", "10"),
+                make_file("a1/b3", "30"),
+                make_file("a2/b2", "20"),
+            ]
+        );
+        assert_eq!(tree.parents, vec![hgid]);
+
+        // A checkpoint can be rolled back to more than once.
+        tree.remove(repo_path("a1/b3")).unwrap();
+        tree.rollback(checkpoint);
+        assert_eq!(
+            tree.get_file(repo_path("a1/b3")).unwrap(),
+            Some(make_meta("30"))
+        );
+    }
+
+    #[test]
+    fn test_subtree_view() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b3"), make_meta("30"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+
+        let mut tree = TreeManifest::durable(store.clone(), hgid);
+        let mut view = tree.subtree_view(repo_path("a1")).unwrap();
+        assert_eq!(
+            view.files(&AlwaysMatcher::new())
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            vec![make_file("b1/c1", "10"), make_file("b2", "20")]
+        );
+        // Only the root and the directories below the prefix were read.
+        assert_eq!(tree.fetch_stats().fetches, 3);
+
+        view.insert(repo_path_buf("b1/c2"), make_meta("40"))
+            .unwrap();
+        assert_eq!(tree.get_file(repo_path("a1/b1/c2")).unwrap(), None);
+        let view_hgid = view.flush().unwrap();
+        tree.graft(repo_path("a1"), view_hgid).unwrap();
+        assert_eq!(
+            tree.get_file(repo_path("a1/b1/c2")).unwrap(),
+            Some(make_meta("40"))
+        );
+
+        assert!(tree
+            .subtree_view(repo_path("a3"))
+            .unwrap()
+            .files(&AlwaysMatcher::new())
+            .next()
+            .is_none());
+        assert!(tree.subtree_view(repo_path("a2/b3")).is_err());
+    }
+
+    #[test]
+    fn test_file_aux_data() {
+        let store = Arc::new(TestStore::new());
+        let entry = format!("a\0{}\01\0{}\0\nb\0{}x\n", hgid("1"), 12, hgid("2"));
+        store
+            .insert(RepoPath::empty(), hgid("10"), Bytes::from(entry))
+            .unwrap();
+        let tree = TreeManifest::durable(store, hgid("10"));
+
+        let meta = tree.get_file(repo_path("a")).unwrap().unwrap();
+        assert_eq!(meta.size, Some(12));
+        assert_eq!(meta.content_sha256, None);
+        // Aux data doesn't take part in comparisons.
+        assert_eq!(meta, make_meta("1"));
+        assert_eq!(tree.get_file(repo_path("b")).unwrap().unwrap().size, None);
+    }
+
+    #[test]
+    fn test_graft() {
+        let store = Arc::new(TestStore::new());
+        let mut source = TreeManifest::ephemeral(store.clone());
+        source
+            .insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        source
+            .insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        let source_hgid = source.flush().unwrap();
+        let a1 = get_hgid(&source, repo_path("a1"));
+        let b1 = get_hgid(&source, repo_path("a1/b1"));
+
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b3"), make_meta("30"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2"), make_meta("40")).unwrap();
+        tree.graft(repo_path("a1"), a1).unwrap();
+        match tree.get_link(repo_path("a1")).unwrap() {
+            Some(Durable(entry)) => assert!(entry.links.get().is_none()),
+            _ => panic!("expected a durable link"),
+        }
+        assert!(tree.graft(repo_path("a2"), a1).is_err());
+        assert!(tree.graft(repo_path("a2/b1"), b1).is_err());
+
+        let hgid = tree.flush().unwrap();
+        let tree = TreeManifest::durable(store.clone(), hgid);
+        assert_eq!(
+            tree.files(&AlwaysMatcher::new())
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            vec![
+                make_file("a1/b1/c1", "10"),
+                make_file("a1/b2", "20"),
+                make_file("a2", "40"),
+            ]
+        );
+
+        // Missing parents are created.
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.graft(repo_path("a1/b1"), b1).unwrap();
+        assert_eq!(
+            tree.get_file(repo_path("a1/b1/c1")).unwrap(),
+            Some(make_meta("10"))
+        );
+
+        let mut tree = TreeManifest::ephemeral(store);
+        tree.graft(RepoPath::empty(), source_hgid).unwrap();
+        assert_eq!(tree.flush().unwrap(), source_hgid);
+    }
+
     #[test]
     fn test_finalize_with_zero_and_one_parents() {
         let store = Arc::new(TestStore::new());
@@ -1018,6 +2394,28 @@ mod tests {
         assert_eq!(update_changed[2].4, NULL_ID);
     }
 
+    #[test]
+    fn test_finalize_root() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1"), make_meta("10"))
+            .unwrap();
+        let finalized = tree.finalize(vec![]).unwrap();
+        let root = finalized.root();
+        let entries: Vec<_> = finalized.collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].0, RepoPathBuf::new());
+        assert_eq!(entries[1].1, root);
+        // Nothing was written to the store.
+        assert!(store.get(RepoPath::empty(), root).is_err());
+
+        // An unchanged tree creates no directory, and keeps the root of its parent.
+        let parent = tree.clone();
+        let finalized = tree.finalize(vec![&parent]).unwrap();
+        assert_eq!(finalized.root(), root);
+        assert_eq!(finalized.count(), 0);
+    }
+
     #[test]
     fn test_finalize_merge() {
         let store = Arc::new(TestStore::new());
@@ -1064,6 +2462,156 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_walk() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2/c1"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b3"), make_meta("30"))
+            .unwrap();
+        tree.insert(repo_path_buf("a3"), make_meta("40")).unwrap();
+        let root = tree.flush().unwrap();
+
+        let walk = |control: &dyn Fn(&RepoPath) -> WalkControl| {
+            let tree = TreeManifest::durable(store.clone(), root);
+            let mut visited = Vec::new();
+            tree.walk(|path, metadata| {
+                let kind = match metadata {
+                    FsNodeMetadata::File(_) => "file",
+                    FsNodeMetadata::Directory(_) => "dir",
+                };
+                visited.push(format!("{} {}", kind, path));
+                Ok(control(path))
+            })
+            .unwrap();
+            (visited.join(", "), tree.fetch_stats().fetches)
+        };
+
+        assert_eq!(
+            walk(&|_| WalkControl::Continue),
+            (
+                "dir , dir a1, file a1/b1, dir a1/b2, file a1/b2/c1, dir a2, file a2/b3, file a3"
+                    .to_string(),
+                4
+            )
+        );
+        // Skipped directories are not read.
+        assert_eq!(
+            walk(&|path| match path.as_str() {
+                "a1" => WalkControl::Skip,
+                _ => WalkControl::Continue,
+            }),
+            ("dir , dir a1, dir a2, file a2/b3, file a3".to_string(), 2)
+        );
+        assert_eq!(
+            walk(&|path| match path.as_str() {
+                "a1/b1" => WalkControl::Stop,
+                _ => WalkControl::Continue,
+            }),
+            ("dir , dir a1, file a1/b1".to_string(), 2)
+        );
+        assert_eq!(walk(&|_| WalkControl::Skip), ("dir ".to_string(), 0));
+
+        let tree = TreeManifest::durable(store.clone(), root);
+        let error = tree
+            .walk(|_, _| Err(anyhow::format_err!("visitor failed")))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "visitor failed");
+    }
+
+    /// A store recording the parents of the inserted trees.
+    struct ParentsStore {
+        store: TestStore,
+        inserts: parking_lot::Mutex<Vec<(RepoPathBuf, HgId, HgId, HgId)>>,
+    }
+
+    impl TreeStore for ParentsStore {
+        fn get(&self, path: &RepoPath, hgid: HgId) -> Result<Bytes> {
+            self.store.get(path, hgid)
+        }
+
+        fn insert(&self, path: &RepoPath, hgid: HgId, data: Bytes) -> Result<()> {
+            self.store.insert(path, hgid, data)
+        }
+
+        fn insert_with_parents(
+            &self,
+            path: &RepoPath,
+            hgid: HgId,
+            data: Bytes,
+            p1: HgId,
+            p2: HgId,
+        ) -> Result<()> {
+            self.inserts.lock().push((path.to_owned(), hgid, p1, p2));
+            self.store.insert(path, hgid, data)
+        }
+    }
+
+    #[test]
+    fn test_flush_with_parents() {
+        let store = Arc::new(ParentsStore {
+            store: TestStore::new(),
+            inserts: parking_lot::Mutex::new(Vec::new()),
+        });
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b2"), make_meta("20"))
+            .unwrap();
+        let root1 = tree.flush_with_parents().unwrap();
+        let a1 = get_hgid(&tree, repo_path("a1"));
+        let null = *HgId::null_id();
+        assert_eq!(
+            store.inserts.lock().drain(..).collect::<Vec<_>>(),
+            vec![
+                (repo_path_buf("a1"), a1, null, null),
+                (
+                    repo_path_buf("a2"),
+                    get_hgid(&tree, repo_path("a2")),
+                    null,
+                    null
+                ),
+                (RepoPathBuf::new(), root1, null, null),
+            ]
+        );
+
+        // The changed directories have the directories they replace as parents.
+        let mut tree = TreeManifest::durable(store.clone(), root1);
+        tree.insert(repo_path_buf("a1/b3"), make_meta("30"))
+            .unwrap();
+        let root2 = tree.flush_with_parents().unwrap();
+        assert_eq!(
+            store.inserts.lock().drain(..).collect::<Vec<_>>(),
+            vec![
+                (
+                    repo_path_buf("a1"),
+                    get_hgid(&tree, repo_path("a1")),
+                    a1,
+                    null
+                ),
+                (RepoPathBuf::new(), root2, root1, null),
+            ]
+        );
+        assert_eq!(
+            tree.get_file(repo_path("a1/b3")).unwrap(),
+            Some(make_meta("30"))
+        );
+
+        // A merge has both trees as parents.
+        let mut tree = TreeManifest::durable(store.clone(), root2).with_parents(vec![root2, root1]);
+        tree.insert(repo_path_buf("a3"), make_meta("40")).unwrap();
+        let root3 = tree.flush_with_parents().unwrap();
+        assert_eq!(
+            store.inserts.lock().drain(..).collect::<Vec<_>>(),
+            vec![(RepoPathBuf::new(), root3, root2, root1)]
+        );
+        assert_eq!(tree.flush_with_parents().unwrap(), root3);
+        assert!(store.inserts.lock().is_empty());
+    }
+
     #[test]
     fn test_finalize_file_to_directory() {
         let store = Arc::new(TestStore::new());
@@ -1486,4 +3034,222 @@ mod tests {
             ]),
         );
     }
+
+    #[test]
+    fn test_list_durable() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b3"), make_meta("30"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+        let b1 = get_hgid(&tree, repo_path("a1/b1"));
+        let tree = TreeManifest::durable(store, hgid);
+
+        assert_eq!(
+            tree.list(repo_path("a1")).unwrap(),
+            List::Directory(vec![
+                (
+                    path_component_buf("b1"),
+                    FsNodeMetadata::Directory(Some(b1))
+                ),
+                (
+                    path_component_buf("b2"),
+                    FsNodeMetadata::File(make_meta("20"))
+                ),
+            ]),
+        );
+        assert_eq!(tree.fetch_stats().fetches, 2);
+        // The parents are loaded, only the listed directory is read.
+        tree.list(repo_path("a2")).unwrap();
+        assert_eq!(tree.fetch_stats().fetches, 3);
+        tree.list(repo_path("a1/b1")).unwrap();
+        assert_eq!(tree.fetch_stats().fetches, 4);
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b3/c3"), make_meta("30"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+        let fetched = || {
+            store
+                .fetches()
+                .into_iter()
+                .map(|keys| keys.into_iter().map(|key| key.path).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        let tree = TreeManifest::durable(store.clone(), hgid);
+        tree.prefetch(&AlwaysMatcher::new(), Some(1)).unwrap();
+        assert_eq!(
+            fetched(),
+            vec![
+                vec![RepoPathBuf::new()],
+                vec![repo_path_buf("a1"), repo_path_buf("a2")]
+            ]
+        );
+
+        store.prefetched.lock().clear();
+        let tree = TreeManifest::durable(store.clone(), hgid);
+        let matcher = TreeMatcher::from_rules(["a1/**"].iter()).unwrap();
+        tree.prefetch(&matcher, None).unwrap();
+        assert_eq!(
+            fetched(),
+            vec![
+                vec![RepoPathBuf::new()],
+                vec![repo_path_buf("a1")],
+                vec![repo_path_buf("a1/b1")]
+            ]
+        );
+
+        // The directories are loaded, iterating them does not fetch anything else.
+        assert_eq!(tree.files(&matcher).count(), 2);
+        assert_eq!(fetched().len(), 3);
+    }
+
+    #[test]
+    fn test_prefetch_uses_get_batch() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a3/b3"), make_meta("30"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+
+        let tree = TreeManifest::durable(store.clone(), hgid);
+        tree.prefetch(&AlwaysMatcher::new(), None).unwrap();
+        let batches = store
+            .batches()
+            .into_iter()
+            .map(|keys| keys.into_iter().map(|key| key.path).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            batches,
+            vec![
+                vec![RepoPathBuf::new()],
+                vec![
+                    repo_path_buf("a1"),
+                    repo_path_buf("a2"),
+                    repo_path_buf("a3")
+                ]
+            ]
+        );
+
+        // Loaded entries are not requested again.
+        tree.prefetch(&AlwaysMatcher::new(), None).unwrap();
+        assert_eq!(store.batches().len(), 2);
+        assert_eq!(
+            tree.get_file(repo_path("a2/b2")).unwrap(),
+            Some(make_meta("20"))
+        );
+    }
+
+    #[test]
+    fn test_prefetch_in_background() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b2"), make_meta("20"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+
+        let tree = TreeManifest::durable(store.clone(), hgid);
+        let pool = WorkerPool::new(workerpool::PoolConfig::with_threads(1));
+        tree.prefetch_in_background(&pool, Arc::new(AlwaysMatcher::new()), None);
+        // Dropping the pool waits for the prefetch.
+        drop(pool);
+        assert_eq!(store.batches().len(), 2);
+
+        // The directories were loaded in this tree.
+        assert_eq!(
+            tree.get_file(repo_path("a2/b2")).unwrap(),
+            Some(make_meta("20"))
+        );
+        assert_eq!(store.batches().len(), 2);
+        assert_eq!(store.fetches().len(), 2);
+    }
+
+    /// A store whose reads fail with the queued errors before succeeding.
+    struct FlakyStore {
+        store: TestStore,
+        failures: parking_lot::Mutex<Vec<std::io::ErrorKind>>,
+    }
+
+    impl TreeStore for FlakyStore {
+        fn get(&self, path: &RepoPath, hgid: HgId) -> Result<Bytes> {
+            match self.failures.lock().pop() {
+                Some(kind) => Err(std::io::Error::new(kind, "flaky").into()),
+                None => self.store.get(path, hgid),
+            }
+        }
+
+        fn insert(&self, path: &RepoPath, hgid: HgId, data: Bytes) -> Result<()> {
+            self.store.insert(path, hgid, data)
+        }
+    }
+
+    fn flaky_tree(failures: Vec<std::io::ErrorKind>) -> (Arc<FlakyStore>, HgId) {
+        let store = Arc::new(FlakyStore {
+            store: TestStore::new(),
+            failures: parking_lot::Mutex::new(Vec::new()),
+        });
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a/b"), make_meta("10")).unwrap();
+        let hgid = tree.flush().unwrap();
+        *store.failures.lock() = failures;
+        (store, hgid)
+    }
+
+    #[test]
+    fn test_retry_transient_failures() {
+        use std::io::ErrorKind::*;
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        };
+
+        let (store, hgid) = flaky_tree(vec![TimedOut, ConnectionReset]);
+        let tree = TreeManifest::durable(store.clone(), hgid).with_retry_policy(policy.clone());
+        assert_eq!(
+            tree.get_file(repo_path("a/b")).unwrap(),
+            Some(make_meta("10"))
+        );
+
+        // Transient failures that outlast the retries are not cached.
+        let (store, hgid) = flaky_tree(vec![TimedOut; 4]);
+        let tree = TreeManifest::durable(store.clone(), hgid).with_retry_policy(policy.clone());
+        assert!(tree.get_file(repo_path("a/b")).is_err());
+        assert_eq!(store.failures.lock().len(), 1);
+        assert_eq!(
+            tree.get_file(repo_path("a/b")).unwrap(),
+            Some(make_meta("10"))
+        );
+
+        // "Not found" is permanent: it is neither retried nor forgotten.
+        let (store, hgid) = flaky_tree(vec![NotFound]);
+        let tree = TreeManifest::durable(store.clone(), hgid).with_retry_policy(policy);
+        assert!(tree.get_file(repo_path("a/b")).is_err());
+        assert!(store.failures.lock().is_empty());
+        assert!(tree.get_file(repo_path("a/b")).is_err());
+
+        let (store, hgid) = flaky_tree(vec![TimedOut]);
+        let tree = TreeManifest::durable(store, hgid).with_retry_policy(RetryPolicy::never());
+        assert!(tree.get_file(repo_path("a/b")).is_err());
+        assert!(tree.get_file(repo_path("a/b")).is_ok());
+    }
 }
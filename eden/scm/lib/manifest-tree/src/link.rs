@@ -5,9 +5,18 @@
  * GNU General Public License version 2.
  */
 
-use std::{cmp::Ordering, collections::BTreeMap, sync::Arc};
-
-use anyhow::{bail, format_err, Context, Result};
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc,
+    },
+};
+
+use anyhow::{bail, Result};
 use once_cell::sync::OnceCell;
 
 use manifest::{File, FileMetadata, FsNodeMetadata};
@@ -27,7 +36,9 @@ pub enum Link {
     /// available in memory. They need to be persisted to be available in future. They are the
     /// mutable type of an inner node. They store the contents of a directory that has been
     /// modified.
-    Ephemeral(BTreeMap<PathComponentBuf, Link>),
+    /// The contents are shared by clones of the tree, and copied on write, so that cloning a
+    /// tree does not copy its modified directories.
+    Ephemeral(Arc<BTreeMap<PathComponentBuf, Link>>),
     /// `Durable` nodes are inner nodes that come from storage. Their contents can be
     /// shared between multiple instances of Tree. They are lazily evaluated. Their children
     /// list will be read from storage only when it is accessed.
@@ -36,18 +47,86 @@ pub enum Link {
 pub use self::Link::*;
 
 // TODO: Use Vec instead of BTreeMap
-/// The inner structure of a durable link. Of note is that failures are cached "forever".
+/// The inner structure of a durable link. Of note is that permanent failures are cached
+/// "forever".
 // The interesting question about this structure is what do we do when we have a failure when
 // reading from storage?
-// We can cache the failure or we don't cache it. Caching it is mostly fine if we had an error
-// reading from local storage or when deserializing. It is not the best option if our storage
-// is remote and we hit a network blip. On the other hand we would not want to always retry when
-// there is a failure on remote storage, we'd want to have a least an exponential backoff on
-// retries. Long story short is that caching the failure is a reasonable place to start from.
+// Caching the failure is fine if we had an error reading from local storage, the data is
+// missing or it can't be deserialized. It is not the best option if our storage is remote and
+// we hit a network blip. `InnerStore` retries transient failures with an exponential backoff,
+// and when they persist they are returned without being cached, so the next access tries again.
 #[derive(Debug)]
 pub struct DurableEntry {
     pub hgid: HgId,
-    pub links: OnceCell<Result<BTreeMap<PathComponentBuf, Link>>>,
+    /// Set once, by the first thread that loads the links. Other threads loading them at the
+    /// same time wait for it, so the entry is read from the store once.
+    pub links: OnceCell<Result<BTreeMap<PathComponentBuf, Link>, DurableEntryError>>,
+    /// When the links were last accessed, as a tick of the clock of the tree. Used to evict the
+    /// least recently used entries.
+    last_access: AtomicU64,
+    /// The number of files under the entry. It is computed once since the contents of a durable
+    /// entry never change, and it is kept when the links are unloaded.
+    file_count: OnceCell<usize>,
+}
+
+/// What went wrong when loading the children of a durable entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DurableErrorKind {
+    /// The store failed to return the entry.
+    Fetch,
+    /// The store returned data that is not a valid tree entry.
+    Deserialize,
+}
+
+/// The failure to load the children of a durable entry.
+///
+/// Permanent failures are cached by the entry and handed to every caller, so the underlying
+/// cause is shared rather than owned.
+#[derive(Clone, Debug)]
+pub struct DurableEntryError {
+    pub kind: DurableErrorKind,
+    /// The store key of the entry, made of its path and hgid.
+    pub key: Key,
+    cause: Arc<anyhow::Error>,
+}
+
+impl DurableEntryError {
+    fn new(kind: DurableErrorKind, path: &RepoPath, hgid: HgId, cause: anyhow::Error) -> Self {
+        DurableEntryError {
+            kind,
+            key: Key::new(path.to_owned(), hgid),
+            cause: Arc::new(cause),
+        }
+    }
+
+    pub fn path(&self) -> &RepoPath {
+        &self.key.path
+    }
+
+    pub fn hgid(&self) -> HgId {
+        self.key.hgid
+    }
+
+    /// The error reported by the store or the parser.
+    pub fn cause(&self) -> &anyhow::Error {
+        &self.cause
+    }
+}
+
+impl fmt::Display for DurableEntryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.kind {
+            DurableErrorKind::Fetch => "failed fetching from store",
+            DurableErrorKind::Deserialize => "failed to deserialize manifest entry",
+        };
+        write!(f, "{} ({}, {})", action, self.key.path, self.key.hgid)
+    }
+}
+
+impl Error for DurableEntryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.cause.as_ref().as_ref())
+    }
 }
 
 impl Link {
@@ -55,9 +134,8 @@ impl Link {
         Link::Durable(Arc::new(DurableEntry::new(hgid)))
     }
 
-    #[cfg(test)]
     pub fn ephemeral() -> Link {
-        Link::Ephemeral(BTreeMap::new())
+        Link::Ephemeral(Arc::new(BTreeMap::new()))
     }
 
     pub fn mut_ephemeral_links(
@@ -68,10 +146,10 @@ impl Link {
         loop {
             match self {
                 Leaf(_) => bail!("Path {} is a file but a directory was expected.", parent),
-                Ephemeral(ref mut links) => return Ok(links),
+                Ephemeral(ref mut links) => return Ok(Arc::make_mut(links)),
                 Durable(ref entry) => {
                     let durable_links = entry.materialize_links(store, parent)?;
-                    *self = Ephemeral(durable_links.clone());
+                    *self = Ephemeral(Arc::new(durable_links.clone()));
                 }
             }
         }
@@ -94,6 +172,80 @@ impl Link {
         }
     }
 
+    /// Returns the number of files under the link, 1 for a file. The counts of durable
+    /// directories are cached, so their contents are only traversed once.
+    pub fn count_files(&self, store: &InnerStore, path: &RepoPath) -> Result<usize> {
+        let links = match self {
+            Leaf(_) => return Ok(1),
+            Ephemeral(links) => links,
+            Durable(entry) => match entry.file_count.get() {
+                Some(count) => return Ok(*count),
+                None => entry.materialize_links(store, path)?,
+            },
+        };
+        let mut count = 0;
+        let mut child_path = path.to_owned();
+        for (component, link) in links {
+            child_path.push(component.as_path_component());
+            count += link.count_files(store, &child_path)?;
+            child_path.pop();
+        }
+        if let Durable(entry) = self {
+            entry.file_count.get_or_init(|| count);
+        }
+        Ok(count)
+    }
+
+    /// Returns whether the link has the same contents as `other`, which comes from
+    /// `other_store`. Durable directories with the same hgid are equal without being loaded.
+    pub fn same_as(
+        &self,
+        store: &InnerStore,
+        other: &Link,
+        other_store: &InnerStore,
+        path: &RepoPath,
+    ) -> Result<bool> {
+        let (links, other_links) = match (self, other) {
+            (Leaf(metadata), Leaf(other_metadata)) => return Ok(metadata == other_metadata),
+            (Leaf(_), _) | (_, Leaf(_)) => return Ok(false),
+            (Durable(entry), Durable(other_entry)) if entry.hgid == other_entry.hgid => {
+                return Ok(true);
+            }
+            (Ephemeral(links), Ephemeral(other_links)) if Arc::ptr_eq(links, other_links) => {
+                return Ok(true);
+            }
+            _ => (self.links(store, path)?, other.links(other_store, path)?),
+        };
+        if links.len() != other_links.len() {
+            return Ok(false);
+        }
+        let mut child_path = path.to_owned();
+        for ((component, link), (other_component, other_link)) in links.iter().zip(other_links) {
+            if component != other_component {
+                return Ok(false);
+            }
+            child_path.push(component.as_path_component());
+            if !link.same_as(store, other_link, other_store, &child_path)? {
+                return Ok(false);
+            }
+            child_path.pop();
+        }
+        Ok(true)
+    }
+
+    /// Returns the children of a directory, loading them from the store if needed.
+    fn links(
+        &self,
+        store: &InnerStore,
+        path: &RepoPath,
+    ) -> Result<&BTreeMap<PathComponentBuf, Link>> {
+        match self {
+            Leaf(_) => bail!("Path {} is a file but a directory was expected.", path),
+            Ephemeral(links) => Ok(links),
+            Durable(entry) => entry.materialize_links(store, path),
+        }
+    }
+
     pub fn matches(&self, matcher: &impl Matcher, path: &RepoPath) -> bool {
         match self {
             Link::Leaf(_) => matcher.matches_file(path),
@@ -109,46 +261,132 @@ impl DurableEntry {
         DurableEntry {
             hgid,
             links: OnceCell::new(),
+            last_access: AtomicU64::new(0),
+            file_count: OnceCell::new(),
         }
     }
 
+    /// Creates an entry whose links are already loaded, for example because they were just
+    /// written to the store.
+    pub fn with_links(
+        hgid: HgId,
+        links: BTreeMap<PathComponentBuf, Link>,
+        store: &InnerStore,
+    ) -> Self {
+        let entry = DurableEntry::new(hgid);
+        entry.links.set(Ok(links)).unwrap();
+        entry.touch(store);
+        entry
+    }
+
+    /// When the links were last accessed. Later accesses return larger values.
+    pub fn last_access(&self) -> u64 {
+        self.last_access.load(atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn touch(&self, store: &InnerStore) {
+        let now = store.memory().tick();
+        self.last_access.store(now, atomic::Ordering::Relaxed);
+    }
+
+    /// Drops the loaded links, so they are read from the store again when accessed.
+    pub fn unload(&mut self) {
+        self.links = OnceCell::new();
+    }
+
     pub fn materialize_links(
         &self,
         store: &InnerStore,
         path: &RepoPath,
     ) -> Result<&BTreeMap<PathComponentBuf, Link>> {
-        // TODO: be smarter around how failures are handled when reading from the store
-        // Currently this loses the stacktrace
-        let result = self.links.get_or_init(|| {
-            let entry = store
-                .get_entry(path, self.hgid)
-                .with_context(|| format!("failed fetching from store ({}, {})", path, self.hgid))?;
-            let mut links = BTreeMap::new();
-            for element_result in entry.elements() {
-                let element = element_result.with_context(|| {
-                    format!(
-                        "failed to deserialize manifest entry {:?} for ({}, {})",
-                        entry, path, self.hgid
-                    )
-                })?;
-                let link = match element.flag {
-                    store::Flag::File(file_type) => {
-                        Leaf(FileMetadata::new(element.hgid, file_type))
+        let result = self
+            .links
+            .get_or_try_init(|| match store.get_entry(path, self.hgid) {
+                Ok(entry) => Ok(self.load_links(store, entry, path)),
+                Err(e) => {
+                    let transient = store::is_transient(&e);
+                    let e = DurableEntryError::new(DurableErrorKind::Fetch, path, self.hgid, e);
+                    if transient {
+                        Err(e)
+                    } else {
+                        Ok(Err(e))
                     }
-                    store::Flag::Directory => Link::durable(element.hgid),
-                };
-                links.insert(element.component, link);
-            }
-            Ok(links)
-        });
-        result.as_ref().map_err(|e| format_err!("{:?}", e))
+                }
+            })?;
+        self.touch(store);
+        Ok(result.as_ref().map_err(|e| e.clone())?)
     }
 
-    pub fn get_links(&self) -> Option<Result<&BTreeMap<PathComponentBuf, Link>>> {
+    /// Load the links of multiple durable entries with a single `get_batch` call to the store.
+    /// Entries that are already loaded are skipped. A failure of the batch as a whole is not
+    /// cached: the entries stay unloaded and will be fetched individually when accessed.
+    pub fn materialize_links_batch(
+        store: &InnerStore,
+        entries: &[(&RepoPath, &DurableEntry)],
+    ) -> Result<()> {
+        let pending: Vec<_> = entries
+            .iter()
+            .filter(|(_, entry)| entry.links.get().is_none())
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let keys: Vec<Key> = pending
+            .iter()
+            .map(|(path, entry)| Key::new((*path).to_owned(), entry.hgid))
+            .collect();
+        let fetched = store.get_entry_batch(&keys)?;
+        for ((path, entry), fetched) in pending.into_iter().zip(fetched) {
+            entry
+                .links
+                .get_or_init(|| entry.load_links(store, fetched, path));
+            entry.touch(store);
+        }
+        Ok(())
+    }
+
+    /// Parses the links read from the store, and accounts for their memory in the tree.
+    pub(crate) fn load_links(
+        &self,
+        store: &InnerStore,
+        entry: store::Entry,
+        path: &RepoPath,
+    ) -> Result<BTreeMap<PathComponentBuf, Link>, DurableEntryError> {
+        let links = self.parse_links(entry, path)?;
+        store.memory().loaded(&links);
+        Ok(links)
+    }
+
+    pub(crate) fn parse_links(
+        &self,
+        entry: store::Entry,
+        path: &RepoPath,
+    ) -> Result<BTreeMap<PathComponentBuf, Link>, DurableEntryError> {
+        let mut links = BTreeMap::new();
+        for element_result in entry.elements() {
+            let element = element_result.map_err(|e| {
+                let e = e.context(format!("invalid entry {:?}", entry));
+                DurableEntryError::new(DurableErrorKind::Deserialize, path, self.hgid, e)
+            })?;
+            let link = match element.flag {
+                store::Flag::File(file_type) => Leaf(FileMetadata {
+                    size: element.size,
+                    content_sha256: element.content_sha256,
+                    ..FileMetadata::new(element.hgid, file_type)
+                }),
+                store::Flag::Directory => Link::durable(element.hgid),
+            };
+            links.insert(element.component, link);
+        }
+        Ok(links)
+    }
+
+    pub fn get_links(
+        &self,
+    ) -> Option<Result<&BTreeMap<PathComponentBuf, Link>, DurableEntryError>> {
         self.links
             .get()
-            .as_ref()
-            .map(|result| result.as_ref().map_err(|e| format_err!("{:?}", e)))
+            .map(|result| result.as_ref().map_err(|e| e.clone()))
     }
 }
 
@@ -275,9 +513,10 @@ impl PartialOrd for DirLink<'_> {
 mod tests {
     use super::*;
 
+    use bytes::Bytes;
     use types::testutil::*;
 
-    use crate::testutil::*;
+    use crate::{testutil::*, TreeStore};
 
     #[test]
     fn test_file_from_link() {
@@ -346,4 +585,31 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_durable_entry_errors() {
+        let test_store = Arc::new(TestStore::new());
+        let store = InnerStore::new(test_store.clone());
+        let path = repo_path("a");
+
+        let missing = DurableEntry::new(hgid("1"));
+        let err = missing.materialize_links(&store, path).unwrap_err();
+        let err = err.downcast_ref::<DurableEntryError>().unwrap();
+        assert_eq!(err.kind, DurableErrorKind::Fetch);
+        assert_eq!(err.key, Key::new(path.to_owned(), hgid("1")));
+        assert!(err.source().is_some());
+        assert_eq!(
+            missing.get_links().unwrap().unwrap_err().key,
+            Key::new(path.to_owned(), hgid("1"))
+        );
+
+        test_store
+            .insert(path, hgid("2"), Bytes::from(&b"garbage"[..]))
+            .unwrap();
+        let corrupt = DurableEntry::new(hgid("2"));
+        let err = corrupt.materialize_links(&store, path).unwrap_err();
+        let err = err.downcast_ref::<DurableEntryError>().unwrap();
+        assert_eq!(err.kind, DurableErrorKind::Deserialize);
+        assert_eq!(err.hgid(), hgid("2"));
+    }
 }
@@ -0,0 +1,191 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Memory accounting and eviction of the directories loaded in a tree.
+//!
+//! Sizes are estimates: they count the entries of the loaded directories, and not the
+//! allocator overhead or the unused capacity of the maps.
+
+use std::{
+    collections::BTreeMap,
+    mem::size_of,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use types::PathComponentBuf;
+
+use crate::link::{Durable, DurableEntry, Ephemeral, Leaf, Link};
+
+/// The eviction state of a tree, shared with its clones: the clock ordering the accesses to
+/// its durable entries, and an estimate of its memory usage that is cheap to check.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryTracker {
+    clock: AtomicU64,
+    /// The usage measured by the last eviction, plus the size of the directories read from the
+    /// store since then.
+    usage: AtomicUsize,
+}
+
+impl MemoryTracker {
+    /// Returns the next tick of the access clock.
+    pub(crate) fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Accounts for the links of a directory read from the store.
+    pub(crate) fn loaded(&self, links: &BTreeMap<PathComponentBuf, Link>) {
+        self.usage
+            .fetch_add(links_usage_shallow(links), Ordering::Relaxed);
+    }
+
+    /// Whether the directories read since the last eviction may have taken the usage over
+    /// `limit`. Directories modified in memory are not counted until the next eviction.
+    pub(crate) fn over_limit(&self, limit: usize) -> bool {
+        self.usage.load(Ordering::Relaxed) > limit
+    }
+}
+
+/// The estimated size of the directories loaded below `link`, including `link`.
+pub(crate) fn memory_usage(link: &Link) -> usize {
+    match link {
+        Leaf(_) => 0,
+        Ephemeral(links) => links_usage(links),
+        Durable(entry) => match entry.links.get() {
+            Some(Ok(links)) => links_usage(links),
+            _ => 0,
+        },
+    }
+}
+
+fn links_usage(links: &BTreeMap<PathComponentBuf, Link>) -> usize {
+    links_usage_shallow(links) + links.values().map(memory_usage).sum::<usize>()
+}
+
+/// The estimated size of the entries of a directory, without its subdirectories.
+fn links_usage_shallow(links: &BTreeMap<PathComponentBuf, Link>) -> usize {
+    links
+        .iter()
+        .map(|(component, link)| {
+            let durable = match link {
+                Durable(_) => size_of::<DurableEntry>(),
+                _ => 0,
+            };
+            size_of::<PathComponentBuf>() + component.as_str().len() + size_of::<Link>() + durable
+        })
+        .sum()
+}
+
+/// Unloads the least recently used durable directories below `link` until the estimated
+/// memory usage is at most `limit`. Returns the estimated number of bytes freed.
+///
+/// An entry is as recent as the most recently used entry below it, so that a directory is not
+/// unloaded while its subdirectories are in use. Entries shared with other trees are kept,
+/// since unloading them would not free their memory.
+pub(crate) fn evict(link: &mut Link, limit: usize, tracker: &MemoryTracker) -> usize {
+    let usage = memory_usage(link);
+    let freed = evict_usage(link, usage, limit);
+    tracker.usage.store(usage - freed, Ordering::Relaxed);
+    freed
+}
+
+fn evict_usage(link: &mut Link, usage: usize, limit: usize) -> usize {
+    if usage <= limit {
+        return 0;
+    }
+
+    let mut candidates = Vec::new();
+    collect_candidates(link, &mut candidates);
+    candidates.sort_unstable();
+    let mut excess = usage - limit;
+    let mut threshold = None;
+    for (last_access, size) in candidates {
+        threshold = Some(last_access);
+        if size >= excess {
+            break;
+        }
+        excess -= size;
+    }
+
+    match threshold {
+        Some(threshold) => evict_older(link, threshold).1,
+        None => 0,
+    }
+}
+
+/// Returns the most recent access below `link`, and pushes the most recent access and own
+/// size of each durable entry that can be unloaded.
+fn collect_candidates(link: &mut Link, candidates: &mut Vec<(u64, usize)>) -> u64 {
+    match link {
+        Leaf(_) => 0,
+        Ephemeral(links) => match Arc::get_mut(links) {
+            Some(links) => links
+                .values_mut()
+                .map(|link| collect_candidates(link, candidates))
+                .max()
+                .unwrap_or(0),
+            None => 0,
+        },
+        Durable(entry) => {
+            let last_access = entry.last_access();
+            let entry = match Arc::get_mut(entry) {
+                Some(entry) => entry,
+                None => return last_access,
+            };
+            match entry.links.get_mut() {
+                Some(Ok(links)) => {
+                    let size = links_usage_shallow(links);
+                    let last_access = links
+                        .values_mut()
+                        .map(|link| collect_candidates(link, candidates))
+                        .fold(last_access, u64::max);
+                    candidates.push((last_access, size));
+                    last_access
+                }
+                _ => last_access,
+            }
+        }
+    }
+}
+
+/// Unloads the durable entries below `link` whose most recent access is not after
+/// `threshold`. Returns the most recent access below `link`, and the bytes freed.
+fn evict_older(link: &mut Link, threshold: u64) -> (u64, usize) {
+    let visit = |links: &mut BTreeMap<PathComponentBuf, Link>, last_access: u64| {
+        links
+            .values_mut()
+            .map(|link| evict_older(link, threshold))
+            .fold((last_access, 0), |(a, x), (b, y)| (a.max(b), x + y))
+    };
+    match link {
+        Leaf(_) => (0, 0),
+        Ephemeral(links) => match Arc::get_mut(links) {
+            Some(links) => visit(links, 0),
+            None => (0, 0),
+        },
+        Durable(entry) => {
+            let last_access = entry.last_access();
+            let entry = match Arc::get_mut(entry) {
+                Some(entry) => entry,
+                None => return (last_access, 0),
+            };
+            let (last_access, mut freed) = match entry.links.get_mut() {
+                Some(Ok(links)) => visit(links, last_access),
+                _ => return (last_access, 0),
+            };
+            if last_access <= threshold {
+                if let Some(Ok(links)) = entry.links.get() {
+                    freed += links_usage(links);
+                }
+                entry.unload();
+            }
+            (last_access, freed)
+        }
+    }
+}
@@ -0,0 +1,246 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Model-based testing of `TreeManifest`.
+//!
+//! Random sequences of `Operation`s are applied both to a tree and to a `BTreeMap` of its
+//! files, which serves as the model of what the tree should contain. After every operation,
+//! the results and the files of the tree are checked against the model. Flushed trees are read
+//! back from the store, so durable directories are checked as well as ephemeral ones.
+//!
+//! ```ignore
+//! quickcheck! {
+//!     fn test_tree(operations: Vec<Operation>) -> bool {
+//!         ModelTest::new().run(&operations);
+//!         true
+//!     }
+//! }
+//! ```
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use quickcheck::{Arbitrary, Gen};
+use rand::Rng;
+
+use manifest::{DiffEntry, DiffType, File, FileMetadata, FileType, Manifest};
+use pathmatcher::AlwaysMatcher;
+use types::{HgId, RepoPathBuf};
+
+use crate::{testutil::TestStore, Diff, TreeManifest};
+
+/// An operation applied to both the tree and the model.
+#[derive(Clone, Debug)]
+pub enum Operation {
+    Insert(RepoPathBuf, FileMetadata),
+    Remove(RepoPathBuf),
+    Get(RepoPathBuf),
+    /// Writes the tree to the store and checks the tree read back from the store.
+    Flush,
+    /// Replaces the tree with the one read from the store at the last flush.
+    Reload,
+    /// Diffs the tree at the last flush against the tree.
+    Diff,
+}
+
+impl Arbitrary for Operation {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        match g.gen_range(0, 10) {
+            0..=4 => Operation::Insert(arbitrary_path(g), arbitrary_metadata(g)),
+            5 | 6 => Operation::Remove(arbitrary_path(g)),
+            7 => Operation::Get(arbitrary_path(g)),
+            8 => match g.gen_range(0, 3) {
+                0 => Operation::Reload,
+                _ => Operation::Flush,
+            },
+            _ => Operation::Diff,
+        }
+    }
+}
+
+// Paths are made of few components, so that operations often hit the same files and
+// directories, and inserting a file where there is a directory, or below a file, happens.
+fn arbitrary_path<G: Gen>(g: &mut G) -> RepoPathBuf {
+    let depth = g.gen_range(1, 4);
+    let components: Vec<&str> = (0..depth)
+        .map(|_| ["a", "b", "c"][g.gen_range(0, 3)])
+        .collect();
+    RepoPathBuf::from_string(components.join("/")).unwrap()
+}
+
+fn arbitrary_metadata<G: Gen>(g: &mut G) -> FileMetadata {
+    let mut bytes = [0u8; HgId::len()];
+    bytes[HgId::len() - 1] = g.gen_range(1, 5);
+    let file_type = match g.gen_range(0, 4) {
+        0 => FileType::Executable,
+        1 => FileType::Symlink,
+        _ => FileType::Regular,
+    };
+    FileMetadata::new(HgId::from_byte_array(bytes), file_type)
+}
+
+/// A tree backed by a `TestStore`, with the model of its files. Panics when the tree does not
+/// behave like the model.
+pub struct ModelTest {
+    store: Arc<TestStore>,
+    tree: TreeManifest,
+    model: BTreeMap<RepoPathBuf, FileMetadata>,
+    // The hgid of the root and the model at the last flush.
+    flushed: Option<(HgId, BTreeMap<RepoPathBuf, FileMetadata>)>,
+}
+
+impl Default for ModelTest {
+    fn default() -> Self {
+        let store = Arc::new(TestStore::new());
+        ModelTest {
+            tree: TreeManifest::ephemeral(store.clone()),
+            store,
+            model: BTreeMap::new(),
+            flushed: None,
+        }
+    }
+}
+
+impl ModelTest {
+    /// Starts with an empty tree.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies `operations` in order.
+    pub fn run(&mut self, operations: &[Operation]) {
+        for operation in operations {
+            self.apply(operation);
+        }
+    }
+
+    /// Applies `operation` to the tree and the model, then checks the files of the tree.
+    pub fn apply(&mut self, operation: &Operation) {
+        match operation {
+            Operation::Insert(path, metadata) => {
+                let result = self.tree.insert(path.clone(), *metadata);
+                // A file can't be inserted where there is a directory or below a file.
+                let conflicts = self.model.keys().any(|existing| {
+                    existing != path
+                        && (existing
+                            .parents()
+                            .any(|parent| parent == path.as_repo_path())
+                            || path
+                                .parents()
+                                .any(|parent| parent == existing.as_repo_path()))
+                });
+                assert_eq!(result.is_err(), conflicts, "{:?}", operation);
+                if !conflicts {
+                    self.model.insert(path.clone(), *metadata);
+                }
+            }
+            Operation::Remove(path) => {
+                let removed = self.tree.remove(path).unwrap();
+                assert_eq!(removed, self.model.remove(path), "{:?}", operation);
+            }
+            Operation::Get(path) => {
+                let metadata = self.tree.get_file(path).unwrap();
+                assert_eq!(metadata.as_ref(), self.model.get(path), "{:?}", operation);
+            }
+            Operation::Flush => {
+                let hgid = self.tree.flush().unwrap();
+                let durable = TreeManifest::durable(self.store.clone(), hgid);
+                assert_eq!(files(&durable), self.model, "{:?}", operation);
+                self.flushed = Some((hgid, self.model.clone()));
+            }
+            Operation::Reload => {
+                if let Some((hgid, model)) = &self.flushed {
+                    self.tree = TreeManifest::durable(self.store.clone(), *hgid);
+                    self.model = model.clone();
+                }
+            }
+            Operation::Diff => {
+                let (left, left_model) = match &self.flushed {
+                    Some((hgid, model)) => {
+                        (TreeManifest::durable(self.store.clone(), *hgid), model)
+                    }
+                    None => return,
+                };
+                let matcher = AlwaysMatcher::new();
+                let mut entries = Diff::new(&left, &self.tree, &matcher)
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .unwrap();
+                entries.sort_by(|a, b| a.path.cmp(&b.path));
+                assert_eq!(entries, diff(left_model, &self.model), "{:?}", operation);
+            }
+        }
+        assert_eq!(files(&self.tree), self.model, "after {:?}", operation);
+    }
+}
+
+fn files(tree: &TreeManifest) -> BTreeMap<RepoPathBuf, FileMetadata> {
+    tree.files(&AlwaysMatcher::new())
+        .map(|file| {
+            let file = file.unwrap();
+            (file.path, file.meta)
+        })
+        .collect()
+}
+
+fn diff(
+    left: &BTreeMap<RepoPathBuf, FileMetadata>,
+    right: &BTreeMap<RepoPathBuf, FileMetadata>,
+) -> Vec<DiffEntry> {
+    let mut entries: Vec<DiffEntry> = Vec::new();
+    for (path, left_metadata) in left {
+        match right.get(path) {
+            None => entries.push(DiffEntry::left(File::new(path.clone(), *left_metadata))),
+            Some(right_metadata) if right_metadata != left_metadata => {
+                entries.push(DiffEntry::new(
+                    path.clone(),
+                    DiffType::Changed(*left_metadata, *right_metadata),
+                ))
+            }
+            Some(_) => (),
+        }
+    }
+    for (path, right_metadata) in right {
+        if !left.contains_key(path) {
+            entries.push(DiffEntry::right(File::new(path.clone(), *right_metadata)));
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use quickcheck::quickcheck;
+    use types::testutil::*;
+
+    use crate::testutil::make_meta;
+
+    #[test]
+    fn test_model_conflicts() {
+        ModelTest::new().run(&[
+            Operation::Insert(repo_path_buf("a/b"), make_meta("1")),
+            Operation::Insert(repo_path_buf("a"), make_meta("2")),
+            Operation::Insert(repo_path_buf("a/b/c"), make_meta("3")),
+            Operation::Flush,
+            Operation::Remove(repo_path_buf("a")),
+            Operation::Insert(repo_path_buf("a/c"), make_meta("4")),
+            Operation::Diff,
+            Operation::Reload,
+            Operation::Remove(repo_path_buf("a/b")),
+            Operation::Get(repo_path_buf("a/b")),
+            Operation::Diff,
+        ]);
+    }
+
+    quickcheck! {
+        fn test_model(operations: Vec<Operation>) -> bool {
+            ModelTest::new().run(&operations);
+            true
+        }
+    }
+}
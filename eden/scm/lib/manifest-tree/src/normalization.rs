@@ -0,0 +1,80 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::Arc;
+
+use unicode_normalization::UnicodeNormalization;
+
+/// Unicode normalization forms of path components.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NormalizationForm {
+    /// Path components are used as they are.
+    None,
+    /// Canonical composition, used by most Linux and Windows tools.
+    Nfc,
+    /// Canonical decomposition, used by HFS+ on macOS.
+    Nfd,
+}
+
+/// Converts a path component to a Unicode normalization form. `UnicodeNormalizer` implements
+/// the standard forms; closures implement this trait too.
+pub trait Normalize: Send + Sync {
+    fn normalize(&self, form: NormalizationForm, component: &str) -> String;
+}
+
+impl<F> Normalize for F
+where
+    F: Fn(NormalizationForm, &str) -> String + Send + Sync,
+{
+    fn normalize(&self, form: NormalizationForm, component: &str) -> String {
+        self(form, component)
+    }
+}
+
+/// Normalizes path components with the Unicode tables of the `unicode-normalization` crate.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnicodeNormalizer;
+
+impl Normalize for UnicodeNormalizer {
+    fn normalize(&self, form: NormalizationForm, component: &str) -> String {
+        match form {
+            NormalizationForm::None => component.to_string(),
+            NormalizationForm::Nfc => component.nfc().collect(),
+            NormalizationForm::Nfd => component.nfd().collect(),
+        }
+    }
+}
+
+/// The normalization form applied to the path components given to `insert` and `get`.
+#[derive(Clone)]
+pub struct NormalizationPolicy {
+    form: NormalizationForm,
+    normalizer: Arc<dyn Normalize>,
+}
+
+impl NormalizationPolicy {
+    /// Normalizes path components to `form` with `UnicodeNormalizer`.
+    pub fn from_form(form: NormalizationForm) -> Self {
+        NormalizationPolicy::new(form, Arc::new(UnicodeNormalizer))
+    }
+
+    pub fn new(form: NormalizationForm, normalizer: Arc<dyn Normalize>) -> Self {
+        NormalizationPolicy { form, normalizer }
+    }
+
+    pub fn form(&self) -> NormalizationForm {
+        self.form
+    }
+
+    /// Returns `component` in the normalization form of the policy.
+    pub fn normalize(&self, component: &str) -> String {
+        match self.form {
+            NormalizationForm::None => component.to_string(),
+            form => self.normalizer.normalize(form, component),
+        }
+    }
+}
@@ -0,0 +1,434 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use anyhow::Result;
+
+use manifest::{DiffEntry, DiffType, File, FileMetadata, FsNodeMetadata, Manifest};
+use types::{RepoPath, RepoPathBuf};
+
+use crate::{is_under, TreeManifest};
+
+/// An entry of a diff in which removed and added files can be paired up as renames.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RenameDiffEntry {
+    Diff(DiffEntry),
+    Renamed {
+        from: File,
+        to: File,
+    },
+    /// A directory moved with all its contents, standing for the renames of all its files.
+    RenamedDirectory {
+        from: RepoPathBuf,
+        to: RepoPathBuf,
+    },
+}
+
+/// Scores how similar a removed file and an added file are, from 0 (unrelated) to 1 (same
+/// content). This is where content-based detection plugs in, to find files that were renamed
+/// and modified. Closures implement this trait.
+pub trait Similarity {
+    fn similarity(&self, from: &File, to: &File) -> Result<f64>;
+}
+
+impl<F> Similarity for F
+where
+    F: Fn(&File, &File) -> Result<f64>,
+{
+    fn similarity(&self, from: &File, to: &File) -> Result<f64> {
+        self(from, to)
+    }
+}
+
+/// Pairs the files removed and added by a diff as renames.
+///
+/// An added file is a rename of a removed file with the same file node. When a `Similarity`
+/// is set, the added files left over are paired with the most similar removed file scoring at
+/// least the threshold. Each removed file is the source of one rename at most.
+#[derive(Default)]
+pub struct RenameDetector<'a> {
+    similarity: Option<(&'a dyn Similarity, f64)>,
+    trees: Option<(&'a TreeManifest, &'a TreeManifest)>,
+}
+
+impl<'a> RenameDetector<'a> {
+    /// Detects exact renames only.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Also pairs files with a `similarity` score of at least `threshold`.
+    pub fn with_similarity(mut self, similarity: &'a dyn Similarity, threshold: f64) -> Self {
+        self.similarity = Some((similarity, threshold));
+        self
+    }
+
+    /// Also reports directories moved as a whole, with the same files, as a single
+    /// `RenamedDirectory` entry rather than a rename for every file. `left` and `right` are the
+    /// trees that the diff compares; they tell whether a directory was removed or added.
+    pub fn with_directories(mut self, left: &'a TreeManifest, right: &'a TreeManifest) -> Self {
+        self.trees = Some((left, right));
+        self
+    }
+
+    /// Collects `entries`, as returned by `Manifest::diff`, replacing the removed and added
+    /// entries of renamed files with a `Renamed` entry where the added file was. Other entries
+    /// keep their order.
+    pub fn detect(
+        &self,
+        entries: impl IntoIterator<Item = Result<DiffEntry>>,
+    ) -> Result<Vec<RenameDiffEntry>> {
+        let entries = entries.into_iter().collect::<Result<Vec<_>>>()?;
+        let directories = match self.trees {
+            Some((left, right)) => directory_renames(&entries, left, right)?,
+            None => Vec::new(),
+        };
+
+        // The entries of the files in renamed directories are replaced by a single entry,
+        // where the first added file was.
+        let mut result = Vec::new();
+        let mut reported = vec![false; directories.len()];
+        let mut files = Vec::new();
+        let mut positions = Vec::new();
+        for (index, entry) in entries.into_iter().enumerate() {
+            let renamed = directories
+                .iter()
+                .position(|(from, to)| match entry.diff_type {
+                    DiffType::LeftOnly(_) => is_under(&entry.path, from),
+                    DiffType::RightOnly(_) => is_under(&entry.path, to),
+                    DiffType::Changed(..) => false,
+                });
+            match renamed {
+                Some(renamed) => {
+                    if let DiffType::RightOnly(_) = entry.diff_type {
+                        if !reported[renamed] {
+                            reported[renamed] = true;
+                            let (from, to) = directories[renamed].clone();
+                            result.push((index, RenameDiffEntry::RenamedDirectory { from, to }));
+                        }
+                    }
+                }
+                None => {
+                    positions.push(index);
+                    files.push(entry);
+                }
+            }
+        }
+        for (position, entry) in self.detect_files(files)? {
+            result.push((positions[position], entry));
+        }
+        result.sort_by_key(|(index, _)| *index);
+        Ok(result.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// Pairs the removed and added files of `entries`. Returns the resulting entries with the
+    /// index of the entry they take the place of.
+    fn detect_files(&self, entries: Vec<DiffEntry>) -> Result<Vec<(usize, RenameDiffEntry)>> {
+        let mut removed_by_hgid = HashMap::new();
+        for (index, entry) in entries.iter().enumerate() {
+            if let DiffType::LeftOnly(meta) = entry.diff_type {
+                removed_by_hgid
+                    .entry(meta.hgid)
+                    .or_insert_with(VecDeque::new)
+                    .push_back(index);
+            }
+        }
+
+        // `sources[i]` is the index of the removed file that the added file `i` was renamed
+        // from.
+        let mut sources = vec![None; entries.len()];
+        let mut is_source = vec![false; entries.len()];
+        let mut unpaired = Vec::new();
+        for (index, entry) in entries.iter().enumerate() {
+            if let DiffType::RightOnly(meta) = entry.diff_type {
+                match removed_by_hgid
+                    .get_mut(&meta.hgid)
+                    .and_then(|removed| removed.pop_front())
+                {
+                    Some(source) => {
+                        sources[index] = Some(source);
+                        is_source[source] = true;
+                    }
+                    None => unpaired.push(index),
+                }
+            }
+        }
+
+        if let Some((similarity, threshold)) = self.similarity {
+            for index in unpaired {
+                let to = to_file(&entries[index]);
+                let mut best: Option<(usize, f64)> = None;
+                for (source, entry) in entries.iter().enumerate() {
+                    if is_source[source] {
+                        continue;
+                    }
+                    if let DiffType::LeftOnly(_) = entry.diff_type {
+                        let score = similarity.similarity(&from_file(entry), &to)?;
+                        let is_better = match best {
+                            Some((_, best)) => score > best,
+                            None => score >= threshold,
+                        };
+                        if is_better {
+                            best = Some((source, score));
+                        }
+                    }
+                }
+                if let Some((source, _)) = best {
+                    sources[index] = Some(source);
+                    is_source[source] = true;
+                }
+            }
+        }
+
+        let result = entries
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !is_source[*index])
+            .map(|(index, entry)| match sources[index] {
+                Some(source) => (
+                    index,
+                    RenameDiffEntry::Renamed {
+                        from: from_file(&entries[source]),
+                        to: to_file(entry),
+                    },
+                ),
+                None => (index, RenameDiffEntry::Diff(entry.clone())),
+            })
+            .collect();
+        Ok(result)
+    }
+}
+
+/// Finds the directories that are only in `left` and have the same files as a directory that
+/// is only in `right`, as `(from, to)` pairs. When a directory is renamed, its subdirectories
+/// are not reported.
+fn directory_renames(
+    entries: &[DiffEntry],
+    left: &TreeManifest,
+    right: &TreeManifest,
+) -> Result<Vec<(RepoPathBuf, RepoPathBuf)>> {
+    // The files removed and added under each directory, by path relative to the directory.
+    let mut removed: BTreeMap<RepoPathBuf, Vec<(String, FileMetadata)>> = BTreeMap::new();
+    let mut added: BTreeMap<RepoPathBuf, Vec<(String, FileMetadata)>> = BTreeMap::new();
+    for entry in entries {
+        let (directories, metadata) = match entry.diff_type {
+            DiffType::LeftOnly(metadata) => (&mut removed, metadata),
+            DiffType::RightOnly(metadata) => (&mut added, metadata),
+            DiffType::Changed(..) => continue,
+        };
+        for directory in entry.path.parents().filter(|parent| !parent.is_empty()) {
+            let relative = entry.path.as_str()[directory.as_str().len() + 1..].to_string();
+            directories
+                .entry(directory.to_owned())
+                .or_default()
+                .push((relative, metadata));
+        }
+    }
+
+    let mut added_by_files: HashMap<Vec<(String, FileMetadata)>, Vec<RepoPathBuf>> = HashMap::new();
+    for (directory, mut files) in added {
+        if !is_directory(left, &directory)? {
+            files.sort();
+            added_by_files.entry(files).or_default().push(directory);
+        }
+    }
+    let mut sources = Vec::new();
+    for (directory, mut files) in removed {
+        if !is_directory(right, &directory)? {
+            files.sort();
+            sources.push((directory, files));
+        }
+    }
+    // Parents come first, so that their subdirectories are skipped.
+    sources.sort_by_key(|(directory, _)| directory.components().count());
+
+    let mut renames: Vec<(RepoPathBuf, RepoPathBuf)> = Vec::new();
+    for (from, files) in sources {
+        if renames.iter().any(|(source, _)| is_under(&from, source)) {
+            continue;
+        }
+        if let Some(targets) = added_by_files.get_mut(&files) {
+            let target = targets
+                .iter()
+                .position(|to| !renames.iter().any(|(_, target)| is_under(to, target)));
+            if let Some(target) = target {
+                let to = targets.remove(target);
+                renames.push((from, to));
+            }
+        }
+    }
+    Ok(renames)
+}
+
+fn is_directory(tree: &TreeManifest, path: &RepoPath) -> Result<bool> {
+    match tree.get(path)? {
+        Some(FsNodeMetadata::Directory(_)) => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+fn from_file(entry: &DiffEntry) -> File {
+    File::new(entry.path.clone(), entry.diff_type.left().unwrap())
+}
+
+fn to_file(entry: &DiffEntry) -> File {
+    File::new(entry.path.clone(), entry.diff_type.right().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use manifest::Manifest;
+    use pathmatcher::AlwaysMatcher;
+    use types::testutil::*;
+
+    use crate::{
+        testutil::{make_file, make_meta, TestStore},
+        Diff, TreeManifest,
+    };
+
+    #[test]
+    fn test_exact_renames() {
+        let store = Arc::new(TestStore::new());
+        let mut left = TreeManifest::ephemeral(store.clone());
+        left.insert(repo_path_buf("a1/b1"), make_meta("10"))
+            .unwrap();
+        left.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        left.insert(repo_path_buf("a2"), make_meta("30")).unwrap();
+        let mut right = TreeManifest::ephemeral(store);
+        right
+            .insert(repo_path_buf("a3/b1"), make_meta("10"))
+            .unwrap();
+        right
+            .insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        right.insert(repo_path_buf("a4"), make_meta("40")).unwrap();
+
+        let matcher = AlwaysMatcher::new();
+        let entries = RenameDetector::new()
+            .detect(Diff::new(&left, &right, &matcher))
+            .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                RenameDiffEntry::Diff(DiffEntry::left(make_file("a2", "30"))),
+                RenameDiffEntry::Diff(DiffEntry::right(make_file("a4", "40"))),
+                RenameDiffEntry::Renamed {
+                    from: make_file("a1/b1", "10"),
+                    to: make_file("a3/b1", "10"),
+                },
+            ]
+        );
+
+        // Each removed file is the source of one rename at most.
+        let mut right = right.clone();
+        right.insert(repo_path_buf("a5"), make_meta("10")).unwrap();
+        let entries = RenameDetector::new()
+            .detect(Diff::new(&left, &right, &matcher))
+            .unwrap();
+        // The diff yields `a5` before `a3/b1`.
+        assert!(entries.contains(&RenameDiffEntry::Renamed {
+            from: make_file("a1/b1", "10"),
+            to: make_file("a5", "10"),
+        }));
+        assert!(
+            entries.contains(&RenameDiffEntry::Diff(DiffEntry::right(make_file(
+                "a3/b1", "10"
+            ))))
+        );
+    }
+
+    #[test]
+    fn test_directory_renames() {
+        let store = Arc::new(TestStore::new());
+        let mut left = TreeManifest::ephemeral(store.clone());
+        for (path, hex) in &[
+            ("a1/b1/c1", "10"),
+            ("a1/b2", "20"),
+            ("a2/b3", "30"),
+            ("a2/b4", "40"),
+            ("a3/b5", "50"),
+        ] {
+            left.insert(repo_path_buf(path), make_meta(hex)).unwrap();
+        }
+        let mut right = TreeManifest::ephemeral(store);
+        for (path, hex) in &[
+            ("a4/b1/c1", "10"),
+            ("a4/b2", "20"),
+            ("a2/b3", "30"),
+            ("a5/b4", "40"),
+            ("a6/b5", "50"),
+            ("a6/b6", "60"),
+        ] {
+            right.insert(repo_path_buf(path), make_meta(hex)).unwrap();
+        }
+
+        let matcher = AlwaysMatcher::new();
+        let entries = RenameDetector::new()
+            .with_directories(&left, &right)
+            .detect(Diff::new(&left, &right, &matcher))
+            .unwrap();
+        // `a2` is still there, and `a6` has another file.
+        assert_eq!(
+            entries,
+            vec![
+                RenameDiffEntry::RenamedDirectory {
+                    from: repo_path_buf("a1"),
+                    to: repo_path_buf("a4"),
+                },
+                RenameDiffEntry::Renamed {
+                    from: make_file("a2/b4", "40"),
+                    to: make_file("a5/b4", "40"),
+                },
+                RenameDiffEntry::Renamed {
+                    from: make_file("a3/b5", "50"),
+                    to: make_file("a6/b5", "50"),
+                },
+                RenameDiffEntry::Diff(DiffEntry::right(make_file("a6/b6", "60"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_similar_renames() {
+        let store = Arc::new(TestStore::new());
+        let mut left = TreeManifest::ephemeral(store.clone());
+        left.insert(repo_path_buf("a1"), make_meta("10")).unwrap();
+        left.insert(repo_path_buf("a2"), make_meta("20")).unwrap();
+        let mut right = TreeManifest::ephemeral(store);
+        right.insert(repo_path_buf("b1"), make_meta("11")).unwrap();
+        right.insert(repo_path_buf("b2"), make_meta("30")).unwrap();
+
+        // Files are similar when their nodes are close.
+        let similarity = |from: &File, to: &File| -> Result<f64> {
+            let distance =
+                (from.meta.hgid.as_ref()[19] as f64 - to.meta.hgid.as_ref()[19] as f64).abs();
+            Ok(1.0 / (1.0 + distance))
+        };
+        let matcher = AlwaysMatcher::new();
+        let entries = RenameDetector::new()
+            .with_similarity(&similarity, 0.5)
+            .detect(Diff::new(&left, &right, &matcher))
+            .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                RenameDiffEntry::Diff(DiffEntry::left(make_file("a2", "20"))),
+                RenameDiffEntry::Renamed {
+                    from: make_file("a1", "10"),
+                    to: make_file("b1", "11"),
+                },
+                RenameDiffEntry::Diff(DiffEntry::right(make_file("b2", "30"))),
+            ]
+        );
+    }
+}
@@ -0,0 +1,257 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::{bail, Result};
+
+use pathmatcher::{DirectoryMatch, Matcher};
+use types::{RepoPath, RepoPathBuf};
+
+use crate::is_under;
+
+/// The paths of a sparse checkout, as include and exclude rules on path prefixes.
+///
+/// A file is in the profile when it is under an include rule, or when there are no include
+/// rules, and it is not under an exclude rule. As a `Matcher`, the profile rules out the
+/// directories outside of it, so `files`, `dirs`, `diff` and `prefetch` don't load them.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SparseProfile {
+    includes: Vec<RepoPathBuf>,
+    excludes: Vec<RepoPathBuf>,
+    profiles: Vec<String>,
+}
+
+impl SparseProfile {
+    /// Parses a sparse profile in the Mercurial format:
+    ///
+    /// ```text
+    /// # Comments start with '#'.
+    /// %include other/profile
+    /// [include]
+    /// path/to/dir
+    /// [exclude]
+    /// path/to/dir/excluded
+    /// ```
+    ///
+    /// Rules before the first section are includes. Rules can have a `path:` prefix; other
+    /// kinds of patterns are rejected. The `[metadata]` section is ignored. The profiles named
+    /// by `%include` are listed by `included_profiles`, for the caller to load and `extend`
+    /// this profile with.
+    pub fn parse(text: &str) -> Result<Self> {
+        enum Section {
+            Include,
+            Exclude,
+            Metadata,
+        }
+
+        let mut profile = SparseProfile::default();
+        let mut section = Section::Include;
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("%include ") {
+                profile.profiles.push(name.trim().to_string());
+                continue;
+            }
+            match line {
+                "[include]" => section = Section::Include,
+                "[exclude]" => section = Section::Exclude,
+                "[metadata]" => section = Section::Metadata,
+                _ => {
+                    let rules = match section {
+                        Section::Include => &mut profile.includes,
+                        Section::Exclude => &mut profile.excludes,
+                        Section::Metadata => continue,
+                    };
+                    rules.push(parse_rule(line).map_err(|e| {
+                        e.context(format!("invalid sparse rule on line {}", index + 1))
+                    })?);
+                }
+            }
+        }
+        Ok(profile)
+    }
+
+    /// The names of the profiles that this profile includes with `%include`.
+    pub fn included_profiles(&self) -> &[String] {
+        &self.profiles
+    }
+
+    /// Adds the rules of `other` to this profile, as when `other` is included with `%include`.
+    pub fn extend(&mut self, other: &SparseProfile) {
+        self.includes.extend_from_slice(&other.includes);
+        self.excludes.extend_from_slice(&other.excludes);
+        self.profiles.extend_from_slice(&other.profiles);
+    }
+
+    fn is_included(&self, path: &RepoPath) -> bool {
+        self.includes.is_empty() || self.includes.iter().any(|rule| is_under(path, rule))
+    }
+
+    fn is_excluded(&self, path: &RepoPath) -> bool {
+        self.excludes.iter().any(|rule| is_under(path, rule))
+    }
+}
+
+impl Matcher for SparseProfile {
+    fn matches_directory(&self, path: &RepoPath) -> DirectoryMatch {
+        if self.is_excluded(path) {
+            return DirectoryMatch::Nothing;
+        }
+        let has_rule_below = |rules: &[RepoPathBuf]| {
+            rules
+                .iter()
+                .any(|rule| rule.as_repo_path() != path && is_under(rule, path))
+        };
+        if self.is_included(path) {
+            if has_rule_below(&self.excludes) {
+                DirectoryMatch::ShouldTraverse
+            } else {
+                DirectoryMatch::Everything
+            }
+        } else if has_rule_below(&self.includes) {
+            DirectoryMatch::ShouldTraverse
+        } else {
+            DirectoryMatch::Nothing
+        }
+    }
+
+    fn matches_file(&self, path: &RepoPath) -> bool {
+        self.is_included(path) && !self.is_excluded(path)
+    }
+}
+
+fn parse_rule(line: &str) -> Result<RepoPathBuf> {
+    let path = match line.strip_prefix("path:") {
+        Some(path) => path,
+        None if line.contains(':') || line.contains('*') => {
+            bail!("'{}' is not a path prefix", line)
+        }
+        None => line,
+    };
+    let path = path.trim_end_matches('/');
+    if path.is_empty() {
+        bail!("empty path");
+    }
+    Ok(RepoPathBuf::from_string(path.to_string())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use manifest::Manifest;
+    use types::testutil::*;
+
+    use crate::{
+        testutil::{make_file, make_meta, TestStore},
+        TreeManifest,
+    };
+
+    #[test]
+    fn test_parse() {
+        let profile = SparseProfile::parse(
+            "# comment\n\
+             %include base.sparse\n\
+             a1\n\
+             [exclude]\n\
+             a1/b1/\n\
+             path:a1/b2\n\
+             [metadata]\n\
+             title: example\n\
+             [include]\n\
+             a2/b3\n",
+        )
+        .unwrap();
+        assert_eq!(profile.included_profiles(), ["base.sparse"]);
+        assert_eq!(
+            profile.includes,
+            vec![repo_path_buf("a1"), repo_path_buf("a2/b3")]
+        );
+        assert_eq!(
+            profile.excludes,
+            vec![repo_path_buf("a1/b1"), repo_path_buf("a1/b2")]
+        );
+
+        assert!(SparseProfile::parse("glob:a1/*").is_err());
+        assert!(SparseProfile::parse("[exclude]\na1/**").is_err());
+    }
+
+    #[test]
+    fn test_matcher() {
+        let profile = SparseProfile::parse("a1\na2/b3\n[exclude]\na1/b1").unwrap();
+        assert_eq!(
+            profile.matches_directory(RepoPath::empty()),
+            DirectoryMatch::ShouldTraverse
+        );
+        assert_eq!(
+            profile.matches_directory(repo_path("a1")),
+            DirectoryMatch::ShouldTraverse
+        );
+        assert_eq!(
+            profile.matches_directory(repo_path("a1/b1")),
+            DirectoryMatch::Nothing
+        );
+        assert_eq!(
+            profile.matches_directory(repo_path("a1/b2")),
+            DirectoryMatch::Everything
+        );
+        assert_eq!(
+            profile.matches_directory(repo_path("a2")),
+            DirectoryMatch::ShouldTraverse
+        );
+        assert_eq!(
+            profile.matches_directory(repo_path("a3")),
+            DirectoryMatch::Nothing
+        );
+        assert!(profile.matches_file(repo_path("a1/b2")));
+        assert!(profile.matches_file(repo_path("a2/b3/c1")));
+        assert!(!profile.matches_file(repo_path("a1/b1/c1")));
+        assert!(!profile.matches_file(repo_path("a2/b4")));
+
+        // Without includes, everything that is not excluded is in the profile.
+        let profile = SparseProfile::parse("[exclude]\na1").unwrap();
+        assert_eq!(
+            profile.matches_directory(RepoPath::empty()),
+            DirectoryMatch::ShouldTraverse
+        );
+        assert_eq!(
+            profile.matches_directory(repo_path("a2")),
+            DirectoryMatch::Everything
+        );
+        assert!(!profile.matches_file(repo_path("a1/b1")));
+        assert!(profile.matches_file(repo_path("a2")));
+    }
+
+    #[test]
+    fn test_sparse_files() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        tree.insert(repo_path_buf("a1/b1/c1"), make_meta("10"))
+            .unwrap();
+        tree.insert(repo_path_buf("a1/b2"), make_meta("20"))
+            .unwrap();
+        tree.insert(repo_path_buf("a2/b3/c2"), make_meta("30"))
+            .unwrap();
+        tree.insert(repo_path_buf("a3/b4"), make_meta("40"))
+            .unwrap();
+        let hgid = tree.flush().unwrap();
+        let tree = TreeManifest::durable(store, hgid);
+
+        let mut profile = SparseProfile::parse("a1\n[exclude]\na1/b1").unwrap();
+        profile.extend(&SparseProfile::parse("a2/b3").unwrap());
+        assert_eq!(
+            tree.files(&profile).collect::<Result<Vec<_>>>().unwrap(),
+            vec![make_file("a1/b2", "20"), make_file("a2/b3/c2", "30")]
+        );
+        // The directories outside of the profile are not read.
+        assert_eq!(tree.fetch_stats().fetches, 4);
+    }
+}
@@ -5,13 +5,28 @@
  * GNU General Public License version 2.
  */
 
-use std::{str::from_utf8, sync::Arc};
-
-use anyhow::{format_err, Result};
+use std::{
+    borrow::Cow,
+    io,
+    str::from_utf8,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, format_err, Result};
 use bytes::{Bytes, BytesMut};
 
 use manifest::FileType;
-use types::{HgId, Key, PathComponent, PathComponentBuf, RepoPath};
+use types::{HgId, Key, PathComponent, PathComponentBuf, RepoPath, RepoPathBuf};
+
+use crate::{
+    hash::{HgTreeHasher, TreeHasher},
+    memory::MemoryTracker,
+};
 
 /// The `TreeStore` is an abstraction layer for the tree manifest that decouples how or where the
 /// data is stored. This allows more easy iteration on serialization format. It also simplifies
@@ -19,8 +34,43 @@ use types::{HgId, Key, PathComponent, PathComponentBuf, RepoPath};
 pub trait TreeStore {
     fn get(&self, path: &RepoPath, hgid: HgId) -> Result<Bytes>;
 
+    /// Store the serialized tree node `data` under `path` and `hgid`.
+    ///
+    /// The hgid is computed from the contents of the node, so inserting a node that is already
+    /// in the store must succeed without changing it: a store may keep the existing data or
+    /// overwrite it, as the bytes are the same. An inserted node should be readable with `get`
+    /// right away, but is only guaranteed to be persisted once `flush` returns.
     fn insert(&self, path: &RepoPath, hgid: HgId, data: Bytes) -> Result<()>;
 
+    /// Insert a tree node along with the nodes of its parent trees, `p1` and `p2`, which are
+    /// null when the node has fewer parents. Stores that keep trees as deltas against their
+    /// parents, like Mercurial tree manifest servers, should override this. The default
+    /// implementation ignores the parents and calls `insert`.
+    fn insert_with_parents(
+        &self,
+        path: &RepoPath,
+        hgid: HgId,
+        data: Bytes,
+        _p1: HgId,
+        _p2: HgId,
+    ) -> Result<()> {
+        self.insert(path, hgid, data)
+    }
+
+    /// Persist the nodes inserted so far. `TreeManifest::flush` calls this once it inserted all
+    /// the modified directories. The default implementation does nothing, which suits stores
+    /// that persist every insert, or keep the nodes in memory.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The scheme computing the ids of the directories written to this store. Backends identify
+    /// the same directory differently, so the ids must match the ones the backend expects. The
+    /// default is the Mercurial scheme, `HgTreeHasher`.
+    fn hasher(&self) -> &dyn TreeHasher {
+        &HgTreeHasher
+    }
+
     /// Indicate to the store that we will be attempting to access the given
     /// tree nodes soon. Some stores (especially ones that may perform network
     /// I/O) may use this information to prepare for these accesses (e.g., by
@@ -29,16 +79,216 @@ pub trait TreeStore {
     fn prefetch(&self, _keys: Vec<Key>) -> Result<()> {
         Ok(())
     }
+
+    /// Retrieve the data of multiple tree nodes at once, in the same order as `keys`. Stores
+    /// that can serve many nodes in a single round-trip should override this. The default
+    /// implementation calls `get` for every key.
+    fn get_batch(&self, keys: &[Key]) -> Result<Vec<Bytes>> {
+        keys.iter()
+            .map(|key| self.get(&key.path, key.hgid))
+            .collect()
+    }
+}
+
+/// How reads from the store are retried when they fail with a transient error (see
+/// `is_transient`). The delay between attempts starts at `initial_backoff` and doubles after
+/// every attempt, up to `max_backoff`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The number of times a read is attempted, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Reads are attempted once.
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Whether a failure to read from the store may go away when retried. I/O errors are
+/// transient, except for "not found". Other errors, like missing or malformed data, are
+/// permanent.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|error| error.kind() != io::ErrorKind::NotFound)
+}
+
+/// Notified of every tree read from the store, for example to report telemetry.
+///
+/// `latency` is the time spent in the store, including retries. The trees fetched by a single
+/// `get_batch` call are reported one by one, with the latency of the whole batch. Closures
+/// implement this trait.
+pub trait FetchObserver: Send + Sync {
+    fn on_fetch(&self, path: &RepoPath, hgid: HgId, size: usize, latency: Duration);
+}
+
+impl<F> FetchObserver for F
+where
+    F: Fn(&RepoPath, HgId, usize, Duration) + Send + Sync,
+{
+    fn on_fetch(&self, path: &RepoPath, hgid: HgId, size: usize, latency: Duration) {
+        self(path, hgid, size, latency)
+    }
+}
+
+/// The trees read from the store by a tree manifest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FetchStats {
+    /// The number of trees fetched.
+    pub fetches: u64,
+    /// The number of calls to the store, where a batch counts as one call.
+    pub requests: u64,
+    /// The size of the trees fetched, in bytes.
+    pub bytes: u64,
+    /// The time spent in the store, including retries.
+    pub latency: Duration,
+}
+
+#[derive(Default)]
+struct FetchCounters {
+    fetches: AtomicU64,
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    latency_micros: AtomicU64,
 }
 
 #[derive(Clone)]
 pub struct InnerStore {
     tree_store: Arc<dyn TreeStore + Send + Sync>,
+    retry_policy: RetryPolicy,
+    fetch_observer: Option<Arc<dyn FetchObserver>>,
+    fetch_counters: Arc<FetchCounters>,
+    memory: Arc<MemoryTracker>,
+    // Prepended to the paths of the directories, for trees rooted below the root of the store.
+    prefix: RepoPathBuf,
 }
 
 impl InnerStore {
     pub fn new(tree_store: Arc<dyn TreeStore + Send + Sync>) -> Self {
-        InnerStore { tree_store }
+        InnerStore {
+            tree_store,
+            retry_policy: RetryPolicy::default(),
+            fetch_observer: None,
+            fetch_counters: Default::default(),
+            memory: Default::default(),
+            prefix: RepoPathBuf::new(),
+        }
+    }
+
+    /// The eviction state of the tree using this store.
+    pub(crate) fn memory(&self) -> &MemoryTracker {
+        &self.memory
+    }
+
+    /// Returns a store sharing this one, for a tree rooted at `prefix`. The paths it is given are
+    /// relative to `prefix`.
+    pub fn with_prefix(&self, prefix: &RepoPath) -> Self {
+        let mut store = self.clone();
+        if !prefix.is_empty() {
+            store.prefix.push(prefix);
+        }
+        store
+    }
+
+    fn store_path<'a>(&self, path: &'a RepoPath) -> Cow<'a, RepoPath> {
+        if self.prefix.is_empty() {
+            Cow::Borrowed(path)
+        } else if path.is_empty() {
+            Cow::Owned(self.prefix.clone())
+        } else {
+            let mut store_path = self.prefix.clone();
+            store_path.push(path);
+            Cow::Owned(store_path)
+        }
+    }
+
+    fn store_keys<'a>(&self, keys: &'a [Key]) -> Cow<'a, [Key]> {
+        if self.prefix.is_empty() {
+            Cow::Borrowed(keys)
+        } else {
+            let keys = keys
+                .iter()
+                .map(|key| Key::new(self.store_path(&key.path).into_owned(), key.hgid))
+                .collect::<Vec<_>>();
+            Cow::Owned(keys)
+        }
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn set_fetch_observer(&mut self, fetch_observer: Arc<dyn FetchObserver>) {
+        self.fetch_observer = Some(fetch_observer);
+    }
+
+    pub fn fetch_stats(&self) -> FetchStats {
+        let counters = &self.fetch_counters;
+        FetchStats {
+            fetches: counters.fetches.load(Ordering::Relaxed),
+            requests: counters.requests.load(Ordering::Relaxed),
+            bytes: counters.bytes.load(Ordering::Relaxed),
+            latency: Duration::from_micros(counters.latency_micros.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn record_fetches<'a>(
+        &self,
+        fetched: impl IntoIterator<Item = (&'a RepoPath, HgId, usize)>,
+        latency: Duration,
+    ) {
+        let counters = &self.fetch_counters;
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        counters
+            .latency_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        for (path, hgid, size) in fetched {
+            counters.fetches.fetch_add(1, Ordering::Relaxed);
+            counters.bytes.fetch_add(size as u64, Ordering::Relaxed);
+            if let Some(observer) = &self.fetch_observer {
+                observer.on_fetch(path, hgid, size, latency);
+            }
+        }
+    }
+
+    fn retry<T>(&self, mut read: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match read() {
+                Err(e) if attempt < self.retry_policy.max_attempts && is_transient(&e) => {
+                    let backoff = self.retry_policy.backoff(attempt);
+                    tracing::debug!(attempt, ?backoff, "retrying tree read: {:?}", e);
+                    thread::sleep(backoff);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
     }
 
     pub fn get_entry(&self, path: &RepoPath, hgid: HgId) -> Result<Entry> {
@@ -47,22 +297,81 @@ impl InnerStore {
             id = AsRef::<str>::as_ref(&hgid.to_hex())
         )
         .in_scope(|| {
-            let bytes = self.tree_store.get(path, hgid)?;
+            let path = self.store_path(path);
+            let start = Instant::now();
+            let bytes = self.retry(|| self.tree_store.get(&path, hgid))?;
+            self.record_fetches(Some((path.as_ref(), hgid, bytes.len())), start.elapsed());
             Ok(Entry(bytes))
         })
     }
 
+    pub fn get_entry_batch(&self, keys: &[Key]) -> Result<Vec<Entry>> {
+        tracing::debug_span!("tree::store::get_batch", count = keys.len()).in_scope(|| {
+            let keys = self.store_keys(keys);
+            let start = Instant::now();
+            let data = self.retry(|| self.tree_store.get_batch(&keys))?;
+            if data.len() != keys.len() {
+                bail!(
+                    "store returned {} tree entries for {} keys",
+                    data.len(),
+                    keys.len()
+                );
+            }
+            let fetched = keys
+                .iter()
+                .zip(&data)
+                .map(|(key, bytes)| (key.path.as_repo_path(), key.hgid, bytes.len()));
+            self.record_fetches(fetched, start.elapsed());
+            Ok(data.into_iter().map(Entry).collect())
+        })
+    }
+
     pub fn insert_entry(&self, path: &RepoPath, hgid: HgId, entry: Entry) -> Result<()> {
+        let path = self.store_path(path);
+        tracing::debug_span!(
+            "tree::store::insert",
+            path = path.as_str(),
+            id = AsRef::<str>::as_ref(&hgid.to_hex())
+        )
+        .in_scope(|| self.tree_store.insert(&path, hgid, entry.0))
+    }
+
+    pub fn insert_entry_with_parents(
+        &self,
+        path: &RepoPath,
+        hgid: HgId,
+        entry: Entry,
+        p1: HgId,
+        p2: HgId,
+    ) -> Result<()> {
+        let path = self.store_path(path);
         tracing::debug_span!(
             "tree::store::insert",
             path = path.as_str(),
             id = AsRef::<str>::as_ref(&hgid.to_hex())
         )
-        .in_scope(|| self.tree_store.insert(path, hgid, entry.0))
+        .in_scope(|| {
+            self.tree_store
+                .insert_with_parents(&path, hgid, entry.0, p1, p2)
+        })
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        tracing::debug_span!("tree::store::flush").in_scope(|| self.tree_store.flush())
+    }
+
+    pub fn hasher(&self) -> &dyn TreeHasher {
+        self.tree_store.hasher()
     }
 
     pub fn prefetch(&self, keys: impl IntoIterator<Item = Key>) -> Result<()> {
-        let keys: Vec<Key> = keys.into_iter().collect();
+        let keys: Vec<Key> = keys
+            .into_iter()
+            .map(|key| match self.store_path(&key.path) {
+                Cow::Borrowed(_) => key,
+                Cow::Owned(path) => Key::new(path, key.hgid),
+            })
+            .collect();
         tracing::debug_span!(
             "tree::store::prefetch",
             ids = {
@@ -80,22 +389,30 @@ impl InnerStore {
 ///
 /// The ABNF specification for the current serialization is:
 /// Entry         = 1*( Element LF )
-/// Element       = PathComponent %x00 HgId [ Flag ]
+/// Element       = PathComponent %x00 HgId [ Flag ] [ AuxData ]
 /// Flag          = %s"x" / %s"l" / %s"t"
 /// PathComponent = 1*( %x01-%x09 / %x0B-%xFF )
 /// HgId          = 40HEXDIG
+/// AuxData       = %x00 AuxVersion %x00 [ Size ] %x00 [ Sha256 ]
+/// AuxVersion    = %s"1"
+/// Size          = 1*DIGIT
+/// Sha256        = 64HEXDIG
 ///
 /// In this case an `Entry` is equivalent to the contents of a directory. The elements of the
 /// directory are described by `Element`. `Entry` is a list of serialized `Element`s that are
 /// separated by `\n`. An `Element` will always have a name (`PathComponent`) and a hash (`HgId`).
 /// `Elements` may be different types of files or they can be directories. The type of element is
 /// described by the flag or the absence of the flag. When the flag is missing we have a regular
-/// file, the various flag options are: `x` for executable, `l` for symlink and `d` for directory.
+/// file, the various flag options are: `x` for executable, `l` for symlink and `t` for directory.
 /// It should be noted that Nodes are represented in their hex format rather than a straight up
 /// binary format so they are 40 characters long rather than 20 bytes.
 /// Check the documentation of the `PathComponent` struct for more details about it's
 /// representation. For this serialization format it is important that they don't contain
 /// `\0` or `\n`.
+/// Stores may add the size and the SHA-256 of the content of files in `AuxData`. Mercurial
+/// doesn't write it, and it is not part of the entries written by the tree, so it doesn't change
+/// the hash of directories. `AuxData` starts with a version so that a reader that doesn't know
+/// the layout of the fields rejects the element with a clear error instead of misparsing it.
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Entry(Bytes);
 
@@ -103,11 +420,17 @@ pub struct EntryMut(BytesMut);
 
 /// The `Element` is a parsed element of a directory. Directory elements are either files either
 /// direcotries. The type of element is signaled by `Flag`.
+///
+/// `size` and `content_sha256` come from the optional `AuxData` of the element. They are only
+/// carried while reading: when the tree is flushed, the directories that are rewritten are
+/// serialized from the name, hgid and flag of their children, so their aux data is dropped.
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Element {
     pub component: PathComponentBuf,
     pub hgid: HgId,
     pub flag: Flag,
+    pub size: Option<u64>,
+    pub content_sha256: Option<[u8; 32]>,
 }
 
 /// Used to signal the type of element in a directory: file or directory.
@@ -137,6 +460,32 @@ impl Entry {
         Ok(Entry(underlying.freeze()))
     }
 
+    /// Parses a directory in the serialization format described above, as it is sent on the
+    /// wire and stored by Mercurial. Every element is validated, and the elements must be
+    /// sorted by name without duplicates so that the hash of the directory is well defined.
+    pub fn parse(bytes: Bytes) -> Result<Entry> {
+        let entry = Entry(bytes);
+        let mut previous: Option<PathComponentBuf> = None;
+        for element in entry.elements() {
+            let element = element?;
+            if let Some(previous) = &previous {
+                if previous >= &element.component {
+                    bail!(
+                        "tree manifest entry is not sorted: '{}' is followed by '{}'",
+                        previous,
+                        element.component
+                    );
+                }
+            }
+            previous = Some(element.component);
+        }
+        Ok(entry)
+    }
+
+    pub(crate) fn from_bytes(bytes: Bytes) -> Entry {
+        Entry(bytes)
+    }
+
     // used in tests, finalize and subtree_diff
     pub fn to_bytes(self) -> Bytes {
         self.0
@@ -197,12 +546,17 @@ impl<'a> Iterator for Elements<'a> {
     }
 }
 
+/// The version of the `AuxData` layout written by `Element::to_byte_vec`.
+const AUX_DATA_VERSION: &str = "1";
+
 impl Element {
     pub fn new(component: PathComponentBuf, hgid: HgId, flag: Flag) -> Element {
         Element {
             component,
             hgid,
             flag,
+            size: None,
+            content_sha256: None,
         }
     }
 
@@ -212,30 +566,69 @@ impl Element {
             None => return Err(format_err!("did not find path delimiter")),
         };
         let component = PathComponent::from_utf8(&byte_slice[..path_len])?.to_owned();
-        if path_len + HgId::hex_len() > byte_slice.len() {
+        if path_len + 1 + HgId::hex_len() > byte_slice.len() {
             return Err(format_err!("hgid length is shorter than expected"));
         }
-        if byte_slice.len() > path_len + HgId::hex_len() + 2 {
+        let rest = &byte_slice[path_len + HgId::hex_len() + 1..];
+        let (flag, aux) = match rest.iter().position(|&x| x == b'\0') {
+            Some(position) => (&rest[..position], Some(&rest[position + 1..])),
+            None => (rest, None),
+        };
+        if flag.len() > 1 {
             return Err(format_err!("entry longer than expected"));
         }
         // TODO: We don't need this conversion to string
         let utf8_parsed = from_utf8(&byte_slice[path_len + 1..path_len + HgId::hex_len() + 1])?;
         let hgid = HgId::from_str(utf8_parsed)?;
-        let flag = match byte_slice.get(path_len + HgId::hex_len() + 1) {
+        let flag = match flag.first() {
             None => Flag::File(FileType::Regular),
             Some(b'x') => Flag::File(FileType::Executable),
             Some(b'l') => Flag::File(FileType::Symlink),
             Some(b't') => Flag::Directory,
             Some(bad_flag) => return Err(format_err!("invalid flag {}", bad_flag)),
         };
-        let element = Element {
-            component,
-            hgid,
-            flag,
-        };
+        let mut element = Element::new(component, hgid, flag);
+        if let Some(aux) = aux {
+            element.parse_aux(aux)?;
+        }
         Ok(element)
     }
 
+    fn parse_aux(&mut self, aux: &[u8]) -> Result<()> {
+        if self.flag == Flag::Directory {
+            return Err(format_err!("directory '{}' has file data", self.component));
+        }
+        let aux = from_utf8(aux)?;
+        let (version, aux) = match aux.find('\0') {
+            Some(position) => (&aux[..position], &aux[position + 1..]),
+            None => return Err(format_err!("did not find file data version")),
+        };
+        if version != AUX_DATA_VERSION {
+            return Err(format_err!("unsupported file data version {:?}", version));
+        }
+        let (size, sha256) = match aux.find('\0') {
+            Some(position) => (&aux[..position], &aux[position + 1..]),
+            None => return Err(format_err!("did not find file data delimiter")),
+        };
+        if !size.is_empty() {
+            if !size.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(format_err!("invalid file size {:?}", size));
+            }
+            self.size = Some(size.parse()?);
+        }
+        if !sha256.is_empty() {
+            if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(format_err!("invalid file content hash {:?}", sha256));
+            }
+            let mut content_sha256 = [0; 32];
+            for (i, byte) in content_sha256.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&sha256[i * 2..i * 2 + 2], 16)?;
+            }
+            self.content_sha256 = Some(content_sha256);
+        }
+        Ok(())
+    }
+
     fn to_byte_vec(&self) -> Vec<u8> {
         let component = self.component.as_byte_slice();
         // TODO: benchmark taking a buffer as a parameter
@@ -253,6 +646,20 @@ impl Element {
         if let Some(byte) = flag {
             buffer.push(byte);
         }
+        if self.size.is_some() || self.content_sha256.is_some() {
+            buffer.push(0);
+            buffer.extend_from_slice(AUX_DATA_VERSION.as_bytes());
+            buffer.push(0);
+            if let Some(size) = self.size {
+                buffer.extend_from_slice(size.to_string().as_bytes());
+            }
+            buffer.push(0);
+            if let Some(content_sha256) = &self.content_sha256 {
+                for byte in content_sha256 {
+                    buffer.extend_from_slice(format!("{:02x}", byte).as_bytes());
+                }
+            }
+        }
         buffer
     }
 }
@@ -307,6 +714,15 @@ mod tests {
         assert!(Element::from_byte_slice(&buffer).is_err());
     }
 
+    #[test]
+    fn test_element_truncated_hgid() {
+        let hex = hgid("1").to_hex();
+        let byte_slice = format!("a\0{}", &hex[..HgId::hex_len() - 1]);
+        assert!(Element::from_byte_slice(byte_slice.as_bytes()).is_err());
+        let entry = format!("a\0{}\n", &hex[..HgId::hex_len() - 1]);
+        assert!(Entry::parse(Bytes::from(entry)).is_err());
+    }
+
     #[test]
     fn test_roundtrip_serialization_on_directory() {
         let component = PathComponentBuf::from_string(String::from("c")).unwrap();
@@ -319,6 +735,34 @@ mod tests {
         assert_eq!(buffer.to_vec(), byte_slice.to_vec());
     }
 
+    #[test]
+    fn test_element_aux_data() {
+        let sha256 = format!("{:064x}", 0x1f);
+        let byte_slice = format!("a\0{}x\01\0{}\0{}", hgid("1"), 12, sha256);
+        let element = Element::from_byte_slice(byte_slice.as_bytes()).unwrap();
+        let mut content_sha256 = [0; 32];
+        content_sha256[31] = 0x1f;
+        assert_eq!(element.flag, Flag::File(FileType::Executable));
+        assert_eq!(element.size, Some(12));
+        assert_eq!(element.content_sha256, Some(content_sha256));
+        assert_eq!(element.to_byte_vec(), byte_slice.as_bytes());
+
+        let byte_slice = format!("a\0{}\01\0{}\0", hgid("1"), 12);
+        let element = Element::from_byte_slice(byte_slice.as_bytes()).unwrap();
+        assert_eq!(element.size, Some(12));
+        assert_eq!(element.content_sha256, None);
+        assert_eq!(element.to_byte_vec(), byte_slice.as_bytes());
+
+        let parse = |s: String| Element::from_byte_slice(s.as_bytes());
+        assert!(parse(format!("a\0{}t\01\0{}\0", hgid("1"), 12)).is_err());
+        assert!(parse(format!("a\0{}\01\0-1\0", hgid("1"))).is_err());
+        assert!(parse(format!("a\0{}\01\0\0{}", hgid("1"), &sha256[1..])).is_err());
+        // Elements without a version or with an unknown one are rejected.
+        assert!(parse(format!("a\0{}\0{}\0", hgid("1"), 12)).is_err());
+        let err = parse(format!("a\0{}\02\0{}\0", hgid("1"), 12)).unwrap_err();
+        assert!(err.to_string().contains("unsupported file data version"));
+    }
+
     quickcheck! {
         fn test_rountrip_serialization(
             component: PathComponentBuf,
@@ -334,4 +778,53 @@ mod tests {
             Element::from_byte_slice(&buffer).unwrap() == element
         }
     }
+
+    #[test]
+    fn test_entry_parse() {
+        let foo = format!("foo\0{}x\n", hgid("1"));
+        let bar = format!("bar\0{}t\n", hgid("2"));
+        let entry = Entry::parse(Bytes::from(format!("{}{}", bar, foo))).unwrap();
+        assert_eq!(
+            entry.elements().collect::<Result<Vec<_>>>().unwrap(),
+            vec![
+                Element::new(path_component_buf("bar"), hgid("2"), Flag::Directory),
+                Element::new(
+                    path_component_buf("foo"),
+                    hgid("1"),
+                    Flag::File(FileType::Executable)
+                ),
+            ]
+        );
+        assert_eq!(
+            Entry::from_elements(entry.elements()).unwrap(),
+            entry.clone()
+        );
+        assert_eq!(entry.to_bytes(), format!("{}{}", bar, foo).as_bytes());
+
+        assert!(Entry::parse(Bytes::new()).is_ok());
+        assert!(Entry::parse(Bytes::from(format!("{}{}", foo, bar))).is_err());
+        assert!(Entry::parse(Bytes::from(format!("{}{}", foo, foo))).is_err());
+        assert!(Entry::parse(Bytes::from(foo.trim_end().to_string())).is_err());
+        assert!(Entry::parse(Bytes::from(format!("foo\0{}q\n", hgid("1")))).is_err());
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        };
+        let backoffs: Vec<_> = (1..5).map(|attempt| policy.backoff(attempt)).collect();
+        assert_eq!(
+            backoffs,
+            vec![
+                Duration::from_millis(100),
+                Duration::from_millis(200),
+                Duration::from_millis(400),
+                Duration::from_millis(500)
+            ]
+        );
+        assert_eq!(policy.backoff(100), Duration::from_millis(500));
+    }
 }
@@ -58,6 +58,9 @@ pub(crate) fn make_tree<'a>(
 pub struct TestStore {
     entries: RwLock<HashMap<RepoPathBuf, HashMap<HgId, Bytes>>>,
     pub prefetched: Mutex<Vec<Vec<Key>>>,
+    pub batches: Mutex<Vec<Vec<Key>>>,
+    /// The number of entries in the store after each `flush`.
+    pub flushed: Mutex<Vec<usize>>,
 }
 
 impl TestStore {
@@ -65,6 +68,8 @@ impl TestStore {
         TestStore {
             entries: RwLock::new(HashMap::new()),
             prefetched: Mutex::new(Vec::new()),
+            batches: Mutex::new(Vec::new()),
+            flushed: Mutex::new(Vec::new()),
         }
     }
 
@@ -72,6 +77,16 @@ impl TestStore {
     pub fn fetches(&self) -> Vec<Vec<Key>> {
         self.prefetched.lock().clone()
     }
+
+    #[allow(unused)]
+    pub fn batches(&self) -> Vec<Vec<Key>> {
+        self.batches.lock().clone()
+    }
+
+    #[allow(unused)]
+    pub fn flushes(&self) -> Vec<usize> {
+        self.flushed.lock().clone()
+    }
 }
 
 impl TreeStore for TestStore {
@@ -93,8 +108,26 @@ impl TreeStore for TestStore {
         Ok(())
     }
 
+    fn flush(&self) -> Result<()> {
+        let count = self
+            .entries
+            .read()
+            .values()
+            .map(|entries| entries.len())
+            .sum();
+        self.flushed.lock().push(count);
+        Ok(())
+    }
+
     fn prefetch(&self, keys: Vec<Key>) -> Result<()> {
         self.prefetched.lock().push(keys);
         Ok(())
     }
+
+    fn get_batch(&self, keys: &[Key]) -> Result<Vec<Bytes>> {
+        self.batches.lock().push(keys.to_vec());
+        keys.iter()
+            .map(|key| self.get(&key.path, key.hgid))
+            .collect()
+    }
 }
@@ -0,0 +1,72 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::BTreeSet;
+
+use pathmatcher::{DirectoryMatch, Matcher};
+use types::{RepoPath, RepoPathBuf};
+
+/// A set of directories, like the directories affected by changes to some files.
+///
+/// As a [`Matcher`], it selects the files directly in the directories of the set, so a
+/// traversal only visits those directories. Build it with [`dirs_of`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DirSet {
+    dirs: BTreeSet<RepoPathBuf>,
+}
+
+/// Returns the directories containing `files`, and all their ancestors, including the root.
+pub fn dirs_of<'a>(files: impl IntoIterator<Item = &'a RepoPath>) -> DirSet {
+    let mut dirs = BTreeSet::new();
+    for file in files {
+        let mut dir = file.parent();
+        while let Some(path) = dir {
+            // The ancestors of a directory in the set are already in the set.
+            if !dirs.insert(path.to_owned()) {
+                break;
+            }
+            dir = path.parent();
+        }
+    }
+    DirSet { dirs }
+}
+
+impl DirSet {
+    pub fn contains(&self, dir: &RepoPath) -> bool {
+        self.dirs.contains(dir)
+    }
+
+    pub fn len(&self) -> usize {
+        self.dirs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirs.is_empty()
+    }
+
+    /// Returns the directories in sorted order. A directory comes before its subdirectories.
+    pub fn iter(&self) -> impl Iterator<Item = &RepoPath> {
+        self.dirs.iter().map(|dir| dir.as_repo_path())
+    }
+}
+
+impl Matcher for DirSet {
+    fn matches_directory(&self, path: &RepoPath) -> DirectoryMatch {
+        if self.contains(path) {
+            DirectoryMatch::ShouldTraverse
+        } else {
+            DirectoryMatch::Nothing
+        }
+    }
+
+    fn matches_file(&self, path: &RepoPath) -> bool {
+        match path.parent() {
+            Some(dir) => self.contains(dir),
+            None => false,
+        }
+    }
+}
@@ -12,11 +12,18 @@
 //! repository. The file path and file revision are then used to retrieve the contents of the
 //! file thus achieving the reconstruction of the entire repository state.
 
+mod dirset;
+
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+
 use anyhow::Result;
 
 use pathmatcher::Matcher;
 use types::{HgId, PathComponentBuf, RepoPath, RepoPathBuf};
 
+pub use crate::dirset::{dirs_of, DirSet};
+
 /// Manifest describes a mapping between file path ([`String`]) and file metadata ([`FileMetadata`]).
 /// Fundamentally it is just a Map<file_path, file_metadata>.
 ///
@@ -71,6 +78,17 @@ pub trait Manifest {
         Ok(result)
     }
 
+    /// Retrieve the Directory at a path. The identifier of the directory is returned when the
+    /// directory is persisted and unchanged.
+    /// Paths that are not directories return None.
+    fn get_dir(&self, dir_path: &RepoPath) -> Result<Option<Directory>> {
+        let result = self.get(dir_path)?.and_then(|fs_hgid| match fs_hgid {
+            FsNodeMetadata::Directory(hgid) => Some(Directory::new(dir_path.to_owned(), hgid)),
+            FsNodeMetadata::File(_) => None,
+        });
+        Ok(result)
+    }
+
     /// Returns an iterator over all the files in the Manifest that satisfy the given Matcher.
     fn files<'a, M: Matcher>(
         &'a self,
@@ -153,10 +171,18 @@ impl File {
 /// The contents of the Manifest for a file.
 /// * hgid: used to determine the revision of the file in the repository.
 /// * file_type: determines the type of the file.
-#[derive(Clone, Copy, Debug, Default, Ord, PartialOrd, Eq, PartialEq, Hash)]
+/// * size: the size of the file content in bytes, when the store provides it.
+/// * content_sha256: the SHA-256 of the file content, when the store provides it.
+///
+/// The size and the content hash are derived from the content that `hgid` identifies, so they
+/// are ignored when comparing and hashing `FileMetadata`. Knowing them or not doesn't make two
+/// revisions of a file different.
+#[derive(Clone, Copy, Debug, Default)]
 pub struct FileMetadata {
     pub hgid: HgId,
     pub file_type: FileType,
+    pub size: Option<u64>,
+    pub content_sha256: Option<[u8; 32]>,
 }
 
 /// The types of files that are supported.
@@ -184,7 +210,12 @@ impl Default for FileType {
 
 impl FileMetadata {
     pub fn new(hgid: HgId, file_type: FileType) -> Self {
-        Self { hgid, file_type }
+        Self {
+            hgid,
+            file_type,
+            size: None,
+            content_sha256: None,
+        }
     }
 
     /// Creates `FileMetadata` with file_type set to `FileType::Regular`.
@@ -201,6 +232,48 @@ impl FileMetadata {
     pub fn symlink(hgid: HgId) -> Self {
         Self::new(hgid, FileType::Symlink)
     }
+
+    /// Sets the size of the file content.
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Sets the SHA-256 of the file content.
+    pub fn with_content_sha256(mut self, content_sha256: [u8; 32]) -> Self {
+        self.content_sha256 = Some(content_sha256);
+        self
+    }
+
+    fn key(&self) -> (&HgId, &FileType) {
+        (&self.hgid, &self.file_type)
+    }
+}
+
+impl PartialEq for FileMetadata {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for FileMetadata {}
+
+impl PartialOrd for FileMetadata {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FileMetadata {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl Hash for FileMetadata {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
 }
 
 /// Represents a file that is different between two tree manifests.
@@ -0,0 +1,449 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::Result;
+
+use types::RepoPathBuf;
+
+use crate::parse::{split_lines, FilePatch, FileType, Hunk, Line};
+use crate::target::PatchTarget;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ApplyOpts {
+    /// Maximum number of context lines that can be ignored at the start and
+    /// at the end of a hunk when it does not apply as is.
+    pub fuzz: usize,
+    /// Write files even if some of their hunks are rejected.
+    pub partial: bool,
+}
+
+impl Default for ApplyOpts {
+    fn default() -> Self {
+        ApplyOpts {
+            fuzz: 2,
+            partial: false,
+        }
+    }
+}
+
+/// How a hunk was applied.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HunkStatus {
+    /// The hunk was applied `offset` lines away from the position in its
+    /// header, ignoring `fuzz` context lines at its start and end.
+    Applied { offset: isize, fuzz: usize },
+    /// The hunk could not be applied.
+    Rejected,
+}
+
+/// The result of applying hunks to some content.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Applied {
+    /// The content with all the hunks that could be applied.
+    pub content: Vec<u8>,
+    /// The status of each hunk, in the order of the patch.
+    pub hunks: Vec<HunkStatus>,
+}
+
+impl Applied {
+    /// Returns `true` if no hunk was rejected.
+    pub fn is_clean(&self) -> bool {
+        self.hunks.iter().all(|h| *h != HunkStatus::Rejected)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FileResult {
+    /// The path of the file after the patch, or before if it was removed.
+    pub path: RepoPathBuf,
+    pub status: FileStatus,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum FileStatus {
+    /// All the hunks were applied.
+    Applied(Vec<HunkStatus>),
+    /// Some hunks were rejected. The file was only written if
+    /// `ApplyOpts::partial` is set.
+    Rejected(Vec<HunkStatus>),
+    /// The file to patch does not exist.
+    Missing,
+    /// The file to add already exists.
+    Exists,
+    /// The patch changes binary content, which is not supported.
+    Binary,
+}
+
+/// Apply `hunks` to `content`.
+///
+/// A hunk that does not match at the position in its header is searched for
+/// at the nearest position after the previous hunk. If it is not found, up to
+/// `fuzz` context lines are ignored at its start and end. Hunks that still
+/// can't be found are rejected, the other hunks are still applied.
+///
+/// # Example
+/// ```
+/// use patch::{apply_hunks, parse, HunkStatus};
+/// let patch = parse(b"--- a/x\n+++ b/x\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n").unwrap();
+/// let applied = apply_hunks(b"z\na\nb\n", &patch[0].hunks, 0);
+/// assert_eq!(applied.content, b"z\na\nc\n");
+/// assert_eq!(applied.hunks, [HunkStatus::Applied { offset: 1, fuzz: 0 }]);
+/// ```
+pub fn apply_hunks(content: &[u8], hunks: &[Hunk], fuzz: usize) -> Applied {
+    let lines = split_lines(content);
+    let mut result = Vec::with_capacity(content.len());
+    let mut statuses = Vec::with_capacity(hunks.len());
+    // Position in `lines` after the last applied hunk.
+    let mut cursor = 0;
+    // Difference between the actual and expected positions of the last applied hunk.
+    let mut offset: isize = 0;
+
+    for hunk in hunks {
+        let matched = (0..=fuzz).find_map(|fuzz| {
+            let (lead, trail) = fuzz_context(hunk, fuzz)?;
+            let old = hunk.old_lines().collect::<Vec<_>>();
+            let pattern = &old[lead..old.len() - trail];
+            // Pure insertions are positioned after their start line.
+            let expected = if hunk.old_len == 0 {
+                hunk.old_start
+            } else {
+                hunk.old_start.saturating_sub(1)
+            } + lead;
+            let pos = find(&lines, pattern, cursor, expected as isize + offset)?;
+            Some((pos, lead, trail, fuzz, expected))
+        });
+        let (pos, lead, trail, fuzz, expected) = match matched {
+            Some(matched) => matched,
+            None => {
+                statuses.push(HunkStatus::Rejected);
+                continue;
+            }
+        };
+
+        lines[cursor..pos]
+            .iter()
+            .for_each(|l| result.extend_from_slice(l));
+        // Skip the ignored context lines, they are left untouched in the file.
+        let contexts = hunk
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| matches!(l, Line::Context(_)))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let start = if lead > 0 { contexts[lead - 1] + 1 } else { 0 };
+        let end = if trail > 0 {
+            contexts[contexts.len() - trail]
+        } else {
+            hunk.lines.len()
+        };
+        let mut old_pos = pos;
+        for line in &hunk.lines[start..end] {
+            match line {
+                Line::Context(_) => {
+                    result.extend_from_slice(lines[old_pos]);
+                    old_pos += 1;
+                }
+                Line::Remove(_) => old_pos += 1,
+                Line::Add(text) => result.extend_from_slice(text),
+            }
+        }
+
+        cursor = old_pos;
+        offset = pos as isize - expected as isize;
+        statuses.push(HunkStatus::Applied { offset, fuzz });
+    }
+
+    lines[cursor..]
+        .iter()
+        .for_each(|l| result.extend_from_slice(l));
+    Applied {
+        content: result,
+        hunks: statuses,
+    }
+}
+
+/// Number of context lines to ignore at the start and end of `hunk` for the
+/// given amount of `fuzz`. Returns `None` if that ignores no more lines than
+/// a smaller amount of fuzz.
+fn fuzz_context(hunk: &Hunk, fuzz: usize) -> Option<(usize, usize)> {
+    let is_context = |l: &&Line| matches!(l, Line::Context(_));
+    let lead = hunk.lines.iter().take_while(is_context).count();
+    let trail = hunk.lines.iter().rev().take_while(is_context).count();
+    // A hunk with only context lines has all of them counted twice.
+    let (lead, trail) = if lead == hunk.lines.len() {
+        (lead, 0)
+    } else {
+        (lead, trail)
+    };
+    if fuzz > 0 && fuzz > lead && fuzz > trail {
+        return None;
+    }
+    Some((lead.min(fuzz), trail.min(fuzz)))
+}
+
+/// Find `pattern` in `lines` at or after `start`, as close as possible to `expected`.
+fn find(lines: &[&[u8]], pattern: &[&[u8]], start: usize, expected: isize) -> Option<usize> {
+    let last = lines.len().checked_sub(pattern.len())?;
+    if start > last {
+        return None;
+    }
+    let expected = expected.max(start as isize).min(last as isize) as usize;
+    let matches = |pos: usize| lines[pos..pos + pattern.len()] == *pattern;
+    for distance in 0..=(last - start) {
+        if expected + distance <= last && matches(expected + distance) {
+            return Some(expected + distance);
+        }
+        if distance > 0 && expected >= start + distance && matches(expected - distance) {
+            return Some(expected - distance);
+        }
+        if expected + distance > last && expected < start + distance {
+            break;
+        }
+    }
+    None
+}
+
+/// Apply `patches` to the files of `target`.
+///
+/// Each file is patched independently: a file with rejected hunks does not
+/// prevent other files from being patched.
+pub fn apply_patch(
+    target: &mut dyn PatchTarget,
+    patches: &[FilePatch],
+    opts: &ApplyOpts,
+) -> Result<Vec<FileResult>> {
+    patches
+        .iter()
+        .filter_map(|patch| {
+            let path = patch.new_path.as_ref().or(patch.old_path.as_ref())?;
+            let status = apply_file(target, patch, opts);
+            Some(status.map(|status| FileResult {
+                path: path.clone(),
+                status,
+            }))
+        })
+        .collect()
+}
+
+fn apply_file(
+    target: &mut dyn PatchTarget,
+    patch: &FilePatch,
+    opts: &ApplyOpts,
+) -> Result<FileStatus> {
+    if patch.binary {
+        return Ok(FileStatus::Binary);
+    }
+
+    let (old_content, old_type) = match &patch.old_path {
+        None => {
+            if let Some(new_path) = &patch.new_path {
+                if target.read(new_path)?.is_some() {
+                    return Ok(FileStatus::Exists);
+                }
+            }
+            (Vec::new(), FileType::Regular)
+        }
+        Some(old_path) => match target.read(old_path)? {
+            None => return Ok(FileStatus::Missing),
+            Some(file) => file,
+        },
+    };
+
+    let applied = apply_hunks(&old_content, &patch.hunks, opts.fuzz);
+    if !applied.is_clean() && !opts.partial {
+        return Ok(FileStatus::Rejected(applied.hunks));
+    }
+
+    match &patch.new_path {
+        None => {
+            if let Some(old_path) = &patch.old_path {
+                target.remove(old_path)?;
+            }
+        }
+        Some(new_path) => {
+            let file_type = patch.new_type.unwrap_or(old_type);
+            target.write(new_path, &applied.content, file_type)?;
+            if let Some(old_path) = &patch.old_path {
+                if old_path != new_path && !patch.copy {
+                    target.remove(old_path)?;
+                }
+            }
+        }
+    }
+
+    if applied.is_clean() {
+        Ok(FileStatus::Applied(applied.hunks))
+    } else {
+        Ok(FileStatus::Rejected(applied.hunks))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use types::testutil::*;
+
+    use crate::parse::parse;
+    use crate::target::MemoryTarget;
+
+    fn hunks(patch: &str) -> Vec<Hunk> {
+        let text = format!("--- a/x\n+++ b/x\n{}", patch);
+        parse(text.as_bytes()).unwrap().remove(0).hunks
+    }
+
+    #[test]
+    fn test_apply_hunks() {
+        let hunks = hunks("@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n@@ -5,0 +6,1 @@\n+f\n");
+        let applied = apply_hunks(b"a\nb\nc\nd\ne\n", &hunks, 0);
+        assert_eq!(applied.content, b"a\nB\nc\nd\ne\nf\n");
+        assert!(applied.is_clean());
+
+        // The hunks are found at an offset.
+        let applied = apply_hunks(b"0\na\nb\nc\nd\ne\n", &hunks, 0);
+        assert_eq!(applied.content, b"0\na\nB\nc\nd\ne\nf\n");
+        assert_eq!(
+            applied.hunks,
+            [
+                HunkStatus::Applied { offset: 1, fuzz: 0 },
+                HunkStatus::Applied { offset: 1, fuzz: 0 }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_hunks_fuzz() {
+        let hunks = hunks("@@ -1,5 +1,5 @@\n a\n b\n-c\n+C\n d\n e\n");
+        let content = b"a\nB\nc\nd\nE\n";
+        let applied = apply_hunks(content, &hunks, 1);
+        assert_eq!(applied.hunks, [HunkStatus::Rejected]);
+        assert_eq!(applied.content, content);
+
+        let applied = apply_hunks(content, &hunks, 2);
+        assert_eq!(applied.hunks, [HunkStatus::Applied { offset: 0, fuzz: 2 }]);
+        assert_eq!(applied.content, b"a\nB\nC\nd\nE\n");
+    }
+
+    #[test]
+    fn test_apply_hunks_reject() {
+        let hunks = hunks("@@ -1 +1 @@\n-x\n+X\n@@ -3 +3 @@\n-c\n+C\n");
+        let applied = apply_hunks(b"a\nb\nc\n", &hunks, 2);
+        assert_eq!(
+            applied.hunks,
+            [
+                HunkStatus::Rejected,
+                HunkStatus::Applied { offset: 0, fuzz: 0 }
+            ]
+        );
+        assert_eq!(applied.content, b"a\nb\nC\n");
+        assert!(!applied.is_clean());
+    }
+
+    #[test]
+    fn test_apply_hunks_missing_newline() {
+        let hunks = hunks("@@ -1 +1 @@\n-a\n\\ No newline at end of file\n+a\n");
+        let applied = apply_hunks(b"a", &hunks, 0);
+        assert_eq!(applied.content, b"a\n");
+        assert_eq!(apply_hunks(b"a\n", &hunks, 0).hunks, [HunkStatus::Rejected]);
+    }
+
+    #[test]
+    fn test_apply_patch() {
+        let mut target = MemoryTarget::new();
+        target.insert(
+            repo_path_buf("modified"),
+            b"a\nb\n".to_vec(),
+            FileType::Regular,
+        );
+        target.insert(repo_path_buf("removed"), b"r\n".to_vec(), FileType::Regular);
+        target.insert(repo_path_buf("renamed"), b"n\n".to_vec(), FileType::Regular);
+        target.insert(
+            repo_path_buf("conflict"),
+            b"x\n".to_vec(),
+            FileType::Regular,
+        );
+
+        let patch = b"diff --git a/modified b/modified
+--- a/modified
++++ b/modified
+@@ -1,2 +1,2 @@
+ a
+-b
++B
+diff --git a/removed b/removed
+deleted file mode 100644
+--- a/removed
++++ /dev/null
+@@ -1 +0,0 @@
+-r
+diff --git a/renamed b/dir/new
+old mode 100644
+new mode 100755
+rename from renamed
+rename to dir/new
+diff --git a/added b/added
+new file mode 100644
+--- /dev/null
++++ b/added
+@@ -0,0 +1 @@
++added
+diff --git a/conflict b/conflict
+--- a/conflict
++++ b/conflict
+@@ -1 +1 @@
+-y
++z
+diff --git a/missing b/missing
+--- a/missing
++++ b/missing
+@@ -1 +1 @@
+-y
++z
+";
+        let patches = parse(patch).unwrap();
+        let results = apply_patch(&mut target, &patches, &ApplyOpts::default()).unwrap();
+        let statuses = results
+            .into_iter()
+            .map(|r| (r.path.into_string(), r.status))
+            .collect::<Vec<_>>();
+        let clean = |n| FileStatus::Applied(vec![HunkStatus::Applied { offset: 0, fuzz: 0 }; n]);
+        assert_eq!(
+            statuses,
+            [
+                ("modified".to_string(), clean(1)),
+                ("removed".to_string(), clean(1)),
+                ("dir/new".to_string(), clean(0)),
+                ("added".to_string(), clean(1)),
+                (
+                    "conflict".to_string(),
+                    FileStatus::Rejected(vec![HunkStatus::Rejected])
+                ),
+                ("missing".to_string(), FileStatus::Missing),
+            ]
+        );
+
+        let files = target
+            .files()
+            .map(|(path, content, file_type)| (path.as_str(), content, file_type))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [
+                ("added", &b"added\n"[..], FileType::Regular),
+                ("conflict", b"x\n", FileType::Regular),
+                ("dir/new", b"n\n", FileType::Executable),
+                ("modified", b"a\nB\n", FileType::Regular),
+            ]
+        );
+
+        // Adding a file that exists is refused.
+        let patches = parse(b"--- /dev/null\n+++ b/added\n@@ -0,0 +1 @@\n+a\n").unwrap();
+        let results = apply_patch(&mut target, &patches, &ApplyOpts::default()).unwrap();
+        assert_eq!(results[0].status, FileStatus::Exists);
+    }
+}
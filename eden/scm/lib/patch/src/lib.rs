@@ -0,0 +1,29 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Parse and apply unified diff patches.
+//!
+//! Patches in the git extended format (as produced by `hg diff --git` or the
+//! `xdiff` crate) and plain unified diffs are supported. Hunks that don't
+//! apply at their recorded position are searched for nearby, and a limited
+//! amount of context can be ignored ("fuzz"). Hunks that still don't apply
+//! are reported as rejected instead of failing the whole patch.
+//!
+//! Patches are applied to a [`PatchTarget`]: files in a working copy with
+//! [`FsTarget`], files in a tree manifest with [`TreeTarget`], which is used
+//! to apply patches without touching the working copy, for example during
+//! in-memory rebases, or files held in memory with [`MemoryTarget`].
+
+mod apply;
+mod parse;
+mod target;
+
+pub use crate::apply::{
+    apply_hunks, apply_patch, Applied, ApplyOpts, FileResult, FileStatus, HunkStatus,
+};
+pub use crate::parse::{parse, FilePatch, FileType, Hunk, Line, ParseError};
+pub use crate::target::{ContentStore, FsTarget, MemoryTarget, PatchTarget, TreeTarget};
@@ -0,0 +1,494 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::str::from_utf8;
+
+use thiserror::Error;
+
+use types::RepoPathBuf;
+
+#[derive(Error, Debug)]
+#[error("malformed patch at line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileType {
+    Regular,
+    Executable,
+    Symlink,
+}
+
+/// The changes to a single file.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FilePatch {
+    /// Path of the file before the patch, `None` if the file is added.
+    pub old_path: Option<RepoPathBuf>,
+    /// Path of the file after the patch, `None` if the file is removed.
+    pub new_path: Option<RepoPathBuf>,
+    /// When the paths differ, whether the file is copied rather than renamed.
+    pub copy: bool,
+    pub old_type: Option<FileType>,
+    pub new_type: Option<FileType>,
+    /// The patch changes binary content, which is not included.
+    pub binary: bool,
+    pub hunks: Vec<Hunk>,
+}
+
+/// A group of changed lines with their surrounding context.
+///
+/// Line numbers start from 1. Lines include their trailing `\n`, except the
+/// last line of a file that doesn't end with a newline.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub lines: Vec<Line>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Line {
+    Context(Vec<u8>),
+    Remove(Vec<u8>),
+    Add(Vec<u8>),
+}
+
+impl FilePatch {
+    fn new() -> Self {
+        FilePatch {
+            old_path: None,
+            new_path: None,
+            copy: false,
+            old_type: None,
+            new_type: None,
+            binary: false,
+            hunks: Vec::new(),
+        }
+    }
+}
+
+impl Line {
+    fn text(&self) -> &[u8] {
+        match self {
+            Line::Context(text) | Line::Remove(text) | Line::Add(text) => text,
+        }
+    }
+
+    fn text_mut(&mut self) -> &mut Vec<u8> {
+        match self {
+            Line::Context(text) | Line::Remove(text) | Line::Add(text) => text,
+        }
+    }
+}
+
+impl Hunk {
+    /// The lines that the hunk expects in the file before the patch.
+    pub fn old_lines(&self) -> impl Iterator<Item = &[u8]> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Context(text) | Line::Remove(text) => Some(&text[..]),
+            Line::Add(_) => None,
+        })
+    }
+
+    /// The lines of the file after the patch.
+    pub fn new_lines(&self) -> impl Iterator<Item = &[u8]> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Context(text) | Line::Add(text) => Some(&text[..]),
+            Line::Remove(_) => None,
+        })
+    }
+}
+
+/// Parse a patch containing changes to one or more files.
+///
+/// Lines that are not part of a file patch, like a commit message before the
+/// first file, are ignored.
+///
+/// # Example
+/// ```
+/// use patch::{parse, Line};
+/// let patches = parse(b"--- a/x\n+++ b/x\n@@ -1,1 +1,1 @@\n-a\n+b\n").unwrap();
+/// assert_eq!(patches.len(), 1);
+/// assert_eq!(
+///     patches[0].hunks[0].lines,
+///     [Line::Remove(b"a\n".to_vec()), Line::Add(b"b\n".to_vec())]
+/// );
+/// ```
+pub fn parse(text: &[u8]) -> Result<Vec<FilePatch>, ParseError> {
+    let mut parser = Parser {
+        lines: split_lines(text),
+        pos: 0,
+    };
+    parser.parse()
+}
+
+struct Parser<'a> {
+    lines: Vec<&'a [u8]>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn parse(&mut self) -> Result<Vec<FilePatch>, ParseError> {
+        let mut patches: Vec<FilePatch> = Vec::new();
+        // Whether the patch being parsed has a git header, so that extended
+        // header lines belong to it.
+        let mut in_git_header = false;
+        while let Some(line) = self.next_line() {
+            let line = trim_newline(line);
+            if let Some(rest) = line.strip_prefix(b"diff --git a/") {
+                let (old, new) = split_git_paths(rest);
+                let mut patch = FilePatch::new();
+                patch.old_path = Some(self.path(old)?);
+                patch.new_path = Some(self.path(new)?);
+                patches.push(patch);
+                in_git_header = true;
+            } else if line.starts_with(b"--- ") && self.peek_line_starts_with(b"+++ ") {
+                let old = self.diff_path(&line[4..])?;
+                let new = trim_newline(self.next_line().unwrap());
+                let new = self.diff_path(&new[4..])?;
+                if !in_git_header {
+                    patches.push(FilePatch::new());
+                }
+                let patch = patches.last_mut().unwrap();
+                patch.old_path = old;
+                patch.new_path = new;
+                in_git_header = false;
+            } else if line.starts_with(b"@@ ") {
+                if patches.is_empty() {
+                    return Err(self.error("hunk without file header"));
+                }
+                let hunk = self.hunk(line)?;
+                patches.last_mut().unwrap().hunks.push(hunk);
+                in_git_header = false;
+            } else if in_git_header {
+                self.git_header(patches.last_mut().unwrap(), line)?;
+            }
+        }
+        Ok(patches)
+    }
+
+    fn git_header(&self, patch: &mut FilePatch, line: &[u8]) -> Result<(), ParseError> {
+        if let Some(mode) = line.strip_prefix(b"new file mode ") {
+            patch.old_path = None;
+            patch.new_type = Some(self.mode(mode)?);
+        } else if let Some(mode) = line.strip_prefix(b"deleted file mode ") {
+            patch.new_path = None;
+            patch.old_type = Some(self.mode(mode)?);
+        } else if let Some(mode) = line.strip_prefix(b"old mode ") {
+            patch.old_type = Some(self.mode(mode)?);
+        } else if let Some(mode) = line.strip_prefix(b"new mode ") {
+            patch.new_type = Some(self.mode(mode)?);
+        } else if let Some(path) = line.strip_prefix(b"rename from ") {
+            patch.old_path = Some(self.path(path)?);
+        } else if let Some(path) = line.strip_prefix(b"rename to ") {
+            patch.new_path = Some(self.path(path)?);
+        } else if let Some(path) = line.strip_prefix(b"copy from ") {
+            patch.old_path = Some(self.path(path)?);
+            patch.copy = true;
+        } else if let Some(path) = line.strip_prefix(b"copy to ") {
+            patch.new_path = Some(self.path(path)?);
+            patch.copy = true;
+        } else if line.starts_with(b"Binary file") || line == b"GIT binary patch" {
+            patch.binary = true;
+        }
+        Ok(())
+    }
+
+    fn hunk(&mut self, header: &[u8]) -> Result<Hunk, ParseError> {
+        let (old_start, old_len, new_start, new_len) = match parse_hunk_header(header) {
+            Some(header) => header,
+            None => return Err(self.error("invalid hunk header")),
+        };
+        let mut hunk = Hunk {
+            old_start,
+            old_len,
+            new_start,
+            new_len,
+            lines: Vec::new(),
+        };
+        let (mut old_remaining, mut new_remaining) = (old_len, new_len);
+        while old_remaining > 0 || new_remaining > 0 {
+            let line = match self.next_line() {
+                Some(line) => line,
+                None => return Err(self.error("hunk is shorter than its header says")),
+            };
+            let (kind, text) = match line.split_first() {
+                // Some tools strip the space of empty context lines.
+                Some((b'\n', _)) => (b' ', line),
+                Some((kind, text)) => (*kind, text),
+                None => (b' ', line),
+            };
+            let text = text.to_vec();
+            match kind {
+                b' ' if old_remaining > 0 && new_remaining > 0 => {
+                    old_remaining -= 1;
+                    new_remaining -= 1;
+                    hunk.lines.push(Line::Context(text));
+                }
+                b'-' if old_remaining > 0 => {
+                    old_remaining -= 1;
+                    hunk.lines.push(Line::Remove(text));
+                }
+                b'+' if new_remaining > 0 => {
+                    new_remaining -= 1;
+                    hunk.lines.push(Line::Add(text));
+                }
+                b'\\' => self.no_newline(&mut hunk)?,
+                _ => return Err(self.error("unexpected line in hunk")),
+            }
+            if old_remaining == 0 && new_remaining == 0 && self.peek_line_starts_with(b"\\") {
+                self.next_line();
+                self.no_newline(&mut hunk)?;
+            }
+        }
+        Ok(hunk)
+    }
+
+    /// Handle a "\ No newline at end of file" marker for the last line.
+    fn no_newline(&self, hunk: &mut Hunk) -> Result<(), ParseError> {
+        match hunk.lines.last_mut() {
+            Some(line) if line.text().ends_with(b"\n") => {
+                line.text_mut().pop();
+                Ok(())
+            }
+            _ => Err(self.error("unexpected missing newline marker")),
+        }
+    }
+
+    fn next_line(&mut self) -> Option<&'a [u8]> {
+        let line = self.lines.get(self.pos).cloned();
+        if line.is_some() {
+            self.pos += 1;
+        }
+        line
+    }
+
+    fn peek_line_starts_with(&self, prefix: &[u8]) -> bool {
+        match self.lines.get(self.pos) {
+            Some(line) => line.starts_with(prefix),
+            None => false,
+        }
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        ParseError {
+            line: self.pos,
+            message: message.to_string(),
+        }
+    }
+
+    fn path(&self, path: &[u8]) -> Result<RepoPathBuf, ParseError> {
+        RepoPathBuf::from_utf8(path.to_vec()).map_err(|e| self.error(&e.to_string()))
+    }
+
+    /// Parse the path of a "---" or "+++" line.
+    fn diff_path(&self, path: &[u8]) -> Result<Option<RepoPathBuf>, ParseError> {
+        // Some tools add a timestamp after a tab.
+        let path = match path.iter().position(|&c| c == b'\t') {
+            Some(tab) => &path[..tab],
+            None => path,
+        };
+        if path == b"/dev/null" {
+            return Ok(None);
+        }
+        let path = match path.iter().position(|&c| c == b'/') {
+            Some(slash) => &path[slash + 1..],
+            None => path,
+        };
+        Ok(Some(self.path(path)?))
+    }
+
+    fn mode(&self, mode: &[u8]) -> Result<FileType, ParseError> {
+        match mode {
+            b"100644" => Ok(FileType::Regular),
+            b"100755" => Ok(FileType::Executable),
+            b"120000" => Ok(FileType::Symlink),
+            _ => Err(self.error("unsupported file mode")),
+        }
+    }
+}
+
+/// Split the `X b/Y` part of a `diff --git a/X b/Y` line. Paths may contain
+/// " b/", so prefer the split where both paths are the same.
+fn split_git_paths(rest: &[u8]) -> (&[u8], &[u8]) {
+    if rest.len() > 3 {
+        let mid = (rest.len() - 3) / 2;
+        if mid * 2 + 3 == rest.len()
+            && &rest[mid..mid + 3] == b" b/"
+            && rest[..mid] == rest[mid + 3..]
+        {
+            return (&rest[..mid], &rest[mid + 3..]);
+        }
+    }
+    match rest.windows(3).position(|w| w == b" b/") {
+        Some(i) => (&rest[..i], &rest[i + 3..]),
+        None => (rest, rest),
+    }
+}
+
+/// Parse `@@ -a,b +c,d @@`. The lengths are optional and default to 1.
+fn parse_hunk_header(line: &[u8]) -> Option<(usize, usize, usize, usize)> {
+    let line = from_utf8(line).ok()?;
+    let mut parts = line.split(' ');
+    if parts.next()? != "@@" {
+        return None;
+    }
+    let range = |part: &str, prefix: char| -> Option<(usize, usize)> {
+        let part = part.strip_prefix(prefix)?;
+        let mut numbers = part.splitn(2, ',');
+        let start = numbers.next()?.parse().ok()?;
+        let len = match numbers.next() {
+            Some(len) => len.parse().ok()?,
+            None => 1,
+        };
+        Some((start, len))
+    };
+    let (old_start, old_len) = range(parts.next()?, '-')?;
+    let (new_start, new_len) = range(parts.next()?, '+')?;
+    if parts.next()? != "@@" {
+        return None;
+    }
+    Some((old_start, old_len, new_start, new_len))
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\n").unwrap_or(line)
+}
+
+/// Split a text into lines, keeping the trailing `\n` of each line.
+pub(crate) fn split_lines(text: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, &c) in text.iter().enumerate() {
+        if c == b'\n' {
+            lines.push(&text[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use types::testutil::*;
+
+    #[test]
+    fn test_parse_git() {
+        let text = b"commit message
+
+diff --git a/x b/y
+old mode 100644
+new mode 100755
+rename from x
+rename to y
+--- a/x
++++ b/y
+@@ -1,3 +1,3 @@
+ a
+-b
++c
+ d
+\\ No newline at end of file
+diff --git a/z b/z
+deleted file mode 100644
+--- a/z
++++ /dev/null
+@@ -1 +0,0 @@
+-z
+diff --git a/bin b/bin
+new file mode 100644
+Binary file bin has changed
+";
+        let patches = parse(text).unwrap();
+        assert_eq!(patches.len(), 3);
+
+        assert_eq!(patches[0].old_path, Some(repo_path_buf("x")));
+        assert_eq!(patches[0].new_path, Some(repo_path_buf("y")));
+        assert_eq!(patches[0].old_type, Some(FileType::Regular));
+        assert_eq!(patches[0].new_type, Some(FileType::Executable));
+        assert!(!patches[0].copy);
+        assert_eq!(
+            patches[0].hunks,
+            [Hunk {
+                old_start: 1,
+                old_len: 3,
+                new_start: 1,
+                new_len: 3,
+                lines: vec![
+                    Line::Context(b"a\n".to_vec()),
+                    Line::Remove(b"b\n".to_vec()),
+                    Line::Add(b"c\n".to_vec()),
+                    Line::Context(b"d".to_vec()),
+                ]
+            }]
+        );
+
+        assert_eq!(patches[1].old_path, Some(repo_path_buf("z")));
+        assert_eq!(patches[1].new_path, None);
+        assert_eq!(patches[1].hunks[0].old_len, 1);
+        assert_eq!(patches[1].hunks[0].lines, [Line::Remove(b"z\n".to_vec())]);
+
+        assert_eq!(patches[2].old_path, None);
+        assert_eq!(patches[2].new_path, Some(repo_path_buf("bin")));
+        assert!(patches[2].binary);
+    }
+
+    #[test]
+    fn test_parse_unified() {
+        let text = b"--- x.orig\t2020-01-01 00:00:00
++++ x\t2020-01-01 00:00:00
+@@ -2,0 +3,2 @@
++a
++b
+--- /dev/null
++++ b/dir/y
+@@ -0,0 +1 @@
++y
+";
+        let patches = parse(text).unwrap();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].old_path, Some(repo_path_buf("x.orig")));
+        assert_eq!(patches[0].new_path, Some(repo_path_buf("x")));
+        assert_eq!(
+            patches[0].hunks[0].new_lines().collect::<Vec<_>>(),
+            [b"a\n", b"b\n"]
+        );
+        assert_eq!(patches[1].old_path, None);
+        assert_eq!(patches[1].new_path, Some(repo_path_buf("dir/y")));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = parse(b"@@ -1 +1 @@\n-a\n+b\n").unwrap_err();
+        assert_eq!(error.line, 1);
+        let error = parse(b"--- a/x\n+++ b/x\n@@ -1,2 +1,2 @@\n-a\n+b\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "malformed patch at line 5: hunk is shorter than its header says"
+        );
+        assert!(parse(b"--- a/x\n+++ b/x\n@@ -1 +1 foo\n").is_err());
+    }
+
+    #[test]
+    fn test_split_git_paths() {
+        assert_eq!(
+            split_git_paths(b"x b/y b/x b/y"),
+            (&b"x b/y"[..], &b"x b/y"[..])
+        );
+        assert_eq!(split_git_paths(b"x b/x"), (&b"x"[..], &b"x"[..]));
+        assert_eq!(split_git_paths(b"x b/y"), (&b"x"[..], &b"y"[..]));
+    }
+}
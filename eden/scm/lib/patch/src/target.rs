@@ -0,0 +1,388 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use manifest::{FileMetadata, Manifest};
+use manifest_tree::TreeManifest;
+use types::{HgId, RepoPath, RepoPathBuf};
+
+use crate::parse::FileType;
+
+/// A set of files that patches can be applied to.
+pub trait PatchTarget {
+    /// Returns the content and the type of the file, or `None` if the file
+    /// does not exist. The content of a symlink is its destination.
+    fn read(&self, path: &RepoPath) -> Result<Option<(Vec<u8>, FileType)>>;
+
+    /// Creates or replaces the file.
+    fn write(&mut self, path: &RepoPath, content: &[u8], file_type: FileType) -> Result<()>;
+
+    /// Removes the file.
+    fn remove(&mut self, path: &RepoPath) -> Result<()>;
+}
+
+/// The files of a working copy.
+pub struct FsTarget {
+    root: PathBuf,
+}
+
+impl FsTarget {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        FsTarget {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, path: &RepoPath) -> PathBuf {
+        self.root.join(path.as_str())
+    }
+}
+
+impl PatchTarget for FsTarget {
+    fn read(&self, path: &RepoPath) -> Result<Option<(Vec<u8>, FileType)>> {
+        let path = self.path(path);
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.file_type().is_symlink() {
+            let destination = fs::read_link(&path)?;
+            let destination = destination.to_string_lossy().into_owned().into_bytes();
+            return Ok(Some((destination, FileType::Symlink)));
+        }
+        Ok(Some((fs::read(&path)?, file_type(&metadata))))
+    }
+
+    fn write(&mut self, path: &RepoPath, content: &[u8], file_type: FileType) -> Result<()> {
+        let path = self.path(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() || file_type == FileType::Symlink => {
+                fs::remove_file(&path)?
+            }
+            _ => {}
+        }
+        match file_type {
+            FileType::Symlink => symlink(content, &path)?,
+            FileType::Regular | FileType::Executable => {
+                fs::write(&path, content)?;
+                set_executable(&path, file_type == FileType::Executable)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &RepoPath) -> Result<()> {
+        fs::remove_file(self.path(path))?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn file_type(metadata: &fs::Metadata) -> FileType {
+    use std::os::unix::fs::PermissionsExt;
+    if metadata.permissions().mode() & 0o111 != 0 {
+        FileType::Executable
+    } else {
+        FileType::Regular
+    }
+}
+
+#[cfg(not(unix))]
+fn file_type(_metadata: &fs::Metadata) -> FileType {
+    FileType::Regular
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path, executable: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    let mode = if executable {
+        // Give execute permission to whoever can read the file.
+        mode | ((mode & 0o444) >> 2)
+    } else {
+        mode & !0o111
+    };
+    permissions.set_mode(mode);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path, _executable: bool) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(destination: &[u8], path: &Path) -> Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    std::os::unix::fs::symlink(OsStr::from_bytes(destination), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn symlink(destination: &[u8], path: &Path) -> Result<()> {
+    // Like Mercurial, write the destination as the content of a regular file
+    // on platforms without symlinks.
+    fs::write(path, destination)?;
+    Ok(())
+}
+
+/// Files held in memory.
+///
+/// This allows applying patches without a working copy or a store, for
+/// example to check whether a patch applies to some given files.
+#[derive(Clone, Default, Debug)]
+pub struct MemoryTarget {
+    files: BTreeMap<RepoPathBuf, (Vec<u8>, FileType)>,
+}
+
+impl MemoryTarget {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert(&mut self, path: RepoPathBuf, content: Vec<u8>, file_type: FileType) {
+        self.files.insert(path, (content, file_type));
+    }
+
+    /// The files, in sorted order.
+    pub fn files(&self) -> impl Iterator<Item = (&RepoPath, &[u8], FileType)> {
+        self.files
+            .iter()
+            .map(|(path, (content, file_type))| (path.as_repo_path(), &content[..], *file_type))
+    }
+}
+
+impl PatchTarget for MemoryTarget {
+    fn read(&self, path: &RepoPath) -> Result<Option<(Vec<u8>, FileType)>> {
+        Ok(self.files.get(path).cloned())
+    }
+
+    fn write(&mut self, path: &RepoPath, content: &[u8], file_type: FileType) -> Result<()> {
+        self.insert(path.to_owned(), content.to_vec(), file_type);
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &RepoPath) -> Result<()> {
+        self.files.remove(path);
+        Ok(())
+    }
+}
+
+/// Stores the contents of the files of a [`TreeTarget`].
+pub trait ContentStore {
+    /// Returns the content of the file `path` with the given id.
+    fn get(&self, path: &RepoPath, hgid: HgId) -> Result<Vec<u8>>;
+
+    /// Stores the new content of the file `path` and returns its id.
+    fn insert(&mut self, path: &RepoPath, content: &[u8]) -> Result<HgId>;
+}
+
+/// The files of a tree manifest, with their contents in a [`ContentStore`].
+///
+/// This allows applying patches without a working copy, for example to
+/// rebase commits in memory. The manifest is only changed in memory. It is
+/// up to the caller to flush it once the patch is applied.
+pub struct TreeTarget<'a, S> {
+    tree: &'a mut TreeManifest,
+    store: S,
+}
+
+impl<'a, S: ContentStore> TreeTarget<'a, S> {
+    pub fn new(tree: &'a mut TreeManifest, store: S) -> Self {
+        TreeTarget { tree, store }
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+}
+
+impl<'a, S: ContentStore> PatchTarget for TreeTarget<'a, S> {
+    fn read(&self, path: &RepoPath) -> Result<Option<(Vec<u8>, FileType)>> {
+        match self.tree.get_file(path)? {
+            Some(meta) => {
+                let content = self.store.get(path, meta.hgid)?;
+                Ok(Some((content, from_manifest_type(meta.file_type))))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn write(&mut self, path: &RepoPath, content: &[u8], file_type: FileType) -> Result<()> {
+        let hgid = self.store.insert(path, content)?;
+        let meta = FileMetadata::new(hgid, to_manifest_type(file_type));
+        self.tree.insert(path.to_owned(), meta)
+    }
+
+    fn remove(&mut self, path: &RepoPath) -> Result<()> {
+        self.tree.remove(path)?;
+        Ok(())
+    }
+}
+
+fn from_manifest_type(file_type: manifest::FileType) -> FileType {
+    match file_type {
+        manifest::FileType::Regular => FileType::Regular,
+        manifest::FileType::Executable => FileType::Executable,
+        manifest::FileType::Symlink => FileType::Symlink,
+    }
+}
+
+fn to_manifest_type(file_type: FileType) -> manifest::FileType {
+    match file_type {
+        FileType::Regular => manifest::FileType::Regular,
+        FileType::Executable => manifest::FileType::Executable,
+        FileType::Symlink => manifest::FileType::Symlink,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use anyhow::format_err;
+    use manifest_tree::testutil::TestStore;
+    use tempfile::tempdir;
+    use types::testutil::*;
+
+    use crate::{apply_patch, parse, ApplyOpts};
+
+    #[test]
+    fn test_fs_target() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("a"), b"1\n2\n").unwrap();
+        let mut target = FsTarget::new(dir.path());
+
+        let patches = parse(
+            b"diff --git a/a b/a
+old mode 100644
+new mode 100755
+--- a/a
++++ b/a
+@@ -1,2 +1,2 @@
+-1
++one
+ 2
+diff --git a/d/l b/d/l
+new file mode 120000
+--- /dev/null
++++ b/d/l
+@@ -0,0 +1 @@
++../a
+\\ No newline at end of file
+",
+        )
+        .unwrap();
+        apply_patch(&mut target, &patches, &ApplyOpts::default()).unwrap();
+
+        assert_eq!(
+            target.read(repo_path("a")).unwrap(),
+            Some((b"one\n2\n".to_vec(), FileType::Executable))
+        );
+        assert_eq!(
+            target.read(repo_path("d/l")).unwrap(),
+            Some((b"../a".to_vec(), FileType::Symlink))
+        );
+        assert_eq!(target.read(repo_path("missing")).unwrap(), None);
+
+        target.remove(repo_path("d/l")).unwrap();
+        assert_eq!(target.read(repo_path("d/l")).unwrap(), None);
+        assert!(dir.path().join("a").exists());
+    }
+
+    #[derive(Default)]
+    struct TestContentStore {
+        contents: HashMap<HgId, Vec<u8>>,
+    }
+
+    impl ContentStore for TestContentStore {
+        fn get(&self, path: &RepoPath, hgid: HgId) -> Result<Vec<u8>> {
+            self.contents
+                .get(&hgid)
+                .cloned()
+                .ok_or_else(|| format_err!("{} {} not found", path, hgid))
+        }
+
+        fn insert(&mut self, _path: &RepoPath, content: &[u8]) -> Result<HgId> {
+            let hgid = HgId::from_byte_array([self.contents.len() as u8 + 1; HgId::len()]);
+            self.contents.insert(hgid, content.to_vec());
+            Ok(hgid)
+        }
+    }
+
+    #[test]
+    fn test_tree_target() {
+        let mut tree = TreeManifest::ephemeral(Arc::new(TestStore::new()));
+        let mut store = TestContentStore::default();
+        let a = store.insert(repo_path("a"), b"1\n2\n").unwrap();
+        let b = store.insert(repo_path("d/b"), b"b\n").unwrap();
+        tree.insert(repo_path_buf("a"), FileMetadata::regular(a))
+            .unwrap();
+        tree.insert(repo_path_buf("d/b"), FileMetadata::regular(b))
+            .unwrap();
+
+        let patches = parse(
+            b"diff --git a/a b/a
+old mode 100644
+new mode 100755
+--- a/a
++++ b/a
+@@ -1,2 +1,2 @@
+-1
++one
+ 2
+diff --git a/d/b b/d/b
+deleted file mode 100644
+--- a/d/b
++++ /dev/null
+@@ -1 +0,0 @@
+-b
+diff --git a/d/l b/d/l
+new file mode 120000
+--- /dev/null
++++ b/d/l
+@@ -0,0 +1 @@
++../a
+\\ No newline at end of file
+",
+        )
+        .unwrap();
+        let mut target = TreeTarget::new(&mut tree, store);
+        apply_patch(&mut target, &patches, &ApplyOpts::default()).unwrap();
+
+        assert_eq!(
+            target.read(repo_path("a")).unwrap(),
+            Some((b"one\n2\n".to_vec(), FileType::Executable))
+        );
+        assert_eq!(
+            target.read(repo_path("d/l")).unwrap(),
+            Some((b"../a".to_vec(), FileType::Symlink))
+        );
+        assert_eq!(target.read(repo_path("d/b")).unwrap(), None);
+        assert_eq!(tree.get_file(repo_path("d/b")).unwrap(), None);
+        assert_eq!(
+            tree.get_file(repo_path("a")).unwrap().unwrap().file_type,
+            manifest::FileType::Executable
+        );
+    }
+}
@@ -0,0 +1,26 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! In-memory rebase.
+//!
+//! Replays a stack of commits onto a new destination by merging tree
+//! manifests and file contents in memory. The working copy is never
+//! touched, so rebasing commits that are not checked out is fast and safe.
+//!
+//! The rebase is a state machine driven by the caller: each
+//! [`Rebase::step`] rebases one commit, creating the new commit through
+//! [`RebaseRepo::create_commit`]. When the changes of a commit cannot be
+//! merged automatically, the rebase stops and reports the [`Conflict`]s.
+//! The caller can then resolve them (or fall back to an on-disk rebase).
+
+mod merge;
+mod rebase;
+
+pub use crate::merge::{merge_text, MergedText};
+pub use crate::rebase::{
+    CommitInfo, Conflict, ConflictKind, Rebase, RebaseError, RebaseRepo, State, Step,
+};
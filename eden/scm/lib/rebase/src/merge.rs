@@ -0,0 +1,220 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Line based three-way merge of file contents.
+
+use std::ops::Range;
+
+use textdiff::{diff_slices, split_lines, Algorithm, Hunk};
+
+/// The result of a three-way merge.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergedText {
+    /// The merged content. Conflicting regions are surrounded by conflict
+    /// markers.
+    pub content: Vec<u8>,
+    /// The number of conflicting regions.
+    pub conflicts: usize,
+}
+
+impl MergedText {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts == 0
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Local,
+    Other,
+}
+
+/// Merge the changes made from `base` to `local` and from `base` to
+/// `other`.
+///
+/// Changes that touch or overlap each other conflict, unless both sides
+/// made the same change. Conflicting regions are written as:
+///
+/// ```text
+/// <<<<<<< {labels[0]}
+/// local lines
+/// =======
+/// other lines
+/// >>>>>>> {labels[1]}
+/// ```
+pub fn merge_text(base: &[u8], local: &[u8], other: &[u8], labels: [&str; 2]) -> MergedText {
+    if local == other || base == other {
+        return clean(local);
+    }
+    if base == local {
+        return clean(other);
+    }
+
+    let base = split_lines(base);
+    let local = split_lines(local);
+    let other = split_lines(other);
+    let local_hunks = diff_slices(&base, &local, Algorithm::Myers);
+    let other_hunks = diff_slices(&base, &other, Algorithm::Myers);
+
+    let mut changes: Vec<(Side, &Hunk)> = local_hunks
+        .iter()
+        .map(|h| (Side::Local, h))
+        .chain(other_hunks.iter().map(|h| (Side::Other, h)))
+        .collect();
+    changes.sort_by_key(|(_, h)| (h.remove.start, h.remove.end));
+
+    let mut merged = MergedText {
+        content: Vec::new(),
+        conflicts: 0,
+    };
+    let mut pos = 0;
+    let mut i = 0;
+    while i < changes.len() {
+        // Group the changes that touch each other into a region of `base`.
+        let start = changes[i].1.remove.start;
+        let mut end = changes[i].1.remove.end;
+        let mut j = i + 1;
+        while j < changes.len() && changes[j].1.remove.start <= end {
+            end = end.max(changes[j].1.remove.end);
+            j += 1;
+        }
+        let region = &changes[i..j];
+
+        extend(&mut merged.content, &base[pos..start]);
+        let local_range = side_range(region, Side::Local, start..end);
+        let other_range = side_range(region, Side::Other, start..end);
+        match (local_range, other_range) {
+            (Some(l), None) => extend(&mut merged.content, &local[l]),
+            (None, Some(o)) => extend(&mut merged.content, &other[o]),
+            (Some(l), Some(o)) if local[l.clone()] == other[o.clone()] => {
+                extend(&mut merged.content, &local[l])
+            }
+            (Some(l), Some(o)) => {
+                merged.conflicts += 1;
+                let content = &mut merged.content;
+                content.extend_from_slice(format!("<<<<<<< {}\n", labels[0]).as_bytes());
+                extend_terminated(content, &local[l]);
+                content.extend_from_slice(b"=======\n");
+                extend_terminated(content, &other[o]);
+                content.extend_from_slice(format!(">>>>>>> {}\n", labels[1]).as_bytes());
+            }
+            (None, None) => unreachable!("a region contains at least one change"),
+        }
+        pos = end;
+        i = j;
+    }
+    extend(&mut merged.content, &base[pos..]);
+    merged
+}
+
+fn clean(content: &[u8]) -> MergedText {
+    MergedText {
+        content: content.to_vec(),
+        conflicts: 0,
+    }
+}
+
+/// The lines of one side that replace the `region` of `base`, or `None` if
+/// that side did not change the region.
+fn side_range(changes: &[(Side, &Hunk)], side: Side, region: Range<usize>) -> Option<Range<usize>> {
+    let mut hunks = changes.iter().filter(|(s, _)| *s == side).map(|(_, h)| h);
+    let first = hunks.next()?;
+    let last = hunks.next_back().unwrap_or(first);
+    let start = first.add.start - (first.remove.start - region.start);
+    let end = last.add.end + (region.end - last.remove.end);
+    Some(start..end)
+}
+
+fn extend(content: &mut Vec<u8>, lines: &[&[u8]]) {
+    for line in lines {
+        content.extend_from_slice(line);
+    }
+}
+
+/// Like `extend`, but make sure the content ends with a newline so the
+/// following conflict marker starts on its own line.
+fn extend_terminated(content: &mut Vec<u8>, lines: &[&[u8]]) {
+    extend(content, lines);
+    if !lines.is_empty() && !content.ends_with(b"\n") {
+        content.push(b'\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(base: &str, local: &str, other: &str) -> (String, usize) {
+        let merged = merge_text(
+            base.as_bytes(),
+            local.as_bytes(),
+            other.as_bytes(),
+            ["dest", "source"],
+        );
+        (String::from_utf8(merged.content).unwrap(), merged.conflicts)
+    }
+
+    #[test]
+    fn test_merge_trivial() {
+        assert_eq!(merge("a\n", "a\n", "b\n"), ("b\n".to_string(), 0));
+        assert_eq!(merge("a\n", "b\n", "a\n"), ("b\n".to_string(), 0));
+        assert_eq!(merge("a\n", "b\n", "b\n"), ("b\n".to_string(), 0));
+    }
+
+    #[test]
+    fn test_merge_disjoint_changes() {
+        assert_eq!(
+            merge(
+                "1\n2\n3\n4\n5\n",
+                "one\n2\n3\n4\n5\n",
+                "1\n2\n3\n4\nfive\n6\n"
+            ),
+            ("one\n2\n3\n4\nfive\n6\n".to_string(), 0)
+        );
+        assert_eq!(
+            merge("1\n2\n3\n", "0\n1\n2\n3\n", "1\n3\n"),
+            ("0\n1\n3\n".to_string(), 0)
+        );
+    }
+
+    #[test]
+    fn test_merge_same_change() {
+        assert_eq!(
+            merge("1\n2\n3\n", "1\ntwo\n3\n4\n", "1\ntwo\n3\n"),
+            ("1\ntwo\n3\n4\n".to_string(), 0)
+        );
+    }
+
+    #[test]
+    fn test_merge_conflict() {
+        assert_eq!(
+            merge("1\n2\n3\n", "1\nx\n3\n", "1\ny\n3\n"),
+            (
+                "1\n<<<<<<< dest\nx\n=======\ny\n>>>>>>> source\n3\n".to_string(),
+                1
+            )
+        );
+        // Insertions at the same position conflict.
+        assert_eq!(
+            merge("1\n", "1\nx", "1\ny\n"),
+            (
+                "1\n<<<<<<< dest\nx\n=======\ny\n>>>>>>> source\n".to_string(),
+                1
+            )
+        );
+    }
+
+    #[test]
+    fn test_merge_adjacent_changes_conflict() {
+        let (merged, conflicts) = merge("1\n2\n3\n", "1\nx\n3\n", "1\n2\ny\n");
+        assert_eq!(conflicts, 1);
+        assert_eq!(
+            merged,
+            "1\n<<<<<<< dest\nx\n3\n=======\n2\ny\n>>>>>>> source\n"
+        );
+    }
+}
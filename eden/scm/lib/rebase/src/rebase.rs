@@ -0,0 +1,627 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+use thiserror::Error;
+
+use manifest::{DiffEntry, FileMetadata, FileType, Manifest};
+use manifest_tree::{TreeManifest, TreeStore};
+use pathmatcher::AlwaysMatcher;
+use types::{HgId, RepoPath, RepoPathBuf};
+
+use crate::merge::merge_text;
+
+/// Labels used in conflict markers, for the destination and the commit
+/// being rebased.
+const LABELS: [&str; 2] = ["dest", "source"];
+
+/// The repository operations used by [`Rebase`].
+pub trait RebaseRepo {
+    /// The manifest and parents of a commit.
+    fn commit(&self, commit: HgId) -> Result<CommitInfo>;
+
+    /// The content of a file revision.
+    fn read_file(&self, path: &RepoPath, hgid: HgId) -> Result<Vec<u8>>;
+
+    /// Stores a new file revision and returns its id.
+    fn write_file(&self, path: &RepoPath, content: &[u8]) -> Result<HgId>;
+
+    /// Creates a commit with the metadata (author, message, ...) of
+    /// `original`, on top of `parent` and with the given manifest.
+    /// Returns the id of the new commit.
+    fn create_commit(&self, original: HgId, parent: HgId, manifest: HgId) -> Result<HgId>;
+
+    /// The files renamed between two manifests, as `(from, to)` pairs.
+    /// Changes to a file renamed by the destination are applied to its new
+    /// path. The `copytrace` crate can find renames that were not recorded.
+    /// The default implementation doesn't follow renames.
+    fn find_renames(&self, _old: HgId, _new: HgId) -> Result<Vec<(RepoPathBuf, RepoPathBuf)>> {
+        Ok(Vec::new())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitInfo {
+    pub manifest: HgId,
+    pub parents: Vec<HgId>,
+}
+
+#[derive(Debug, Error)]
+pub enum RebaseError {
+    #[error("cannot rebase merge commit {0}")]
+    MergeCommit(HgId),
+    #[error("unresolved conflicts: {0:?}")]
+    Unresolved(Vec<RepoPathBuf>),
+    #[error("{0} is not in conflict")]
+    NotConflicted(RepoPathBuf),
+}
+
+/// A file that could not be merged automatically.
+///
+/// The metadata is `None` when the file does not exist on that side.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub path: RepoPathBuf,
+    pub kind: ConflictKind,
+    pub base: Option<FileMetadata>,
+    pub local: Option<FileMetadata>,
+    pub other: Option<FileMetadata>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Both sides changed the same lines. `merged` is the merged content,
+    /// with conflict markers around the conflicting regions.
+    Content { merged: Vec<u8> },
+    /// Both sides changed a binary file or a symlink.
+    Binary,
+    /// One side changed the file and the other deleted it.
+    ChangeDelete,
+    /// Both sides changed the type of the file differently.
+    FileType,
+}
+
+/// What happened during a [`Rebase::step`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// The commit `old` was rebased as `new`.
+    Rebased { old: HgId, new: HgId },
+    /// The commit became empty and was dropped.
+    Skipped(HgId),
+    /// The commit has conflicts. Resolve them with [`Rebase::resolve`]
+    /// and call [`Rebase::step`] again to commit it.
+    Conflicted(HgId),
+    /// All commits were rebased.
+    Done,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// There are commits left to rebase.
+    Ready,
+    /// The current commit has unresolved conflicts.
+    Conflicted,
+    /// All commits were rebased.
+    Done,
+}
+
+/// Replays a stack of commits onto a new destination.
+///
+/// Manifests and file contents are merged in memory, without a working
+/// copy. Commits are rebased one at a time with [`Rebase::step`]. When a
+/// commit has conflicts the rebase stops until the caller resolves them.
+pub struct Rebase<R> {
+    repo: R,
+    store: Arc<dyn TreeStore + Send + Sync>,
+    commits: Vec<HgId>,
+    /// Index in `commits` of the next commit to rebase.
+    next: usize,
+    /// Whether `commits[next]` has been merged into `tree` and waits for
+    /// its conflicts to be resolved.
+    merging: bool,
+    /// The new parent of `commits[next]`.
+    parent: HgId,
+    parent_manifest: HgId,
+    /// The manifest of `parent`, plus the merged changes of the commit
+    /// being rebased.
+    tree: TreeManifest,
+    conflicts: BTreeMap<RepoPathBuf, Conflict>,
+    rebased: Vec<(HgId, Option<HgId>)>,
+}
+
+impl<R: RebaseRepo> Rebase<R> {
+    /// Prepares to rebase `commits` onto `dest`.
+    ///
+    /// `commits` must be a linear stack, ordered from the bottom to the top.
+    pub fn new(
+        repo: R,
+        store: Arc<dyn TreeStore + Send + Sync>,
+        commits: Vec<HgId>,
+        dest: HgId,
+    ) -> Result<Self> {
+        let parent_manifest = repo.commit(dest)?.manifest;
+        let tree = TreeManifest::durable(store.clone(), parent_manifest);
+        Ok(Rebase {
+            repo,
+            store,
+            commits,
+            next: 0,
+            merging: false,
+            parent: dest,
+            parent_manifest,
+            tree,
+            conflicts: BTreeMap::new(),
+            rebased: Vec::new(),
+        })
+    }
+
+    pub fn state(&self) -> State {
+        if !self.conflicts.is_empty() {
+            State::Conflicted
+        } else if self.next == self.commits.len() {
+            State::Done
+        } else {
+            State::Ready
+        }
+    }
+
+    /// The unresolved conflicts of the current commit.
+    pub fn conflicts(&self) -> impl Iterator<Item = &Conflict> {
+        self.conflicts.values()
+    }
+
+    /// The commits rebased so far, with their new ids. Skipped commits
+    /// have no new id.
+    pub fn rebased(&self) -> &[(HgId, Option<HgId>)] {
+        &self.rebased
+    }
+
+    /// Rebases the next commit.
+    ///
+    /// Fails with [`RebaseError::Unresolved`] if the current commit still
+    /// has conflicts.
+    pub fn step(&mut self) -> Result<Step> {
+        if !self.conflicts.is_empty() {
+            let paths = self.conflicts.keys().cloned().collect();
+            return Err(RebaseError::Unresolved(paths).into());
+        }
+        if self.next == self.commits.len() {
+            return Ok(Step::Done);
+        }
+        let commit = self.commits[self.next];
+        if !self.merging {
+            self.merge(commit)?;
+            self.merging = true;
+            if !self.conflicts.is_empty() {
+                return Ok(Step::Conflicted(commit));
+            }
+        }
+        self.commit(commit)
+    }
+
+    /// Resolves a conflict of the current commit. `None` deletes the file.
+    pub fn resolve(
+        &mut self,
+        path: &RepoPath,
+        resolution: Option<(&[u8], FileType)>,
+    ) -> Result<()> {
+        if self.conflicts.remove(path).is_none() {
+            return Err(RebaseError::NotConflicted(path.to_owned()).into());
+        }
+        let meta = match resolution {
+            Some((content, file_type)) => {
+                let hgid = self.repo.write_file(path, content)?;
+                Some(FileMetadata::new(hgid, file_type))
+            }
+            None => None,
+        };
+        self.set_file(path, meta)
+    }
+
+    /// Applies the changes of `commit` relative to its parent to `tree`.
+    fn merge(&mut self, commit: HgId) -> Result<()> {
+        let info = self.repo.commit(commit)?;
+        let base_manifest = match info.parents.as_slice() {
+            [] => None,
+            [parent] => Some(self.repo.commit(*parent)?.manifest),
+            _ => return Err(RebaseError::MergeCommit(commit).into()),
+        };
+        let base = match base_manifest {
+            Some(manifest) => TreeManifest::durable(self.store.clone(), manifest),
+            None => TreeManifest::ephemeral(self.store.clone()),
+        };
+        let other = TreeManifest::durable(self.store.clone(), info.manifest);
+        let matcher = AlwaysMatcher::new();
+        let changes = base
+            .diff(&other, &matcher)
+            .collect::<Result<Vec<DiffEntry>>>()?;
+
+        // Only looked up when a file changed by `commit` is missing from the destination.
+        let mut renames: Option<BTreeMap<RepoPathBuf, RepoPathBuf>> = None;
+        for DiffEntry { path, diff_type } in changes {
+            let base = diff_type.left();
+            let other = diff_type.right();
+            let mut local_path = path.clone();
+            let mut local = self.tree.get_file(&path)?;
+            if let (None, Some(_), Some(base_manifest)) = (local, base, base_manifest) {
+                if renames.is_none() {
+                    let found = self
+                        .repo
+                        .find_renames(base_manifest, self.parent_manifest)?;
+                    renames = Some(found.into_iter().collect());
+                }
+                if let Some(to) = renames.as_ref().and_then(|r| r.get(&path)) {
+                    local_path = to.clone();
+                    local = self.tree.get_file(&local_path)?;
+                }
+            }
+            match self.merge_file(&path, &local_path, base, local, other)? {
+                Ok(meta) => self.set_file(&local_path, meta)?,
+                Err(kind) => {
+                    let conflict = Conflict {
+                        path: local_path.clone(),
+                        kind,
+                        base,
+                        local,
+                        other,
+                    };
+                    self.conflicts.insert(local_path, conflict);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges a file changed from `base` to `other` with its `local`
+    /// version, which is at `local_path` if the destination renamed it.
+    /// Returns the merged file, or `None` if it is deleted.
+    fn merge_file(
+        &self,
+        path: &RepoPath,
+        local_path: &RepoPath,
+        base: Option<FileMetadata>,
+        local: Option<FileMetadata>,
+        other: Option<FileMetadata>,
+    ) -> Result<std::result::Result<Option<FileMetadata>, ConflictKind>> {
+        if local == other || local == base {
+            return Ok(Ok(other));
+        }
+        let (local, other) = match (local, other) {
+            (Some(local), Some(other)) => (local, other),
+            _ => return Ok(Err(ConflictKind::ChangeDelete)),
+        };
+
+        let file_type = if local.file_type == other.file_type {
+            local.file_type
+        } else {
+            match base {
+                Some(base) if base.file_type == local.file_type => other.file_type,
+                Some(base) if base.file_type == other.file_type => local.file_type,
+                _ => return Ok(Err(ConflictKind::FileType)),
+            }
+        };
+        if local.hgid == other.hgid {
+            return Ok(Ok(Some(FileMetadata::new(local.hgid, file_type))));
+        }
+        if file_type == FileType::Symlink {
+            return Ok(Err(ConflictKind::Binary));
+        }
+
+        let base = match base {
+            Some(base) => self.repo.read_file(path, base.hgid)?,
+            None => Vec::new(),
+        };
+        let local_hgid = local.hgid;
+        let local = self.repo.read_file(local_path, local_hgid)?;
+        let other = self.repo.read_file(path, other.hgid)?;
+        if [&base, &local, &other].iter().any(|c| c.contains(&0)) {
+            return Ok(Err(ConflictKind::Binary));
+        }
+        let merged = merge_text(&base, &local, &other, LABELS);
+        if !merged.is_clean() {
+            return Ok(Err(ConflictKind::Content {
+                merged: merged.content,
+            }));
+        }
+        // Reuse the local revision when the other side doesn't change its
+        // content, so commits whose changes are already in the destination
+        // become empty.
+        let hgid = if merged.content == local {
+            local_hgid
+        } else {
+            self.repo.write_file(local_path, &merged.content)?
+        };
+        Ok(Ok(Some(FileMetadata::new(hgid, file_type))))
+    }
+
+    fn set_file(&mut self, path: &RepoPath, meta: Option<FileMetadata>) -> Result<()> {
+        match meta {
+            Some(meta) => self.tree.insert(path.to_owned(), meta),
+            None => self.tree.remove(path).map(|_| ()),
+        }
+    }
+
+    /// Commits the merged `tree` as the rebased version of `commit`.
+    fn commit(&mut self, commit: HgId) -> Result<Step> {
+        let manifest = self.tree.flush()?;
+        self.merging = false;
+        self.next += 1;
+        if manifest == self.parent_manifest {
+            self.rebased.push((commit, None));
+            return Ok(Step::Skipped(commit));
+        }
+        let new = self.repo.create_commit(commit, self.parent, manifest)?;
+        self.parent = new;
+        self.parent_manifest = manifest;
+        self.rebased.push((commit, Some(new)));
+        Ok(Step::Rebased { old: commit, new })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use parking_lot::Mutex;
+
+    use manifest_tree::testutil::TestStore;
+    use types::testutil::*;
+
+    struct TestRepo {
+        store: Arc<TestStore>,
+        files: Mutex<HashMap<HgId, Vec<u8>>>,
+        commits: Mutex<HashMap<HgId, CommitInfo>>,
+        last_id: Mutex<u64>,
+        renames: Vec<(RepoPathBuf, RepoPathBuf)>,
+    }
+
+    impl TestRepo {
+        fn new() -> Self {
+            TestRepo {
+                store: Arc::new(TestStore::new()),
+                files: Default::default(),
+                commits: Default::default(),
+                last_id: Default::default(),
+                renames: Vec::new(),
+            }
+        }
+
+        fn new_id(&self) -> HgId {
+            let mut last_id = self.last_id.lock();
+            *last_id += 1;
+            let mut bytes = [0; HgId::len()];
+            bytes[..8].copy_from_slice(&last_id.to_be_bytes());
+            HgId::from_byte_array(bytes)
+        }
+
+        fn tree(&self, commit: HgId) -> TreeManifest {
+            let manifest = self.commits.lock()[&commit].manifest;
+            TreeManifest::durable(self.store.clone(), manifest)
+        }
+
+        /// Creates a commit on top of `parent`. Files with `None` content
+        /// are removed.
+        fn make_commit(&self, parent: Option<HgId>, changes: &[(&str, Option<&str>)]) -> HgId {
+            let mut tree = match parent {
+                Some(parent) => self.tree(parent),
+                None => TreeManifest::ephemeral(self.store.clone()),
+            };
+            for (path, content) in changes {
+                match content {
+                    Some(content) => {
+                        let hgid = self
+                            .write_file(repo_path(path), content.as_bytes())
+                            .unwrap();
+                        tree.insert(repo_path_buf(path), FileMetadata::regular(hgid))
+                            .unwrap();
+                    }
+                    None => {
+                        tree.remove(repo_path(path)).unwrap();
+                    }
+                }
+            }
+            let manifest = tree.flush().unwrap();
+            let commit = self.new_id();
+            let parents = parent.into_iter().collect();
+            self.commits
+                .lock()
+                .insert(commit, CommitInfo { manifest, parents });
+            commit
+        }
+
+        fn files(&self, commit: HgId) -> Vec<(String, String)> {
+            let tree = self.tree(commit);
+            let matcher = AlwaysMatcher::new();
+            tree.files(&matcher)
+                .map(|file| {
+                    let file = file.unwrap();
+                    let content = self.read_file(&file.path, file.meta.hgid).unwrap();
+                    (file.path.to_string(), String::from_utf8(content).unwrap())
+                })
+                .collect()
+        }
+
+        fn parents(&self, commit: HgId) -> Vec<HgId> {
+            self.commits.lock()[&commit].parents.clone()
+        }
+    }
+
+    impl RebaseRepo for &TestRepo {
+        fn commit(&self, commit: HgId) -> Result<CommitInfo> {
+            Ok(self.commits.lock()[&commit].clone())
+        }
+
+        fn read_file(&self, _path: &RepoPath, hgid: HgId) -> Result<Vec<u8>> {
+            Ok(self.files.lock()[&hgid].clone())
+        }
+
+        fn write_file(&self, _path: &RepoPath, content: &[u8]) -> Result<HgId> {
+            let hgid = self.new_id();
+            self.files.lock().insert(hgid, content.to_vec());
+            Ok(hgid)
+        }
+
+        fn create_commit(&self, _original: HgId, parent: HgId, manifest: HgId) -> Result<HgId> {
+            let commit = self.new_id();
+            let parents = vec![parent];
+            self.commits
+                .lock()
+                .insert(commit, CommitInfo { manifest, parents });
+            Ok(commit)
+        }
+
+        fn find_renames(&self, _old: HgId, _new: HgId) -> Result<Vec<(RepoPathBuf, RepoPathBuf)>> {
+            Ok(self.renames.clone())
+        }
+    }
+
+    fn files(files: &[(&str, &str)]) -> Vec<(String, String)> {
+        files
+            .iter()
+            .map(|(path, content)| (path.to_string(), content.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_rebase_clean() {
+        let repo = TestRepo::new();
+        let base = repo.make_commit(None, &[("a", Some("1\n2\n3\n")), ("d/b", Some("b\n"))]);
+        let dest = repo.make_commit(Some(base), &[("a", Some("one\n2\n3\n"))]);
+        let c1 = repo.make_commit(Some(base), &[("a", Some("1\n2\nthree\n"))]);
+        let c2 = repo.make_commit(Some(c1), &[("c", Some("c\n")), ("d/b", None)]);
+
+        let mut rebase = Rebase::new(&repo, repo.store.clone(), vec![c1, c2], dest).unwrap();
+        let new1 = match rebase.step().unwrap() {
+            Step::Rebased { old, new } if old == c1 => new,
+            step => panic!("unexpected {:?}", step),
+        };
+        let new2 = match rebase.step().unwrap() {
+            Step::Rebased { old, new } if old == c2 => new,
+            step => panic!("unexpected {:?}", step),
+        };
+        assert_eq!(rebase.step().unwrap(), Step::Done);
+        assert_eq!(rebase.state(), State::Done);
+        assert_eq!(rebase.rebased(), &[(c1, Some(new1)), (c2, Some(new2))]);
+
+        assert_eq!(repo.parents(new1), vec![dest]);
+        assert_eq!(repo.parents(new2), vec![new1]);
+        assert_eq!(
+            repo.files(new1),
+            files(&[("a", "one\n2\nthree\n"), ("d/b", "b\n")])
+        );
+        assert_eq!(
+            repo.files(new2),
+            files(&[("a", "one\n2\nthree\n"), ("c", "c\n")])
+        );
+    }
+
+    #[test]
+    fn test_rebase_conflict() {
+        let repo = TestRepo::new();
+        let base = repo.make_commit(None, &[("a", Some("1\n2\n3\n"))]);
+        let dest = repo.make_commit(Some(base), &[("a", Some("1\nx\n3\n"))]);
+        let commit = repo.make_commit(Some(base), &[("a", Some("1\ny\n3\n"))]);
+
+        let mut rebase = Rebase::new(&repo, repo.store.clone(), vec![commit], dest).unwrap();
+        assert_eq!(rebase.step().unwrap(), Step::Conflicted(commit));
+        assert_eq!(rebase.state(), State::Conflicted);
+        let conflicts: Vec<_> = rebase.conflicts().cloned().collect();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, repo_path_buf("a"));
+        assert_eq!(
+            conflicts[0].kind,
+            ConflictKind::Content {
+                merged: b"1\n<<<<<<< dest\nx\n=======\ny\n>>>>>>> source\n3\n".to_vec()
+            }
+        );
+        assert!(rebase.step().is_err());
+        assert!(rebase.resolve(repo_path("b"), None).is_err());
+
+        rebase
+            .resolve(repo_path("a"), Some((b"1\nxy\n3\n", FileType::Regular)))
+            .unwrap();
+        assert_eq!(rebase.state(), State::Ready);
+        let new = match rebase.step().unwrap() {
+            Step::Rebased { new, .. } => new,
+            step => panic!("unexpected {:?}", step),
+        };
+        assert_eq!(repo.files(new), files(&[("a", "1\nxy\n3\n")]));
+        assert_eq!(rebase.step().unwrap(), Step::Done);
+    }
+
+    #[test]
+    fn test_rebase_change_delete() {
+        let repo = TestRepo::new();
+        let base = repo.make_commit(None, &[("a", Some("a\n")), ("b", Some("b\n"))]);
+        let dest = repo.make_commit(Some(base), &[("a", None)]);
+        let commit = repo.make_commit(Some(base), &[("a", Some("a2\n"))]);
+
+        let mut rebase = Rebase::new(&repo, repo.store.clone(), vec![commit], dest).unwrap();
+        assert_eq!(rebase.step().unwrap(), Step::Conflicted(commit));
+        let conflict = rebase.conflicts().next().unwrap().clone();
+        assert_eq!(conflict.kind, ConflictKind::ChangeDelete);
+        assert_eq!(conflict.local, None);
+
+        // Keeping the deletion leaves nothing to commit.
+        rebase.resolve(repo_path("a"), None).unwrap();
+        assert_eq!(rebase.step().unwrap(), Step::Skipped(commit));
+        assert_eq!(rebase.rebased(), &[(commit, None)]);
+    }
+
+    #[test]
+    fn test_rebase_follows_renames() {
+        let mut repo = TestRepo::new();
+        let base = repo.make_commit(None, &[("a", Some("1\n2\n3\n"))]);
+        let dest = repo.make_commit(Some(base), &[("a", None), ("b", Some("one\n2\n3\n"))]);
+        let commit = repo.make_commit(Some(base), &[("a", Some("1\n2\nthree\n"))]);
+        repo.renames = vec![(repo_path_buf("a"), repo_path_buf("b"))];
+
+        let mut rebase = Rebase::new(&repo, repo.store.clone(), vec![commit], dest).unwrap();
+        let new = match rebase.step().unwrap() {
+            Step::Rebased { new, .. } => new,
+            step => panic!("unexpected {:?}", step),
+        };
+        assert_eq!(repo.files(new), files(&[("b", "one\n2\nthree\n")]));
+    }
+
+    #[test]
+    fn test_rebase_skips_empty_commits() {
+        let repo = TestRepo::new();
+        let base = repo.make_commit(None, &[("a", Some("1\n"))]);
+        let dest = repo.make_commit(Some(base), &[("a", Some("2\n"))]);
+        let commit = repo.make_commit(Some(base), &[("a", Some("2\n"))]);
+
+        let mut rebase = Rebase::new(&repo, repo.store.clone(), vec![commit], dest).unwrap();
+        assert_eq!(rebase.step().unwrap(), Step::Skipped(commit));
+        assert_eq!(rebase.step().unwrap(), Step::Done);
+    }
+
+    #[test]
+    fn test_rebase_merge_commit() {
+        let repo = TestRepo::new();
+        let base = repo.make_commit(None, &[("a", Some("1\n"))]);
+        let merge = repo.make_commit(Some(base), &[]);
+        repo.commits
+            .lock()
+            .get_mut(&merge)
+            .unwrap()
+            .parents
+            .push(base);
+
+        let mut rebase = Rebase::new(&repo, repo.store.clone(), vec![merge], base).unwrap();
+        let err = rebase.step().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RebaseError>(),
+            Some(RebaseError::MergeCommit(_))
+        ));
+    }
+}
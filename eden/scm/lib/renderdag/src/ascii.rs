@@ -214,7 +214,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::render::GraphRowRenderer;
+    use crate::render::{Ancestor, GraphRowRenderer, ParentOrder, Renderer};
     use crate::test_fixtures::{self, TestFixture};
     use crate::test_utils::render_string;
 
@@ -419,6 +419,60 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parent_order() {
+        let render = |parent_order| {
+            let mut renderer = GraphRowRenderer::new()
+                .with_parent_order(parent_order)
+                .output()
+                .with_min_row_height(0)
+                .build_ascii();
+            let parent = |node| Ancestor::Parent(node);
+            let rows = vec![
+                ("E", vec![parent("B")]),
+                ("D", vec![parent("B"), parent("C")]),
+                ("C", vec![parent("A")]),
+                ("B", vec![parent("A")]),
+                ("A", vec![]),
+            ];
+            let mut out = String::from("\n");
+            renderer.reserve("D");
+            for (node, parents) in rows {
+                out.push_str(&renderer.next_row(node, parents, "o".into(), node.into()));
+            }
+            out
+        };
+
+        // The first parent of D stays in the column it was assigned to.
+        assert_eq!(
+            render(ParentOrder::Recency),
+            r#"
+  o  E
+o |  D
+|\|
+o |  C
+| o  B
+|/
+o  A
+"#
+        );
+
+        // The first parent of D moves to its column, and the second parent
+        // goes to the right.
+        assert_eq!(
+            render(ParentOrder::FirstParent),
+            r#"
+  o  E
+o |    D
++-+-.
+|   o  C
+o   |  B
++---'
+o  A
+"#
+        );
+    }
+
     #[test]
     fn long_messages() {
         assert_eq!(
@@ -58,6 +58,7 @@ pub(crate) trait ColumnsExt<N> {
     fn find(&self, node: &N) -> Option<usize>;
     fn find_empty(&self, index: usize) -> Option<usize>;
     fn first_empty(&self) -> Option<usize>;
+    fn first_empty_after(&self, index: usize) -> Option<usize>;
     fn new_empty(&mut self) -> usize;
     fn reset(&mut self);
 }
@@ -91,6 +92,10 @@ where
         None
     }
 
+    fn first_empty_after(&self, index: usize) -> Option<usize> {
+        (index + 1..self.len()).find(|&i| self[i] == Column::Empty)
+    }
+
     fn new_empty(&mut self) -> usize {
         self.push(Column::Empty);
         self.len() - 1
@@ -10,6 +10,7 @@ mod ascii_large;
 mod box_drawing;
 mod column;
 mod output;
+mod page;
 mod render;
 
 #[cfg(test)]
@@ -21,4 +22,9 @@ mod test_utils;
 pub use crate::ascii::AsciiRenderer;
 pub use crate::ascii_large::AsciiLargeRenderer;
 pub use crate::box_drawing::BoxDrawingRenderer;
-pub use crate::render::{Ancestor, GraphRowRenderer, LinkLine, NodeLine, PadLine, Renderer};
+pub use crate::page::{
+    render_dag_next_page, render_dag_page, render_page, Continuation, Page, PageNode,
+};
+pub use crate::render::{
+    Ancestor, GraphRowRenderer, LinkLine, NodeLine, PadLine, ParentOrder, Renderer,
+};
@@ -0,0 +1,316 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::Result;
+use dag::{spanset::Span, spanset::SpanSet, Dag, Id};
+
+use crate::render::{Ancestor, Renderer};
+
+/// Position to resume a paginated rendering from.
+///
+/// A continuation records the next node to render and the state of the
+/// renderer after the previous page. Rendering the next page from it
+/// produces the same rows as rendering the whole graph in one go, without
+/// re-rendering the rows before it.
+#[derive(Clone)]
+pub struct Continuation<N, R> {
+    node: N,
+    renderer: R,
+}
+
+/// A page of rendered graph rows.
+pub struct Page<N, R>
+where
+    R: Renderer<N>,
+{
+    /// The rendered rows.
+    pub rows: Vec<R::Output>,
+
+    /// Where to continue rendering, or `None` if there are no more rows.
+    pub next: Option<Continuation<N, R>>,
+}
+
+/// Description of a node to render.
+pub struct PageNode<N> {
+    pub node: N,
+    pub parents: Vec<Ancestor<N>>,
+    pub glyph: String,
+    pub message: String,
+}
+
+impl<N, R> Continuation<N, R> {
+    /// The next node to render.
+    pub fn node(&self) -> &N {
+        &self.node
+    }
+}
+
+/// Render at most `limit` rows using `renderer`.
+///
+/// `nodes` should start from the node that `renderer` expects next. That is,
+/// the first node for a new renderer, or [`Continuation::node`] when
+/// continuing from a previous page. Nodes after the page are not consumed
+/// except for the first one, which becomes the node of the continuation.
+pub fn render_page<N, R>(
+    mut renderer: R,
+    nodes: impl IntoIterator<Item = Result<PageNode<N>>>,
+    limit: usize,
+) -> Result<Page<N, R>>
+where
+    R: Renderer<N>,
+{
+    let mut rows = Vec::new();
+    let mut nodes = nodes.into_iter();
+    while rows.len() < limit {
+        match nodes.next() {
+            None => break,
+            Some(node) => {
+                let node = node?;
+                rows.push(renderer.next_row(node.node, node.parents, node.glyph, node.message));
+            }
+        }
+    }
+    let next = match nodes.next() {
+        None => None,
+        Some(node) => Some(Continuation {
+            node: node?.node,
+            renderer,
+        }),
+    };
+    Ok(Page { rows, next })
+}
+
+/// Render at most `limit` rows of the graph of `set`, in descending id order,
+/// starting from the highest id in `set` using `renderer`.
+///
+/// `describe` returns the glyph and the message for a given id.
+///
+/// Parents outside `set` are rendered as ancestors if they have ancestors
+/// in `set`, or as anonymous ancestors otherwise.
+pub fn render_dag_page<R>(
+    dag: &Dag,
+    set: &SpanSet,
+    renderer: R,
+    limit: usize,
+    describe: impl Fn(Id) -> Result<(String, String)>,
+) -> Result<Page<Id, R>>
+where
+    R: Renderer<Id>,
+{
+    dag_page(dag, set.clone(), renderer, limit, describe)
+}
+
+/// Render the page of `set` after the one that returned `continuation`.
+///
+/// `set` and `describe` should be the same as for the previous pages. The
+/// renderer of the previous pages is used, with its options.
+pub fn render_dag_next_page<R>(
+    dag: &Dag,
+    set: &SpanSet,
+    continuation: Continuation<Id, R>,
+    limit: usize,
+    describe: impl Fn(Id) -> Result<(String, String)>,
+) -> Result<Page<Id, R>>
+where
+    R: Renderer<Id>,
+{
+    let Continuation { node, renderer } = continuation;
+    let remaining = SpanSet::from(Span::from(Id::MIN..=node));
+    dag_page(dag, set.intersection(&remaining), renderer, limit, describe)
+}
+
+fn dag_page<R>(
+    dag: &Dag,
+    set: SpanSet,
+    renderer: R,
+    limit: usize,
+    describe: impl Fn(Id) -> Result<(String, String)>,
+) -> Result<Page<Id, R>>
+where
+    R: Renderer<Id>,
+{
+    // The ids of the page, and the first id of the next page if there is one.
+    let ids: Vec<Id> = set.iter().take(limit.saturating_add(1)).collect();
+    let rows = ids.len().min(limit);
+    let parent_ids = ids[..rows]
+        .iter()
+        .map(|&id| dag.parent_ids(id))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Parents outside `set` are connected to their closest ancestors in
+    // `set`. The ancestors in `set` of all such parents of the page are found
+    // with a single query. Parents without any are anonymous, and the others
+    // only look for their closest ancestors among those.
+    let outside = SpanSet::from_spans(
+        parent_ids
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|&id| !set.contains(id)),
+    );
+    let reachable = if outside.is_empty() {
+        SpanSet::empty()
+    } else {
+        dag.ancestors(outside)?.intersection(&set)
+    };
+    let closest_ancestors = |id: Id| -> Result<SpanSet> {
+        if reachable.is_empty() {
+            Ok(SpanSet::empty())
+        } else {
+            dag.heads(dag.ancestors(id)?.intersection(&reachable))
+        }
+    };
+
+    let nodes = ids.iter().zip(parent_ids).map(|(&id, parent_ids)| {
+        let mut parents = Vec::new();
+        for parent_id in parent_ids {
+            if set.contains(parent_id) {
+                parents.push(Ancestor::Parent(parent_id));
+            } else {
+                let ancestors = closest_ancestors(parent_id)?;
+                if ancestors.is_empty() {
+                    parents.push(Ancestor::Anonymous);
+                }
+                for ancestor_id in ancestors.iter() {
+                    if !parents.iter().any(|p| p.id() == Some(&ancestor_id)) {
+                        parents.push(Ancestor::Ancestor(ancestor_id));
+                    }
+                }
+            }
+        }
+        let (glyph, message) = describe(id)?;
+        Ok(PageNode {
+            node: id,
+            parents,
+            glyph,
+            message,
+        })
+    });
+    // The first id of the next page has no parents computed. Only its id is
+    // used, for the continuation.
+    let next = ids.get(rows).map(|&id| {
+        Ok(PageNode {
+            node: id,
+            parents: Vec::new(),
+            glyph: String::new(),
+            message: String::new(),
+        })
+    });
+    render_page(renderer, nodes.chain(next), limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use dag::{Group, IdMap, VertexName};
+    use tempfile::tempdir;
+
+    use crate::render::GraphRowRenderer;
+    use crate::test_fixtures::{self, TestFixture};
+
+    fn render_pages(
+        fixture: &TestFixture,
+        set: impl Fn(&Dag) -> SpanSet,
+        limit: usize,
+    ) -> Vec<String> {
+        let dir = tempdir().unwrap();
+        let mut id_map = IdMap::open(dir.path().join("id")).unwrap();
+        let mut dag = Dag::open(dir.path().join("dag")).unwrap();
+        let parents = drawdag::parse(fixture.dag);
+        let parents_by_name = move |name: VertexName| -> Result<Vec<VertexName>> {
+            let name = String::from_utf8(name.as_ref().to_vec()).unwrap();
+            Ok(parents[&name]
+                .iter()
+                .map(|p| VertexName::copy_from(p.as_bytes()))
+                .collect())
+        };
+        let mut last_head = Id(0);
+        for head in fixture.heads.iter() {
+            id_map
+                .assign_head(head.as_bytes().into(), &parents_by_name, Group::MASTER)
+                .unwrap();
+            last_head = id_map.find_id_by_name(head.as_bytes()).unwrap().unwrap();
+        }
+        let parents_by_id = id_map.build_get_parents_by_id(&parents_by_name);
+        dag.build_segments_volatile(last_head, &parents_by_id)
+            .unwrap();
+
+        let set = set(&dag);
+        let describe = |id: Id| -> Result<(String, String)> {
+            let name = id_map.find_name_by_id(id)?.unwrap();
+            Ok(("o".to_string(), String::from_utf8(name.to_vec())?))
+        };
+        let renderer = GraphRowRenderer::new().output().build_box_drawing();
+        let mut page = render_dag_page(&dag, &set, renderer, limit, describe).unwrap();
+        let mut pages = vec![page.rows.concat()];
+        while let Some(continuation) = page.next {
+            page = render_dag_next_page(&dag, &set, continuation, limit, describe).unwrap();
+            pages.push(page.rows.concat());
+        }
+        pages
+    }
+
+    #[test]
+    fn test_pages_match_full_rendering() {
+        for fixture in [
+            test_fixtures::BASIC,
+            test_fixtures::BRANCHES_AND_MERGES,
+            test_fixtures::OCTOPUS_BRANCH_AND_MERGE,
+        ]
+        .iter()
+        {
+            let full = render_pages(fixture, |dag| dag.all().unwrap(), usize::MAX);
+            assert_eq!(full.len(), 1);
+            for limit in 1..5 {
+                let pages = render_pages(fixture, |dag| dag.all().unwrap(), limit);
+                assert_eq!(pages.concat(), full[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pages_subset() {
+        // Render ids 4 to 9 of the "A-B-C-...-J" chain, two rows per page.
+        let fixture = TestFixture {
+            dag: "A-B-C-D-E-F-G-H-I-J-K",
+            messages: &[],
+            heads: &["K"],
+            reserve: &[],
+            ancestors: &[],
+            missing: &[],
+        };
+        let set = |_dag: &Dag| SpanSet::from(Span::from(Id(4)..=Id(9)));
+        let pages = render_pages(&fixture, set, 2);
+        assert_eq!(
+            pages,
+            [
+                "o  J\n│\no  I\n│\n",
+                "o  H\n│\no  G\n│\n",
+                "o  F\n│\no  E\n│\n~\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pages_ancestors() {
+        // Render A, C, E and F of "A-B-C-D-E" with "B-F". E is connected to
+        // C, and F to A, through parents outside the set.
+        let fixture = TestFixture {
+            dag: "A-B-C-D-E\n   \\\n    F",
+            messages: &[],
+            heads: &["E", "F"],
+            reserve: &[],
+            ancestors: &[],
+            missing: &[],
+        };
+        let set = |_dag: &Dag| SpanSet::from_spans(vec![Id(0), Id(2), Id(4), Id(5)]);
+        let pages = render_pages(&fixture, set, 2);
+        assert_eq!(pages, ["o  F\n╷\n╷ o  E\n╭─┤\n", "╷ o  C\n╭─╯\no  A\n\n"]);
+        assert_eq!(render_pages(&fixture, set, usize::MAX), [pages.concat()]);
+    }
+}
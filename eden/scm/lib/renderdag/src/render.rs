@@ -37,6 +37,22 @@ pub trait Renderer<N> {
 /// Converts a sequence of DAG node descriptions into rendered graph rows.
 pub struct GraphRowRenderer<N> {
     columns: Vec<Column<N>>,
+    parent_order: ParentOrder,
+}
+
+/// How the columns of the parents of a node are assigned.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParentOrder {
+    /// Parents keep the columns they were last assigned, and new parents take
+    /// the leftmost empty columns.  Only a single parent is moved to the
+    /// column of its child.  This is the default.
+    Recency,
+
+    /// The first parent continues in the column of its child, moving from a
+    /// column on the right if necessary, and the other new parents take the
+    /// empty columns to the right of it in order.  This keeps the mainline on
+    /// the left.
+    FirstParent,
 }
 
 /// Ancestor type indication for an ancestor or parent node.
@@ -71,7 +87,7 @@ impl<N> Ancestor<N> {
         }
     }
 
-    fn id(&self) -> Option<&N> {
+    pub(crate) fn id(&self) -> Option<&N> {
         match self {
             Ancestor::Ancestor(n) => Some(&n),
             Ancestor::Parent(n) => Some(&n),
@@ -206,9 +222,16 @@ where
     pub fn new() -> Self {
         GraphRowRenderer {
             columns: Vec::new(),
+            parent_order: ParentOrder::Recency,
         }
     }
 
+    /// Set how the columns of the parents of a node are assigned.
+    pub fn with_parent_order(mut self, parent_order: ParentOrder) -> Self {
+        self.parent_order = parent_order;
+        self
+    }
+
     /// Build an output renderer from this renderer.
     pub fn output(self) -> OutputRendererBuilder<N, Self> {
         OutputRendererBuilder::new(self)
@@ -300,24 +323,41 @@ where
         let mut pad_lines: Vec<_> = self.columns.iter().map(|c| c.to_pad_line()).collect();
 
         // Assign each parent to a column.
+        let first_parent_order = self.parent_order == ParentOrder::FirstParent;
         let mut parent_columns = BTreeMap::new();
-        for p in parents.iter() {
+        let mut first_parent_column = None;
+        for (n, p) in parents.iter().enumerate() {
             // Check if the parent already has a column.
             if let Some(parent_id) = p.id() {
                 if let Some(index) = self.columns.find(parent_id) {
                     self.columns[index].merge(&p.to_column());
                     parent_columns.insert(index, p);
+                    first_parent_column = first_parent_column.or(Some(index));
                     continue;
                 }
             }
             // Assign the parent to an empty column, preferring the column
-            // the current node is going in, to maintain linearity.
-            if let Some(index) = self.columns.find_empty(column) {
+            // the current node is going in, to maintain linearity.  With
+            // first parent ordering, that column is kept for the first
+            // parent, and the others go to its right.
+            let empty = if first_parent_order && n > 0 {
+                let first_parent_moves = matches!(first_parent_column, Some(c) if c > column);
+                if !first_parent_moves && self.columns[column] == Column::Empty {
+                    Some(column)
+                } else {
+                    self.columns.first_empty_after(column)
+                }
+            } else {
+                self.columns.find_empty(column)
+            };
+            if let Some(index) = empty {
                 self.columns[index].merge(&p.to_column());
                 parent_columns.insert(index, p);
+                first_parent_column = first_parent_column.or(Some(index));
                 continue;
             }
             // There are no empty columns left.  Make a new column.
+            first_parent_column = first_parent_column.or(Some(self.columns.len()));
             parent_columns.insert(self.columns.len(), p);
             node_line.push(NodeLine::Blank);
             pad_lines.push(PadLine::Blank);
@@ -335,12 +375,12 @@ where
         }
 
         // Check if we can move the parent to the current column.
-        if parents.len() == 1 {
-            if let Some((&parent_column, _)) = parent_columns.iter().next() {
-                if parent_column > column {
-                    // This node has a single parent which was already
-                    // assigned to a column to the right of this one.
-                    // Move the parent to this column.
+        if parents.len() == 1 || first_parent_order {
+            if let Some(parent_column) = first_parent_column {
+                if parent_column > column && !parent_columns.contains_key(&column) {
+                    // This node has a single parent, or a first parent,
+                    // which was already assigned to a column to the right
+                    // of this one.  Move the parent to this column.
                     self.columns.swap(column, parent_column);
                     let parent = parent_columns
                         .remove(&parent_column)
@@ -0,0 +1,24 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! repo-http - Read-only HTTP access to a local repository.
+//!
+//! [`RepoService`] answers commit graph queries, tree listings and file content requests
+//! using the Rust stores directly, so that web UIs and editor integrations can inspect a
+//! repository without spawning `hg` processes. The service itself does not do any I/O: it maps
+//! a method and a URL to a [`Response`]. The `server` feature adds [`Server`], which serves a
+//! `RepoService` over HTTP.
+
+mod service;
+
+#[cfg(feature = "server")]
+mod server;
+
+pub use crate::service::{FileStore, RepoService, Response};
+
+#[cfg(feature = "server")]
+pub use crate::server::Server;
@@ -0,0 +1,152 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+
+use anyhow::{format_err, Result};
+use tiny_http::{Header, ListenAddr};
+
+use crate::{RepoService, Response};
+
+/// Serves a [`RepoService`] over HTTP.
+///
+/// Requests are answered one at a time on the thread calling [`Server::run`]. The service is
+/// meant for local tools, so the server should be bound to a loopback address. Requests are only
+/// answered if their `Host`, and their `Origin` if they have one, name a loopback address with
+/// the port of the server. Otherwise a web page could read the repository by pointing one of its
+/// domains at the loopback address (DNS rebinding).
+pub struct Server {
+    http: tiny_http::Server,
+    service: Arc<RepoService>,
+}
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs, service: Arc<RepoService>) -> Result<Self> {
+        let http = tiny_http::Server::http(addr).map_err(|e| format_err!("{}", e))?;
+        Ok(Server { http, service })
+    }
+
+    /// The address the server listens on. Useful when binding to port 0.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match self.http.server_addr() {
+            ListenAddr::IP(addr) => Some(addr),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// Answers requests until an error occurs.
+    pub fn run(&self) -> Result<()> {
+        loop {
+            self.handle_next()?;
+        }
+    }
+
+    /// Waits for the next request and answers it.
+    pub fn handle_next(&self) -> Result<()> {
+        let request = self.http.recv()?;
+        let response = if self.is_local(&request) {
+            self.service
+                .handle(request.method().as_str(), request.url())
+        } else {
+            Response::error(403, "requests must come from a local client")
+        };
+        let content_type = Header::from_bytes(&b"Content-Type"[..], response.content_type)
+            .map_err(|_| format_err!("invalid content type {}", response.content_type))?;
+        let http_response = tiny_http::Response::from_data(response.body)
+            .with_status_code(response.status)
+            .with_header(content_type);
+        request.respond(http_response)?;
+        Ok(())
+    }
+
+    /// Whether the `Host` and `Origin` headers of `request` name the server on a loopback
+    /// address.
+    fn is_local(&self, request: &tiny_http::Request) -> bool {
+        let port = match self.addr() {
+            Some(addr) => addr.port(),
+            None => return false,
+        };
+        let is_local_host = |host: &str| {
+            ["localhost", "127.0.0.1", "[::1]"]
+                .iter()
+                .any(|name| host.eq_ignore_ascii_case(&format!("{}:{}", name, port)))
+        };
+        let header = |name: &'static str| {
+            request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv(name))
+                .map(|header| header.value.as_str())
+        };
+        match header("Host") {
+            Some(host) if is_local_host(host) => {}
+            _ => return false,
+        }
+        match header("Origin") {
+            None => true,
+            Some(origin) => {
+                matches!(origin.strip_prefix("http://"), Some(host) if is_local_host(host))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    /// Sends a GET request for `/graph/heads` with the given headers and returns the response.
+    fn get(headers: impl Fn(u16) -> String) -> String {
+        let server = Server::bind("127.0.0.1:0", Arc::new(RepoService::new())).unwrap();
+        let addr = server.addr().unwrap();
+        let handle = thread::spawn(move || server.handle_next());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request = format!(
+            "GET /graph/heads HTTP/1.1\r\n{}Connection: close\r\n\r\n",
+            headers(addr.port())
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        handle.join().unwrap().unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve() {
+        let response = get(|port| format!("Host: localhost:{}\r\n", port));
+        assert!(response.starts_with("HTTP/1.1 501"), "{}", response);
+        assert!(response.contains("Content-Type: application/json"));
+        assert!(response.ends_with(r#"{"error":"commit graph not available"}"#));
+    }
+
+    #[test]
+    fn test_foreign_host() {
+        let forbidden = |response: String| response.starts_with("HTTP/1.1 403");
+        assert!(forbidden(get(|_| "Host: example.com\r\n".to_string())));
+        assert!(forbidden(get(|port| format!(
+            "Host: example.com:{}\r\n",
+            port
+        ))));
+        assert!(forbidden(get(|_| "Host: localhost:1\r\n".to_string())));
+        assert!(forbidden(get(|port| format!(
+            "Host: 127.0.0.1:{}\r\nOrigin: http://example.com\r\n",
+            port
+        ))));
+        assert!(!forbidden(get(|port| format!(
+            "Host: [::1]:{}\r\nOrigin: http://localhost:{}\r\n",
+            port, port
+        ))));
+    }
+}
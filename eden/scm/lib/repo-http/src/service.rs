@@ -0,0 +1,528 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{format_err, Result};
+use serde::Serialize;
+
+use dag::{idmap::IdMapLike, nameddag::LowLevelAccess, spanset::SpanSet, Id, NamedDag};
+use manifest::{FileType, FsNodeMetadata, List, Manifest};
+use manifest_tree::{TreeManifest, TreeStore};
+use types::{HgId, RepoPath, RepoPathBuf};
+
+/// Source of file content for the `/file` endpoint.
+///
+/// This is kept separate from the stores of the `revisionstore` crate so that the service can be
+/// embedded without depending on them. Adapting a `DataStore` only requires stripping the copy
+/// metadata header from the data it returns.
+pub trait FileStore: Send + Sync {
+    /// Returns the content of the file revision, or `None` if the store does not have it.
+    fn get_file(&self, path: &RepoPath, hgid: HgId) -> Result<Option<Vec<u8>>>;
+}
+
+/// The answer to a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn json(value: &impl Serialize) -> Result<Self> {
+        Ok(Response {
+            status: 200,
+            content_type: "application/json",
+            body: serde_json::to_vec(value)?,
+        })
+    }
+
+    fn bytes(body: Vec<u8>) -> Self {
+        Response {
+            status: 200,
+            content_type: "application/octet-stream",
+            body,
+        }
+    }
+
+    pub(crate) fn error(status: u16, message: &str) -> Self {
+        #[derive(Serialize)]
+        struct Error<'a> {
+            error: &'a str,
+        }
+        Response {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec(&Error { error: message })
+                .expect("serializing a string does not fail"),
+        }
+    }
+}
+
+/// Reasons a request could not be answered.
+enum RequestError {
+    BadRequest(String),
+    NotFound(String),
+    MethodNotAllowed,
+    Unavailable(&'static str),
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for RequestError {
+    fn from(error: anyhow::Error) -> Self {
+        RequestError::Internal(error)
+    }
+}
+
+impl From<RequestError> for Response {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::BadRequest(message) => Response::error(400, &message),
+            RequestError::NotFound(message) => Response::error(404, &message),
+            RequestError::MethodNotAllowed => Response::error(405, "method not allowed"),
+            RequestError::Unavailable(what) => {
+                Response::error(501, &format!("{} not available", what))
+            }
+            RequestError::Internal(error) => Response::error(500, &format!("{:#}", error)),
+        }
+    }
+}
+
+type RequestResult<T> = std::result::Result<T, RequestError>;
+
+/// Answers read-only queries about a repository.
+///
+/// Each kind of data comes from its own store, and the endpoints of the stores that were not
+/// provided respond with `501 Not Implemented`. All the endpoints use `GET` and, except for
+/// file content, respond with JSON. Commits, trees and files are identified by their hex
+/// hashes.
+///
+/// Commit graph:
+/// - `/graph/heads`: the heads of the graph.
+/// - `/graph/parents/{commit}`, `/graph/children/{commit}`.
+/// - `/graph/ancestors/{commit}?limit={n}`, `/graph/descendants/{commit}?limit={n}`: the
+///   commits are sorted from the newest to the oldest.
+/// - `/graph/is_ancestor/{ancestor}/{descendant}`: a boolean.
+/// - `/graph/gca/{commit}/{commit}`: the greatest common ancestors.
+///
+/// Trees:
+/// - `/tree/{manifest}/{path}`: the entries of the directory at `path`, which is empty for the
+///   root, as objects with `name`, `type` (`directory`, `file`, `executable` or `symlink`) and
+///   `hgid` fields.
+///
+/// Files:
+/// - `/file/{hgid}/{path}`: the raw content of the file revision.
+#[derive(Default)]
+pub struct RepoService {
+    dag: Option<Mutex<NamedDag>>,
+    tree_store: Option<Arc<dyn TreeStore + Send + Sync>>,
+    file_store: Option<Arc<dyn FileStore>>,
+}
+
+impl RepoService {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_dag(mut self, dag: NamedDag) -> Self {
+        self.dag = Some(Mutex::new(dag));
+        self
+    }
+
+    pub fn with_tree_store(mut self, store: Arc<dyn TreeStore + Send + Sync>) -> Self {
+        self.tree_store = Some(store);
+        self
+    }
+
+    pub fn with_file_store(mut self, store: Arc<dyn FileStore>) -> Self {
+        self.file_store = Some(store);
+        self
+    }
+
+    /// Reloads the commit graph from disk, picking up commits added by other processes.
+    pub fn reload(&self) -> Result<()> {
+        if let Some(dag) = &self.dag {
+            dag.lock()
+                .map_err(|_| format_err!("commit graph lock poisoned"))?
+                .reload()?;
+        }
+        Ok(())
+    }
+
+    /// Answers a request. `url` is the path of the request, optionally followed by a query
+    /// string.
+    pub fn handle(&self, method: &str, url: &str) -> Response {
+        match self.route(method, url) {
+            Ok(response) => response,
+            Err(error) => error.into(),
+        }
+    }
+
+    fn route(&self, method: &str, url: &str) -> RequestResult<Response> {
+        if method != "GET" {
+            return Err(RequestError::MethodNotAllowed);
+        }
+        let (path, query) = match url.find('?') {
+            Some(index) => (&url[..index], &url[index + 1..]),
+            None => (url, ""),
+        };
+        let segments: Vec<String> = path
+            .trim_start_matches('/')
+            .split('/')
+            .map(percent_decode)
+            .collect::<RequestResult<_>>()?;
+        let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+        let limit = query_param(query, "limit")?;
+
+        match segments.as_slice() {
+            ["graph", "heads"] => self.graph(|dag, map| {
+                let heads = dag.heads(dag.all()?)?;
+                names(map, heads, None)
+            }),
+            ["graph", "parents", commit] => self.graph(|dag, map| {
+                let parents = dag.parent_ids(lookup(map, commit)?)?;
+                names(map, SpanSet::from_spans(parents), None)
+            }),
+            ["graph", "children", commit] => self.graph(|dag, map| {
+                let children = dag.children(lookup(map, commit)?)?;
+                names(map, children, None)
+            }),
+            ["graph", "ancestors", commit] => self.graph(|dag, map| {
+                let ancestors = dag.ancestors(lookup(map, commit)?)?;
+                names(map, ancestors, limit)
+            }),
+            ["graph", "descendants", commit] => self.graph(|dag, map| {
+                let descendants = dag.descendants(lookup(map, commit)?)?;
+                names(map, descendants, limit)
+            }),
+            ["graph", "is_ancestor", ancestor, descendant] => self.graph(|dag, map| {
+                let ancestor = lookup(map, ancestor)?;
+                let descendant = lookup(map, descendant)?;
+                Ok(Response::json(&dag.is_ancestor(ancestor, descendant)?)?)
+            }),
+            ["graph", "gca", a, b] => self.graph(|dag, map| {
+                let set = SpanSet::from_spans(vec![lookup(map, a)?, lookup(map, b)?]);
+                names(map, dag.gca_all(set)?, None)
+            }),
+            ["tree", manifest, path @ ..] => self.tree(parse_hgid(manifest)?, &join(path)?),
+            ["file", hgid, path @ ..] if !path.is_empty() => {
+                self.file(parse_hgid(hgid)?, &join(path)?)
+            }
+            _ => Err(RequestError::NotFound(format!("no endpoint at {}", path))),
+        }
+    }
+
+    fn graph(
+        &self,
+        query: impl FnOnce(&dag::Dag, &dag::IdMap) -> RequestResult<Response>,
+    ) -> RequestResult<Response> {
+        let dag = self
+            .dag
+            .as_ref()
+            .ok_or(RequestError::Unavailable("commit graph"))?
+            .lock()
+            .map_err(|_| format_err!("commit graph lock poisoned"))?;
+        query(dag.dag(), dag.map())
+    }
+
+    fn tree(&self, manifest: HgId, path: &RepoPath) -> RequestResult<Response> {
+        #[derive(Serialize)]
+        struct TreeEntry {
+            name: String,
+            #[serde(rename = "type")]
+            kind: &'static str,
+            hgid: Option<String>,
+        }
+
+        let store = self
+            .tree_store
+            .clone()
+            .ok_or(RequestError::Unavailable("tree store"))?;
+        let tree = TreeManifest::durable(store, manifest);
+        match tree.list(path)? {
+            List::NotFound => Err(RequestError::NotFound(format!("{} not found", path))),
+            List::File => Err(RequestError::BadRequest(format!(
+                "{} is not a directory",
+                path
+            ))),
+            List::Directory(entries) => {
+                let entries: Vec<TreeEntry> = entries
+                    .into_iter()
+                    .map(|(name, node)| {
+                        let (kind, hgid) = match node {
+                            FsNodeMetadata::Directory(hgid) => ("directory", hgid),
+                            FsNodeMetadata::File(meta) => {
+                                let kind = match meta.file_type {
+                                    FileType::Regular => "file",
+                                    FileType::Executable => "executable",
+                                    FileType::Symlink => "symlink",
+                                };
+                                (kind, Some(meta.hgid))
+                            }
+                        };
+                        TreeEntry {
+                            name: name.as_str().to_string(),
+                            kind,
+                            hgid: hgid.map(|hgid| hgid.to_hex()),
+                        }
+                    })
+                    .collect();
+                Ok(Response::json(&entries)?)
+            }
+        }
+    }
+
+    fn file(&self, hgid: HgId, path: &RepoPath) -> RequestResult<Response> {
+        let store = self
+            .file_store
+            .as_ref()
+            .ok_or(RequestError::Unavailable("file store"))?;
+        match store.get_file(path, hgid)? {
+            Some(content) => Ok(Response::bytes(content)),
+            None => Err(RequestError::NotFound(format!(
+                "{} {} not found",
+                path, hgid
+            ))),
+        }
+    }
+}
+
+fn parse_hgid(hex: &str) -> RequestResult<HgId> {
+    HgId::from_str(hex).map_err(|_| RequestError::BadRequest(format!("invalid hash: {}", hex)))
+}
+
+fn lookup(map: &dag::IdMap, hex: &str) -> RequestResult<Id> {
+    let hgid = parse_hgid(hex)?;
+    map.find_id_by_name(hgid.as_ref())?
+        .ok_or_else(|| RequestError::NotFound(format!("commit {} not found", hex)))
+}
+
+/// Responds with the hex hashes of the commits in `set`, from the newest to the oldest.
+fn names(map: &dag::IdMap, set: SpanSet, limit: Option<usize>) -> RequestResult<Response> {
+    let names = set
+        .iter()
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|id| Ok(map.vertex_name(id)?.to_hex()))
+        .collect::<Result<Vec<String>>>()?;
+    Ok(Response::json(&names)?)
+}
+
+fn join(components: &[&str]) -> RequestResult<RepoPathBuf> {
+    let path = components
+        .iter()
+        .filter(|component| !component.is_empty())
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("/");
+    RepoPathBuf::from_string(path.clone())
+        .map_err(|_| RequestError::BadRequest(format!("invalid path: {}", path)))
+}
+
+fn query_param(query: &str, name: &str) -> RequestResult<Option<usize>> {
+    for pair in query.split('&') {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next() == Some(name) {
+            let value = parts.next().unwrap_or("");
+            return value
+                .parse()
+                .map(Some)
+                .map_err(|_| RequestError::BadRequest(format!("invalid {}: {}", name, value)));
+        }
+    }
+    Ok(None)
+}
+
+fn percent_decode(segment: &str) -> RequestResult<String> {
+    let invalid = || RequestError::BadRequest(format!("invalid escape in {}", segment));
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use dag::VertexName;
+    use manifest::FileMetadata;
+    use manifest_tree::testutil::TestStore;
+    use types::testutil::*;
+
+    fn commit(name: &str) -> HgId {
+        let mut bytes = [0u8; HgId::len()];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        HgId::from_byte_array(bytes)
+    }
+
+    /// A - B - C
+    ///      \
+    ///       D
+    fn make_dag(dir: &tempfile::TempDir) -> NamedDag {
+        let parents: HashMap<HgId, Vec<HgId>> = vec![
+            (commit("A"), vec![]),
+            (commit("B"), vec![commit("A")]),
+            (commit("C"), vec![commit("B")]),
+            (commit("D"), vec![commit("B")]),
+        ]
+        .into_iter()
+        .collect();
+        let parent_names = |name: VertexName| -> Result<Vec<VertexName>> {
+            let hgid = HgId::from_slice(name.as_ref())?;
+            Ok(parents[&hgid]
+                .iter()
+                .map(|p| VertexName::copy_from(p.as_ref()))
+                .collect())
+        };
+        let mut dag = NamedDag::open(dir.path()).unwrap();
+        let heads = [commit("C"), commit("D")]
+            .iter()
+            .map(|h| VertexName::copy_from(h.as_ref()))
+            .collect::<Vec<_>>();
+        dag.build(parent_names, &heads, &[]).unwrap();
+        dag
+    }
+
+    fn json(response: Response) -> serde_json::Value {
+        assert_eq!(
+            response.status,
+            200,
+            "{:?}",
+            String::from_utf8(response.body)
+        );
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    fn hexes(names: &[&str]) -> serde_json::Value {
+        names
+            .iter()
+            .map(|name| commit(name).to_hex())
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    #[test]
+    fn test_graph_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = RepoService::new().with_dag(make_dag(&dir));
+        let get = |url: String| service.handle("GET", &url);
+
+        assert_eq!(json(get("/graph/heads".into())), hexes(&["D", "C"]));
+        let b = commit("B").to_hex();
+        assert_eq!(json(get(format!("/graph/parents/{}", b))), hexes(&["A"]));
+        assert_eq!(
+            json(get(format!("/graph/children/{}", b))),
+            hexes(&["D", "C"])
+        );
+        let c = commit("C").to_hex();
+        assert_eq!(
+            json(get(format!("/graph/ancestors/{}", c))),
+            hexes(&["C", "B", "A"])
+        );
+        assert_eq!(
+            json(get(format!("/graph/ancestors/{}?limit=2", c))),
+            hexes(&["C", "B"])
+        );
+        let d = commit("D").to_hex();
+        assert_eq!(
+            json(get(format!("/graph/is_ancestor/{}/{}", b, d))),
+            serde_json::Value::Bool(true)
+        );
+        assert_eq!(
+            json(get(format!("/graph/is_ancestor/{}/{}", c, d))),
+            serde_json::Value::Bool(false)
+        );
+        assert_eq!(json(get(format!("/graph/gca/{}/{}", c, d))), hexes(&["B"]));
+    }
+
+    #[test]
+    fn test_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = RepoService::new().with_dag(make_dag(&dir));
+        let status = |method: &str, url: &str| service.handle(method, url).status;
+
+        assert_eq!(status("POST", "/graph/heads"), 405);
+        assert_eq!(status("GET", "/nothing"), 404);
+        assert_eq!(status("GET", "/graph/parents/xyz"), 400);
+        let unknown = commit("Z").to_hex();
+        assert_eq!(status("GET", &format!("/graph/parents/{}", unknown)), 404);
+        assert_eq!(
+            status("GET", &format!("/graph/ancestors/{}?limit=x", unknown)),
+            400
+        );
+        assert_eq!(status("GET", &format!("/tree/{}", unknown)), 501);
+    }
+
+    #[test]
+    fn test_tree_listing() {
+        let store = Arc::new(TestStore::new());
+        let mut tree = TreeManifest::ephemeral(store.clone());
+        let file = FileMetadata::executable(hgid("10"));
+        tree.insert(repo_path_buf("a b/c"), file).unwrap();
+        tree.insert(repo_path_buf("d"), FileMetadata::regular(hgid("20")))
+            .unwrap();
+        let root = tree.flush().unwrap();
+
+        let service = RepoService::new().with_tree_store(store);
+        let listing = json(service.handle("GET", &format!("/tree/{}", root)));
+        let entries = listing.as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["name"], "a b");
+        assert_eq!(entries[0]["type"], "directory");
+        assert_eq!(entries[1]["name"], "d");
+        assert_eq!(entries[1]["type"], "file");
+        assert_eq!(entries[1]["hgid"], hgid("20").to_hex());
+
+        let listing = json(service.handle("GET", &format!("/tree/{}/a%20b", root)));
+        assert_eq!(
+            listing,
+            serde_json::json!([{"name": "c", "type": "executable", "hgid": hgid("10").to_hex()}])
+        );
+        assert_eq!(
+            service.handle("GET", &format!("/tree/{}/d", root)).status,
+            400
+        );
+        assert_eq!(
+            service.handle("GET", &format!("/tree/{}/e", root)).status,
+            404
+        );
+    }
+
+    struct TestFileStore(HashMap<HgId, Vec<u8>>);
+
+    impl FileStore for TestFileStore {
+        fn get_file(&self, _path: &RepoPath, hgid: HgId) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.get(&hgid).cloned())
+        }
+    }
+
+    #[test]
+    fn test_file_content() {
+        let mut files = HashMap::new();
+        files.insert(hgid("1"), b"content\n".to_vec());
+        let service = RepoService::new().with_file_store(Arc::new(TestFileStore(files)));
+
+        let response = service.handle("GET", &format!("/file/{}/a/b", hgid("1")));
+        assert_eq!(response, Response::bytes(b"content\n".to_vec()));
+        let response = service.handle("GET", &format!("/file/{}/a/b", hgid("2")));
+        assert_eq!(response.status, 404);
+    }
+}
@@ -0,0 +1,180 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Bloom filters of the nodes stored in a pack.
+//!
+//! A lookup of a key that isn't in a pack still binary searches its index. With dozens of packs,
+//! most of them don't have the key, so the filter is consulted first to skip them cheaply.
+//!
+//! The filter is stored next to the index of the pack. Packs written before filters existed don't
+//! have one, and are always searched.
+//!
+//! ```text
+//! bloom = <version: u8> <hash count: u8> <bit count: u64> <bits>
+//! ```
+
+use std::{
+    fs::File,
+    io::{Cursor, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use anyhow::Result;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use thiserror::Error;
+
+use types::HgId;
+
+#[derive(Debug, Error)]
+#[error("Bloom Filter Error: {0:?}")]
+struct BloomFilterError(String);
+
+const VERSION: u8 = 1;
+
+/// With 10 bits per node and 7 hashes, about 1% of the absent nodes are reported as present.
+const BITS_PER_NODE: u64 = 10;
+const HASH_COUNT: u8 = 7;
+
+#[derive(Debug)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    bit_count: u64,
+    hash_count: u8,
+}
+
+impl BloomFilter {
+    /// Create an empty filter sized for `node_count` nodes.
+    pub fn new(node_count: usize) -> Self {
+        let byte_count = (node_count as u64 * BITS_PER_NODE / 8).max(8);
+        BloomFilter {
+            bits: vec![0; byte_count as usize],
+            bit_count: byte_count * 8,
+            hash_count: HASH_COUNT,
+        }
+    }
+
+    /// Read the filter at `path`. Returns `None` if there is no such file.
+    pub fn open(path: &Path) -> Result<Option<Self>> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        Ok(Some(BloomFilter::read(&buf)?))
+    }
+
+    pub fn read(buf: &[u8]) -> Result<Self> {
+        let mut cur = Cursor::new(buf);
+        let version = cur.read_u8()?;
+        if version != VERSION {
+            return Err(BloomFilterError(format!("unsupported version '{:?}'", version)).into());
+        }
+        let hash_count = cur.read_u8()?;
+        let bit_count = cur.read_u64::<BigEndian>()?;
+        let bits = &buf[cur.position() as usize..];
+        if hash_count == 0 || bit_count == 0 || bits.len() as u64 * 8 != bit_count {
+            return Err(BloomFilterError(format!(
+                "invalid filter of {} bits with {} hashes in {} bytes",
+                bit_count,
+                hash_count,
+                bits.len()
+            ))
+            .into());
+        }
+        Ok(BloomFilter {
+            bits: bits.to_vec(),
+            bit_count,
+            hash_count,
+        })
+    }
+
+    pub fn write<T: Write>(&self, writer: &mut T) -> Result<()> {
+        writer.write_u8(VERSION)?;
+        writer.write_u8(self.hash_count)?;
+        writer.write_u64::<BigEndian>(self.bit_count)?;
+        writer.write_all(&self.bits)?;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, hgid: &HgId) {
+        for bit in self.bit_positions(hgid) {
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Returns `false` if `hgid` was never inserted. A `true` may be a false positive.
+    pub fn contains(&self, hgid: &HgId) -> bool {
+        self.bit_positions(hgid)
+            .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Nodes are SHA-1 hashes, so their bytes are already uniformly distributed and are used as
+    /// the two base hashes of a double hashing scheme.
+    fn bit_positions(&self, hgid: &HgId) -> impl Iterator<Item = u64> {
+        let mut cur = Cursor::new(hgid.as_ref());
+        let h1 = cur.read_u64::<BigEndian>().unwrap_or_default();
+        let h2 = cur.read_u64::<BigEndian>().unwrap_or_default() | 1;
+        let bit_count = self.bit_count;
+        (0..self.hash_count as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bit_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use quickcheck::quickcheck;
+    use tempfile::TempDir;
+
+    use types::testutil::*;
+
+    #[test]
+    fn test_contains() {
+        let mut filter = BloomFilter::new(3);
+        filter.insert(&hgid("1"));
+        filter.insert(&hgid("2"));
+        assert!(filter.contains(&hgid("1")));
+        assert!(filter.contains(&hgid("2")));
+        assert!(!filter.contains(&HgId::from_slice(&[0xff; 20]).unwrap()));
+    }
+
+    #[test]
+    fn test_open() {
+        let tempdir = TempDir::new().unwrap();
+        let path = tempdir.path().join("pack.databloom");
+        assert!(BloomFilter::open(&path).unwrap().is_none());
+
+        let mut filter = BloomFilter::new(1);
+        filter.insert(&hgid("1"));
+        let mut buf = Vec::new();
+        filter.write(&mut buf).unwrap();
+        std::fs::write(&path, &buf).unwrap();
+        assert!(BloomFilter::open(&path)
+            .unwrap()
+            .unwrap()
+            .contains(&hgid("1")));
+
+        // A truncated filter would report present nodes as absent.
+        std::fs::write(&path, &buf[..buf.len() - 1]).unwrap();
+        assert!(BloomFilter::open(&path).is_err());
+    }
+
+    quickcheck! {
+        fn test_no_false_negatives(hgids: Vec<HgId>) -> bool {
+            let mut filter = BloomFilter::new(hgids.len());
+            for hgid in &hgids {
+                filter.insert(hgid);
+            }
+            let mut buf = Vec::new();
+            filter.write(&mut buf).unwrap();
+            let filter = BloomFilter::read(&buf).unwrap();
+            hgids.iter().all(|hgid| filter.contains(hgid))
+        }
+    }
+}
@@ -78,6 +78,9 @@
 //!
 //! ```
 //! [1]: new in version 1.
+//!
+//! A `.databloom` bloom filter of the hgids in the pack may be written next to
+//! the index, see the `bloomfilter` module. Packs without one are still valid.
 
 use std::{
     cell::RefCell,
@@ -99,7 +102,8 @@ use lz4_pyframe::decompress;
 use types::{HgId, Key, RepoPath};
 use util::path::remove_file;
 
-use crate::dataindex::{DataIndex, DeltaBaseOffset};
+use crate::bloomfilter::BloomFilter;
+use crate::dataindex::{DataIndex, DeltaBaseOffset, IndexEntry};
 use crate::datastore::{DataStore, Delta, Metadata};
 use crate::localstore::LocalStore;
 use crate::repack::{Repackable, ToKeys};
@@ -119,9 +123,11 @@ pub struct DataPack {
     mmap: Mmap,
     version: DataPackVersion,
     index: DataIndex,
+    bloom: Option<BloomFilter>,
     base_path: Arc<PathBuf>,
     pack_path: PathBuf,
     index_path: PathBuf,
+    bloom_path: PathBuf,
 }
 
 pub struct DataEntry<'a> {
@@ -280,13 +286,19 @@ impl DataPack {
         let mmap = unsafe { MmapOptions::new().len(len as usize).map(&file)? };
         let version = DataPackVersion::new(mmap[0])?;
         let index_path = path.with_extension("dataidx");
+        let bloom_path = path.with_extension("databloom");
+        // The filter only speeds up the lookups of absent keys, the pack is still usable without a
+        // valid one.
+        let bloom = BloomFilter::open(&bloom_path).unwrap_or(None);
         Ok(DataPack {
             mmap,
             version,
             index: DataIndex::new(&index_path)?,
+            bloom,
             base_path: Arc::new(base_path),
             pack_path,
             index_path,
+            bloom_path,
         })
     }
 
@@ -309,6 +321,18 @@ impl DataPack {
     pub fn index_path(&self) -> &Path {
         &self.index_path
     }
+
+    pub fn bloom_path(&self) -> &Path {
+        &self.bloom_path
+    }
+
+    /// Look up `hgid` in the index, unless the bloom filter shows it isn't in the pack.
+    fn get_entry(&self, hgid: &HgId) -> Result<Option<IndexEntry>> {
+        match &self.bloom {
+            Some(bloom) if !bloom.contains(hgid) => Ok(None),
+            _ => self.index.get_entry(hgid),
+        }
+    }
 }
 
 impl DataStore for DataPack {
@@ -319,7 +343,7 @@ impl DataStore for DataPack {
     }
 
     fn get_delta(&self, key: &Key) -> Result<Option<Delta>> {
-        let entry = match self.index.get_entry(&key.hgid)? {
+        let entry = match self.get_entry(&key.hgid)? {
             None => return Ok(None),
             Some(entry) => entry,
         };
@@ -336,7 +360,7 @@ impl DataStore for DataPack {
 
     fn get_delta_chain(&self, key: &Key) -> Result<Option<Vec<Delta>>> {
         let mut chain: Vec<Delta> = Default::default();
-        let mut next_entry = match self.index.get_entry(&key.hgid)? {
+        let mut next_entry = match self.get_entry(&key.hgid)? {
             None => return Ok(None),
             Some(entry) => entry,
         };
@@ -361,7 +385,7 @@ impl DataStore for DataPack {
     }
 
     fn get_meta(&self, key: &Key) -> Result<Option<Metadata>> {
-        let index_entry = match self.index.get_entry(&key.hgid)? {
+        let index_entry = match self.get_entry(&key.hgid)? {
             None => return Ok(None),
             Some(entry) => entry,
         };
@@ -379,7 +403,7 @@ impl LocalStore for DataPack {
     fn get_missing(&self, keys: &[Key]) -> Result<Vec<Key>> {
         Ok(keys
             .iter()
-            .filter(|k| match self.index.get_entry(&k.hgid) {
+            .filter(|k| match self.get_entry(&k.hgid) {
                 Ok(None) | Err(_) => true,
                 Ok(Some(_)) => false,
             })
@@ -400,10 +424,13 @@ impl Repackable for DataPack {
         // sure we close and unmap them before deletion.
         let pack_path = replace(&mut self.pack_path, Default::default());
         let index_path = replace(&mut self.index_path, Default::default());
+        let bloom_path = replace(&mut self.bloom_path, Default::default());
         drop(self);
 
         let result1 = remove_file(&pack_path);
         let result2 = remove_file(&index_path);
+        // Packs written before bloom filters existed don't have one.
+        let _ = remove_file(&bloom_path);
         // Only check for errors after both have run. That way if pack_path doesn't exist,
         // index_path is still deleted.
         result1?;
@@ -691,7 +718,7 @@ pub mod tests {
         let pack = make_datapack(&tempdir, &revisions);
         assert_eq!(
             tempdir.path().read_dir().unwrap().collect::<Vec<_>>().len(),
-            2
+            3
         );
         pack.delete().unwrap();
         assert_eq!(
@@ -700,6 +727,33 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_bloom_filter() {
+        let tempdir = TempDir::new().unwrap();
+
+        let revisions = vec![(
+            Delta {
+                data: Bytes::from(&[1, 2, 3, 4][..]),
+                base: None,
+                key: key("a", "1"),
+            },
+            Default::default(),
+        )];
+        let pack = make_datapack(&tempdir, &revisions);
+        assert!(pack.bloom_path().exists());
+        assert!(pack.get_delta(&key("a", "1")).unwrap().is_some());
+        assert_eq!(
+            pack.get_missing(&[key("a", "1"), key("a", "2")]).unwrap(),
+            vec![key("a", "2")]
+        );
+
+        // Packs without a filter still find their keys.
+        remove_file(pack.bloom_path()).unwrap();
+        let pack = DataPack::new(pack.base_path()).unwrap();
+        assert!(pack.get_delta(&key("a", "1")).unwrap().is_some());
+        assert!(pack.get_delta(&key("a", "2")).unwrap().is_none());
+    }
+
     #[test]
     fn test_delete_while_open() {
         let tempdir = TempDir::new().unwrap();
@@ -718,6 +772,7 @@ pub mod tests {
         assert!(pack.delete().is_ok());
         assert!(!pack2.pack_path().exists());
         assert!(!pack2.index_path().exists());
+        assert!(!pack2.bloom_path().exists());
     }
 
     #[test]
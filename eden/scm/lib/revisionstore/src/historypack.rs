@@ -81,6 +81,9 @@
 //!
 //! ```
 //! [1]: new in version 1.
+//!
+//! A `.histbloom` bloom filter of the hgids in the pack may be written next to
+//! the index, see the `bloomfilter` module. Packs without one are still valid.
 
 use std::{
     fs::File,
@@ -98,7 +101,8 @@ use thiserror::Error;
 use types::{HgId, Key, NodeInfo, RepoPath, RepoPathBuf};
 use util::path::remove_file;
 
-use crate::historyindex::HistoryIndex;
+use crate::bloomfilter::BloomFilter;
+use crate::historyindex::{HistoryIndex, NodeIndexEntry};
 use crate::historystore::HistoryStore;
 use crate::localstore::LocalStore;
 use crate::repack::{Repackable, ToKeys};
@@ -247,9 +251,11 @@ pub struct HistoryPack {
     #[allow(dead_code)]
     version: HistoryPackVersion,
     index: HistoryIndex,
+    bloom: Option<BloomFilter>,
     base_path: Arc<PathBuf>,
     pack_path: PathBuf,
     index_path: PathBuf,
+    bloom_path: PathBuf,
 }
 
 impl HistoryPack {
@@ -272,13 +278,19 @@ impl HistoryPack {
         }
 
         let index_path = path.with_extension("histidx");
+        let bloom_path = path.with_extension("histbloom");
+        // The filter only speeds up the lookups of absent keys, the pack is still usable without a
+        // valid one.
+        let bloom = BloomFilter::open(&bloom_path).unwrap_or(None);
         Ok(HistoryPack {
             mmap,
             version,
             index: HistoryIndex::new(&index_path)?,
+            bloom,
             base_path: Arc::new(base_path),
             pack_path,
             index_path,
+            bloom_path,
         })
     }
 
@@ -298,6 +310,18 @@ impl HistoryPack {
         &self.index_path
     }
 
+    pub fn bloom_path(&self) -> &Path {
+        &self.bloom_path
+    }
+
+    /// Look up `key` in the index, unless the bloom filter shows it isn't in the pack.
+    fn get_hgid_entry(&self, key: &Key) -> Result<Option<NodeIndexEntry>> {
+        match &self.bloom {
+            Some(bloom) if !bloom.contains(&key.hgid) => Ok(None),
+            _ => self.index.get_hgid_entry(key),
+        }
+    }
+
     fn read_file_section_header(&self, offset: u64) -> Result<FileSectionHeader> {
         FileSectionHeader::read(&self.mmap.as_ref().get_err(offset as usize..)?)
     }
@@ -327,7 +351,7 @@ impl HistoryPack {
 
 impl HistoryStore for HistoryPack {
     fn get_node_info(&self, key: &Key) -> Result<Option<NodeInfo>> {
-        let hgid_location = match self.index.get_hgid_entry(key)? {
+        let hgid_location = match self.get_hgid_entry(key)? {
             None => return Ok(None),
             Some(location) => location,
         };
@@ -343,7 +367,7 @@ impl LocalStore for HistoryPack {
     fn get_missing(&self, keys: &[Key]) -> Result<Vec<Key>> {
         Ok(keys
             .iter()
-            .filter(|k| match self.index.get_hgid_entry(&k) {
+            .filter(|k| match self.get_hgid_entry(&k) {
                 Ok(None) | Err(_) => true,
                 Ok(Some(_)) => false,
             })
@@ -364,10 +388,13 @@ impl Repackable for HistoryPack {
         // sure we close and unmap them before deletion.
         let pack_path = replace(&mut self.pack_path, Default::default());
         let index_path = replace(&mut self.index_path, Default::default());
+        let bloom_path = replace(&mut self.bloom_path, Default::default());
         drop(self);
 
         let result1 = remove_file(&pack_path);
         let result2 = remove_file(&index_path);
+        // Packs written before bloom filters existed don't have one.
+        let _ = remove_file(&bloom_path);
         // Only check for errors after both have run. That way if pack_path doesn't exist,
         // index_path is still deleted.
         result1?;
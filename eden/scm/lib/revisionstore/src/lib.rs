@@ -8,6 +8,7 @@
 //! revisionstore - Data and history store for generic revision data (usually commit, manifest,
 //! and file data)
 
+mod bloomfilter;
 mod contentstore;
 mod dataindex;
 mod edenapi;
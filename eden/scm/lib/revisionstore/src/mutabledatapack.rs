@@ -25,6 +25,7 @@ use thiserror::Error;
 use lz4_pyframe::compress;
 use types::{HgId, Key};
 
+use crate::bloomfilter::BloomFilter;
 use crate::dataindex::{DataIndex, DeltaLocation};
 use crate::datapack::{DataEntry, DataPackVersion};
 use crate::datastore::{DataStore, Delta, Metadata, MutableDeltaStore};
@@ -181,7 +182,7 @@ impl MutableDeltaStore for MutableDataPack {
 }
 
 impl MutablePack for MutableDataPackInner {
-    fn build_files(mut self) -> Result<(NamedTempFile, NamedTempFile, PathBuf)> {
+    fn build_files(mut self) -> Result<(NamedTempFile, NamedTempFile, NamedTempFile, PathBuf)> {
         if self.mem_index.is_empty() {
             return Err(EmptyMutablePack.into());
         }
@@ -189,9 +190,17 @@ impl MutablePack for MutableDataPackInner {
         let mut index_file = PackWriter::new(NamedTempFile::new_in(&self.dir)?);
         DataIndex::write(&mut index_file, &self.mem_index)?;
 
+        let mut bloom = BloomFilter::new(self.mem_index.len());
+        for hgid in self.mem_index.keys() {
+            bloom.insert(hgid);
+        }
+        let mut bloom_file = PackWriter::new(NamedTempFile::new_in(&self.dir)?);
+        bloom.write(&mut bloom_file)?;
+
         Ok((
             self.data_file.into_inner()?,
             index_file.into_inner()?,
+            bloom_file.into_inner()?,
             self.dir.join(&self.hasher.result_str()),
         ))
     }
@@ -202,7 +211,7 @@ impl MutablePack for MutableDataPackInner {
 }
 
 impl MutablePack for MutableDataPack {
-    fn build_files(self) -> Result<(NamedTempFile, NamedTempFile, PathBuf)> {
+    fn build_files(self) -> Result<(NamedTempFile, NamedTempFile, NamedTempFile, PathBuf)> {
         let mut guard = self.inner.lock();
         let new_inner = MutableDataPackInner::new(&guard.dir, DataPackVersion::One)?;
         let old_inner = replace(&mut *guard, new_inner);
@@ -24,6 +24,7 @@ use thiserror::Error;
 
 use types::{Key, NodeInfo, RepoPath, RepoPathBuf};
 
+use crate::bloomfilter::BloomFilter;
 use crate::error::EmptyMutablePack;
 use crate::historyindex::{FileSectionLocation, HistoryIndex, NodeLocation};
 use crate::historypack::{FileSectionHeader, HistoryEntry, HistoryPackVersion};
@@ -155,7 +156,7 @@ impl MutableHistoryStore for MutableHistoryPack {
 }
 
 impl MutablePack for MutableHistoryPackInner {
-    fn build_files(self) -> Result<(NamedTempFile, NamedTempFile, PathBuf)> {
+    fn build_files(self) -> Result<(NamedTempFile, NamedTempFile, NamedTempFile, PathBuf)> {
         if self.mem_index.is_empty() {
             return Err(EmptyMutablePack.into());
         }
@@ -204,9 +205,18 @@ impl MutablePack for MutableHistoryPackInner {
         let mut index_file = PackWriter::new(NamedTempFile::new_in(&self.dir)?);
         HistoryIndex::write(&mut index_file, &file_sections, &nodes)?;
 
+        let node_count = self.mem_index.values().map(|hgid_map| hgid_map.len()).sum();
+        let mut bloom = BloomFilter::new(node_count);
+        for key in self.mem_index.values().flat_map(|hgid_map| hgid_map.keys()) {
+            bloom.insert(&key.hgid);
+        }
+        let mut bloom_file = PackWriter::new(NamedTempFile::new_in(&self.dir)?);
+        bloom.write(&mut bloom_file)?;
+
         Ok((
             data_file.into_inner()?,
             index_file.into_inner()?,
+            bloom_file.into_inner()?,
             self.dir.join(hasher.result_str()),
         ))
     }
@@ -217,7 +227,7 @@ impl MutablePack for MutableHistoryPackInner {
 }
 
 impl MutablePack for MutableHistoryPack {
-    fn build_files(self) -> Result<(NamedTempFile, NamedTempFile, PathBuf)> {
+    fn build_files(self) -> Result<(NamedTempFile, NamedTempFile, NamedTempFile, PathBuf)> {
         let mut guard = self.inner.lock();
         let new_inner = MutableHistoryPackInner::new(&guard.dir, HistoryPackVersion::One)?;
         let old_inner = replace(&mut *guard, new_inner);
@@ -43,9 +43,10 @@ fn persist(file: NamedTempFile, path: PathBuf) -> Result<()> {
 }
 
 pub trait MutablePack {
-    /// Make the data and index pack files with the data added to it. Also returns the fullpath of
-    /// the files. After calling this function, the `MutablePack` is consumed and is no longer usable.
-    fn build_files(self) -> Result<(NamedTempFile, NamedTempFile, PathBuf)>;
+    /// Make the data, index and bloom filter pack files with the data added to it. Also returns the
+    /// fullpath of the files. After calling this function, the `MutablePack` is consumed and is no
+    /// longer usable.
+    fn build_files(self) -> Result<(NamedTempFile, NamedTempFile, NamedTempFile, PathBuf)>;
 
     /// Returns the extension for this kind of pack files.
     fn extension(&self) -> &'static str;
@@ -58,9 +59,10 @@ pub trait MutablePack {
     {
         let extension = self.extension().to_string();
         let pack_extension = extension.clone() + "pack";
-        let index_extension = extension + "idx";
+        let index_extension = extension.clone() + "idx";
+        let bloom_extension = extension + "bloom";
 
-        let (packfile, indexfile, base_filepath) = match self.build_files() {
+        let (packfile, indexfile, bloomfile, base_filepath) = match self.build_files() {
             Err(err) => {
                 if err.downcast_ref::<EmptyMutablePack>().is_some() {
                     return Ok(None);
@@ -75,13 +77,17 @@ pub trait MutablePack {
         make_readonly(&mut perms);
 
         packfile.as_file().set_permissions(perms.clone())?;
-        indexfile.as_file().set_permissions(perms)?;
+        indexfile.as_file().set_permissions(perms.clone())?;
+        bloomfile.as_file().set_permissions(perms)?;
 
         let packfile_path = base_filepath.with_extension(pack_extension);
         let indexfile_path = base_filepath.with_extension(index_extension);
+        let bloomfile_path = base_filepath.with_extension(bloom_extension);
 
         persist(packfile, packfile_path)?;
         persist(indexfile, indexfile_path)?;
+        // A pack opened before its filter is persisted is searched without it.
+        persist(bloomfile, bloomfile_path)?;
 
         Ok(Some(base_filepath))
     }
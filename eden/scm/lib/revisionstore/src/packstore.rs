@@ -773,12 +773,12 @@ mod tests {
         let datapack = OpenOptions::new().write(true).open(path)?;
         datapack.set_len(datapack.metadata()?.len() / 2)?;
 
-        assert_eq!(read_dir(&tempdir)?.count(), 2);
+        assert_eq!(read_dir(&tempdir)?.count(), 3);
 
         let packstore = DataPackStore::new(&tempdir, CorruptionPolicy::IGNORE);
         assert!(packstore.get_delta(&k1)?.is_none());
 
-        assert_eq!(read_dir(&tempdir)?.count(), 2);
+        assert_eq!(read_dir(&tempdir)?.count(), 3);
         Ok(())
     }
 
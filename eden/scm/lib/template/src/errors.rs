@@ -0,0 +1,28 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[error("template parse error at {pos}: {message}")]
+pub struct ParseError {
+    /// The position of the error, in characters.
+    pub pos: usize,
+    pub message: String,
+}
+
+#[derive(Error, Debug)]
+pub enum RenderError {
+    #[error("unknown keyword '{0}'")]
+    UnknownKeyword(String),
+    #[error("unknown function '{0}'")]
+    UnknownFunction(String),
+    #[error("unknown filter '{0}'")]
+    UnknownFilter(String),
+    #[error("{0}() {1}")]
+    InvalidArguments(String, &'static str),
+}
@@ -0,0 +1,539 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::fmt::Write;
+
+use anyhow::{bail, Result};
+use chrono::FixedOffset;
+
+use dag::IdMap;
+use hgtime::HgTime;
+use types::HgId;
+
+use crate::errors::RenderError;
+use crate::parser::{self, Expr};
+use crate::style::Styler;
+
+/// The commit data templates are rendered against.
+#[derive(Clone, Debug)]
+pub struct Commit {
+    pub node: HgId,
+    pub parents: Vec<HgId>,
+    pub author: String,
+    pub date: HgTime,
+    pub description: String,
+    pub bookmarks: Vec<String>,
+}
+
+/// Resolves abbreviated hashes for `shortest()`.
+pub trait PrefixLookup {
+    /// Count the nodes whose hex form starts with `hex_prefix`. Counting can stop at `limit`.
+    fn count_hex_prefix(&self, hex_prefix: &str, limit: usize) -> Result<usize>;
+}
+
+impl PrefixLookup for IdMap {
+    fn count_hex_prefix(&self, hex_prefix: &str, limit: usize) -> Result<usize> {
+        Ok(self
+            .find_names_by_hex_prefix(hex_prefix.as_bytes(), limit)?
+            .len())
+    }
+}
+
+/// Repository state used while rendering, besides the commit itself.
+#[derive(Clone, Copy, Default)]
+pub struct Env<'a> {
+    /// Required by `shortest()`.
+    pub prefix_lookup: Option<&'a dyn PrefixLookup>,
+    /// Styles `label()`. Labels are ignored if this is `None`.
+    pub styler: Option<&'a dyn Styler>,
+}
+
+/// A parsed template.
+#[derive(Clone, Debug)]
+pub struct Template {
+    exprs: Vec<Expr>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self> {
+        Ok(Template {
+            exprs: parser::parse(template)?,
+        })
+    }
+
+    /// Render `commit` and append the result to `out`.
+    pub fn render_to(&self, out: &mut String, commit: &Commit, env: &Env) -> Result<()> {
+        let context = Context { commit, env };
+        for expr in &self.exprs {
+            context.eval(expr)?.render_to(out);
+        }
+        Ok(())
+    }
+
+    pub fn render(&self, commit: &Commit, env: &Env) -> Result<String> {
+        let mut out = String::new();
+        self.render_to(&mut out, commit, env)?;
+        Ok(out)
+    }
+}
+
+const DEFAULT_DATE_FORMAT: &str = "%a %b %d %H:%M:%S %Y %1%2";
+
+#[derive(Clone, Debug)]
+enum Value {
+    Text(String),
+    Int(i64),
+    Date(HgTime),
+    Node(HgId),
+    List(Vec<Value>),
+}
+
+impl Value {
+    fn render_to(&self, out: &mut String) {
+        match self {
+            Value::Text(text) => out.push_str(text),
+            Value::Int(value) => write!(out, "{}", value).unwrap(),
+            Value::Date(date) => write!(out, "{} {}", date.unixtime, date.offset).unwrap(),
+            Value::Node(node) => out.push_str(&node.to_hex()),
+            Value::List(values) => {
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push(' ');
+                    }
+                    value.render_to(out);
+                }
+            }
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        self.render_to(&mut out);
+        out
+    }
+
+    fn is_true(&self) -> bool {
+        match self {
+            Value::Text(text) => !text.is_empty(),
+            Value::List(values) => !values.is_empty(),
+            _ => true,
+        }
+    }
+}
+
+struct Context<'a> {
+    commit: &'a Commit,
+    env: &'a Env<'a>,
+}
+
+impl<'a> Context<'a> {
+    fn eval(&self, expr: &Expr) -> Result<Value> {
+        let value = match expr {
+            Expr::Text(text) => Value::Text(text.clone()),
+            Expr::Template(exprs) => {
+                let mut out = String::new();
+                for expr in exprs {
+                    self.eval(expr)?.render_to(&mut out);
+                }
+                Value::Text(out)
+            }
+            Expr::Int(value) => Value::Int(*value),
+            Expr::Keyword(name) => self.keyword(name)?,
+            Expr::Call(name, args) => self.call(name, args)?,
+            Expr::Filter(expr, name) => filter(name, self.eval(expr)?)?,
+        };
+        Ok(value)
+    }
+
+    fn keyword(&self, name: &str) -> Result<Value> {
+        let commit = self.commit;
+        let parent = |i: usize| {
+            let parent = commit.parents.get(i).unwrap_or(HgId::null_id());
+            Value::Node(*parent)
+        };
+        let value = match name {
+            "node" => Value::Node(commit.node),
+            "p1node" => parent(0),
+            "p2node" => parent(1),
+            "parents" => Value::List(commit.parents.iter().cloned().map(Value::Node).collect()),
+            "author" => Value::Text(commit.author.clone()),
+            "desc" => Value::Text(commit.description.clone()),
+            "date" => Value::Date(commit.date),
+            "bookmarks" => Value::List(commit.bookmarks.iter().cloned().map(Value::Text).collect()),
+            _ => return Err(RenderError::UnknownKeyword(name.to_string()).into()),
+        };
+        Ok(value)
+    }
+
+    fn call(&self, name: &str, args: &[Expr]) -> Result<Value> {
+        let arity = |min: usize, max: usize| {
+            if args.len() < min || args.len() > max {
+                Err(RenderError::InvalidArguments(
+                    name.to_string(),
+                    "got an unexpected number of arguments",
+                ))
+            } else {
+                Ok(())
+            }
+        };
+        let invalid = |message| RenderError::InvalidArguments(name.to_string(), message);
+
+        let value = match name {
+            "shortest" => {
+                arity(1, 2)?;
+                let node = match self.eval(&args[0])? {
+                    Value::Node(node) => node,
+                    value => HgId::from_str(&value.render())
+                        .map_err(|_| invalid("expects a full hash"))?,
+                };
+                let min_len = match args.get(1) {
+                    Some(arg) => self.int(arg).ok_or_else(|| invalid("expects an integer"))?,
+                    None => 4,
+                };
+                let lookup = match self.env.prefix_lookup {
+                    Some(lookup) => lookup,
+                    None => bail!("shortest() requires a commit graph"),
+                };
+                Value::Text(shortest(lookup, &node, min_len.max(1) as usize)?)
+            }
+            "date" => {
+                arity(1, 2)?;
+                let date = match self.eval(&args[0])? {
+                    Value::Date(date) => date,
+                    _ => return Err(invalid("expects a date").into()),
+                };
+                let format = match args.get(1) {
+                    Some(arg) => self.eval(arg)?.render(),
+                    None => DEFAULT_DATE_FORMAT.to_string(),
+                };
+                Value::Text(format_date(date, &format)?)
+            }
+            "label" => {
+                arity(2, 2)?;
+                let label = self.eval(&args[0])?.render();
+                let text = self.eval(&args[1])?.render();
+                match self.env.styler {
+                    Some(styler) => Value::Text(styler.style(&label, &text)),
+                    None => Value::Text(text),
+                }
+            }
+            "if" => {
+                arity(2, 3)?;
+                if self.eval(&args[0])?.is_true() {
+                    self.eval(&args[1])?
+                } else {
+                    self.eval_or_empty(args.get(2))?
+                }
+            }
+            "ifeq" => {
+                arity(3, 4)?;
+                if self.eval(&args[0])?.render() == self.eval(&args[1])?.render() {
+                    self.eval(&args[2])?
+                } else {
+                    self.eval_or_empty(args.get(3))?
+                }
+            }
+            "join" => {
+                arity(2, 2)?;
+                let values = match self.eval(&args[0])? {
+                    Value::List(values) => values,
+                    value => vec![value],
+                };
+                let separator = self.eval(&args[1])?.render();
+                let values: Vec<String> = values.iter().map(Value::render).collect();
+                Value::Text(values.join(&separator))
+            }
+            "pad" => {
+                arity(2, 4)?;
+                let text = self.eval(&args[0])?.render();
+                let width = self
+                    .int(&args[1])
+                    .ok_or_else(|| invalid("expects an integer width"))?;
+                let fill = match args.get(2) {
+                    Some(arg) => {
+                        let fill = self.eval(arg)?.render();
+                        let mut chars = fill.chars();
+                        match (chars.next(), chars.next()) {
+                            (Some(ch), None) => ch,
+                            _ => return Err(invalid("expects a single fill character").into()),
+                        }
+                    }
+                    None => ' ',
+                };
+                let left = self.eval_or_empty(args.get(3))?.is_true();
+                let padding: String = std::iter::repeat_n(
+                    fill,
+                    (width.max(0) as usize).saturating_sub(text.chars().count()),
+                )
+                .collect();
+                if left {
+                    Value::Text(padding + &text)
+                } else {
+                    Value::Text(text + &padding)
+                }
+            }
+            _ => return Err(RenderError::UnknownFunction(name.to_string()).into()),
+        };
+        Ok(value)
+    }
+
+    fn eval_or_empty(&self, expr: Option<&Expr>) -> Result<Value> {
+        match expr {
+            Some(expr) => self.eval(expr),
+            None => Ok(Value::Text(String::new())),
+        }
+    }
+
+    fn int(&self, expr: &Expr) -> Option<i64> {
+        match self.eval(expr).ok()? {
+            Value::Int(value) => Some(value),
+            value => value.render().trim().parse().ok(),
+        }
+    }
+}
+
+fn filter(name: &str, value: Value) -> Result<Value> {
+    let text = |value: &Value| value.render();
+    let date = |value: &Value| match value {
+        Value::Date(date) => Ok(*date),
+        _ => Err(RenderError::InvalidArguments(
+            name.to_string(),
+            "expects a date",
+        )),
+    };
+    let value = match name {
+        "short" => Value::Text(text(&value).chars().take(12).collect()),
+        "firstline" => Value::Text(text(&value).lines().next().unwrap_or("").to_string()),
+        "strip" => Value::Text(text(&value).trim().to_string()),
+        "upper" => Value::Text(text(&value).to_uppercase()),
+        "lower" => Value::Text(text(&value).to_lowercase()),
+        "count" => match value {
+            Value::List(values) => Value::Int(values.len() as i64),
+            value => Value::Int(text(&value).chars().count() as i64),
+        },
+        "email" => Value::Text(email(&text(&value)).to_string()),
+        "user" => {
+            let email = email(&text(&value)).to_string();
+            Value::Text(email.split('@').next().unwrap_or("").to_string())
+        }
+        "person" => Value::Text(person(&text(&value)).to_string()),
+        "hgdate" => {
+            let date = date(&value)?;
+            Value::Text(format!("{} {}", date.unixtime, date.offset))
+        }
+        "isodate" => Value::Text(format_date(date(&value)?, "%Y-%m-%d %H:%M %1%2")?),
+        "shortdate" => Value::Text(format_date(date(&value)?, "%Y-%m-%d")?),
+        "rfc822date" => Value::Text(format_date(date(&value)?, "%a, %d %b %Y %H:%M:%S %1%2")?),
+        _ => return Err(RenderError::UnknownFilter(name.to_string()).into()),
+    };
+    Ok(value)
+}
+
+/// The email part of an author, like "foo@example.com" in "Foo <foo@example.com>".
+fn email(author: &str) -> &str {
+    match (author.find('<'), author.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &author[start + 1..end],
+        (Some(start), None) => &author[start + 1..],
+        _ => author,
+    }
+}
+
+/// The name part of an author, like "Foo" in "Foo <foo@example.com>". Falls back to the user
+/// part of the email.
+fn person(author: &str) -> &str {
+    match author.find('<') {
+        Some(start) if !author[..start].trim().is_empty() => {
+            author[..start].trim().trim_matches('"')
+        }
+        _ => {
+            let email = email(author);
+            email.split('@').next().unwrap_or(email)
+        }
+    }
+}
+
+/// Format `date` using strftime directives in its own timezone. `%1` and `%2` are the hours and
+/// minutes of the timezone, as in Mercurial's `datestr`.
+fn format_date(date: HgTime, format: &str) -> Result<String> {
+    // HgTime offsets are seconds west of UTC.
+    let east = -date.offset;
+    let sign = if east < 0 { '-' } else { '+' };
+    let minutes = east.abs() / 60;
+    let format = format
+        .replace("%1", &format!("{}{:02}", sign, minutes / 60))
+        .replace("%2", &format!("{:02}", minutes % 60));
+    let timezone = match FixedOffset::east_opt(east) {
+        Some(timezone) => timezone,
+        None => bail!("invalid timezone offset {}", date.offset),
+    };
+    let mut out = String::new();
+    if write!(
+        out,
+        "{}",
+        date.to_utc().with_timezone(&timezone).format(&format)
+    )
+    .is_err()
+    {
+        bail!("invalid date format {:?}", format);
+    }
+    Ok(out)
+}
+
+/// The shortest unambiguous hex prefix of `node`, at least `min_len` long. Prefixes made of
+/// digits only are skipped, since they could be mistaken for revision numbers.
+fn shortest(lookup: &dyn PrefixLookup, node: &HgId, min_len: usize) -> Result<String> {
+    let hex = node.to_hex();
+    for len in min_len..hex.len() {
+        let prefix = &hex[..len];
+        if prefix.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        if lookup.count_hex_prefix(prefix, 2)? <= 1 {
+            return Ok(hex[..len].to_string());
+        }
+    }
+    Ok(hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use types::testutil::*;
+
+    use crate::style::AnsiStyler;
+
+    fn commit() -> Commit {
+        Commit {
+            node: hgid("1234abcd"),
+            parents: vec![hgid("1234"), hgid("5678")],
+            author: "Test User <test@example.com>".to_string(),
+            date: HgTime {
+                unixtime: 1577836800,
+                offset: -3600,
+            },
+            description: "subject\n\nbody".to_string(),
+            bookmarks: vec!["feature".to_string(), "main".to_string()],
+        }
+    }
+
+    fn render(template: &str) -> String {
+        render_with(template, &Env::default())
+    }
+
+    fn render_with(template: &str, env: &Env) -> String {
+        Template::parse(template)
+            .unwrap()
+            .render(&commit(), env)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_keywords_and_filters() {
+        assert_eq!(render("{node|short}"), "000000000000");
+        assert_eq!(render("{p2node}"), hgid("5678").to_hex());
+        assert_eq!(render("{parents|count}"), "2");
+        assert_eq!(render("{desc|firstline}"), "subject");
+        assert_eq!(
+            render("{author|user} {author|email}"),
+            "test test@example.com"
+        );
+        assert_eq!(render("{author|person|upper}"), "TEST USER");
+        assert_eq!(render("{bookmarks}"), "feature main");
+        assert_eq!(render("{join(bookmarks, ', ')}"), "feature, main");
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(render("{date}"), "1577836800 -3600");
+        assert_eq!(render("{date|isodate}"), "2020-01-01 01:00 +0100");
+        assert_eq!(render("{date|shortdate}"), "2020-01-01");
+        assert_eq!(
+            render("{date(date)}|{date(date, '%H:%M')}"),
+            "Wed Jan 01 01:00:00 2020 +0100|01:00"
+        );
+    }
+
+    #[test]
+    fn test_conditionals() {
+        assert_eq!(
+            render("{if(bookmarks, '[{bookmarks}]', 'none')}"),
+            "[feature main]"
+        );
+        assert_eq!(render("{if('', 'x')}"), "");
+        assert_eq!(render("{ifeq(author|user, 'test', 'me', 'other')}"), "me");
+        assert_eq!(
+            render("{pad(author|user, 6, '.')}|{pad(1, 3, '0', 1)}"),
+            "test..|001"
+        );
+    }
+
+    #[test]
+    fn test_label() {
+        assert_eq!(
+            render("{label('log.changeset', desc|firstline)}"),
+            "subject"
+        );
+        let mut effects = HashMap::new();
+        effects.insert("log.changeset".to_string(), vec!["yellow".to_string()]);
+        let styler = AnsiStyler::new(effects);
+        let env = Env {
+            styler: Some(&styler),
+            ..Default::default()
+        };
+        assert_eq!(
+            render_with("{label('log.changeset', desc|firstline)}", &env),
+            "\x1b[0;33msubject\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_shortest() {
+        let node = |prefix: &str| HgId::from_str(&format!("{:0<40}", prefix)).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut map = IdMap::open(dir.path()).unwrap();
+        map.insert(dag::Id(0), node("12f").as_ref()).unwrap();
+        map.insert(dag::Id(1), node("abcd").as_ref()).unwrap();
+        map.insert(dag::Id(2), node("abce").as_ref()).unwrap();
+        let env = Env {
+            prefix_lookup: Some(&map),
+            ..Default::default()
+        };
+        let commit = Commit {
+            node: node("abcd"),
+            parents: vec![node("12f")],
+            ..commit()
+        };
+        let template =
+            Template::parse("{shortest(node, 1)} {shortest(p1node, 1)} {shortest(p1node)}")
+                .unwrap();
+        // "1" and "12" are unique, but could be revision numbers.
+        assert_eq!(template.render(&commit, &env).unwrap(), "abcd 12f 12f0");
+        assert!(template.render(&commit, &Env::default()).is_err());
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let err = |template| {
+            let err = Template::parse(template)
+                .unwrap()
+                .render(&commit(), &Env::default())
+                .unwrap_err();
+            err.to_string()
+        };
+        assert_eq!(err("{rev}"), "unknown keyword 'rev'");
+        assert_eq!(err("{desc|json}"), "unknown filter 'json'");
+        assert_eq!(err("{sum(1)}"), "unknown function 'sum'");
+        assert_eq!(err("{date(desc)}"), "date() expects a date");
+        assert_eq!(
+            err("{if(desc)}"),
+            "if() got an unexpected number of arguments"
+        );
+    }
+}
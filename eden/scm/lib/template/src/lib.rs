@@ -0,0 +1,24 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! template - Render commits using the hg template language.
+//!
+//! This implements the subset of the template language used by `hg log -T`: keywords like
+//! `{node}` and `{desc}`, filters like `{date|isodate}`, and functions like `shortest()`,
+//! `date()`, `if()` and `label()`. A [`Template`] is parsed once and can then render many
+//! [`Commit`]s without going through Python.
+//!
+//! Abbreviated hashes are resolved by a [`PrefixLookup`], which is implemented for the commit
+//! graph's [`IdMap`](dag::IdMap). Color labels are rendered by a [`Styler`].
+
+pub mod errors;
+mod eval;
+mod parser;
+mod style;
+
+pub use crate::eval::{Commit, Env, PrefixLookup, Template};
+pub use crate::style::{AnsiStyler, Styler};
@@ -0,0 +1,246 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Parser of the template language.
+//!
+//! A template is literal text with expressions in braces. An expression is a keyword, an
+//! integer, a quoted string or a function call, optionally followed by `|filter`s. Quoted
+//! strings are templates themselves, so `"{node}"` as a function argument is expanded.
+
+use crate::errors::ParseError;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Expr {
+    Text(String),
+    /// A quoted string. It is a template itself.
+    Template(Vec<Expr>),
+    Int(i64),
+    Keyword(String),
+    Call(String, Vec<Expr>),
+    Filter(Box<Expr>, String),
+}
+
+/// Parse a template into a list of [`Expr`]s to be concatenated.
+pub(crate) fn parse(template: &str) -> Result<Vec<Expr>, ParseError> {
+    let chars: Vec<char> = template.chars().collect();
+    let mut parser = Parser { chars, pos: 0 };
+    parser.template(None)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    /// Parse a template until the `end` quote, or the end of the input.
+    fn template(&mut self, end: Option<char>) -> Result<Vec<Expr>, ParseError> {
+        let mut exprs = Vec::new();
+        let mut text = String::new();
+        loop {
+            let ch = match self.peek() {
+                None => match end {
+                    None => break,
+                    Some(quote) => return Err(self.error(format!("missing {}", quote))),
+                },
+                Some(ch) => ch,
+            };
+            self.pos += 1;
+            match ch {
+                _ if Some(ch) == end => break,
+                '\\' => text.push(self.escape()),
+                '{' => {
+                    if !text.is_empty() {
+                        exprs.push(Expr::Text(std::mem::take(&mut text)));
+                    }
+                    exprs.push(self.expr()?);
+                    self.skip_spaces();
+                    if self.peek() != Some('}') {
+                        return Err(self.error("expected '}'"));
+                    }
+                    self.pos += 1;
+                }
+                _ => text.push(ch),
+            }
+        }
+        if !text.is_empty() {
+            exprs.push(Expr::Text(text));
+        }
+        Ok(exprs)
+    }
+
+    fn escape(&mut self) -> char {
+        match self.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some(ch) => ch,
+            None => '\\',
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.term()?;
+        loop {
+            self.skip_spaces();
+            if self.peek() != Some('|') {
+                return Ok(expr);
+            }
+            self.pos += 1;
+            self.skip_spaces();
+            let name = self.ident();
+            if name.is_empty() {
+                return Err(self.error("expected filter name"));
+            }
+            expr = Expr::Filter(Box::new(expr), name);
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, ParseError> {
+        self.skip_spaces();
+        match self.peek() {
+            Some(quote @ '"') | Some(quote @ '\'') => {
+                self.pos += 1;
+                Ok(Expr::Template(self.template(Some(quote))?))
+            }
+            Some(ch) if ch.is_ascii_digit() || ch == '-' => {
+                let start = self.pos;
+                self.pos += 1;
+                while matches!(self.peek(), Some(ch) if ch.is_ascii_digit()) {
+                    self.pos += 1;
+                }
+                let digits: String = self.chars[start..self.pos].iter().collect();
+                digits
+                    .parse()
+                    .map(Expr::Int)
+                    .map_err(|_| self.error(format!("invalid integer {}", digits)))
+            }
+            _ => {
+                let name = self.ident();
+                if name.is_empty() {
+                    return Err(self.error("expected expression"));
+                }
+                self.skip_spaces();
+                if self.peek() != Some('(') {
+                    return Ok(Expr::Keyword(name));
+                }
+                self.pos += 1;
+                let mut args = Vec::new();
+                loop {
+                    self.skip_spaces();
+                    if self.peek() == Some(')') && args.is_empty() {
+                        self.pos += 1;
+                        break;
+                    }
+                    args.push(self.expr()?);
+                    self.skip_spaces();
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some(')') => {
+                            self.pos += 1;
+                            break;
+                        }
+                        _ => return Err(self.error("expected ',' or ')'")),
+                    }
+                }
+                Ok(Expr::Call(name, args))
+            }
+        }
+    }
+
+    fn ident(&mut self) -> String {
+        let start = self.pos;
+        while matches!(self.peek(), Some(ch) if ch.is_ascii_alphanumeric() || ch == '_') {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(ch) if ch.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let ch = self.peek();
+        if ch.is_some() {
+            self.pos += 1;
+        }
+        ch
+    }
+
+    fn error(&self, message: impl ToString) -> ParseError {
+        ParseError {
+            pos: self.pos,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use Expr::*;
+
+    fn text(s: &str) -> Expr {
+        Text(s.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(r"{node|short} \{x\}\n").unwrap(),
+            vec![
+                Filter(Box::new(Keyword("node".to_string())), "short".to_string()),
+                text(" {x}\n"),
+            ]
+        );
+        assert_eq!(
+            parse(r#"{ label("log.node", shortest(node, 6)) }"#).unwrap(),
+            vec![Call(
+                "label".to_string(),
+                vec![
+                    Template(vec![text("log.node")]),
+                    Call(
+                        "shortest".to_string(),
+                        vec![Keyword("node".to_string()), Int(6)]
+                    ),
+                ]
+            )]
+        );
+        assert_eq!(
+            parse(r#"{if(bookmarks, ' [{bookmarks}]')}"#).unwrap(),
+            vec![Call(
+                "if".to_string(),
+                vec![
+                    Keyword("bookmarks".to_string()),
+                    Template(vec![
+                        text(" ["),
+                        Keyword("bookmarks".to_string()),
+                        text("]")
+                    ]),
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let pos = |template| parse(template).unwrap_err().pos;
+        assert_eq!(pos("{node"), 5);
+        assert_eq!(pos("ab{}"), 3);
+        assert_eq!(pos("{date(date, 'x}"), 15);
+        assert_eq!(pos("{node|}"), 6);
+        assert_eq!(pos("{f(a b)}"), 5);
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashMap;
+
+/// Renders text produced by the `label()` template function.
+pub trait Styler {
+    fn style(&self, label: &str, text: &str) -> String;
+}
+
+/// Styles labels with ANSI escape codes, like the `color` extension in `ansi` mode.
+///
+/// Effects are configured per label, as in the `[color]` config section. For example,
+/// `log.changeset` mapped to `["yellow"]`. A text with multiple space-separated labels gets the
+/// effects of all of them. Unknown labels and effects are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct AnsiStyler {
+    effects: HashMap<String, Vec<String>>,
+}
+
+impl AnsiStyler {
+    pub fn new(effects: HashMap<String, Vec<String>>) -> Self {
+        AnsiStyler { effects }
+    }
+
+    fn codes(&self, label: &str) -> Vec<u8> {
+        label
+            .split_whitespace()
+            .filter_map(|label| self.effects.get(label))
+            .flatten()
+            .filter_map(|effect| effect_code(effect))
+            .collect()
+    }
+}
+
+impl Styler for AnsiStyler {
+    fn style(&self, label: &str, text: &str) -> String {
+        let codes = self.codes(label);
+        if codes.is_empty() || text.is_empty() {
+            return text.to_string();
+        }
+        let codes: Vec<String> = codes.iter().map(|c| c.to_string()).collect();
+        // Style each line separately, so the escape codes do not span lines when the output is
+        // paged or interleaved.
+        text.split('\n')
+            .map(|line| {
+                if line.is_empty() {
+                    String::new()
+                } else {
+                    format!("\x1b[0;{}m{}\x1b[0m", codes.join(";"), line)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn effect_code(effect: &str) -> Option<u8> {
+    let code = match effect {
+        "none" => 0,
+        "bold" => 1,
+        "italic" => 3,
+        "underline" => 4,
+        "blink" => 5,
+        "inverse" => 7,
+        "black" => 30,
+        "red" => 31,
+        "green" => 32,
+        "yellow" => 33,
+        "blue" => 34,
+        "magenta" => 35,
+        "cyan" => 36,
+        "white" => 37,
+        "black_background" => 40,
+        "red_background" => 41,
+        "green_background" => 42,
+        "yellow_background" => 43,
+        "blue_background" => 44,
+        "magenta_background" => 45,
+        "cyan_background" => 46,
+        "white_background" => 47,
+        _ => return None,
+    };
+    Some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ansi_styler() {
+        let mut effects = HashMap::new();
+        effects.insert("log.changeset".to_string(), vec!["yellow".to_string()]);
+        effects.insert(
+            "bookmark".to_string(),
+            vec!["bold".to_string(), "unknown".to_string()],
+        );
+        let styler = AnsiStyler::new(effects);
+        assert_eq!(styler.style("log.changeset", "abc"), "\x1b[0;33mabc\x1b[0m");
+        assert_eq!(
+            styler.style("log.changeset bookmark", "a\n\nb"),
+            "\x1b[0;33;1ma\x1b[0m\n\n\x1b[0;33;1mb\x1b[0m"
+        );
+        assert_eq!(styler.style("log.summary", "abc"), "abc");
+    }
+}
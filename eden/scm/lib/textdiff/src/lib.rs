@@ -0,0 +1,422 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Functions to find the difference between two texts.
+//!
+//! Unlike the `xdiff` crate, this is implemented in Rust and supports
+//! choosing the diff algorithm, ignoring whitespace changes, and diffing
+//! at the word level. The results are structured [`Hunk`]s so callers
+//! like annotate, `hg diff` and server-side rendering can format them
+//! however they need.
+
+mod myers;
+mod patience;
+mod text;
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::Range;
+
+pub use crate::text::{split_lines, split_words};
+
+/// An individual difference between two sequences. Consists of two
+/// ranges that specify which parts of the sequences differ.
+///
+/// If any of the ranges is empty it's still significant because it
+/// specifies the location in the sequence where the other range applies.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Hunk {
+    /// Range from the new sequence to insert
+    pub add: Range<usize>,
+    /// Range from the old sequence to delete
+    pub remove: Range<usize>,
+}
+
+/// The algorithm used to compute the differences.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Algorithm {
+    /// The Myers O(ND) algorithm. Produces a minimal diff.
+    Myers,
+    /// The patience algorithm. Aligns lines that are unique on both sides
+    /// first, which tends to produce more readable diffs for source code
+    /// at the cost of not always being minimal.
+    Patience,
+}
+
+/// How whitespace is treated when comparing lines or words.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Whitespace {
+    /// Whitespace is significant.
+    Exact,
+    /// Ignore all whitespace (`hg diff -w`).
+    IgnoreAll,
+    /// Ignore changes in the amount of whitespace (`hg diff -b`).
+    IgnoreAmount,
+    /// Ignore whitespace at the end of lines (`hg diff -Z`).
+    IgnoreTrailing,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DiffOpts {
+    pub algorithm: Algorithm,
+    pub whitespace: Whitespace,
+}
+
+impl Default for DiffOpts {
+    fn default() -> Self {
+        DiffOpts {
+            algorithm: Algorithm::Myers,
+            whitespace: Whitespace::Exact,
+        }
+    }
+}
+
+/// Computes the hunks of differences between two slices.
+///
+/// # Example
+/// ```
+/// use textdiff::{diff_slices, Algorithm, Hunk};
+/// let a = ["a", "b", "c", "d"];
+/// let b = ["a", "c", "d", "e"];
+/// assert_eq!(
+///     diff_slices(&a, &b, Algorithm::Myers),
+///     [
+///         Hunk {
+///             add: 1..1,
+///             remove: 1..2,
+///         },
+///         Hunk {
+///             add: 3..4,
+///             remove: 4..4,
+///         }
+///     ]
+/// );
+/// ```
+pub fn diff_slices<T: Hash + Eq>(old: &[T], new: &[T], algorithm: Algorithm) -> Vec<Hunk> {
+    let (old, new) = intern(old.iter(), new.iter());
+    diff_keys(&old, &new, algorithm)
+}
+
+/// Computes the hunks of differences between the lines of two texts.
+///
+/// The ranges in the returned hunks are line numbers, starting from 0.
+/// See [`split_lines`] for how lines are counted.
+pub fn diff_lines<T: AsRef<[u8]>>(old_text: T, new_text: T, opts: &DiffOpts) -> Vec<Hunk> {
+    let old_lines = split_lines(old_text.as_ref());
+    let new_lines = split_lines(new_text.as_ref());
+    let (old, new) = intern(
+        old_lines
+            .iter()
+            .map(|l| text::normalize(l, opts.whitespace)),
+        new_lines
+            .iter()
+            .map(|l| text::normalize(l, opts.whitespace)),
+    );
+    diff_keys(&old, &new, opts.algorithm)
+}
+
+/// Computes the hunks of differences between the words of two texts.
+///
+/// This is useful to highlight the changed parts of a modified line. The
+/// ranges in the returned hunks are byte offsets in the texts. See
+/// [`split_words`] for how texts are split into words.
+///
+/// With [`Whitespace::IgnoreAll`] and [`Whitespace::IgnoreAmount`], runs
+/// of whitespace compare equal regardless of their content, so only
+/// changes to other words are reported.
+pub fn diff_words<T: AsRef<[u8]>>(old_text: T, new_text: T, opts: &DiffOpts) -> Vec<Hunk> {
+    let old_words = split_words(old_text.as_ref());
+    let new_words = split_words(new_text.as_ref());
+    let (old, new) = intern(
+        old_words
+            .iter()
+            .map(|w| text::normalize_word(w, opts.whitespace)),
+        new_words
+            .iter()
+            .map(|w| text::normalize_word(w, opts.whitespace)),
+    );
+    let old_offsets = offsets(&old_words);
+    let new_offsets = offsets(&new_words);
+    diff_keys(&old, &new, opts.algorithm)
+        .into_iter()
+        .map(|hunk| Hunk {
+            add: new_offsets[hunk.add.start]..new_offsets[hunk.add.end],
+            remove: old_offsets[hunk.remove.start]..old_offsets[hunk.remove.end],
+        })
+        .collect()
+}
+
+/// Map each item to an integer so that equal items get the same integer.
+/// Comparing integers is much cheaper than comparing lines.
+fn intern<T: Hash + Eq>(
+    old: impl Iterator<Item = T>,
+    new: impl Iterator<Item = T>,
+) -> (Vec<u32>, Vec<u32>) {
+    let mut ids = HashMap::new();
+    let mut id = |item: T| {
+        let next_id = ids.len() as u32;
+        *ids.entry(item).or_insert(next_id)
+    };
+    let old = old.map(&mut id).collect();
+    let new = new.map(&mut id).collect();
+    (old, new)
+}
+
+/// Byte offsets of the start of each word, followed by the length of the text.
+fn offsets(words: &[&[u8]]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(words.len() + 1);
+    let mut offset = 0;
+    offsets.push(offset);
+    for word in words {
+        offset += word.len();
+        offsets.push(offset);
+    }
+    offsets
+}
+
+/// Which items on either side are not part of the common subsequence.
+struct Changes {
+    removed: Vec<bool>,
+    added: Vec<bool>,
+}
+
+fn diff_keys(old: &[u32], new: &[u32], algorithm: Algorithm) -> Vec<Hunk> {
+    let mut changes = Changes {
+        removed: vec![false; old.len()],
+        added: vec![false; new.len()],
+    };
+    match algorithm {
+        Algorithm::Myers => myers::diff(old, new, 0..old.len(), 0..new.len(), &mut changes),
+        Algorithm::Patience => patience::diff(old, new, 0..old.len(), 0..new.len(), &mut changes),
+    }
+    changes.into_hunks()
+}
+
+impl Changes {
+    fn remove(&mut self, range: Range<usize>) {
+        self.removed[range].iter_mut().for_each(|r| *r = true);
+    }
+
+    fn add(&mut self, range: Range<usize>) {
+        self.added[range].iter_mut().for_each(|a| *a = true);
+    }
+
+    /// Group consecutive changes into hunks. Unchanged items on both sides
+    /// are paired in order, so they can be skipped in lockstep.
+    fn into_hunks(self) -> Vec<Hunk> {
+        let (removed, added) = (self.removed, self.added);
+        let mut hunks = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < removed.len() || j < added.len() {
+            let (start_i, start_j) = (i, j);
+            while i < removed.len() && removed[i] {
+                i += 1;
+            }
+            while j < added.len() && added[j] {
+                j += 1;
+            }
+            if (i, j) == (start_i, start_j) {
+                i += 1;
+                j += 1;
+            } else {
+                hunks.push(Hunk {
+                    add: start_j..j,
+                    remove: start_i..i,
+                });
+            }
+        }
+        hunks
+    }
+}
+
+/// Shrink the ranges by removing the common prefix and suffix.
+fn trim(old: &[u32], new: &[u32], a: &mut Range<usize>, b: &mut Range<usize>) {
+    while a.start < a.end && b.start < b.end && old[a.start] == new[b.start] {
+        a.start += 1;
+        b.start += 1;
+    }
+    while a.start < a.end && b.start < b.end && old[a.end - 1] == new[b.end - 1] {
+        a.end -= 1;
+        b.end -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use quickcheck::quickcheck;
+
+    /// Apply hunks from `diff_slices` to `old` to reconstruct `new`.
+    fn apply(old: &[u8], new: &[u8], hunks: &[Hunk]) -> Vec<u8> {
+        let mut result = Vec::new();
+        let mut pos = 0;
+        for hunk in hunks {
+            result.extend_from_slice(&old[pos..hunk.remove.start]);
+            result.extend_from_slice(&new[hunk.add.clone()]);
+            pos = hunk.remove.end;
+        }
+        result.extend_from_slice(&old[pos..]);
+        result
+    }
+
+    /// Size of the diff, to compare against the edit distance.
+    fn cost(hunks: &[Hunk]) -> usize {
+        hunks.iter().map(|h| h.add.len() + h.remove.len()).sum()
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let a = "a\nb\nc\nd\n";
+        let b = "a\nc\nd\ne\n";
+        let opts = DiffOpts::default();
+        assert_eq!(
+            diff_lines(a, b, &opts),
+            [
+                Hunk {
+                    add: 1..1,
+                    remove: 1..2,
+                },
+                Hunk {
+                    add: 3..4,
+                    remove: 4..4,
+                }
+            ]
+        );
+        assert_eq!(diff_lines(a, a, &opts), []);
+        assert_eq!(
+            diff_lines("", a, &opts),
+            [Hunk {
+                add: 0..4,
+                remove: 0..0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_lines_missing_newline() {
+        let opts = DiffOpts::default();
+        assert_eq!(
+            diff_lines("a\nb", "a\nb\n", &opts),
+            [Hunk {
+                add: 1..2,
+                remove: 1..2,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_lines_whitespace() {
+        let a = "if x:\n    return  1\nend\n";
+        let b = "if x:\n\treturn 1 \nend\n";
+        let diff = |whitespace| {
+            let opts = DiffOpts {
+                whitespace,
+                ..Default::default()
+            };
+            diff_lines(a, b, &opts)
+        };
+        let changed = [Hunk {
+            add: 1..2,
+            remove: 1..2,
+        }];
+        assert_eq!(diff(Whitespace::Exact), changed);
+        assert_eq!(diff(Whitespace::IgnoreTrailing), changed);
+        assert_eq!(diff(Whitespace::IgnoreAmount), []);
+        assert_eq!(diff(Whitespace::IgnoreAll), []);
+
+        let b = "if x:\n    return1\nend\n";
+        let diff = |whitespace| {
+            let opts = DiffOpts {
+                whitespace,
+                ..Default::default()
+            };
+            diff_lines(a, b, &opts)
+        };
+        assert_eq!(diff(Whitespace::IgnoreAmount), changed);
+        assert_eq!(diff(Whitespace::IgnoreAll), []);
+    }
+
+    #[test]
+    fn test_diff_lines_patience() {
+        // Patience keeps one of the functions intact instead of matching the
+        // scattered "}" lines.
+        let a = "fn a() {\n  1\n}\n\nfn b() {\n  2\n}\n";
+        let b = "fn b() {\n  2\n}\n\nfn a() {\n  1\n}\n";
+        let opts = DiffOpts {
+            algorithm: Algorithm::Patience,
+            ..Default::default()
+        };
+        assert_eq!(
+            diff_lines(a, b, &opts),
+            [
+                Hunk {
+                    add: 0..0,
+                    remove: 0..4,
+                },
+                Hunk {
+                    add: 2..6,
+                    remove: 6..6,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_words() {
+        let opts = DiffOpts::default();
+        let a = "let x = foo(1, 2);";
+        let b = "let y = foo(1,  3);";
+        let hunks = diff_words(a, b, &opts);
+        let changed = |h: &Hunk| (&a[h.remove.clone()], &b[h.add.clone()]);
+        assert_eq!(
+            hunks.iter().map(changed).collect::<Vec<_>>(),
+            [("x", "y"), (" 2", "  3")]
+        );
+
+        let opts = DiffOpts {
+            whitespace: Whitespace::IgnoreAmount,
+            ..Default::default()
+        };
+        let hunks = diff_words(a, b, &opts);
+        assert_eq!(
+            hunks.iter().map(changed).collect::<Vec<_>>(),
+            [("x", "y"), ("2", "3")]
+        );
+    }
+
+    quickcheck! {
+        fn test_diff_slices_reconstructs(old: Vec<u8>, new: Vec<u8>) -> bool {
+            // Use a small alphabet so there are many common items.
+            let old: Vec<u8> = old.into_iter().map(|b| b % 4).collect();
+            let new: Vec<u8> = new.into_iter().map(|b| b % 4).collect();
+            [Algorithm::Myers, Algorithm::Patience].iter().all(|&algorithm| {
+                let hunks = diff_slices(&old, &new, algorithm);
+                apply(&old, &new, &hunks) == new
+            })
+        }
+
+        fn test_diff_slices_myers_minimal(old: Vec<u8>, new: Vec<u8>) -> bool {
+            let old: Vec<u8> = old.into_iter().take(12).map(|b| b % 3).collect();
+            let new: Vec<u8> = new.into_iter().take(12).map(|b| b % 3).collect();
+            // Dynamic programming edit distance with insertions and deletions only.
+            let mut dist = vec![vec![0; new.len() + 1]; old.len() + 1];
+            for i in 0..=old.len() {
+                for j in 0..=new.len() {
+                    dist[i][j] = if i == 0 || j == 0 {
+                        i + j
+                    } else if old[i - 1] == new[j - 1] {
+                        dist[i - 1][j - 1]
+                    } else {
+                        dist[i - 1][j].min(dist[i][j - 1]) + 1
+                    };
+                }
+            }
+            cost(&diff_slices(&old, &new, Algorithm::Myers)) == dist[old.len()][new.len()]
+        }
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The linear space variant of the Myers diff algorithm, described in
+//! "An O(ND) Difference Algorithm and Its Variations" by Eugene W. Myers.
+
+use std::ops::Range;
+
+use crate::{trim, Changes};
+
+/// Mark the items in `old[a]` and `new[b]` that are not part of a longest
+/// common subsequence.
+pub(crate) fn diff(
+    old: &[u32],
+    new: &[u32],
+    mut a: Range<usize>,
+    mut b: Range<usize>,
+    changes: &mut Changes,
+) {
+    trim(old, new, &mut a, &mut b);
+    if a.is_empty() {
+        changes.add(b);
+    } else if b.is_empty() {
+        changes.remove(a);
+    } else {
+        let snake = middle_snake(old, new, a.clone(), b.clone());
+        diff(
+            old,
+            new,
+            a.start..snake.x.start,
+            b.start..snake.y.start,
+            changes,
+        );
+        diff(old, new, snake.x.end..a.end, snake.y.end..b.end, changes);
+    }
+}
+
+/// A diagonal run of equal items, which is part of a shortest edit path.
+#[derive(Debug, PartialEq)]
+struct Snake {
+    x: Range<usize>,
+    y: Range<usize>,
+}
+
+/// Find the middle snake of a shortest edit path between `old[a]` and
+/// `new[b]` by searching forward from the start and backward from the end
+/// at the same time, until the two searches overlap.
+///
+/// The ranges must be non-empty, and must not start or end with equal items.
+fn middle_snake(old: &[u32], new: &[u32], a: Range<usize>, b: Range<usize>) -> Snake {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let old = |x: isize| old[a.start + x as usize];
+    let new = |y: isize| new[b.start + y as usize];
+
+    // Forward diagonals are `k = x - y`. Backward diagonals are `c = u - v`,
+    // where `u` and `v` are distances from the end. They are related by
+    // `k = delta - c`.
+    let delta = n - m;
+    let odd = delta & 1 == 1;
+    let max = (n + m + 1) / 2;
+    let offset = max + 1;
+    // `forward[k]` is the furthest `x` reached on diagonal `k`.
+    // `backward[c]` is the furthest `u` reached on diagonal `c`.
+    let mut forward = vec![0; 2 * offset as usize + 1];
+    let mut backward = vec![0; 2 * offset as usize + 1];
+
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && forward[i - 1] < forward[i + 1]) {
+                forward[i + 1]
+            } else {
+                forward[i - 1] + 1
+            };
+            let mut y = x - k;
+            let (x0, y0) = (x, y);
+            while x < n && y < m && old(x) == new(y) {
+                x += 1;
+                y += 1;
+            }
+            forward[i] = x;
+            let c = delta - k;
+            if odd && c.abs() < d && x + backward[(c + offset) as usize] >= n {
+                return Snake {
+                    x: a.start + x0 as usize..a.start + x as usize,
+                    y: b.start + y0 as usize..b.start + y as usize,
+                };
+            }
+        }
+
+        for c in (-d..=d).step_by(2) {
+            let i = (c + offset) as usize;
+            let mut u = if c == -d || (c != d && backward[i - 1] < backward[i + 1]) {
+                backward[i + 1]
+            } else {
+                backward[i - 1] + 1
+            };
+            let mut v = u - c;
+            let (u0, v0) = (u, v);
+            while u < n && v < m && old(n - u - 1) == new(m - v - 1) {
+                u += 1;
+                v += 1;
+            }
+            backward[i] = u;
+            let k = delta - c;
+            if !odd && k.abs() <= d && forward[(k + offset) as usize] + u >= n {
+                return Snake {
+                    x: a.start + (n - u) as usize..a.start + (n - u0) as usize,
+                    y: b.start + (m - v) as usize..b.start + (m - v0) as usize,
+                };
+            }
+        }
+    }
+
+    unreachable!("the forward and backward searches always overlap");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_middle_snake() {
+        // "abcabba" -> "cbabac" is the example from the paper.
+        let old = [0, 1, 2, 0, 1, 1, 0];
+        let new = [2, 1, 0, 1, 0, 2];
+        let snake = middle_snake(&old, &new, 0..old.len(), 0..new.len());
+        assert_eq!(old[snake.x.clone()], new[snake.y.clone()]);
+
+        let mut changes = Changes {
+            removed: vec![false; old.len()],
+            added: vec![false; new.len()],
+        };
+        diff(&old, &new, 0..old.len(), 0..new.len(), &mut changes);
+        let removed = changes.removed.iter().filter(|r| **r).count();
+        let added = changes.added.iter().filter(|a| **a).count();
+        assert_eq!(removed + added, 5);
+    }
+}
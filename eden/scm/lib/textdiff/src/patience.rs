@@ -0,0 +1,123 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The patience diff algorithm.
+//!
+//! Items that appear exactly once on both sides are used as anchors. The
+//! longest sequence of anchors that appear in the same order on both sides
+//! is matched, and the gaps between them are diffed recursively. Ranges
+//! without unique items fall back to the Myers algorithm.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::{myers, trim, Changes};
+
+pub(crate) fn diff(
+    old: &[u32],
+    new: &[u32],
+    mut a: Range<usize>,
+    mut b: Range<usize>,
+    changes: &mut Changes,
+) {
+    trim(old, new, &mut a, &mut b);
+    if a.is_empty() {
+        changes.add(b);
+        return;
+    }
+    if b.is_empty() {
+        changes.remove(a);
+        return;
+    }
+
+    let anchors = longest_increasing(&unique_pairs(old, new, a.clone(), b.clone()));
+    if anchors.is_empty() {
+        myers::diff(old, new, a, b, changes);
+        return;
+    }
+
+    let (mut x, mut y) = (a.start, b.start);
+    for (i, j) in anchors {
+        diff(old, new, x..i, y..j, changes);
+        x = i + 1;
+        y = j + 1;
+    }
+    diff(old, new, x..a.end, y..b.end, changes);
+}
+
+/// Positions `(i, j)` such that `old[i] == new[j]` and the item appears
+/// exactly once in `old[a]` and `new[b]`, ordered by `i`.
+fn unique_pairs(old: &[u32], new: &[u32], a: Range<usize>, b: Range<usize>) -> Vec<(usize, usize)> {
+    // Item -> (count in old, position in old, count in new, position in new).
+    let mut counts: HashMap<u32, (usize, usize, usize, usize)> = HashMap::new();
+    for i in a.clone() {
+        let entry = counts.entry(old[i]).or_default();
+        entry.0 += 1;
+        entry.1 = i;
+    }
+    for j in b {
+        if let Some(entry) = counts.get_mut(&new[j]) {
+            entry.2 += 1;
+            entry.3 = j;
+        }
+    }
+    a.filter_map(|i| match counts[&old[i]] {
+        (1, _, 1, j) => Some((i, j)),
+        _ => None,
+    })
+    .collect()
+}
+
+/// The longest subsequence of `pairs` (ordered by the first item) whose
+/// second items are also increasing. Uses patience sorting.
+fn longest_increasing(pairs: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    // `tops[n]` is the index in `pairs` of the top card of pile `n`.
+    let mut tops: Vec<usize> = Vec::new();
+    // `prev[p]` is the top of the previous pile when `pairs[p]` was placed.
+    let mut prev: Vec<Option<usize>> = Vec::with_capacity(pairs.len());
+    for (p, &(_, j)) in pairs.iter().enumerate() {
+        let pile = tops.partition_point(|&t| pairs[t].1 < j);
+        prev.push(if pile > 0 { Some(tops[pile - 1]) } else { None });
+        if pile == tops.len() {
+            tops.push(p);
+        } else {
+            tops[pile] = p;
+        }
+    }
+
+    let mut result = Vec::with_capacity(tops.len());
+    let mut p = tops.last().cloned();
+    while let Some(i) = p {
+        result.push(pairs[i]);
+        p = prev[i];
+    }
+    result.reverse();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unique_pairs() {
+        let old = [1, 2, 3, 2, 4];
+        let new = [4, 3, 2, 1];
+        assert_eq!(
+            unique_pairs(&old, &new, 0..5, 0..4),
+            [(0, 3), (2, 1), (4, 0)]
+        );
+        assert_eq!(unique_pairs(&old, &new, 1..4, 1..3), [(2, 1)]);
+    }
+
+    #[test]
+    fn test_longest_increasing() {
+        let pairs = [(0, 3), (1, 0), (2, 4), (3, 1), (4, 2), (5, 5)];
+        assert_eq!(longest_increasing(&pairs), [(1, 0), (3, 1), (4, 2), (5, 5)]);
+        assert_eq!(longest_increasing(&[]), []);
+    }
+}
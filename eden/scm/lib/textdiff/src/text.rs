@@ -0,0 +1,160 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::borrow::Cow;
+
+use crate::Whitespace;
+
+/// Split a text into lines. Each line includes its trailing `\n`, so a last
+/// line without a newline is different from the same line with one.
+///
+/// # Example
+/// ```
+/// use textdiff::split_lines;
+/// assert_eq!(split_lines(b"a\nb"), [&b"a\n"[..], b"b"]);
+/// assert_eq!(split_lines(b"a\n"), [b"a\n"]);
+/// assert!(split_lines(b"").is_empty());
+/// ```
+pub fn split_lines(text: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, &c) in text.iter().enumerate() {
+        if c == b'\n' {
+            lines.push(&text[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+/// Split a text into words. A word is a run of alphanumeric characters
+/// (including `_` and non-ASCII characters), a run of whitespace, or any
+/// other single character. Concatenating the words gives the original text.
+///
+/// # Example
+/// ```
+/// use textdiff::split_words;
+/// assert_eq!(
+///     split_words(b"foo(a_1,  b)"),
+///     [&b"foo"[..], b"(", b"a_1", b",", b"  ", b"b", b")"]
+/// );
+/// ```
+pub fn split_words(text: &[u8]) -> Vec<&[u8]> {
+    #[derive(PartialEq)]
+    enum Class {
+        Word,
+        Space,
+        Other,
+    }
+    let class = |c: u8| {
+        if c.is_ascii_alphanumeric() || c == b'_' || c >= 0x80 {
+            Class::Word
+        } else if c.is_ascii_whitespace() {
+            Class::Space
+        } else {
+            Class::Other
+        }
+    };
+
+    let mut words = Vec::new();
+    let mut start = 0;
+    for i in 1..=text.len() {
+        let end = match text.get(i) {
+            None => true,
+            Some(&c) => {
+                let current = class(text[start]);
+                current == Class::Other || current != class(c)
+            }
+        };
+        if end {
+            words.push(&text[start..i]);
+            start = i;
+        }
+    }
+    words
+}
+
+/// Normalize a word so that whitespace words compare equal if whitespace
+/// changes are ignored.
+pub(crate) fn normalize_word(word: &[u8], whitespace: Whitespace) -> &[u8] {
+    match whitespace {
+        Whitespace::IgnoreAll | Whitespace::IgnoreAmount
+            if word.iter().all(|c| c.is_ascii_whitespace()) =>
+        {
+            b" "
+        }
+        _ => word,
+    }
+}
+
+/// Normalize a line so that lines that only differ in ignored whitespace
+/// compare equal.
+pub(crate) fn normalize(line: &[u8], whitespace: Whitespace) -> Cow<'_, [u8]> {
+    match whitespace {
+        Whitespace::Exact => Cow::Borrowed(line),
+        Whitespace::IgnoreAll => Cow::Owned(
+            line.iter()
+                .cloned()
+                .filter(|c| !c.is_ascii_whitespace())
+                .collect(),
+        ),
+        Whitespace::IgnoreAmount => {
+            let line = trim_end(line);
+            let mut result = Vec::with_capacity(line.len());
+            let mut space = false;
+            for &c in line {
+                if c.is_ascii_whitespace() {
+                    space = true;
+                } else {
+                    if space {
+                        result.push(b' ');
+                        space = false;
+                    }
+                    result.push(c);
+                }
+            }
+            Cow::Owned(result)
+        }
+        Whitespace::IgnoreTrailing => Cow::Borrowed(trim_end(line)),
+    }
+}
+
+fn trim_end(line: &[u8]) -> &[u8] {
+    let len = line
+        .iter()
+        .rposition(|c| !c.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    &line[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let line = b"  a \t b  \n";
+        let normalize = |whitespace| normalize(line, whitespace).into_owned();
+        assert_eq!(normalize(Whitespace::Exact), line);
+        assert_eq!(normalize(Whitespace::IgnoreAll), b"ab");
+        assert_eq!(normalize(Whitespace::IgnoreAmount), b" a b");
+        assert_eq!(normalize(Whitespace::IgnoreTrailing), b"  a \t b");
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert!(split_words(b"").is_empty());
+        assert_eq!(split_words(b"a"), [b"a"]);
+        assert_eq!(split_words(b"=="), [b"=", b"="]);
+        let text = "caf\u{e9} = 1;\n".as_bytes();
+        assert_eq!(split_words(text).concat(), text);
+        assert_eq!(split_words(text)[0], "caf\u{e9}".as_bytes());
+    }
+}
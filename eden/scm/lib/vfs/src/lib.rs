@@ -0,0 +1,651 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! vfs - Write files in the working copy.
+//!
+//! Files are written to a temporary file next to their destination, then renamed into place, so
+//! that readers never see a partially written file.
+//!
+//! On case-insensitive filesystems, like the defaults on macOS and Windows, renaming a file
+//! over an existing file whose name differs only in case keeps the old name. A checkout that
+//! changes the case of a file would then leave it under the wrong name. [`VFS`] detects such
+//! files and replaces them once the new content is written. To find them without listing a
+//! directory for every write, it indexes the names of the directories it writes to. The index
+//! lives as long as the [`VFS`], so use one per checkout, or call [`VFS::clear_case_index`]
+//! between checkouts.
+//!
+//! Files that cannot be written as-is, like symlinks on Windows, are handled according to a
+//! configurable [`Policy`]. Skipped files can be collected in a [`CheckoutReport`].
+//!
+//! A checkout materializes its files with [`write_parallel`], which runs the writes on a
+//! shared [`WorkerPool`].
+
+mod policy;
+mod report;
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use tempfile::NamedTempFile;
+use thiserror::Error;
+
+use types::{RepoPath, RepoPathBuf};
+use workerpool::{Priority, WorkerPool};
+
+pub use crate::policy::{FileKind, Policy, UnsupportedFile, WriteOutcome};
+pub use crate::report::CheckoutReport;
+
+/// The kind of file to write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateFlag {
+    Regular,
+    Executable,
+    /// The data is the target of the symlink.
+    Symlink,
+}
+
+#[derive(Error, Debug)]
+#[error("'{path}' conflicts with '{}', which differs only in case", .existing.display())]
+pub struct CaseConflict {
+    pub path: RepoPathBuf,
+    pub existing: PathBuf,
+}
+
+/// Access to the files of a working copy.
+pub struct VFS {
+    root: PathBuf,
+    case_sensitive: bool,
+    supports_symlinks: bool,
+    symlink_policy: Policy,
+    special_file_policy: Policy,
+    /// The names of the directories written to on a case-insensitive filesystem, by lowercase
+    /// name, kept up to date by the writes and removals.
+    case_index: Mutex<HashMap<PathBuf, HashMap<String, OsString>>>,
+}
+
+impl VFS {
+    /// Opens the working copy at `root`. Whether the filesystem is case-sensitive is detected
+    /// by creating a temporary file in `root`.
+    pub fn new(root: PathBuf) -> Result<Self> {
+        let case_sensitive = is_case_sensitive(&root)?;
+        Ok(VFS {
+            root,
+            case_sensitive,
+            // Creating symlinks on Windows requires a privilege most users do not have.
+            supports_symlinks: cfg!(unix),
+            symlink_policy: Policy::Materialize,
+            special_file_policy: Policy::Error,
+            case_index: Default::default(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn case_sensitive(&self) -> bool {
+        self.case_sensitive
+    }
+
+    /// Overrides the detected case sensitivity, for filesystems where detection is not
+    /// reliable, like network mounts.
+    pub fn set_case_sensitive(&mut self, case_sensitive: bool) {
+        self.case_sensitive = case_sensitive;
+    }
+
+    pub fn supports_symlinks(&self) -> bool {
+        self.supports_symlinks
+    }
+
+    pub fn set_supports_symlinks(&mut self, supports_symlinks: bool) {
+        self.supports_symlinks = supports_symlinks;
+    }
+
+    /// Sets how symlinks are written when symlinks are not supported. Defaults to
+    /// [`Policy::Materialize`].
+    pub fn set_symlink_policy(&mut self, policy: Policy) {
+        self.symlink_policy = policy;
+    }
+
+    /// Sets what happens when a fifo, socket or device is where a file is to be written.
+    /// Defaults to [`Policy::Error`].
+    pub fn set_special_file_policy(&mut self, policy: Policy) {
+        self.special_file_policy = policy;
+    }
+
+    /// Forgets the names of the directories that were written to. Call it when the working
+    /// copy may have been changed by something other than this `VFS`, like between checkouts.
+    pub fn clear_case_index(&self) {
+        self.case_index.lock().unwrap().clear();
+    }
+
+    pub fn join(&self, path: &RepoPath) -> PathBuf {
+        self.root.join(path.as_str())
+    }
+
+    /// Atomically replaces the file at `path` with `data`, creating its parent directories as
+    /// needed.
+    ///
+    /// On case-insensitive filesystems, a file whose name differs from `path` only in case is
+    /// removed once the new content is written, so that the file ends up with the name in
+    /// `path`. A directory with such a name is not removed, and a [`CaseConflict`] error is
+    /// returned instead. Parent directories whose names differ only in case are reused.
+    ///
+    /// Symlinks on filesystems without symlinks, and special files found at `path`, are
+    /// handled according to their [`Policy`]. An [`UnsupportedFile`] error is returned for
+    /// [`Policy::Error`].
+    pub fn write(&self, path: &RepoPath, data: &[u8], flag: UpdateFlag) -> Result<WriteOutcome> {
+        let filepath = self.join(path);
+        if flag == UpdateFlag::Symlink && !self.supports_symlinks {
+            if let Some(outcome) = self.apply_policy(path, FileKind::Symlink)? {
+                return Ok(outcome);
+            }
+        }
+        if is_special_file(&filepath)? {
+            if let Some(outcome) = self.apply_policy(path, FileKind::Special)? {
+                return Ok(outcome);
+            }
+        }
+
+        let parent = self.create_dir_all(filepath.parent().unwrap_or(&self.root))?;
+        let filepath = match filepath.file_name() {
+            Some(name) => parent.join(name),
+            None => filepath,
+        };
+
+        let mut variant = None;
+        if !self.case_sensitive {
+            if let Some(existing) = self.case_variant(&filepath)? {
+                if fs::symlink_metadata(&existing)?.is_dir() {
+                    return Err(CaseConflict {
+                        path: path.to_owned(),
+                        existing,
+                    }
+                    .into());
+                }
+                variant = Some(existing);
+            }
+        }
+
+        match flag {
+            UpdateFlag::Symlink if self.supports_symlinks => {
+                if let Some(existing) = &variant {
+                    fs::remove_file(existing)?;
+                }
+                write_symlink(&filepath, data)?
+            }
+            _ => {
+                let mut file = NamedTempFile::new_in(&parent)?;
+                file.write_all(data)?;
+                let existing = variant.as_ref().unwrap_or(&filepath);
+                set_executable(file.as_file(), existing, flag == UpdateFlag::Executable)?;
+                file.as_file().sync_all()?;
+                // Only remove the old file once the new content is safely written.
+                if let Some(existing) = &variant {
+                    fs::remove_file(existing)?;
+                }
+                file.persist(&filepath).map_err(|e| e.error)?;
+            }
+        }
+        if !self.case_sensitive {
+            self.index_name(&filepath);
+        }
+        Ok(WriteOutcome::Written(data.len()))
+    }
+
+    /// Creates the directory `dir` in the working copy and its missing parents, and returns
+    /// its path.
+    ///
+    /// On case-insensitive filesystems, an existing directory whose name differs only in case
+    /// is used instead of creating another one, and the returned path has its name.
+    fn create_dir_all(&self, dir: &Path) -> Result<PathBuf> {
+        if self.case_sensitive {
+            fs::create_dir_all(dir)?;
+            return Ok(dir.to_path_buf());
+        }
+        let mut resolved = self.root.clone();
+        for component in dir.strip_prefix(&self.root)?.components() {
+            let exact = resolved.join(component);
+            resolved = if fs::symlink_metadata(&exact).is_ok() {
+                exact
+            } else {
+                match self.case_variant(&exact)? {
+                    Some(existing) => existing,
+                    None => exact,
+                }
+            };
+            if !resolved.is_dir() {
+                match fs::create_dir(&resolved) {
+                    // Another write may have created it concurrently.
+                    Err(e) if e.kind() != ErrorKind::AlreadyExists || !resolved.is_dir() => {
+                        return Err(e.into());
+                    }
+                    _ => self.index_name(&resolved),
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Returns the outcome of a skipped write, or `None` if the file should be written.
+    fn apply_policy(&self, path: &RepoPath, kind: FileKind) -> Result<Option<WriteOutcome>> {
+        let policy = match kind {
+            FileKind::Symlink => self.symlink_policy,
+            FileKind::Special => self.special_file_policy,
+        };
+        match policy {
+            Policy::Materialize => Ok(None),
+            Policy::Skip => Ok(Some(WriteOutcome::Skipped(kind))),
+            Policy::Error => Err(UnsupportedFile {
+                path: path.to_owned(),
+                kind,
+            }
+            .into()),
+        }
+    }
+
+    /// Removes the file at `path`. Removing a file that does not exist is not an error.
+    pub fn remove(&self, path: &RepoPath) -> Result<()> {
+        let filepath = self.join(path);
+        match fs::remove_file(&filepath) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => {
+                if let (Some(parent), Some(name)) = (filepath.parent(), filepath.file_name()) {
+                    if let Some(names) = self.case_index.lock().unwrap().get_mut(parent) {
+                        let key = name.to_string_lossy().to_lowercase();
+                        if names.get(&key).map(|existing| existing.as_os_str()) == Some(name) {
+                            names.remove(&key);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Finds an entry next to `path` whose name is equal to the name of `path` ignoring case,
+    /// but not equal.
+    ///
+    /// The directory is listed the first time one of its entries is looked up, and its names
+    /// are indexed until `clear_case_index` is called.
+    fn case_variant(&self, path: &Path) -> Result<Option<PathBuf>> {
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Ok(None),
+        };
+        let mut index = self.case_index.lock().unwrap();
+        let names = match index.get(parent) {
+            Some(names) => names,
+            None => {
+                let mut names = HashMap::new();
+                for entry in fs::read_dir(parent)? {
+                    let entry_name = entry?.file_name();
+                    names.insert(entry_name.to_string_lossy().to_lowercase(), entry_name);
+                }
+                index.entry(parent.to_path_buf()).or_insert(names)
+            }
+        };
+        match names.get(&name.to_string_lossy().to_lowercase()) {
+            Some(existing) if existing != name => Ok(Some(parent.join(existing))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Records that the entry at `path` has the name of `path`, replacing the entry whose name
+    /// differs only in case, if any. Does nothing if the parent directory is not indexed yet.
+    fn index_name(&self, path: &Path) {
+        if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+            if let Some(names) = self.case_index.lock().unwrap().get_mut(parent) {
+                names.insert(name.to_string_lossy().to_lowercase(), name.to_os_string());
+            }
+        }
+    }
+}
+
+/// Writes `files` with `vfs` on `pool`, as a checkout materializes them, and returns the
+/// outcome of each write in the order of `files`. A failed write does not stop the others.
+///
+/// The writes run in the [`Priority::High`] lane, since a user is waiting for them.
+pub fn write_parallel(
+    vfs: Arc<VFS>,
+    files: Vec<(RepoPathBuf, Vec<u8>, UpdateFlag)>,
+    pool: &WorkerPool,
+) -> Result<Vec<Result<WriteOutcome>>> {
+    let results = pool.map(Priority::High, files, move |(path, data, flag)| {
+        vfs.write(&path, &data, flag)
+    })?;
+    Ok(results)
+}
+
+/// Tests whether names in `dir` are case-sensitive.
+fn is_case_sensitive(dir: &Path) -> Result<bool> {
+    let probe = tempfile::Builder::new()
+        .prefix(".casecheck")
+        .tempfile_in(dir)?;
+    let name = probe.path().file_name().unwrap().to_string_lossy();
+    let swapped: String = name
+        .chars()
+        .map(|c| {
+            if c.is_lowercase() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect();
+    Ok(fs::symlink_metadata(dir.join(swapped)).is_err())
+}
+
+#[cfg(unix)]
+fn write_symlink(path: &Path, target: &[u8]) -> Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    // Symlinks cannot be replaced atomically, since `symlink` fails if `path` exists.
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    std::os::unix::fs::symlink(OsStr::from_bytes(target), path)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_symlink(path: &Path, target: &[u8]) -> Result<()> {
+    let target = std::str::from_utf8(target)?.replace('/', "\\");
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    std::os::windows::fs::symlink_file(target, path)?;
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn write_symlink(_path: &Path, _target: &[u8]) -> Result<()> {
+    anyhow::bail!("symlinks are not supported on this platform")
+}
+
+/// Tests whether `path` is a fifo, socket or device.
+#[cfg(unix)]
+fn is_special_file(path: &Path) -> Result<bool> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => {
+            let file_type = metadata.file_type();
+            Ok(file_type.is_fifo()
+                || file_type.is_socket()
+                || file_type.is_block_device()
+                || file_type.is_char_device())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(not(unix))]
+fn is_special_file(_path: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+/// Sets the mode of the temporary `file` replacing `existing`: the mode of `existing`, or
+/// `0o644` for a new file, with the executable bits set where the read bits are, or cleared.
+#[cfg(unix)]
+fn set_executable(file: &fs::File, existing: &Path, executable: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Temporary files are only readable by their owner, so their mode is not used.
+    let mode = match fs::symlink_metadata(existing) {
+        Ok(metadata) if metadata.is_file() => metadata.permissions().mode() & 0o7777,
+        _ => 0o644,
+    };
+    let mode = if executable {
+        mode | (mode & 0o444) >> 2
+    } else {
+        mode & !0o111
+    };
+    let mut permissions = file.metadata()?.permissions();
+    permissions.set_mode(mode);
+    file.set_permissions(permissions)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_file: &fs::File, _existing: &Path, _executable: bool) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use types::testutil::*;
+
+    fn vfs(case_sensitive: bool) -> (tempfile::TempDir, VFS) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vfs = VFS::new(dir.path().to_path_buf()).unwrap();
+        vfs.set_case_sensitive(case_sensitive);
+        (dir, vfs)
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_write_and_remove() {
+        let (_dir, vfs) = vfs(true);
+        let path = repo_path("a/b/c.txt");
+        assert_eq!(
+            vfs.write(path, b"foo", UpdateFlag::Regular).unwrap(),
+            WriteOutcome::Written(3)
+        );
+        assert_eq!(
+            vfs.write(path, b"bar", UpdateFlag::Regular).unwrap(),
+            WriteOutcome::Written(3)
+        );
+        assert_eq!(fs::read(vfs.join(path)).unwrap(), b"bar");
+        assert_eq!(names(&vfs.join(repo_path("a/b"))), vec!["c.txt"]);
+
+        vfs.remove(path).unwrap();
+        vfs.remove(path).unwrap();
+        assert!(names(&vfs.join(repo_path("a/b"))).is_empty());
+    }
+
+    #[test]
+    fn test_write_parallel() {
+        let (_dir, vfs) = vfs(false);
+        fs::create_dir(vfs.join(repo_path("blocked"))).unwrap();
+        let vfs = Arc::new(vfs);
+        let pool = WorkerPool::new(workerpool::PoolConfig::with_threads(4));
+        let files = (0..20)
+            .map(|i| {
+                let path = repo_path_buf(&format!("dir/sub{}/file{}", i % 3, i));
+                (path, vec![b'x'; i], UpdateFlag::Regular)
+            })
+            .chain(Some((
+                repo_path_buf("blocked"),
+                b"data".to_vec(),
+                UpdateFlag::Regular,
+            )))
+            .collect();
+        let results = write_parallel(vfs.clone(), files, &pool).unwrap();
+        assert_eq!(results.len(), 21);
+        for (i, result) in results[..20].iter().enumerate() {
+            assert_eq!(result.as_ref().unwrap(), &WriteOutcome::Written(i));
+        }
+        // A failed write does not stop the others.
+        assert!(results[20].is_err());
+        assert_eq!(
+            names(&vfs.join(repo_path("dir"))),
+            vec!["sub0", "sub1", "sub2"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_flags() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (_dir, vfs) = vfs(true);
+        let mode = |path| {
+            fs::symlink_metadata(vfs.join(repo_path(path)))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777
+        };
+        vfs.write(repo_path("exec"), b"#!/bin/sh", UpdateFlag::Executable)
+            .unwrap();
+        assert_eq!(mode("exec"), 0o755);
+        vfs.write(repo_path("exec"), b"data", UpdateFlag::Regular)
+            .unwrap();
+        assert_eq!(mode("exec"), 0o644);
+
+        // Only the executable bits of an existing file change.
+        fs::set_permissions(
+            vfs.join(repo_path("exec")),
+            fs::Permissions::from_mode(0o640),
+        )
+        .unwrap();
+        vfs.write(repo_path("exec"), b"#!/bin/sh", UpdateFlag::Executable)
+            .unwrap();
+        assert_eq!(mode("exec"), 0o750);
+        vfs.write(repo_path("exec"), b"data", UpdateFlag::Regular)
+            .unwrap();
+        assert_eq!(mode("exec"), 0o640);
+
+        vfs.write(repo_path("link"), b"exec", UpdateFlag::Symlink)
+            .unwrap();
+        vfs.write(repo_path("link"), b"target", UpdateFlag::Symlink)
+            .unwrap();
+        assert_eq!(
+            fs::read_link(vfs.join(repo_path("link"))).unwrap(),
+            Path::new("target")
+        );
+    }
+
+    #[test]
+    fn test_write_case_change() {
+        let (_dir, vfs) = vfs(false);
+        vfs.write(repo_path("dir/readme"), b"old", UpdateFlag::Regular)
+            .unwrap();
+        vfs.write(repo_path("dir/README"), b"new", UpdateFlag::Regular)
+            .unwrap();
+        assert_eq!(names(&vfs.join(repo_path("dir"))), vec!["README"]);
+        assert_eq!(fs::read(vfs.join(repo_path("dir/README"))).unwrap(), b"new");
+
+        vfs.write(repo_path("Dir"), b"file", UpdateFlag::Regular)
+            .unwrap_err()
+            .downcast::<CaseConflict>()
+            .unwrap();
+
+        // Parent directories differing in case are reused.
+        vfs.write(repo_path("DIR/Sub/a"), b"a", UpdateFlag::Regular)
+            .unwrap();
+        vfs.write(repo_path("dir/sub/b"), b"b", UpdateFlag::Regular)
+            .unwrap();
+        assert_eq!(names(vfs.root()), vec!["dir"]);
+        assert_eq!(names(&vfs.join(repo_path("dir"))), vec!["README", "Sub"]);
+        assert_eq!(names(&vfs.join(repo_path("dir/Sub"))), vec!["a", "b"]);
+
+        // The index of the names follows the writes and removals.
+        vfs.remove(repo_path("dir/README")).unwrap();
+        vfs.write(repo_path("dir/Readme"), b"new", UpdateFlag::Regular)
+            .unwrap();
+        vfs.write(repo_path("dir/ReadMe"), b"new", UpdateFlag::Regular)
+            .unwrap();
+        assert_eq!(names(&vfs.join(repo_path("dir"))), vec!["ReadMe", "Sub"]);
+
+        // Changes made by others are seen once the index is cleared.
+        fs::rename(
+            vfs.join(repo_path("dir/ReadMe")),
+            vfs.join(repo_path("dir/readme")),
+        )
+        .unwrap();
+        vfs.clear_case_index();
+        vfs.write(repo_path("dir/README"), b"new", UpdateFlag::Regular)
+            .unwrap();
+        assert_eq!(names(&vfs.join(repo_path("dir"))), vec!["README", "Sub"]);
+    }
+
+    #[test]
+    fn test_symlink_policy() {
+        let (_dir, mut vfs) = vfs(true);
+        vfs.set_supports_symlinks(false);
+        let path = repo_path("link");
+        vfs.write(path, b"target", UpdateFlag::Symlink).unwrap();
+        assert_eq!(fs::read(vfs.join(path)).unwrap(), b"target");
+        assert!(fs::symlink_metadata(vfs.join(path)).unwrap().is_file());
+
+        vfs.set_symlink_policy(Policy::Skip);
+        assert_eq!(
+            vfs.write(path, b"other", UpdateFlag::Symlink).unwrap(),
+            WriteOutcome::Skipped(FileKind::Symlink)
+        );
+        assert_eq!(fs::read(vfs.join(path)).unwrap(), b"target");
+
+        vfs.set_symlink_policy(Policy::Error);
+        let err = vfs.write(path, b"other", UpdateFlag::Symlink).unwrap_err();
+        let err = err.downcast::<UnsupportedFile>().unwrap();
+        assert_eq!(err.kind, FileKind::Symlink);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_special_file_policy() {
+        use std::os::unix::net::UnixListener;
+
+        let (_dir, mut vfs) = vfs(true);
+        let path = repo_path("socket");
+        let _listener = UnixListener::bind(vfs.join(path)).unwrap();
+        assert!(vfs
+            .write(path, b"data", UpdateFlag::Regular)
+            .unwrap_err()
+            .is::<UnsupportedFile>());
+
+        vfs.set_special_file_policy(Policy::Skip);
+        let mut report = CheckoutReport::default();
+        let outcome = vfs.write(path, b"data", UpdateFlag::Regular).unwrap();
+        report.record(path.to_owned(), outcome);
+        let outcome = vfs
+            .write(repo_path("file"), b"data", UpdateFlag::Regular)
+            .unwrap();
+        report.record(repo_path_buf("file"), outcome);
+        assert_eq!(report.written_files, 1);
+        assert_eq!(report.written_bytes, 4);
+        assert_eq!(
+            report.warnings(),
+            vec!["skipped 'socket': a special file is in the way"]
+        );
+
+        vfs.set_special_file_policy(Policy::Materialize);
+        vfs.write(path, b"data", UpdateFlag::Regular).unwrap();
+        assert_eq!(fs::read(vfs.join(path)).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("skip".parse::<Policy>().unwrap(), Policy::Skip);
+        assert_eq!(
+            "materialize".parse::<Policy>().unwrap(),
+            Policy::Materialize
+        );
+        assert!("ignore".parse::<Policy>().is_err());
+    }
+}
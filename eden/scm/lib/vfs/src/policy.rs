@@ -0,0 +1,75 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+use thiserror::Error;
+
+use types::RepoPathBuf;
+
+/// What to do with a file that cannot be checked out as-is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Write the file anyway. Symlinks are written as regular files containing their target,
+    /// and special files are replaced.
+    Materialize,
+    /// Leave the file alone, and report it as skipped.
+    Skip,
+    /// Fail the write.
+    Error,
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+
+    /// Parses a policy from a config value: "materialize", "skip" or "error".
+    fn from_str(s: &str) -> Result<Self> {
+        let policy = match s {
+            "materialize" => Policy::Materialize,
+            "skip" => Policy::Skip,
+            "error" => Policy::Error,
+            _ => bail!("invalid file policy '{}'", s),
+        };
+        Ok(policy)
+    }
+}
+
+/// Files that policies apply to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    /// A symlink to write on a filesystem without symlink support.
+    Symlink,
+    /// A fifo, socket or device in the working copy, where a file is to be written.
+    Special,
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileKind::Symlink => write!(f, "symlinks are not supported"),
+            FileKind::Special => write!(f, "a special file is in the way"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("cannot write '{path}': {kind}")]
+pub struct UnsupportedFile {
+    pub path: RepoPathBuf,
+    pub kind: FileKind,
+}
+
+/// The result of a successful write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteOutcome {
+    /// The file was written, with the given number of bytes.
+    Written(usize),
+    /// The file was not written because of a [`Policy::Skip`].
+    Skipped(FileKind),
+}
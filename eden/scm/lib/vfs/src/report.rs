@@ -0,0 +1,39 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use types::RepoPathBuf;
+
+use crate::{FileKind, WriteOutcome};
+
+/// A summary of the files written by a checkout.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckoutReport {
+    pub written_files: usize,
+    pub written_bytes: u64,
+    /// Files that were not written, and why.
+    pub skipped: Vec<(RepoPathBuf, FileKind)>,
+}
+
+impl CheckoutReport {
+    pub fn record(&mut self, path: RepoPathBuf, outcome: WriteOutcome) {
+        match outcome {
+            WriteOutcome::Written(bytes) => {
+                self.written_files += 1;
+                self.written_bytes += bytes as u64;
+            }
+            WriteOutcome::Skipped(kind) => self.skipped.push((path, kind)),
+        }
+    }
+
+    /// Warnings to show about skipped files.
+    pub fn warnings(&self) -> Vec<String> {
+        self.skipped
+            .iter()
+            .map(|(path, kind)| format!("skipped '{}': {}", path, kind))
+            .collect()
+    }
+}
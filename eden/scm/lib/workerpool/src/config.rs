@@ -0,0 +1,76 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::thread;
+
+/// Sizing of a [`WorkerPool`](crate::WorkerPool).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// The number of worker threads. Always at least 1.
+    pub threads: usize,
+}
+
+impl Default for PoolConfig {
+    /// One thread per CPU.
+    fn default() -> Self {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        PoolConfig { threads }
+    }
+}
+
+impl PoolConfig {
+    pub fn with_threads(threads: usize) -> Self {
+        PoolConfig {
+            threads: threads.max(1),
+        }
+    }
+
+    /// Reads the config of the pool used by `command`. `get(section, name)` looks up a config
+    /// value.
+    ///
+    /// The number of threads is `worker.<command>.threads`, falling back to `worker.threads`,
+    /// then to the number of CPUs. Invalid values are ignored.
+    pub fn from_config(command: &str, get: impl Fn(&str, &str) -> Option<String>) -> Self {
+        let threads = |name: &str| -> Option<usize> {
+            get("worker", name)?
+                .trim()
+                .parse()
+                .ok()
+                .filter(|&threads| threads > 0)
+        };
+        match threads(&format!("{}.threads", command)).or_else(|| threads("threads")) {
+            Some(threads) => PoolConfig::with_threads(threads),
+            None => PoolConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_from_config() {
+        let mut config = HashMap::new();
+        let from_config = |config: &HashMap<&str, &str>, command| {
+            PoolConfig::from_config(command, |section, name| {
+                assert_eq!(section, "worker");
+                config.get(name).map(|value| value.to_string())
+            })
+        };
+        assert_eq!(from_config(&config, "status"), PoolConfig::default());
+
+        config.insert("threads", "4");
+        config.insert("status.threads", "16");
+        config.insert("checkout.threads", "x");
+        assert_eq!(from_config(&config, "status").threads, 16);
+        assert_eq!(from_config(&config, "checkout").threads, 4);
+        assert_eq!(from_config(&config, "prefetch").threads, 4);
+    }
+}
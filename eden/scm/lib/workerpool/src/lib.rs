@@ -0,0 +1,23 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! workerpool - A shared pool of worker threads.
+//!
+//! Commands that do parallel work, like walking the working copy, run it on a [`WorkerPool`]
+//! instead of spawning their own threads. The number of threads comes from a [`PoolConfig`],
+//! which can be read from the repository config per command, so concurrency is tuned in one
+//! place.
+//!
+//! Jobs are queued in [`Priority`] lanes. Idle workers always take the oldest job of the
+//! highest non-empty lane, so that latency sensitive work is not stuck behind background work
+//! like prefetching.
+
+mod config;
+mod pool;
+
+pub use crate::config::PoolConfig;
+pub use crate::pool::{JobPanicked, Priority, WorkerPool};
@@ -0,0 +1,246 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::PoolConfig;
+
+/// The lane a job is queued in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Work a user is waiting for, like materializing files during checkout.
+    High = 0,
+    Normal = 1,
+    /// Background work, like prefetching trees that might be needed later.
+    Low = 2,
+}
+
+/// Returned by [`WorkerPool::map`] when `f` panicked on one of the items, so that its result
+/// is missing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JobPanicked;
+
+impl fmt::Display for JobPanicked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "worker job panicked")
+    }
+}
+
+impl Error for JobPanicked {}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct State {
+    lanes: [VecDeque<Job>; 3],
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+/// A fixed number of threads running queued jobs.
+///
+/// Dropping the pool waits for the queued jobs to finish.
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(config: PoolConfig) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                lanes: Default::default(),
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+        });
+        let workers = (0..config.threads.max(1))
+            .map(|i| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("worker-{}", i))
+                    .spawn(move || work(&shared))
+                    .expect("failed to spawn worker thread")
+            })
+            .collect();
+        WorkerPool { shared, workers }
+    }
+
+    /// The number of worker threads.
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Queues `job` to run on a worker thread.
+    ///
+    /// A panic in `job` is caught, so that the worker keeps running other jobs.
+    pub fn spawn(&self, priority: Priority, job: impl FnOnce() + Send + 'static) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.lanes[priority as usize].push_back(Box::new(job));
+        drop(state);
+        self.shared.available.notify_one();
+    }
+
+    /// Runs `f` on each item on the worker threads, and returns the results in the order of
+    /// the items.
+    ///
+    /// This blocks until all items are processed, so it must not be called from a job running
+    /// on the same pool: if all workers wait for each other, nothing makes progress.
+    ///
+    /// Returns [`JobPanicked`] if `f` panics on any item. The other items are still processed.
+    pub fn map<T, R, F>(
+        &self,
+        priority: Priority,
+        items: impl IntoIterator<Item = T>,
+        f: F,
+    ) -> Result<Vec<R>, JobPanicked>
+    where
+        T: Send + 'static,
+        R: Send + 'static,
+        F: Fn(T) -> R + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let (sender, receiver) = mpsc::channel();
+        let mut count = 0;
+        for (i, item) in items.into_iter().enumerate() {
+            let f = f.clone();
+            let sender = sender.clone();
+            self.spawn(priority, move || {
+                let _ = sender.send((i, f(item)));
+            });
+            count += 1;
+        }
+        drop(sender);
+
+        let mut results: Vec<Option<R>> = (0..count).map(|_| None).collect();
+        // Senders are dropped without sending if `f` panics, so this ends once every job is done.
+        for (i, result) in receiver {
+            results[i] = Some(result);
+        }
+        results
+            .into_iter()
+            .map(|result| result.ok_or(JobPanicked))
+            .collect()
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.available.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work(shared: &Shared) {
+    loop {
+        let job = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if let Some(job) = state.lanes.iter_mut().find_map(|lane| lane.pop_front()) {
+                    break job;
+                }
+                if state.shutdown {
+                    return;
+                }
+                state = shared.available.wait(state).unwrap();
+            }
+        };
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_map() {
+        let pool = WorkerPool::new(PoolConfig::with_threads(4));
+        assert_eq!(pool.threads(), 4);
+        let results = pool.map(Priority::Normal, 0..100, |i| i * 2).unwrap();
+        assert_eq!(results, (0..100).map(|i| i * 2).collect::<Vec<_>>());
+        assert!(pool
+            .map(Priority::Normal, Vec::<usize>::new(), |i| i)
+            .unwrap()
+            .is_empty());
+
+        let results = pool.map(Priority::Normal, 0..10, |i| {
+            if i == 5 {
+                panic!("job failed");
+            }
+            i
+        });
+        assert_eq!(results, Err(JobPanicked));
+    }
+
+    #[test]
+    fn test_priority_lanes() {
+        let pool = WorkerPool::new(PoolConfig::with_threads(1));
+
+        // Block the only worker while the other jobs are queued.
+        let barrier = Arc::new(Barrier::new(2));
+        let worker_barrier = barrier.clone();
+        pool.spawn(Priority::Normal, move || {
+            worker_barrier.wait();
+        });
+
+        let (sender, receiver) = mpsc::channel();
+        for &priority in &[
+            Priority::Low,
+            Priority::Normal,
+            Priority::High,
+            Priority::Low,
+        ] {
+            let sender = sender.clone();
+            pool.spawn(priority, move || sender.send(priority).unwrap());
+        }
+        drop(sender);
+        barrier.wait();
+        drop(pool);
+
+        let order: Vec<Priority> = receiver.iter().collect();
+        assert_eq!(
+            order,
+            vec![
+                Priority::High,
+                Priority::Normal,
+                Priority::Low,
+                Priority::Low
+            ]
+        );
+    }
+
+    #[test]
+    fn test_panic() {
+        let pool = WorkerPool::new(PoolConfig::with_threads(1));
+        pool.spawn(Priority::High, || panic!("job failed"));
+
+        // The worker survives the panic.
+        let count = Arc::new(AtomicUsize::new(0));
+        let job_count = count.clone();
+        pool.spawn(Priority::High, move || {
+            job_count.fetch_add(1, Ordering::SeqCst);
+        });
+        drop(pool);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
@@ -8,11 +8,13 @@
 use std::fs::{self, DirEntry};
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 use pathmatcher::{DirectoryMatch, Matcher};
 use types::{RepoPath, RepoPathBuf};
+use workerpool::{Priority, WorkerPool};
 
 /// Walker traverses the working copy, starting at the root of the repo,
 /// finding files matched by matcher
@@ -41,25 +43,10 @@ where
     }
 
     fn match_entry(&mut self, next_dir: &RepoPathBuf, entry: io::Result<DirEntry>) -> Result<()> {
-        let entry = entry?;
-        let filename = entry.file_name();
-        let filename = RepoPath::from_str(filename.to_str().unwrap())?;
-        let filetype = entry.file_type()?;
-        let mut candidate_path = next_dir.clone();
-        candidate_path.push(filename);
-        if filetype.is_file() || filetype.is_symlink() {
-            if self.matcher.matches_file(candidate_path.as_repo_path()) {
-                self.file_matches.push(Ok(candidate_path));
-            }
-        } else if filetype.is_dir() {
-            if filename.as_str() != ".hg"
-                && self
-                    .matcher
-                    .matches_directory(candidate_path.as_repo_path())
-                    != DirectoryMatch::Nothing
-            {
-                self.dir_matches.push(candidate_path);
-            }
+        match match_entry(&self.matcher, next_dir, entry)? {
+            Some(Match::File(path)) => self.file_matches.push(Ok(path)),
+            Some(Match::Directory(path)) => self.dir_matches.push(path),
+            None => {}
         }
         Ok(())
     }
@@ -78,6 +65,91 @@ where
     }
 }
 
+enum Match {
+    File(RepoPathBuf),
+    Directory(RepoPathBuf),
+}
+
+fn match_entry(
+    matcher: &impl Matcher,
+    dir: &RepoPath,
+    entry: io::Result<DirEntry>,
+) -> Result<Option<Match>> {
+    let entry = entry?;
+    let filename = entry.file_name();
+    let filename = filename
+        .to_str()
+        .ok_or_else(|| anyhow!("{:?} in '{}' is not valid UTF-8", filename, dir))?;
+    let filename = RepoPath::from_str(filename)?;
+    let filetype = entry.file_type()?;
+    let mut candidate_path = dir.to_owned();
+    candidate_path.push(filename);
+    if filetype.is_file() || filetype.is_symlink() {
+        if matcher.matches_file(candidate_path.as_repo_path()) {
+            return Ok(Some(Match::File(candidate_path)));
+        }
+    } else if filetype.is_dir()
+        && filename.as_str() != ".hg"
+        && matcher.matches_directory(candidate_path.as_repo_path()) != DirectoryMatch::Nothing
+    {
+        return Ok(Some(Match::Directory(candidate_path)));
+    }
+    Ok(None)
+}
+
+/// Finds the files matched by `matcher` like [`Walker`], reading the directories of each level
+/// of the working copy concurrently on `pool`.
+///
+/// Unlike [`Walker`], this is not lazy: all matching files are returned at once, in no
+/// particular order. Errors reading a directory or an entry, like a file name that is not
+/// valid UTF-8, are returned with the files, and the rest of the working copy is still walked.
+pub fn walk_parallel<M>(
+    root: PathBuf,
+    matcher: Arc<M>,
+    pool: &WorkerPool,
+) -> Result<Vec<Result<RepoPathBuf>>>
+where
+    M: Matcher + Send + Sync + 'static,
+{
+    let root = Arc::new(root);
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    if matcher.matches_directory(&RepoPathBuf::new()) != DirectoryMatch::Nothing {
+        dirs.push(RepoPathBuf::new());
+    }
+    while !dirs.is_empty() {
+        let root = root.clone();
+        let matcher = matcher.clone();
+        let results = pool.map(Priority::Normal, dirs, move |dir| -> io::Result<_> {
+            let matches: Vec<_> = fs::read_dir(root.join(dir.as_str()))?
+                .map(|entry| match_entry(&*matcher, &dir, entry))
+                .collect();
+            Ok(matches)
+        })?;
+
+        let mut next_dirs = Vec::new();
+        for matches in results {
+            let matches = match matches {
+                Ok(matches) => matches,
+                Err(e) => {
+                    files.push(Err(e.into()));
+                    continue;
+                }
+            };
+            for entry in matches {
+                match entry {
+                    Ok(Some(Match::File(path))) => files.push(Ok(path)),
+                    Ok(Some(Match::Directory(path))) => next_dirs.push(path),
+                    Ok(None) => {}
+                    Err(e) => files.push(Err(e)),
+                }
+            }
+        }
+        dirs = next_dirs;
+    }
+    Ok(files)
+}
+
 impl<M> Iterator for Walker<M>
 where
     M: Matcher,
@@ -101,6 +173,7 @@ mod tests {
     use tempfile::tempdir;
 
     use pathmatcher::{AlwaysMatcher, NeverMatcher};
+    use workerpool::PoolConfig;
 
     fn create_directory(
         directories: &std::vec::Vec<&str>,
@@ -147,4 +220,50 @@ mod tests {
         assert!(walked_files.is_empty());
         Ok(())
     }
+
+    #[test]
+    fn test_walk_parallel() -> Result<()> {
+        let directories = vec!["dirA", "dirB/dirC/dirD", ".hg/store"];
+        let files = vec![
+            "a.txt",
+            "dirA/a.txt",
+            "dirA/b.txt",
+            "dirB/dirC/dirD/c.txt",
+            ".hg/store/data",
+        ];
+        let root_dir = create_directory(&directories, &files)?;
+        let root_path = PathBuf::from(root_dir.path());
+        let pool = WorkerPool::new(PoolConfig::with_threads(2));
+        let walked_files = walk_parallel(root_path, Arc::new(AlwaysMatcher::new()), &pool)?;
+        let mut walked_files = walked_files
+            .into_iter()
+            .map(|file| Ok(file?.into_string()))
+            .collect::<Result<Vec<_>>>()?;
+        walked_files.sort();
+        assert_eq!(
+            walked_files,
+            vec!["a.txt", "dirA/a.txt", "dirA/b.txt", "dirB/dirC/dirD/c.txt"]
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_walk_parallel_non_utf8() -> Result<()> {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root_dir = create_directory(&vec!["dirA"], &vec!["dirA/a.txt"])?;
+        fs::write(root_dir.path().join(OsStr::from_bytes(b"dirA/\xff")), b"")?;
+        let pool = WorkerPool::new(PoolConfig::with_threads(2));
+        let walked_files = walk_parallel(
+            PathBuf::from(root_dir.path()),
+            Arc::new(AlwaysMatcher::new()),
+            &pool,
+        )?;
+        let (files, errors): (Vec<_>, Vec<_>) = walked_files.into_iter().partition(Result::is_ok);
+        assert_eq!(files.len(), 1);
+        assert_eq!(errors.len(), 1);
+        Ok(())
+    }
 }